@@ -0,0 +1,140 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// Distinguishes temp files of concurrent writes to the same path.
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A file that replaces `path` only once it is complete. Writes go to a
+/// temp file in the same directory; `commit` fsyncs it, renames it over
+/// `path` and fsyncs the directory, so readers, and the file system after
+/// a crash, see either the old content or all of the new. Dropping it
+/// without committing leaves `path` untouched.
+pub struct AtomicFile {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: Option<File>,
+    // Set once the temp file has been renamed into place, after which
+    // there is nothing left to clean up.
+    committed: bool,
+}
+
+impl AtomicFile {
+    /// Starts replacing `path`, creating its parent directories if needed.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let directory = parent_directory(&path);
+        fs::create_dir_all(directory)?;
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a file path", path.display())))?;
+        let temp_name = format!(
+            ".{}.tmp-{}-{}",
+            file_name.to_string_lossy(),
+            process::id(),
+            NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let temp_path = directory.join(temp_name);
+        let file = File::create(&temp_path)?;
+        Ok(Self {
+            path,
+            temp_path,
+            file: Some(file),
+            committed: false,
+        })
+    }
+
+    pub fn commit(mut self) -> io::Result<()> {
+        let file = self.file.take().expect("AtomicFile committed twice");
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&self.temp_path, &self.path)?;
+        self.committed = true;
+        sync_directory(parent_directory(&self.path))
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().expect("AtomicFile already committed").write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().expect("AtomicFile already committed").flush()
+    }
+}
+
+// Also runs when `commit` fails, before or at the rename.
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+/// Replaces `path` with `data` through an `AtomicFile`.
+pub fn write_atomic(path: impl AsRef<Path>, data: &[u8]) -> io::Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(data)?;
+    file.commit()
+}
+
+/// Makes renames and removals in `directory` durable. Only Unix can open a
+/// directory to fsync it; elsewhere this does nothing.
+pub fn sync_directory(directory: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(directory)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = directory;
+    Ok(())
+}
+
+fn parent_directory(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scratch::ScratchDir;
+
+    #[test]
+    fn a_failed_commit_leaves_no_temp_file() {
+        let dir = ScratchDir::new("atomic-file-failed-commit");
+        let path = dir.join("file");
+        fs::write(&path, b"original").unwrap();
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"replacement").unwrap();
+        // Pointing it at a directory instead makes the rename fail.
+        let temp_path = file.temp_path.clone();
+        file.path = dir.join("blocked");
+        fs::create_dir_all(file.path.join("inside")).unwrap();
+        assert!(file.commit().is_err());
+
+        assert!(!temp_path.exists());
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+        let mut names: Vec<String> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        assert_eq!(names, ["blocked", "file"]);
+    }
+
+    #[test]
+    fn dropping_without_commit_keeps_the_original() {
+        let dir = ScratchDir::new("atomic-file-dropped");
+        let path = dir.join("file");
+        fs::write(&path, b"original").unwrap();
+        {
+            let mut file = AtomicFile::create(&path).unwrap();
+            file.write_all(b"replacement").unwrap();
+        }
+        assert_eq!(fs::read(&path).unwrap(), b"original");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    }
+}
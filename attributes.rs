@@ -0,0 +1,52 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::atomic_file::write_atomic;
+use super::paths::SafePath;
+
+/// Tools that store files directly in a directory tree keep each file's
+/// attributes in a JSON sidecar at the same place below this directory.
+pub const ATTRIBUTES_DIR: &str = ".attributes";
+
+/// What users record about a file beyond its contents. The server keeps
+/// them in its metadata and sends them along with copies; tools that store
+/// files in a directory tree keep them in a sidecar, and a file without one
+/// has none of them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileAttributes {
+    /// Overrides the content type guessed from the file's name.
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// Arbitrary key/value pairs set by users.
+    #[serde(default)]
+    pub custom: BTreeMap<String, String>,
+}
+
+impl FileAttributes {
+    pub fn read(storage_dir: impl AsRef<Path>, path: &SafePath) -> io::Result<Self> {
+        match fs::read(sidecar(storage_dir.as_ref(), path)) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn write(&self, storage_dir: impl AsRef<Path>, path: &SafePath) -> io::Result<()> {
+        let sidecar = sidecar(storage_dir.as_ref(), path);
+        if let Some(parent) = sidecar.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_vec(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        write_atomic(&sidecar, &data)
+    }
+}
+
+fn sidecar(storage_dir: &Path, path: &SafePath) -> PathBuf {
+    path.under(storage_dir.join(ATTRIBUTES_DIR))
+}
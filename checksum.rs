@@ -0,0 +1,29 @@
+use sha2::{Digest, Sha256};
+
+/// Hex-encoded SHA-256 of `data`.
+pub fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// Incrementally hashes data that is too large to hold in memory at once.
+pub struct StreamingChecksum {
+    hasher: Sha256,
+}
+
+impl StreamingChecksum {
+    pub fn new() -> Self {
+        Self { hasher: Sha256::new() }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+    }
+
+    pub fn finish(self) -> String {
+        to_hex(&self.hasher.finalize())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
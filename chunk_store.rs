@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use super::atomic_file::write_atomic;
+use super::checksum::{sha256_hex, StreamingChecksum};
+
+pub const CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Describes a stored file as the ordered list of chunks that make it up.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    pub size: u64,
+    pub chunks: Vec<String>,
+}
+
+/// Content-addressed chunk storage. Each chunk lives at
+/// `root/<first two hex digits>/<sha256>`, so identical data written under
+/// any number of file names is kept on disk once. Reference counts track
+/// how many manifests (committed or still being written) point at a chunk;
+/// a chunk is removed when its count drops to zero.
+pub struct ChunkStore {
+    root: PathBuf,
+    ref_counts: HashMap<String, u64>,
+}
+
+impl ChunkStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            ref_counts: HashMap::new(),
+        }
+    }
+
+    /// Stores `data` if no intact identical chunk exists yet and takes a
+    /// reference on it.
+    pub fn put(&mut self, data: &[u8]) -> io::Result<String> {
+        let hash = sha256_hex(data);
+        // An existing chunk is only shared once it is verified; a damaged
+        // one is replaced with `data`, which every file using it needs.
+        if !is_intact(&self.root, &hash)? {
+            write_atomic(self.chunk_path(&hash), data)?;
+        }
+        *self.ref_counts.entry(hash.clone()).or_insert(0) += 1;
+        Ok(hash)
+    }
+
+    /// Takes a reference on chunks that are already on disk, e.g. when
+    /// rebuilding counts from existing manifests.
+    pub fn retain(&mut self, hashes: &[String]) {
+        for hash in hashes {
+            *self.ref_counts.entry(hash.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Drops a reference on each chunk, deleting chunks nothing points at.
+    pub fn release(&mut self, hashes: &[String]) -> io::Result<()> {
+        for hash in hashes {
+            let remaining = match self.ref_counts.get_mut(hash) {
+                Some(count) => {
+                    *count = count.saturating_sub(1);
+                    *count
+                },
+                None => 0,
+            };
+            if remaining == 0 {
+                self.ref_counts.remove(hash);
+                match fs::remove_file(self.chunk_path(hash)) {
+                    Ok(()) => {},
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts back a damaged or missing chunk with `data` fetched elsewhere.
+    /// Chunks nothing refers to any more are left alone; returns whether
+    /// the chunk was written.
+    pub fn repair(&mut self, hash: &str, data: &[u8]) -> io::Result<bool> {
+        if sha256_hex(data) != hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Repair data for chunk {} failed checksum verification", hash)));
+        }
+        if !self.ref_counts.contains_key(hash) {
+            return Ok(false);
+        }
+        write_atomic(self.chunk_path(hash), data)?;
+        Ok(true)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn chunk_path(&self, hash: &str) -> PathBuf {
+        chunk_path(&self.root, hash)
+    }
+}
+
+pub fn chunk_path(root: &Path, hash: &str) -> PathBuf {
+    let prefix = if hash.len() >= 2 { &hash[..2] } else { hash };
+    root.join(prefix).join(hash)
+}
+
+/// Reads a chunk and checks it still hashes to its name.
+pub fn read_chunk(root: &Path, hash: &str) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    File::open(chunk_path(root, hash))?.read_to_end(&mut data)?;
+    if sha256_hex(&data) != hash {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Chunk {} failed checksum verification", hash)));
+    }
+    Ok(data)
+}
+
+// Whether a chunk is on disk and still hashes to its name.
+fn is_intact(root: &Path, hash: &str) -> io::Result<bool> {
+    match read_chunk(root, hash) {
+        Ok(_) => Ok(true),
+        Err(ref e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::InvalidData) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Reads a file back by walking its manifest one chunk at a time. Every
+/// chunk is verified as it is loaded, and the whole-file checksum is checked
+/// once the last byte has been read.
+pub struct ChunkedReader {
+    root: PathBuf,
+    chunks: std::vec::IntoIter<String>,
+    current: Vec<u8>,
+    position: usize,
+    checksum: Option<StreamingChecksum>,
+    expected_checksum: String,
+}
+
+impl ChunkedReader {
+    pub fn new(root: &Path, manifest: Manifest, expected_checksum: &str) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            chunks: manifest.chunks.into_iter(),
+            current: Vec::new(),
+            position: 0,
+            checksum: Some(StreamingChecksum::new()),
+            expected_checksum: expected_checksum.to_string(),
+        }
+    }
+
+    fn verify_file_checksum(&mut self) -> io::Result<()> {
+        if let Some(checksum) = self.checksum.take() {
+            let actual = checksum.finish();
+            if actual != self.expected_checksum {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("File checksum mismatch: expected {}, got {}", self.expected_checksum, actual),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for ChunkedReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.current.len() {
+            match self.chunks.next() {
+                Some(hash) => {
+                    self.current = read_chunk(&self.root, &hash)?;
+                    self.position = 0;
+                    if let Some(checksum) = self.checksum.as_mut() {
+                        checksum.update(&self.current);
+                    }
+                },
+                None => {
+                    self.verify_file_checksum()?;
+                    return Ok(0);
+                },
+            }
+        }
+
+        let count = buf.len().min(self.current.len() - self.position);
+        buf[..count].copy_from_slice(&self.current[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scratch::ScratchDir;
+    use std::slice;
+
+    fn scratch_store(name: &str) -> (ScratchDir, ChunkStore) {
+        let dir = ScratchDir::new(&format!("chunk-store-{}", name));
+        let store = ChunkStore::new(&dir);
+        (dir, store)
+    }
+
+    #[test]
+    fn a_chunk_repeated_in_one_file_is_stored_once_and_counted_twice() {
+        let (_dir, mut store) = scratch_store("repeated");
+        let first = store.put(b"same").unwrap();
+        let second = store.put(b"same").unwrap();
+        assert_eq!(first, second);
+        assert_eq!(store.ref_counts[&first], 2);
+
+        store.release(slice::from_ref(&first)).unwrap();
+        assert_eq!(read_chunk(store.root(), &first).unwrap(), b"same");
+        store.release(slice::from_ref(&first)).unwrap();
+        assert!(!store.chunk_path(&first).exists());
+        assert!(store.ref_counts.is_empty());
+    }
+
+    #[test]
+    fn chunks_shared_across_files_stay_until_the_last_release() {
+        let (_dir, mut store) = scratch_store("shared");
+        let one = vec![store.put(b"shared").unwrap(), store.put(b"only one").unwrap()];
+        let other = vec![store.put(b"shared").unwrap()];
+
+        store.release(&one).unwrap();
+        assert!(store.chunk_path(&other[0]).exists());
+        assert!(!store.chunk_path(&one[1]).exists());
+        store.release(&other).unwrap();
+        assert!(!store.chunk_path(&other[0]).exists());
+    }
+
+    #[test]
+    fn retained_chunks_are_released_like_stored_ones() {
+        let (_dir, mut store) = scratch_store("retained");
+        let hash = store.put(b"content").unwrap();
+        let mut reopened = ChunkStore::new(store.root());
+        reopened.retain(slice::from_ref(&hash));
+        reopened.release(slice::from_ref(&hash)).unwrap();
+        assert!(!store.chunk_path(&hash).exists());
+        // Releasing what nothing holds is not an error.
+        reopened.release(&[hash]).unwrap();
+    }
+
+    #[test]
+    fn a_damaged_chunk_is_replaced_instead_of_shared() {
+        let (_dir, mut store) = scratch_store("damaged");
+        let hash = store.put(b"original").unwrap();
+        fs::write(store.chunk_path(&hash), b"bit rot").unwrap();
+
+        assert_eq!(store.put(b"original").unwrap(), hash);
+        assert_eq!(read_chunk(store.root(), &hash).unwrap(), b"original");
+        assert_eq!(store.ref_counts[&hash], 2);
+    }
+}
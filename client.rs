@@ -1,9 +1,31 @@
+use std::collections::BTreeMap;
 use std::env;
+use std::error::Error;
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
 use serde::{Serialize, Deserialize};
-use serde_json;
+
+// Shared with the server; the client only needs part of each.
+#[allow(dead_code)]
+mod atomic_file;
+#[allow(dead_code)]
+mod attributes;
+#[allow(dead_code)]
+mod checksum;
+#[allow(dead_code)]
+mod paths;
+#[allow(dead_code)]
+mod protocol;
+#[cfg(test)]
+mod scratch;
+
+use atomic_file::AtomicFile;
+use checksum::StreamingChecksum;
+use protocol::{read_message, write_message, ChunkReader, ChunkWriter, Command, ErasureScheme, ServerResponse, CHUNK_SIZE};
+
+const RESPONSE_TIMEOUT_SECS: u64 = 30;
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ClientConfig {
@@ -11,22 +33,8 @@ struct ClientConfig {
     server_port: u16,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-#[serde(tag = "type", content = "content")]
-enum Command {
-    ListFiles,
-    UploadFile { filename: String, contents: Vec<u8> },
-    DownloadFile { filename: String },
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct ServerResponse {
-    message: String,
-    file_contents: Option<Vec<u8>>,
-}
-
 impl ClientConfig {
-    fn new() -> Result<ClientConfig, env::VarError> {
+    fn new() -> Result<ClientConfig, Box<dyn Error>> {
         let server_address = env::var("SERVER_ADDRESS")?;
         let server_port = env::var("SERVER_PORT")?.parse()?;
         Ok(ClientConfig { server_address, server_port })
@@ -36,20 +44,111 @@ impl ClientConfig {
         let addr = format!("{}:{}", self.server_address, self.server_port)
             .to_socket_addrs()?
             .next()
-            .expect("Unable to resolve server address");
-        TcpStream::connect(addr)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Unable to resolve server address"))?;
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(Duration::from_secs(RESPONSE_TIMEOUT_SECS)))?;
+        Ok(stream)
+    }
+
+    fn send_command(&self, command: &Command, stream: &mut TcpStream) -> io::Result<()> {
+        write_message(stream, command)
+    }
+
+    fn receive_response(&self, stream: &mut TcpStream) -> io::Result<ServerResponse> {
+        read_message(stream)?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "Server closed the connection")
+        })
+    }
+
+    fn request(&self, stream: &mut TcpStream, command: Command) -> io::Result<ServerResponse> {
+        self.send_command(&command, stream)?;
+        self.receive_response(stream)
+    }
+
+    /// `erasure` asks for the file to be erasure-coded rather than
+    /// replicated; `None` leaves it to the server's settings.
+    fn upload_file(
+        &self,
+        stream: &mut TcpStream,
+        local_path: &str,
+        filename: &str,
+        erasure: Option<ErasureScheme>,
+    ) -> io::Result<ServerResponse> {
+        // Hash the file up front so the server can reject the upload if
+        // anything was altered in transit.
+        let checksum = file_checksum(local_path)?;
+        let mut file = File::open(local_path)?;
+        let size = file.metadata()?.len();
+        let command = Command::UploadStream {
+            filename: filename.to_string(),
+            size,
+            checksum: Some(checksum),
+            erasure,
+            attributes: None,
+        };
+        self.send_command(&command, stream)?;
+
+        let mut chunks = ChunkWriter::new(stream);
+        io::copy(&mut file, &mut chunks)?;
+        chunks.finish()?;
+        self.receive_response(stream)
     }
 
-    fn send_command(&self, command: Command, stream: &TcpStream) -> io::Result<()> {
-        let serialized = serde_json::to_string(&command)?;
-        stream.write_all(serialized.as_bytes())
+    fn download_file(&self, stream: &mut TcpStream, filename: &str, local_path: &str) -> io::Result<ServerResponse> {
+        self.send_command(&Command::DownloadStream { filename: filename.to_string() }, stream)?;
+        let response = self.receive_response(stream)?;
+        if !response.is_ok() {
+            return Ok(response);
+        }
+
+        // The download only replaces `local_path` once its size and
+        // checksum have been verified.
+        let mut file = AtomicFile::create(local_path)?;
+        let mut chunks = ChunkReader::new(stream);
+        let mut checksum = StreamingChecksum::new();
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        let mut received = 0u64;
+        loop {
+            let count = chunks.read(&mut buffer)?;
+            if count == 0 {
+                break;
+            }
+            checksum.update(&buffer[..count]);
+            file.write_all(&buffer[..count])?;
+            received += count as u64;
+        }
+
+        if Some(received) != response.size {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected {:?} bytes, received {}", response.size, received)));
+        }
+        let actual = checksum.finish();
+        if response.checksum.as_ref() != Some(&actual) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Checksum mismatch for {}: expected {:?}, got {}", filename, response.checksum, actual),
+            ));
+        }
+        file.commit()?;
+        Ok(response)
     }
 
-    fn receive_response(&self, stream: &TcpStream) -> io::Result<ServerResponse> {
-        let mut response = String::new();
-        stream.read_to_string(&mut response)?;
-        let deserialized: ServerResponse = serde_json::from_str(&response)?;
-        Ok(deserialized)
+    /// Keeps an otherwise idle session from hitting the server's idle timeout.
+    fn keep_alive(&self, stream: &mut TcpStream) -> io::Result<()> {
+        self.request(stream, Command::Ping).map(|_| ())
+    }
+}
+
+fn file_checksum(path: &str) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut checksum = StreamingChecksum::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            return Ok(checksum.finish());
+        }
+        checksum.update(&buffer[..count]);
     }
 }
 
@@ -57,37 +156,82 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     let client_config = ClientConfig::new()?;
     println!("Loaded client configuration");
 
-    let stream = client_config.connect()?;
+    let mut stream = client_config.connect()?;
+    client_config.keep_alive(&mut stream)?;
 
-    let list_files_command = Command::ListFiles;
-    client_config.send_command(list_files_command, &stream)?;
-    
-    let response = client_config.receive_response(&stream)
-        .expect("Failed to receive response from server");
-    println!("Server Response: {:?}", response.message);
+    let response = client_config.request(&mut stream, Command::ListFiles)?;
+    println!("Server Response: {:?} {}", response.status, response.message);
+    if let Some(files) = response.files {
+        println!("Files on server: {:?}", files);
+    }
 
-    let mut file_contents = Vec::new();
-    let mut file = File::open("example_file.txt")?;
-    file.read_to_end(&mut file_contents)?;
-    let upload_file_command = Command::UploadFile {
-        filename: "example_file.txt".to_string(),
-        contents: file_contents,
+    let response = client_config.upload_file(&mut stream, "example_file.txt", "incoming/example_file.txt", None)?;
+    println!("Server Response: {:?} {}", response.status, response.message);
+
+    let rename = Command::Rename {
+        from: "incoming/example_file.txt".to_string(),
+        to: "example_file.txt".to_string(),
+        overwrite: true,
     };
-    
-    client_config.send_command(upload_file_command, &stream)?;
+    let response = client_config.request(&mut stream, rename)?;
+    println!("Server Response: {:?} {}", response.status, response.message);
 
-    let download_file_command = Command::DownloadFile {
+    let mut set = BTreeMap::new();
+    set.insert("project".to_string(), "example".to_string());
+    let attributes = Command::SetAttributes {
         filename: "example_file.txt".to_string(),
+        content_type: None,
+        owner: Some("example".to_string()),
+        set,
+        remove: Vec::new(),
     };
-    
-    client_config.send_command(download_file_command, &stream)?;
+    let response = client_config.request(&mut stream, attributes)?;
+    println!("Server Response: {:?} {}", response.status, response.message);
+    let response = client_config.request(&mut stream, Command::Stat { filename: "example_file.txt".to_string() })?;
+    if let Some(metadata) = response.metadata {
+        println!(
+            "{}: {} bytes, {}, owned by {:?}, {}/{} copies, attributes {:?}",
+            metadata.path, metadata.size, metadata.content_type, metadata.owner, metadata.replication.copies, metadata.replication.wanted,
+            metadata.attributes
+        );
+    }
 
-    let response = client_config.receive_response(&stream)
-        .expect("Failed to receive file from server");
-    
-    if let Some(contents) = response.file_contents {
-        println!("Received file contents: {:?}", contents);
+    let versions = Command::ListVersions { filename: "example_file.txt".to_string() };
+    let response = client_config.request(&mut stream, versions)?;
+    for version in response.versions.unwrap_or_default() {
+        println!("Version {}: {} bytes, modified at {}", version.version, version.size, version.modified_at);
     }
 
+    let response = client_config.request(&mut stream, Command::ListDirectory { path: String::new(), recursive: true })?;
+    for entry in response.entries.unwrap_or_default() {
+        println!("{:?} {} ({} bytes)", entry.kind, entry.path, entry.size);
+    }
+
+    let snapshot = Command::CreateSnapshot { name: "example".to_string(), path: String::new() };
+    let response = client_config.request(&mut stream, snapshot)?;
+    println!("Server Response: {:?} {}", response.status, response.message);
+    let response = client_config.request(&mut stream, Command::DiffSnapshot { name: "example".to_string() })?;
+    for change in response.changes.unwrap_or_default() {
+        println!("Changed since snapshot: {:?} {} ({:?})", change.kind, change.path, change.change);
+    }
+    let response = client_config.request(&mut stream, Command::DeleteSnapshot { name: "example".to_string() })?;
+    println!("Server Response: {:?} {}", response.status, response.message);
+
+    let delete = Command::DeleteFile { filename: "example_file.txt".to_string(), purge: false };
+    let response = client_config.request(&mut stream, delete)?;
+    println!("Server Response: {:?} {}", response.status, response.message);
+    let response = client_config.request(&mut stream, Command::ListTrash)?;
+    for trashed in response.trash.unwrap_or_default() {
+        println!("In the trash: {} ({} bytes), deleted at {}", trashed.filename, trashed.size, trashed.deleted_at);
+    }
+    let response = client_config.request(&mut stream, Command::Undelete { filename: "example_file.txt".to_string() })?;
+    println!("Server Response: {:?} {}", response.status, response.message);
+
+    let response = client_config.download_file(&mut stream, "example_file.txt", "example_file.downloaded.txt")?;
+    if !response.is_ok() {
+        return Err(format!("Download failed: {}", response.message).into());
+    }
+    println!("Downloaded {} bytes", response.size.unwrap_or(0));
+
     Ok(())
-}
\ No newline at end of file
+}
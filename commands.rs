@@ -1,24 +1,71 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::{self, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+
+mod atomic_file;
+mod attributes;
+// Shared with the server; the commands only need part of each.
+#[allow(dead_code)]
+mod checksum;
+mod content_type;
+#[allow(dead_code)]
+mod paths;
+#[cfg(test)]
+mod scratch;
+mod search;
+mod trash;
+
+use atomic_file::{write_atomic, AtomicFile};
+use attributes::{FileAttributes, ATTRIBUTES_DIR};
+use checksum::sha256_hex;
+use paths::{PathError, SafePath};
+use search::{Pattern, SearchScan, SearchQuery, SearchResult};
+use trash::{Trash, TRASH_DIR};
+
+// Checksums for stored files are kept beside them in a hidden directory so
+// directory scans over `storage_path` only see user files.
+const CHECKSUM_DIR: &str = ".checksums";
+// Hidden directories that mirror the stored tree and follow its files
+// through deletes and renames.
+const COMPANION_DIRS: &[&str] = &[CHECKSUM_DIR, ATTRIBUTES_DIR];
+const RESERVED_DIRS: &[&str] = &[CHECKSUM_DIR, ATTRIBUTES_DIR, TRASH_DIR];
 
 struct Config {
     storage_path: String,
+    trash: Trash,
 }
 
 impl Config {
     fn new() -> Self {
         let storage_path = env::var("STORAGE_PATH").expect("STORAGE_PATH must be set");
-        Config { storage_path }
+        let trash = Trash::from_env(&storage_path, COMPANION_DIRS).expect("Invalid trash settings");
+        Config { storage_path, trash }
     }
 }
 
 enum Command {
     Upload(String, Vec<u8>),
     Download(String),
+    /// Moves a file to the trash.
     Delete(String),
-    Search(String),
+    /// Brings back the most recently deleted file or directory at a path.
+    Undelete(String),
+    ListTrash,
+    /// Removes what has been in the trash longer than its retention period.
+    PurgeTrash,
+    Search(SearchQuery),
+    /// Sets one custom attribute of a file, which searches can filter on.
+    SetAttribute(String, String, String),
+    MakeDirectory(String),
+    /// Removes a directory; the flag allows removing everything in it.
+    RemoveDirectory(String, bool),
+    /// Lists a directory; the flag includes everything below it.
+    ListDirectory(String, bool),
+    /// Moves a file or directory; the flag allows replacing an existing
+    /// file, or an empty directory.
+    Rename(String, String, bool),
 }
 
 fn process_command(config: &Config, command: Command) -> Result<(), String> {
@@ -35,60 +82,454 @@ fn process_command(config: &Config, command: Command) -> Result<(), String> {
             println!("Deleting file: {}", filename);
             delete_file(config, filename).map_err(|e| format!("Deletion failed: {}", e))
         }
+        Command::Undelete(path) => {
+            println!("Restoring: {}", path);
+            undelete(config, path).map_err(|e| format!("Undelete failed: {}", e))
+        }
+        Command::ListTrash => {
+            println!("Listing trash");
+            list_trash(config).map_err(|e| format!("Listing trash failed: {}", e))
+        }
+        Command::PurgeTrash => {
+            println!("Purging trash");
+            purge_trash(config).map_err(|e| format!("Purging trash failed: {}", e))
+        }
         Command::Search(query) => {
-            println!("Searching for files containing: {}", query);
-            search_files(config, query).map_err(|e| format!("Search failed: {}", e))
+            println!("Searching /{}", query.scope);
+            let results = search_files(config, &query).map_err(|e| format!("Search failed: {}", e))?;
+            if results.is_empty() {
+                println!("No files found matching the query.");
+            }
+            for result in results {
+                println!("Found: /{} ({} bytes, {})", result.path, result.size, result.content_type);
+                for found in result.matches {
+                    println!("    {}: {}", found.line, found.text);
+                }
+            }
+            Ok(())
+        }
+        Command::SetAttribute(filename, key, value) => {
+            println!("Setting {} on {}", key, filename);
+            set_attribute(config, filename, key, value).map_err(|e| format!("Setting attribute failed: {}", e))
+        }
+        Command::MakeDirectory(path) => {
+            println!("Creating directory: {}", path);
+            make_directory(config, path).map_err(|e| format!("Creating directory failed: {}", e))
+        }
+        Command::RemoveDirectory(path, recursive) => {
+            println!("Removing directory: {}", path);
+            remove_directory(config, path, recursive).map_err(|e| format!("Removing directory failed: {}", e))
+        }
+        Command::ListDirectory(path, recursive) => {
+            println!("Listing directory: {}", path);
+            list_directory(config, path, recursive).map_err(|e| format!("Listing directory failed: {}", e))
+        }
+        Command::Rename(from, to, overwrite) => {
+            println!("Renaming {} to {}", from, to);
+            rename(config, from, to, overwrite).map_err(|e| format!("Rename failed: {}", e))
         }
     }
 }
 
 fn upload_file(config: &Config, filename: String, data: Vec<u8>) -> io::Result<()> {
-    let path = Path::new(&config.storage_path).join(&filename);
-    let mut file = File::create(path).map_err(|e| io::Error::new(e.kind(), format!("Failed to create file {}: {}", filename, e)))?;
+    let filename = SafePath::file(&filename)?;
+    let path = local_path(config, &filename)?;
+    // The new content only replaces the old once it is completely on disk,
+    // and only after its checksum is: if anything fails before then, the
+    // old content stays, and a crash between the two shows up as a
+    // mismatch on download rather than as unchecked data.
+    let mut file = AtomicFile::create(path).map_err(|e| io::Error::new(e.kind(), format!("Failed to create file {}: {}", filename, e)))?;
     file.write_all(&data).map_err(|e| io::Error::new(e.kind(), format!("Failed to write to file {}: {}", filename, e)))?;
+    write_atomic(checksum_path(config, &filename), sha256_hex(&data).as_bytes())
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to record checksum for {}: {}", filename, e)))?;
+    file.commit().map_err(|e| io::Error::new(e.kind(), format!("Failed to write to file {}: {}", filename, e)))?;
     println!("Upload successful.");
     Ok(())
 }
 
 fn download_file(config: &Config, filename: String) -> io::Result<()> {
-    let path = Path::new(&config.storage_path).join(&filename);
+    let filename = SafePath::file(&filename)?;
+    let path = local_path(config, &filename)?;
     let mut file = File::open(path).map_err(|e| io::Error::new(e.kind(), format!("Failed to open file {}: {}", filename, e)))?;
     let mut data = Vec::new();
     file.read_to_end(&mut data).map_err(|e| io::Error::new(e.kind(), format!("Failed to read file {}: {}", filename, e)))?;
+    let expected = std::fs::read_to_string(checksum_path(config, &filename))
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to read checksum for {}: {}", filename, e)))?;
+    let actual = sha256_hex(&data);
+    if expected.trim() != actual {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Checksum mismatch for {}: expected {}, got {}", filename, expected.trim(), actual)));
+    }
     println!("Download successful. Data size: {} bytes", data.len());
     Ok(())
 }
 
 fn delete_file(config: &Config, filename: String) -> io::Result<()> {
-    let path = Path::new(&config.storage_path).join(&filename);
-    std::fs::remove_file(path).map_err(|e| io::Error::new(e.kind(), format!("Failed to delete file {}: {}", filename, e)))?;
-    println!("File deleted successfully.");
+    let filename = SafePath::file(&filename)?;
+    if local_path(config, &filename)?.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::IsADirectory, format!("{} is a directory", filename)));
+    }
+    // The checksum goes to the trash with the file.
+    config.trash.delete(&filename).map_err(|e| io::Error::new(e.kind(), format!("Failed to delete file {}: {}", filename, e)))?;
+    println!("File moved to the trash.");
+    Ok(())
+}
+
+fn undelete(config: &Config, path: String) -> io::Result<()> {
+    let path = SafePath::file(&path)?;
+    // Only for the check against the reserved directories.
+    local_path(config, &path)?;
+    config.trash.restore(&path).map_err(|e| io::Error::new(e.kind(), format!("Failed to restore {}: {}", path, e)))?;
+    println!("Restored.");
+    Ok(())
+}
+
+fn list_trash(config: &Config) -> io::Result<()> {
+    for trashed in config.trash.list()? {
+        println!("/{} (deleted at {})", trashed.path, trashed.deleted_at);
+    }
+    Ok(())
+}
+
+fn purge_trash(config: &Config) -> io::Result<()> {
+    let purged = config.trash.purge()?;
+    println!("Purged {} deletes.", purged);
     Ok(())
 }
 
-fn search_files(config: &Config, query: String) -> io::Result<()> {
-    let mut found = false;
-    for entry in std::fs::read_dir(&config.storage_path).map_err(|e| io::Error::new(e.kind(), format!("Failed to read directory {}: {}", &config.storage_path, e)))? {
-        let entry = entry.map_err(|e| io::Error::new(e.kind(), format!("Failed to read directory entry: {}", e)))?;
-        let path = entry.path();
-        if path.is_file() && path.to_string_lossy().contains(&query) {
-            println!("Found: {}", path.display());
-            found = true;
+// Where `path` is stored. The checksum, attribute and trash directories
+// share the storage directory, so their names can't be used.
+fn local_path(config: &Config, path: &SafePath) -> io::Result<PathBuf> {
+    let first = path.as_str().split('/').next().unwrap_or_default();
+    if RESERVED_DIRS.contains(&first) {
+        return Err(PathError::Reserved(first.to_string()).into());
+    }
+    Ok(path.under(&config.storage_path))
+}
+
+fn checksum_path(config: &Config, path: &SafePath) -> PathBuf {
+    companion_path(config, CHECKSUM_DIR, path)
+}
+
+fn companion_path(config: &Config, companion: &str, path: &SafePath) -> PathBuf {
+    path.under(Path::new(&config.storage_path).join(companion))
+}
+
+fn search_files(config: &Config, query: &SearchQuery) -> io::Result<Vec<SearchResult>> {
+    let scope = SafePath::parse(&query.scope)?;
+    if !local_path(config, &scope)?.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Directory {} not found", scope)));
+    }
+    SearchScan::read(&config.storage_path, &scope, query.recursive, RESERVED_DIRS)?.search(query)
+}
+
+fn set_attribute(config: &Config, filename: String, key: String, value: String) -> io::Result<()> {
+    let filename = SafePath::file(&filename)?;
+    if key.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Attribute names can't be empty"));
+    }
+    if !local_path(config, &filename)?.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("File {} not found", filename)));
+    }
+    let mut attributes = FileAttributes::read(&config.storage_path, &filename)?;
+    attributes.custom.insert(key, value);
+    attributes
+        .write(&config.storage_path, &filename)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to record attributes for {}: {}", filename, e)))?;
+    println!("Attribute set.");
+    Ok(())
+}
+
+fn make_directory(config: &Config, path: String) -> io::Result<()> {
+    let path = SafePath::parse(&path)?;
+    std::fs::create_dir_all(local_path(config, &path)?)
+        .map_err(|e| io::Error::new(e.kind(), format!("Failed to create directory {}: {}", path, e)))?;
+    println!("Directory created.");
+    Ok(())
+}
+
+fn remove_directory(config: &Config, path: String, recursive: bool) -> io::Result<()> {
+    let path = SafePath::parse(&path)?;
+    if path.is_root() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "The root directory can't be removed"));
+    }
+    let full_path = local_path(config, &path)?;
+    if !full_path.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("Directory {} not found", path)));
+    }
+    // A directory with files in it goes to the trash whole, checksums and
+    // attributes included; an empty one has nothing worth keeping.
+    let removed = if recursive {
+        config.trash.delete(&path)
+    } else {
+        std::fs::remove_dir(&full_path).and_then(|_| {
+            COMPANION_DIRS.iter().try_for_each(|companion| match std::fs::remove_dir_all(companion_path(config, companion, &path)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            })
+        })
+    };
+    removed.map_err(|e| io::Error::new(e.kind(), format!("Failed to remove directory {}: {}", path, e)))?;
+    println!("Directory removed.");
+    Ok(())
+}
+
+// `fs::rename` is atomic, but replaces whatever is at the target, so
+// without `overwrite` the move goes through `rename_no_replace` instead.
+fn rename(config: &Config, from: String, to: String, overwrite: bool) -> io::Result<()> {
+    let (from, to) = (SafePath::file(&from)?, SafePath::file(&to)?);
+    if paths::is_under(to.as_str(), from.as_str()) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't move {} into itself", from)));
+    }
+    if from == to {
+        println!("Rename successful.");
+        return Ok(());
+    }
+    let (from_path, to_path) = (local_path(config, &from)?, local_path(config, &to)?);
+    if let Some(parent) = to_path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| io::Error::new(e.kind(), format!("Failed to create directory for {}: {}", to, e)))?;
+    }
+    let renamed = if overwrite { std::fs::rename(&from_path, &to_path) } else { rename_no_replace(&from_path, &to_path) };
+    renamed.map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => io::Error::new(e.kind(), format!("{} already exists", to)),
+        _ => io::Error::new(e.kind(), format!("Failed to rename {} to {}: {}", from, to, e)),
+    })?;
+    // Checksums and attributes follow the files, whether one or a whole
+    // directory. Those of a replaced target go first, so none of them is
+    // left behind for what took its place.
+    for companion in COMPANION_DIRS {
+        let target = companion_path(config, companion, &to);
+        let removed = match std::fs::symlink_metadata(&target) {
+            Ok(metadata) if metadata.is_dir() => std::fs::remove_dir_all(&target),
+            Ok(_) => std::fs::remove_file(&target),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = removed {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(io::Error::new(e.kind(), format!("Failed to remove {} of {}: {}", companion, to, e)));
+            }
         }
     }
-    if !found {
-        println!("No files found matching the query.");
+    for companion in COMPANION_DIRS {
+        let target = companion_path(config, companion, &to);
+        let moved = std::fs::create_dir_all(target.parent().unwrap_or(Path::new(&config.storage_path)))
+            .and_then(|_| std::fs::rename(companion_path(config, companion, &from), &target));
+        if let Err(e) = moved {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(io::Error::new(e.kind(), format!("Failed to move {} for {}: {}", companion, from, e)));
+            }
+        }
     }
+    println!("Rename successful.");
     Ok(())
 }
 
+// Moves `from` to `to` unless something is already there, even something
+// created after any check. Linux refuses atomically with `renameat2` and
+// RENAME_NOREPLACE. Elsewhere, and on file systems without it, a file is
+// hard-linked into place and then unlinked, which refuses just the same;
+// only directories, which can't be linked, are checked first.
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    match renameat2_no_replace(from, to) {
+        Err(ref e) if matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) => {},
+        result => return result,
+    }
+    if std::fs::symlink_metadata(from)?.is_dir() {
+        if std::fs::symlink_metadata(to).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Target exists"));
+        }
+        return std::fs::rename(from, to);
+    }
+    std::fs::hard_link(from, to)?;
+    std::fs::remove_file(from)
+}
+
+#[cfg(target_os = "linux")]
+fn renameat2_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let (from, to) = (CString::new(from.as_os_str().as_bytes())?, CString::new(to.as_os_str().as_bytes())?);
+    // SAFETY: both paths are NUL-terminated and outlive the call.
+    let result = unsafe { libc::renameat2(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), libc::RENAME_NOREPLACE) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn list_directory(config: &Config, path: String, recursive: bool) -> io::Result<()> {
+    let path = SafePath::parse(&path)?;
+    let root = Path::new(&config.storage_path);
+    let listed = if recursive {
+        walk(&local_path(config, &path)?)?
+    } else {
+        std::fs::read_dir(local_path(config, &path)?)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to read directory {}: {}", path, e)))?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?
+    };
+    for entry in listed {
+        if RESERVED_DIRS.iter().any(|reserved| entry.starts_with(root.join(reserved))) {
+            continue;
+        }
+        let metadata = std::fs::metadata(&entry)?;
+        let name = entry.strip_prefix(root).unwrap_or(&entry).display();
+        if metadata.is_dir() {
+            println!("{}/", name);
+        } else {
+            println!("{} ({} bytes)", name, metadata.len());
+        }
+    }
+    Ok(())
+}
+
+// Every path below `directory`, depth first, leaving out the checksum,
+// attribute and trash directories.
+fn walk(directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let mut pending = vec![directory.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        let entries = std::fs::read_dir(&current)
+            .map_err(|e| io::Error::new(e.kind(), format!("Failed to read directory {}: {}", current.display(), e)))?;
+        for entry in entries {
+            let entry = entry.map_err(|e| io::Error::new(e.kind(), format!("Failed to read directory entry: {}", e)))?;
+            let path = entry.path();
+            if RESERVED_DIRS.iter().any(|reserved| entry.file_name() == *reserved) {
+                continue;
+            }
+            if path.is_dir() {
+                pending.push(path.clone());
+            }
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
 fn main() -> Result<(), String> {
     let config = Config::new();
+    // Nothing runs in the background here, so expired deletes are purged
+    // whenever the tool starts.
+    process_command(&config, Command::PurgeTrash)?;
 
+    process_command(&config, Command::MakeDirectory("examples".to_string()))?;
+    process_command(&config, Command::Upload("examples/nested.txt".to_string(), b"Nested".to_vec()))?;
+    process_command(&config, Command::Rename("examples/nested.txt".to_string(), "examples/moved/nested.txt".to_string(), false))?;
+    process_command(&config, Command::ListDirectory("".to_string(), true))?;
+    process_command(&config, Command::RemoveDirectory("examples".to_string(), true))?;
     process_command(&config, Command::Upload("example.txt".to_string(), b"Hello World!".to_vec()))?;
     process_command(&config, Command::Download("example.txt".to_string()))?;
     process_command(&config, Command::Delete("example.txt".to_string()))?;
-    process_command(&config, Command::Search("example".to_string()))?;
+    process_command(&config, Command::ListTrash)?;
+    process_command(&config, Command::Undelete("example.txt".to_string()))?;
+    process_command(&config, Command::Delete("example.txt".to_string()))?;
+    process_command(&config, Command::Upload("notes/todo.md".to_string(), b"# Todo\n- write the example\n".to_vec()))?;
+    process_command(&config, Command::SetAttribute("notes/todo.md".to_string(), "project".to_string(), "example".to_string()))?;
+    let mut attributes = BTreeMap::new();
+    attributes.insert("project".to_string(), "example".to_string());
+    let query = SearchQuery {
+        name: Some(Pattern::glob("*.md").map_err(|e| e.to_string())?),
+        content_type: Some("text/*".to_string()),
+        attributes,
+        content: Some(Pattern::regex("(?i)example").map_err(|e| e.to_string())?),
+        ..SearchQuery::default()
+    };
+    process_command(&config, Command::Search(query))?;
+    let query = SearchQuery {
+        scope: "notes".to_string(),
+        recursive: false,
+        name: Some(Pattern::contains("todo")),
+        max_size: Some(1024),
+        ..SearchQuery::default()
+    };
+    process_command(&config, Command::Search(query))?;
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::scratch::ScratchDir;
+
+    fn scratch_config(name: &str) -> (ScratchDir, Config) {
+        let dir = ScratchDir::new(&format!("commands-{}", name));
+        let storage_path = dir.to_string_lossy().into_owned();
+        let trash = Trash::from_env(&storage_path, COMPANION_DIRS).unwrap();
+        (dir, Config { storage_path, trash })
+    }
+
+    fn read(config: &Config, path: &str) -> Vec<u8> {
+        std::fs::read(Path::new(&config.storage_path).join(path)).unwrap()
+    }
+
+    fn attributes(config: &Config, path: &str) -> FileAttributes {
+        FileAttributes::read(&config.storage_path, &SafePath::file(path).unwrap()).unwrap()
+    }
+
+    fn set_owner(config: &Config, path: &str, owner: &str) {
+        let attributes = FileAttributes { owner: Some(owner.to_string()), ..FileAttributes::default() };
+        attributes.write(&config.storage_path, &SafePath::file(path).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn a_failed_checksum_leaves_the_old_content() {
+        let (_dir, config) = scratch_config("upload");
+        upload_file(&config, "file".to_string(), b"old".to_vec()).unwrap();
+
+        // A directory where the checksum should go can't be replaced.
+        let checksum = checksum_path(&config, &SafePath::file("file").unwrap());
+        std::fs::remove_file(&checksum).unwrap();
+        std::fs::create_dir_all(checksum.join("blocker")).unwrap();
+        assert!(upload_file(&config, "file".to_string(), b"new".to_vec()).is_err());
+        assert_eq!(read(&config, "file"), b"old");
+    }
+
+    #[test]
+    fn an_existing_target_is_kept_without_overwrite() {
+        let (_dir, config) = scratch_config("no-replace");
+        upload_file(&config, "a".to_string(), b"a".to_vec()).unwrap();
+        upload_file(&config, "b".to_string(), b"b".to_vec()).unwrap();
+
+        let error = rename(&config, "a".to_string(), "b".to_string(), false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read(&config, "a"), b"a");
+        assert_eq!(read(&config, "b"), b"b");
+        download_file(&config, "b".to_string()).unwrap();
+
+        std::fs::create_dir_all(Path::new(&config.storage_path).join("dir/inner")).unwrap();
+        std::fs::create_dir_all(Path::new(&config.storage_path).join("other")).unwrap();
+        let error = rename(&config, "dir".to_string(), "other".to_string(), false).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert!(Path::new(&config.storage_path).join("dir/inner").is_dir());
+    }
+
+    #[test]
+    fn companions_follow_a_moved_file() {
+        let (_dir, config) = scratch_config("move");
+        upload_file(&config, "a".to_string(), b"a".to_vec()).unwrap();
+        set_owner(&config, "a", "alice");
+
+        rename(&config, "a".to_string(), "dir/b".to_string(), false).unwrap();
+        assert_eq!(read(&config, "dir/b"), b"a");
+        download_file(&config, "dir/b".to_string()).unwrap();
+        assert_eq!(attributes(&config, "dir/b").owner.as_deref(), Some("alice"));
+        assert_eq!(attributes(&config, "a"), FileAttributes::default());
+    }
+
+    #[test]
+    fn overwriting_drops_the_replaced_targets_companions() {
+        let (_dir, config) = scratch_config("overwrite");
+        upload_file(&config, "a".to_string(), b"a".to_vec()).unwrap();
+        upload_file(&config, "b".to_string(), b"b".to_vec()).unwrap();
+        set_owner(&config, "b", "bob");
+
+        rename(&config, "a".to_string(), "b".to_string(), true).unwrap();
+        assert_eq!(read(&config, "b"), b"a");
+        download_file(&config, "b".to_string()).unwrap();
+        assert_eq!(attributes(&config, "b"), FileAttributes::default());
+        assert!(!Path::new(&config.storage_path).join("a").exists());
+    }
+}
@@ -0,0 +1,47 @@
+// Extensions of the file types worth telling apart; anything else is
+// served as opaque bytes.
+const KNOWN_TYPES: &[(&str, &str)] = &[
+    ("txt", "text/plain"),
+    ("md", "text/markdown"),
+    ("csv", "text/csv"),
+    ("log", "text/plain"),
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("toml", "application/toml"),
+    ("rs", "text/x-rust"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("svg", "image/svg+xml"),
+    ("webp", "image/webp"),
+    ("mp3", "audio/mpeg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// The content type a file's name suggests, from its extension.
+pub fn guess(name: &str) -> &'static str {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    let extension = match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => extension,
+        _ => return DEFAULT_CONTENT_TYPE,
+    };
+    KNOWN_TYPES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        .map_or(DEFAULT_CONTENT_TYPE, |(_, content_type)| content_type)
+}
@@ -0,0 +1,770 @@
+use std::cmp::Reverse;
+use std::env;
+use std::io::{self, Read};
+use std::slice;
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+
+use super::checksum::{sha256_hex, StreamingChecksum};
+use super::network::Owner;
+use super::protocol::{Command, ErasureScheme};
+use super::replication::{request, send_command, stream_file, Replicator};
+use super::storage::DistributedFileSystem;
+
+// Shards and stripe descriptors are stored as ordinary files under these
+// reserved prefixes. Shards stay on the node they were written to; the
+// descriptor is replicated and rebalanced like any other file.
+const SHARD_PREFIX: &str = ".shards/";
+const STRIPE_PREFIX: &str = ".stripes/";
+
+// How much of each shard is encoded at a time.
+const BLOCK_LEN: u64 = 64 * 1024;
+
+// GF(2^8) with the polynomial x^8 + x^4 + x^3 + x^2 + 1 and generator 2.
+// The exp table is doubled so a sum of two logs never needs reducing.
+static TABLES: ([u8; 512], [u8; 256]) = build_tables();
+
+const fn build_tables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0u8; 512];
+    let mut log = [0u8; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11d;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+    (exp, log)
+}
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let (exp, log) = &TABLES;
+    exp[log[a as usize] as usize + log[b as usize] as usize]
+}
+
+fn gf_inv(a: u8) -> u8 {
+    let (exp, log) = &TABLES;
+    exp[255 - log[a as usize] as usize]
+}
+
+fn gf_pow(a: u8, n: usize) -> u8 {
+    if n == 0 {
+        return 1;
+    }
+    if a == 0 {
+        return 0;
+    }
+    let (exp, log) = &TABLES;
+    exp[log[a as usize] as usize * n % 255]
+}
+
+type Matrix = Vec<Vec<u8>>;
+
+fn mat_mul(a: &Matrix, b: &Matrix) -> Matrix {
+    a.iter()
+        .map(|row| {
+            (0..b[0].len())
+                .map(|col| row.iter().zip(b).fold(0, |sum, (&x, b_row)| sum ^ gf_mul(x, b_row[col])))
+                .collect()
+        })
+        .collect()
+}
+
+// Gauss-Jordan elimination. `None` if `matrix` is singular.
+fn invert(matrix: &Matrix) -> Option<Matrix> {
+    let size = matrix.len();
+    let mut work: Matrix = matrix
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let mut row = row.clone();
+            row.extend((0..size).map(|j| if i == j { 1 } else { 0 }));
+            row
+        })
+        .collect();
+
+    for col in 0..size {
+        let pivot = (col..size).find(|&row| work[row][col] != 0)?;
+        work.swap(col, pivot);
+        let scale = gf_inv(work[col][col]);
+        for value in work[col].iter_mut() {
+            *value = gf_mul(*value, scale);
+        }
+        let pivot_row = work[col].clone();
+        for (row, values) in work.iter_mut().enumerate() {
+            let factor = values[col];
+            if row != col && factor != 0 {
+                for (value, &pivot_value) in values.iter_mut().zip(&pivot_row) {
+                    *value ^= gf_mul(factor, pivot_value);
+                }
+            }
+        }
+    }
+    Some(work.into_iter().map(|row| row[size..].to_vec()).collect())
+}
+
+// A systematic encoding matrix: the first `data_shards` rows are the
+// identity, so data shards are plain slices of the content, and any
+// `data_shards` rows are linearly independent.
+fn encoding_matrix(scheme: ErasureScheme) -> Matrix {
+    let vandermonde: Matrix = (0..scheme.total())
+        .map(|row| (0..scheme.data_shards).map(|col| gf_pow(row as u8, col)).collect())
+        .collect();
+    let top = invert(&vandermonde[..scheme.data_shards].to_vec()).expect("Vandermonde rows are independent");
+    mat_mul(&vandermonde, &top)
+}
+
+impl ErasureScheme {
+    /// Parses `k+m`, e.g. `4+2` for four data and two parity shards.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let (data, parity) = value
+            .split_once('+')
+            .ok_or_else(|| format!("Invalid erasure scheme '{}', expected data+parity", value))?;
+        let scheme = ErasureScheme {
+            data_shards: data.trim().parse().map_err(|e| format!("Invalid erasure scheme '{}': {}", value, e))?,
+            parity_shards: parity.trim().parse().map_err(|e| format!("Invalid erasure scheme '{}': {}", value, e))?,
+        };
+        scheme.validate().map_err(|e| e.to_string())?;
+        Ok(scheme)
+    }
+
+    pub fn total(&self) -> usize {
+        self.data_shards + self.parity_shards
+    }
+
+    pub fn validate(&self) -> io::Result<()> {
+        if self.data_shards == 0 || self.parity_shards == 0 || self.total() > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Unsupported erasure scheme {}+{}: both counts must be at least 1 and total at most 255",
+                    self.data_shards, self.parity_shards
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+// The length of every shard of a `size`-byte file. The file is split into
+// `data_shards` consecutive parts of this length, the last zero-padded.
+fn shard_len(scheme: ErasureScheme, size: u64) -> u64 {
+    size.div_ceil(scheme.data_shards as u64).max(1)
+}
+
+/// Produces some of a file's shards a block at a time, reading the file's
+/// parts side by side, so encoding never holds more than a block of each.
+struct ShardEncoder<R> {
+    rows: Matrix,
+    // A reader per part, from its start; `None` where no row needs the part.
+    parts: Vec<Option<io::Take<R>>>,
+    remaining: u64,
+}
+
+impl<R: Read> ShardEncoder<R> {
+    /// Encodes shards `indices` of a `size`-byte file. `open(offset)` gives
+    /// a reader over the file from `offset` on.
+    fn new(scheme: ErasureScheme, size: u64, indices: &[usize], mut open: impl FnMut(u64) -> io::Result<R>) -> io::Result<Self> {
+        let len = shard_len(scheme, size);
+        let matrix = encoding_matrix(scheme);
+        let rows: Matrix = indices.iter().map(|&index| matrix[index].clone()).collect();
+        let parts = (0..scheme.data_shards)
+            .map(|part| match rows.iter().any(|row| row[part] != 0) {
+                true => Ok(Some(open(part as u64 * len)?.take(len))),
+                false => Ok(None),
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Self { rows, parts, remaining: len })
+    }
+
+    /// The next block of each shard, in the order they were asked for.
+    /// `None` once the shards are complete.
+    fn next_blocks(&mut self) -> io::Result<Option<Vec<Vec<u8>>>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let len = self.remaining.min(BLOCK_LEN) as usize;
+        self.remaining -= len as u64;
+        let mut blocks = vec![vec![0u8; len]; self.rows.len()];
+        let mut data = vec![0u8; len];
+        for (part, reader) in self.parts.iter_mut().enumerate() {
+            if let Some(reader) = reader {
+                // Past the end of the file the part reads as zeros.
+                data.fill(0);
+                read_full(reader, &mut data)?;
+                for (row, block) in self.rows.iter().zip(blocks.iter_mut()) {
+                    if row[part] != 0 {
+                        for (out, &byte) in block.iter_mut().zip(&data) {
+                            *out ^= gf_mul(row[part], byte);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(Some(blocks))
+    }
+}
+
+// Fills `buf` unless the reader ends first, returning how much was read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(count) => filled += count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// A single shard as a stream.
+struct ShardReader<R> {
+    encoder: ShardEncoder<R>,
+    block: Vec<u8>,
+    position: usize,
+}
+
+impl<R: Read> Read for ShardReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.block.len() {
+            match self.encoder.next_blocks()? {
+                Some(mut blocks) => self.block = blocks.remove(0),
+                None => return Ok(0),
+            }
+            self.position = 0;
+        }
+        let count = buf.len().min(self.block.len() - self.position);
+        buf[..count].copy_from_slice(&self.block[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// Rebuilds the original `size` bytes from any `scheme.data_shards` of the
+/// shards. `shards[i]` is shard `i`, or `None` if it couldn't be read. Sets
+/// of shards are tried one after another until one decodes to `checksum`,
+/// so corrupt shards are outvoted as long as enough others are intact.
+pub fn reconstruct(scheme: ErasureScheme, size: u64, checksum: &str, shards: &[Option<Vec<u8>>]) -> io::Result<Vec<u8>> {
+    // A shard of the wrong length is damaged and of no use.
+    let len = shard_len(scheme, size) as usize;
+    let present: Vec<(usize, &Vec<u8>)> = shards
+        .iter()
+        .enumerate()
+        .filter_map(|(index, shard)| shard.as_ref().filter(|shard| shard.len() == len).map(|shard| (index, shard)))
+        .collect();
+    if present.len() < scheme.data_shards {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Only {} of the {} shards needed are available", present.len(), scheme.data_shards),
+        ));
+    }
+
+    let matrix = encoding_matrix(scheme);
+    let mut chosen: Vec<usize> = (0..scheme.data_shards).collect();
+    loop {
+        let rows: Matrix = chosen.iter().map(|&choice| matrix[present[choice].0].clone()).collect();
+        if let Some(decode) = invert(&rows) {
+            let mut data = Vec::with_capacity(scheme.data_shards * len);
+            for row in &decode {
+                let mut part = vec![0u8; len];
+                for (&coefficient, &choice) in row.iter().zip(&chosen) {
+                    for (out, &byte) in part.iter_mut().zip(present[choice].1.iter()) {
+                        *out ^= gf_mul(coefficient, byte);
+                    }
+                }
+                data.extend_from_slice(&part);
+            }
+            data.truncate(size as usize);
+            if sha256_hex(&data) == checksum {
+                return Ok(data);
+            }
+        }
+        if !next_combination(&mut chosen, present.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No {} of the {} shards available decode to the file's checksum", scheme.data_shards, present.len()),
+            ));
+        }
+    }
+}
+
+// Steps `chosen`, ascending indices below `count`, to the next combination
+// in lexicographic order. `false` after the last one.
+fn next_combination(chosen: &mut [usize], count: usize) -> bool {
+    let size = chosen.len();
+    for position in (0..size).rev() {
+        if chosen[position] < count - size + position {
+            chosen[position] += 1;
+            for next in position + 1..size {
+                chosen[next] = chosen[next - 1] + 1;
+            }
+            return true;
+        }
+    }
+    false
+}
+
+/// What a reader needs to find and decode an erasure-coded file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StripeDescriptor {
+    pub scheme: ErasureScheme,
+    pub size: u64,
+    pub checksum: String,
+}
+
+pub fn shard_name(filename: &str, index: usize) -> String {
+    format!("{}{}/{}", SHARD_PREFIX, filename, index)
+}
+
+pub fn stripe_name(filename: &str) -> String {
+    format!("{}{}", STRIPE_PREFIX, filename)
+}
+
+pub fn is_shard(name: &str) -> bool {
+    name.starts_with(SHARD_PREFIX)
+}
+
+/// The name a stored file is listed under: erasure-coded files appear under
+/// their own name and their shards not at all.
+pub fn listed_name(name: &str) -> Option<&str> {
+    if is_shard(name) {
+        return None;
+    }
+    Some(name.strip_prefix(STRIPE_PREFIX).unwrap_or(name))
+}
+
+/// Whether `name` is a shard, a stripe descriptor, or one of the
+/// directories holding them.
+pub fn is_internal(name: &str) -> bool {
+    [SHARD_PREFIX, STRIPE_PREFIX]
+        .iter()
+        .any(|prefix| name.starts_with(prefix) || name == prefix.trim_end_matches('/'))
+}
+
+/// The internal directories holding the shards and stripe descriptors of
+/// erasure-coded files below `path`.
+pub fn internal_directories(path: &str) -> [String; 2] {
+    [format!("{}{}", SHARD_PREFIX, path), stripe_name(path)]
+}
+
+/// Which files are erasure-coded rather than replicated, by directory.
+#[derive(Debug, Clone, Default)]
+pub struct ErasureConfig {
+    directories: Vec<(String, ErasureScheme)>,
+}
+
+impl ErasureConfig {
+    /// Reads `DFS_ERASURE_DIRS`, a comma-separated list of `dir=k+m`
+    /// entries such as `cold=4+2,archive/2023=6+3`. Files under a listed
+    /// directory are erasure-coded with its scheme; the most specific
+    /// directory wins, and `/` covers every file.
+    pub fn from_env() -> Result<Self, String> {
+        let mut directories = Vec::new();
+        if let Ok(value) = env::var("DFS_ERASURE_DIRS") {
+            for entry in value.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+                let (directory, scheme) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("Invalid DFS_ERASURE_DIRS entry '{}', expected dir=k+m", entry))?;
+                directories.push((directory.trim().trim_matches('/').to_string(), ErasureScheme::parse(scheme)?));
+            }
+        }
+        // Longest first, so the first match is the most specific.
+        directories.sort_by_key(|(directory, _)| Reverse(directory.len()));
+        Ok(Self { directories })
+    }
+
+    pub fn scheme_for(&self, filename: &str) -> Option<ErasureScheme> {
+        self.directories
+            .iter()
+            .find(|(directory, _)| {
+                directory.is_empty()
+                    || filename.strip_prefix(directory.as_str()).is_some_and(|rest| rest.starts_with('/'))
+            })
+            .map(|(_, scheme)| *scheme)
+    }
+}
+
+#[derive(Debug)]
+pub struct StripeOutcome {
+    pub scheme: ErasureScheme,
+    pub stored: usize,
+    /// Distinct nodes the shards landed on. Fewer than the shard count
+    /// means losing one node can cost more than one shard.
+    pub nodes: usize,
+    /// Whether the shards replaced the full local copy. They only do once
+    /// every shard is stored; otherwise the full copy stays and the
+    /// shards that were stored are removed again.
+    pub striped: bool,
+    pub failures: Vec<String>,
+}
+
+/// Erasure-codes the local copy of `filename` into shards placed on
+/// distinct nodes, shard `i` going to the `i`th node of the file's
+/// preference list on the ring, then replicates its stripe descriptor and
+/// drops the full local copy. If any shard can't be stored, the full copy
+/// is kept instead so the file is never left short of shards.
+///
+/// Shards are streamed as they're encoded: one pass over the file works
+/// out their checksums, then each shard is encoded again as it's sent.
+pub fn store_striped(
+    dfs: &Arc<Mutex<DistributedFileSystem>>,
+    replicator: &Replicator,
+    filename: &str,
+    scheme: ErasureScheme,
+) -> io::Result<StripeOutcome> {
+    scheme.validate()?;
+    let entry = dfs.lock().unwrap().stat(filename)?;
+    let open = |offset| open_at(dfs, filename, offset);
+
+    let indices: Vec<usize> = (0..scheme.total()).collect();
+    let mut checksums: Vec<StreamingChecksum> = (0..scheme.total()).map(|_| StreamingChecksum::new()).collect();
+    let mut encoder = ShardEncoder::new(scheme, entry.size, &indices, open)?;
+    while let Some(blocks) = encoder.next_blocks()? {
+        for (checksum, block) in checksums.iter_mut().zip(&blocks) {
+            checksum.update(block);
+        }
+    }
+
+    let nodes = replicator.placement(filename, scheme.total());
+    if nodes.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotConnected, "No nodes to place shards on"));
+    }
+    let mut outcome = StripeOutcome { scheme, stored: 0, nodes: nodes.len(), striped: false, failures: Vec::new() };
+    let mut stored = Vec::new();
+    for (index, checksum) in checksums.into_iter().enumerate() {
+        let name = shard_name(filename, index);
+        let checksum = checksum.finish();
+        let result = ShardEncoder::new(scheme, entry.size, &[index], open).and_then(|encoder| {
+            let mut shard = ShardReader { encoder, block: Vec::new(), position: 0 };
+            match &nodes[index % nodes.len()] {
+                Owner { address: Some(address), .. } => {
+                    stream_file(address, &name, shard_len(scheme, entry.size), checksum, None, &mut shard)
+                },
+                Owner { .. } => store_local(dfs, &name, &checksum, &mut shard),
+            }
+        });
+        match result {
+            Ok(()) => stored.push(index),
+            Err(e) => outcome.failures.push(format!("shard {} on {}: {}", index, nodes[index % nodes.len()].id, e)),
+        }
+    }
+    outcome.stored = stored.len();
+    if outcome.stored < scheme.total() {
+        for index in stored {
+            let name = shard_name(filename, index);
+            let result = match &nodes[index % nodes.len()].address {
+                Some(address) => send_command(address, &Command::DeleteFile { filename: name, purge: true }),
+                None => dfs.lock().unwrap().purge_files(&[name]),
+            };
+            if let Err(e) = result {
+                outcome.failures.push(format!("removing shard {} on {}: {}", index, nodes[index % nodes.len()].id, e));
+            }
+        }
+        return Ok(outcome);
+    }
+
+    let descriptor = StripeDescriptor { scheme, size: entry.size, checksum: entry.checksum };
+    let descriptor_name = stripe_name(filename);
+    // The file's attributes stay with its descriptor.
+    {
+        let mut dfs = dfs.lock().unwrap();
+        dfs.store_files(&[(descriptor_name.clone(), serde_json::to_vec(&descriptor)?)])?;
+        dfs.set_attributes(&descriptor_name, entry.attributes)?;
+    }
+    let replication = replicator.replicate_file(dfs, &descriptor_name);
+    outcome.failures.extend(replication.failures.iter().map(|e| format!("stripe descriptor on {}", e)));
+    if !replication.local_is_owner && replication.failures.is_empty() {
+        dfs.lock().unwrap().purge_files(&[descriptor_name])?;
+    }
+
+    dfs.lock().unwrap().purge_files(&[filename.to_string()])?;
+    outcome.striped = true;
+    Ok(outcome)
+}
+
+// The local copy of `filename` from `offset` on.
+fn open_at(dfs: &Mutex<DistributedFileSystem>, filename: &str, offset: u64) -> io::Result<impl Read> {
+    let (mut reader, _) = dfs.lock().unwrap().open_reader(filename)?;
+    io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+    Ok(reader)
+}
+
+// Writes a shard placed on this node.
+fn store_local<R: Read>(dfs: &Mutex<DistributedFileSystem>, name: &str, checksum: &str, shard: &mut R) -> io::Result<()> {
+    let mut writer = dfs.lock().unwrap().create_writer(name)?;
+    writer.expect_checksum(checksum);
+    if let Err(e) = io::copy(shard, &mut writer) {
+        let _ = dfs.lock().unwrap().abort_writer(writer);
+        return Err(e);
+    }
+    dfs.lock().unwrap().commit_writer(writer).map(|_| ())
+}
+
+/// The stripe descriptor for `filename`, from the local copy or the
+/// descriptor's owners. `Ok(None)` if the file isn't erasure-coded.
+pub fn find_stripe(dfs: &Mutex<DistributedFileSystem>, replicator: &Replicator, filename: &str) -> io::Result<Option<StripeDescriptor>> {
+    let descriptor_name = stripe_name(filename);
+    let local = dfs.lock().unwrap().retrieve_files(slice::from_ref(&descriptor_name));
+    let data = match local {
+        Ok(mut contents) => contents.remove(&descriptor_name).unwrap_or_default(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let command = Command::DownloadFile { filename: descriptor_name.clone() };
+            let remote = replicator
+                .owners(&descriptor_name)
+                .into_iter()
+                .filter_map(|owner| owner.address)
+                .filter_map(|address| request(&address, &command).ok())
+                .find(|response| response.is_ok());
+            match remote.and_then(|response| response.file_contents) {
+                Some(data) => data,
+                None => return Ok(None),
+            }
+        },
+        Err(e) => return Err(e),
+    };
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+/// Reads and decodes an erasure-coded file. Each shard is looked for first
+/// on the node it was placed on and then on every other node, so reads
+/// keep working while up to `parity_shards` shards are lost or their nodes
+/// are down, and across membership changes.
+pub fn read_striped(
+    dfs: &Mutex<DistributedFileSystem>,
+    replicator: &Replicator,
+    filename: &str,
+    descriptor: &StripeDescriptor,
+) -> io::Result<Vec<u8>> {
+    let scheme = descriptor.scheme;
+    scheme.validate()?;
+    let nodes = replicator.placement(filename, scheme.total());
+    let everyone = replicator.placement(filename, usize::MAX);
+
+    let fetch = |index: usize| {
+        let name = shard_name(filename, index);
+        let preferred = nodes.get(index % nodes.len().max(1));
+        let mut candidates = preferred.into_iter().chain(everyone.iter().filter(|node| Some(*node) != preferred));
+        candidates.find_map(|node| match &node.address {
+            Some(address) => {
+                let command = Command::DownloadFile { filename: name.clone() };
+                match request(address, &command) {
+                    Ok(response) if response.is_ok() => response.file_contents,
+                    _ => None,
+                }
+            },
+            None => dfs.lock().unwrap().retrieve_files(slice::from_ref(&name)).ok().and_then(|mut contents| contents.remove(&name)),
+        })
+    };
+
+    // Just enough shards at first; all of them if those don't decode to
+    // the file.
+    let mut shards: Vec<Option<Vec<u8>>> = vec![None; scheme.total()];
+    let (mut wanted, mut found, mut next) = (scheme.data_shards, 0, 0);
+    loop {
+        while found < wanted && next < scheme.total() {
+            shards[next] = fetch(next);
+            found += shards[next].is_some() as usize;
+            next += 1;
+        }
+        match reconstruct(scheme, descriptor.size, &descriptor.checksum, &shards) {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData && next < scheme.total() => wanted = scheme.total(),
+            result => {
+                return result.map_err(|e| io::Error::new(e.kind(), format!("Unable to reconstruct {}: {}", filename, e)));
+            },
+        }
+    }
+}
+
+/// How many of `filename`'s shards can be found, looking for each like
+/// `read_striped` does.
+pub fn count_shards(dfs: &Mutex<DistributedFileSystem>, replicator: &Replicator, filename: &str, scheme: ErasureScheme) -> usize {
+    let nodes = replicator.placement(filename, scheme.total());
+    let everyone = replicator.placement(filename, usize::MAX);
+    (0..scheme.total())
+        .filter(|index| {
+            let name = shard_name(filename, *index);
+            let preferred = nodes.get(index % nodes.len().max(1));
+            let mut candidates = preferred.into_iter().chain(everyone.iter().filter(|node| Some(*node) != preferred));
+            candidates.any(|node| match &node.address {
+                Some(address) => send_command(address, &Command::Stat { filename: name.clone() }).is_ok(),
+                None => dfs.lock().unwrap().stat(&name).is_ok(),
+            })
+        })
+        .count()
+}
+
+/// Removes every shard of `filename` that can be reached, then its stripe
+/// descriptor, to the trash or with `purge` for good.
+pub fn delete_striped(
+    dfs: &Arc<Mutex<DistributedFileSystem>>,
+    replicator: &Replicator,
+    filename: &str,
+    descriptor: &StripeDescriptor,
+    purge: bool,
+) -> Vec<String> {
+    let mut failures = Vec::new();
+    for node in replicator.placement(filename, usize::MAX) {
+        for index in 0..descriptor.scheme.total() {
+            let name = shard_name(filename, index);
+            let result = match &node.address {
+                Some(address) => request(address, &Command::DeleteFile { filename: name, purge }).map(|_| ()),
+                None => match remove(&mut dfs.lock().unwrap(), &[name], purge) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                    result => result,
+                },
+            };
+            // A node that can't be reached is skipped rather than retried
+            // for every shard.
+            if let Err(e) = result {
+                failures.push(format!("shards on {}: {}", node.id, e));
+                break;
+            }
+        }
+    }
+
+    let descriptor_name = stripe_name(filename);
+    let _ = remove(&mut dfs.lock().unwrap(), slice::from_ref(&descriptor_name), purge);
+    let outcome = replicator.replicate_delete(&descriptor_name, purge);
+    failures.extend(outcome.failures.into_iter().map(|e| format!("stripe descriptor on {}", e)));
+    failures
+}
+
+fn remove(dfs: &mut DistributedFileSystem, file_names: &[String], purge: bool) -> io::Result<()> {
+    if purge {
+        dfs.purge_files(file_names)
+    } else {
+        dfs.delete_files(file_names)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    use super::super::network::NetworkTopology;
+    use super::super::scratch::ScratchDir;
+    use super::super::replication::{AckPolicy, ReplicationConfig};
+    use super::super::storage::RetentionPolicy;
+
+    const SCHEME: ErasureScheme = ErasureScheme { data_shards: 4, parity_shards: 2 };
+
+    fn encode(scheme: ErasureScheme, data: &[u8]) -> Vec<Vec<u8>> {
+        let indices: Vec<usize> = (0..scheme.total()).collect();
+        let open = |offset: u64| Ok(&data[(offset as usize).min(data.len())..]);
+        let mut encoder = ShardEncoder::new(scheme, data.len() as u64, &indices, open).unwrap();
+        let mut shards = vec![Vec::new(); scheme.total()];
+        while let Some(blocks) = encoder.next_blocks().unwrap() {
+            for (shard, block) in shards.iter_mut().zip(blocks) {
+                shard.extend(block);
+            }
+        }
+        shards
+    }
+
+    // Spans several blocks and doesn't divide evenly into parts.
+    fn content() -> Vec<u8> {
+        (0..3 * BLOCK_LEN as usize + 12_345).map(|n| (n * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn data_shards_are_the_file_in_order() {
+        let data = content();
+        let shards = encode(SCHEME, &data);
+        assert_eq!(shards.len(), 6);
+        assert!(shards.iter().all(|shard| shard.len() as u64 == shard_len(SCHEME, data.len() as u64)));
+        let mut joined = shards[..4].concat();
+        joined.truncate(data.len());
+        assert_eq!(joined, data);
+    }
+
+    #[test]
+    fn any_data_shards_reconstruct_the_file() {
+        let data = content();
+        let checksum = sha256_hex(&data);
+        let shards = encode(SCHEME, &data);
+        for first in 0..6 {
+            for second in first + 1..6 {
+                let mut available: Vec<Option<Vec<u8>>> = shards.iter().cloned().map(Some).collect();
+                available[first] = None;
+                available[second] = None;
+                assert_eq!(reconstruct(SCHEME, data.len() as u64, &checksum, &available).unwrap(), data);
+            }
+        }
+        let mut available: Vec<Option<Vec<u8>>> = shards.into_iter().map(Some).collect();
+        available[..3].iter_mut().for_each(|shard| *shard = None);
+        assert_eq!(reconstruct(SCHEME, data.len() as u64, &checksum, &available).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn a_corrupt_shard_is_outvoted() {
+        let data = content();
+        let checksum = sha256_hex(&data);
+        let mut available: Vec<Option<Vec<u8>>> = encode(SCHEME, &data).into_iter().map(Some).collect();
+        available[0].as_mut().unwrap()[7] ^= 1;
+        assert_eq!(reconstruct(SCHEME, data.len() as u64, &checksum, &available).unwrap(), data);
+
+        // With two bad shards out of six, only one set of four is clean.
+        available[5].as_mut().unwrap()[7] ^= 1;
+        assert_eq!(reconstruct(SCHEME, data.len() as u64, &checksum, &available).unwrap(), data);
+
+        available[2].as_mut().unwrap()[7] ^= 1;
+        assert_eq!(reconstruct(SCHEME, data.len() as u64, &checksum, &available).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn tiny_and_empty_files_round_trip() {
+        for data in [Vec::new(), vec![42]] {
+            let checksum = sha256_hex(&data);
+            let mut available: Vec<Option<Vec<u8>>> = encode(SCHEME, &data).into_iter().map(Some).collect();
+            available[1] = None;
+            assert_eq!(reconstruct(SCHEME, data.len() as u64, &checksum, &available).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn a_single_shard_streams_like_the_full_encoding() {
+        let data = content();
+        let open = |offset: u64| Ok(&data[(offset as usize).min(data.len())..]);
+        let encoder = ShardEncoder::new(SCHEME, data.len() as u64, &[4], open).unwrap();
+        let mut shard = Vec::new();
+        ShardReader { encoder, block: Vec::new(), position: 0 }.read_to_end(&mut shard).unwrap();
+        assert_eq!(shard, encode(SCHEME, &data)[4]);
+    }
+
+    #[test]
+    fn a_file_is_kept_whole_when_shards_cant_be_stored() {
+        let dir = ScratchDir::new("erasure-unstored-shards");
+        let retention = RetentionPolicy { keep_last: 1, keep_days: None, trash_days: 30 };
+        let dfs = Arc::new(Mutex::new(DistributedFileSystem::open(dir.to_string_lossy().into_owned(), retention).unwrap()));
+        // Every other shard goes to a peer nothing listens for.
+        let mut topology = NetworkTopology::new("local".to_string());
+        topology.add_peer("down".to_string(), "127.0.0.1:1".to_string());
+        let config = ReplicationConfig { factor: 1, ack_policy: AckPolicy::One };
+        let replicator = Replicator::new(config, Arc::new(Mutex::new(topology)));
+        dfs.lock().unwrap().store_files(&[("file".to_string(), content())]).unwrap();
+
+        let outcome = store_striped(&dfs, &replicator, "file", SCHEME).unwrap();
+        assert!(!outcome.striped);
+        assert_eq!(outcome.stored, SCHEME.total() / 2);
+        assert_eq!(outcome.failures.len(), SCHEME.total() / 2);
+
+        // The full copy is still there, and the shards stored here are gone.
+        let dfs = dfs.lock().unwrap();
+        assert_eq!(dfs.list_files(), ["file"]);
+        assert_eq!(dfs.retrieve_files(&["file".to_string()]).unwrap()["file"], content());
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeSet;
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::network::{send_goodbye, send_hello, serve_peers, MyError, PeerEvent};
+use super::node_handler::{spawn_failure_detector, ClusterNode, DistributedFsState, MembershipConfig};
+use super::replication::cluster_key;
+
+/// Controls this node's part in gossip.
+pub struct GossipHandle {
+    local_id: String,
+    key: &'static str,
+    gossip_address: String,
+    seeds: Vec<String>,
+    membership: Arc<DistributedFsState>,
+    timeout: Duration,
+    // Held by the sender for each round, so no Hello follows the goodbyes.
+    leaving: Arc<Mutex<bool>>,
+}
+
+impl GossipHandle {
+    /// Stops gossiping and says goodbye to the seeds and every node this
+    /// one knows about, which marks it dead on their side straight away
+    /// instead of after the failure timeouts.
+    pub fn leave(&self) {
+        *self.leaving.lock().unwrap() = true;
+        for target in gossip_candidates(&self.seeds, &self.membership, &self.gossip_address) {
+            if let Err(e) = send_goodbye(&target, &self.local_id, self.key, self.timeout) {
+                eprintln!("Failed to say goodbye to {}: {}", target, e);
+            }
+        }
+    }
+}
+
+/// Runs gossip-style membership. Every heartbeat interval this node bumps
+/// its own heartbeat and sends its whole view of the cluster, as a `Hello`,
+/// to `fanout` peers taken in turn from the seeds and every node it knows
+/// about. Views received from peers are merged into `membership`, so joins,
+/// departures and failures spread to every node without a central registry
+/// and all nodes converge on the same view.
+///
+/// Seeds are only needed to make first contact; they are gossiped with
+/// alongside everyone else so a partitioned node can find its way back.
+///
+/// Gossip is signed with the cluster key, and gossip from nodes without it
+/// is ignored.
+pub fn spawn_gossip(
+    local_id: String,
+    address: String,
+    gossip_address: String,
+    seeds: Vec<String>,
+    membership: Arc<DistributedFsState>,
+    config: MembershipConfig,
+) -> Result<GossipHandle, MyError> {
+    let key = cluster_key().ok_or_else(|| MyError::Custom("Gossip needs DFS_CLUSTER_KEY".to_string()))?;
+    let listener = TcpListener::bind(&gossip_address)?;
+    println!("Gossiping on {}", gossip_address);
+    membership.set_local_node(&local_id, &address, &gossip_address);
+
+    let receiver_membership = Arc::clone(&membership);
+    thread::spawn(move || {
+        serve_peers(
+            listener,
+            key,
+            Arc::new(move |event| match event {
+                PeerEvent::Hello { sender, content } => match serde_json::from_str::<Vec<ClusterNode>>(&content) {
+                    Ok(digest) => receiver_membership.merge_digest(digest),
+                    Err(e) => eprintln!("Ignoring malformed gossip from {}: {}", sender, e),
+                },
+                PeerEvent::Goodbye { sender } => {
+                    println!("Node {} said goodbye", sender);
+                    receiver_membership.record_departure(&sender);
+                },
+                PeerEvent::DataTransfer { sender, data } => {
+                    eprintln!("Ignoring {} bytes of data from {} on the gossip port", data.len(), sender);
+                },
+            }),
+        )
+    });
+
+    let leaving = Arc::new(Mutex::new(false));
+    let handle = GossipHandle {
+        local_id: local_id.clone(),
+        key,
+        gossip_address: gossip_address.clone(),
+        seeds: seeds.clone(),
+        membership: Arc::clone(&membership),
+        timeout: config.heartbeat_interval,
+        leaving: Arc::clone(&leaving),
+    };
+    let sender_membership = Arc::clone(&membership);
+    thread::spawn(move || {
+        let mut round = 0usize;
+        loop {
+            // A Hello after the goodbyes would bring this node back to life.
+            let leaving = leaving.lock().unwrap();
+            if *leaving {
+                break;
+            }
+            let digest = sender_membership.next_digest();
+            match serde_json::to_string(&digest) {
+                Ok(content) => {
+                    let candidates = gossip_candidates(&seeds, &sender_membership, &gossip_address);
+
+                    // Rotating through the candidates reaches every peer
+                    // within a few rounds. Unreachable peers are the failure
+                    // detector's business; there's nothing to report here.
+                    if !candidates.is_empty() {
+                        let start = round * config.fanout % candidates.len();
+                        let count = config.fanout.min(candidates.len());
+                        for target in candidates.iter().cycle().skip(start).take(count) {
+                            let _ = send_hello(target, &local_id, &content, key, config.heartbeat_interval);
+                        }
+                    }
+                },
+                Err(e) => eprintln!("Failed to encode gossip: {}", e),
+            }
+            drop(leaving);
+            round = round.wrapping_add(1);
+            thread::sleep(config.heartbeat_interval);
+        }
+    });
+
+    spawn_failure_detector(membership, config);
+    Ok(handle)
+}
+
+// The seeds and every node not known to be dead, apart from this one.
+fn gossip_candidates(seeds: &[String], membership: &DistributedFsState, gossip_address: &str) -> Vec<String> {
+    let mut candidates: BTreeSet<String> = seeds.iter().cloned().collect();
+    candidates.extend(membership.gossip_targets());
+    candidates.remove(gossip_address);
+    candidates.into_iter().collect()
+}
@@ -1,57 +1,397 @@
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
 use std::fs::File;
 use std::io::{self, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[path = "../atomic_file.rs"]
+mod atomic_file;
+#[path = "../attributes.rs"]
+mod attributes;
+// Shared with the server; the handler only needs part of each.
+#[allow(dead_code)]
+#[path = "../checksum.rs"]
+mod checksum;
+#[path = "../content_type.rs"]
+mod content_type;
+#[allow(dead_code)]
+#[path = "../paths.rs"]
+mod paths;
+#[allow(dead_code)]
+#[path = "../protocol.rs"]
+mod protocol;
+#[cfg(test)]
+#[path = "../scratch.rs"]
+mod scratch;
+#[path = "../trash.rs"]
+mod trash;
+
+use atomic_file::AtomicFile;
+use attributes::{FileAttributes, ATTRIBUTES_DIR};
+use checksum::StreamingChecksum;
+use paths::{PathError, SafePath};
+use protocol::{FileMetadata, ReplicationState};
+use trash::{Trash, TRASH_DIR};
+
+const TRASH_PURGE_INTERVAL_SECS: u64 = 60 * 60;
+
+const RESERVED_DIRS: &[&str] = &[TRASH_DIR, ATTRIBUTES_DIR];
 
 lazy_static::lazy_static! {
     static ref STORAGE_BASE_PATH: String = env::var("STORAGE_DIR").unwrap_or_else(|_| "data".to_string());
+    // Attribute updates read, change and rewrite a sidecar one at a time.
+    static ref ATTRIBUTES_LOCK: Mutex<()> = Mutex::new(());
+    static ref TRASH: Trash = Trash::from_env(&*STORAGE_BASE_PATH, &[ATTRIBUTES_DIR]).expect("Invalid trash settings");
+}
+
+#[derive(Deserialize)]
+struct FileQuery {
+    filename: String,
 }
 
-async fn handle_file_upload(mut payload: web::Payload) -> impl Responder {
-    let file_path = format!("{}/{}", *STORAGE_BASE_PATH, "your_filename_here");
+async fn handle_file_upload(query: web::Query<FileQuery>, mut payload: web::Payload) -> impl Responder {
+    let (_, file_path) = match resolve_file(&query.filename) {
+        Ok(resolved) => resolved,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
 
-    let mut destination_file = File::create(&file_path)
-        .map_err(|e| HttpResponse::InternalServerError().body(format!("Failed to create file: {}", e)))?;
+    // Readers keep seeing the previous file until the upload is complete.
+    let mut destination_file = match AtomicFile::create(&file_path) {
+        Ok(file) => file,
+        Err(e) => return HttpResponse::InternalServerError().body(format!("Failed to create file: {}", e)),
+    };
 
     while let Some(chunk) = payload.next().await {
-        let data = chunk.map_err(|e| HttpResponse::InternalServerError().body(format!("Failed reading chunk: {}", e)))?;
+        let data = match chunk {
+            Ok(data) => data,
+            Err(e) => return HttpResponse::InternalServerError().body(format!("Failed reading chunk: {}", e)),
+        };
         if let Err(e) = destination_file.write_all(&data) {
             return HttpResponse::InternalServerError().body(format!("Failed to write data: {}", e));
         }
     }
+    if let Err(e) = destination_file.commit() {
+        return HttpResponse::InternalServerError().body(format!("Failed to write data: {}", e));
+    }
 
     HttpResponse::Ok().body("File uploaded successfully")
 }
 
-async fn serve_file_download() -> impl Responder {
-    let file_path = format!("{}/{}", *STORAGE_BASE_PATH, "file_name_to_download");
-    let mut file_to_serve = File::open(&file_path).map_err(|_| HttpResponse::NotFound().body("File not found"))?;
+async fn serve_file_download(query: web::Query<FileQuery>) -> impl Responder {
+    let (_, file_path) = match resolve_file(&query.filename) {
+        Ok(resolved) => resolved,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let mut file_to_serve = match File::open(&file_path) {
+        Ok(file) => file,
+        Err(_) => return HttpResponse::NotFound().body("File not found"),
+    };
     let mut file_contents = Vec::new();
-    file_to_serve.read_to_end(&mut file_contents).map_err(|_| HttpResponse::InternalServerError().body("Failed to read the file"))?;
+    if file_to_serve.read_to_end(&mut file_contents).is_err() {
+        return HttpResponse::InternalServerError().body("Failed to read the file");
+    }
 
     HttpResponse::Ok().content_type("application/octet-stream").body(file_contents)
 }
 
-async fn handle_file_deletion() -> impl Responder {
-    let file_path = format!("{}/{}", *STORAGE_BASE_PATH, "file_name_to_delete");
-    fs::remove_file(&file_path).map_err(|_| HttpResponse::InternalServerError().body("Failed to delete the file"))?;
+// Deleted files go to the trash, where `/undelete` can bring them back
+// until they are purged.
+async fn handle_file_deletion(query: web::Query<FileQuery>) -> impl Responder {
+    let (filename, file_path) = match resolve_file(&query.filename) {
+        Ok(resolved) => resolved,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    if !file_path.is_file() {
+        return HttpResponse::NotFound().body("File not found");
+    }
+    if TRASH.delete(&filename).is_err() {
+        return HttpResponse::InternalServerError().body("Failed to delete the file");
+    }
 
     HttpResponse::Ok().body("File deleted successfully")
 }
 
+#[derive(Serialize)]
+struct TrashEntry {
+    path: String,
+    deleted_at: u64,
+}
+
+async fn handle_undelete(query: web::Query<FileQuery>) -> HttpResponse {
+    let (path, _) = match resolve_file(&query.filename) {
+        Ok(resolved) => resolved,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    match TRASH.restore(&path) {
+        Ok(()) => HttpResponse::Ok().body(format!("Restored {}", path)),
+        Err(e) => error_response(path.as_str(), e),
+    }
+}
+
+async fn list_trash() -> HttpResponse {
+    match TRASH.list() {
+        Ok(trashed) => {
+            let entries: Vec<TrashEntry> =
+                trashed.into_iter().map(|trashed| TrashEntry { path: trashed.path, deleted_at: trashed.deleted_at }).collect();
+            HttpResponse::Ok().json(entries)
+        },
+        Err(e) => HttpResponse::InternalServerError().body(format!("Failed to list the trash: {}", e)),
+    }
+}
+
+// An empty `content_type` or `owner` clears it; keys in `remove` are
+// dropped after `set` is applied.
+#[derive(Deserialize)]
+struct AttributeChange {
+    #[serde(default)]
+    content_type: Option<String>,
+    #[serde(default)]
+    owner: Option<String>,
+    #[serde(default)]
+    set: BTreeMap<String, String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+async fn stat_file(query: web::Query<FileQuery>) -> HttpResponse {
+    let (filename, file_path) = match resolve_file(&query.filename) {
+        Ok(resolved) => resolved,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    match file_metadata(&filename, &file_path) {
+        Ok(metadata) => HttpResponse::Ok().json(metadata),
+        Err(e) => error_response(filename.as_str(), e),
+    }
+}
+
+async fn set_attributes(query: web::Query<FileQuery>, change: web::Json<AttributeChange>) -> HttpResponse {
+    let (filename, file_path) = match resolve_file(&query.filename) {
+        Ok(resolved) => resolved,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    if change.set.keys().any(|key| key.is_empty()) {
+        return HttpResponse::BadRequest().body("Attribute names can't be empty");
+    }
+    if !file_path.is_file() {
+        return HttpResponse::NotFound().body("File not found");
+    }
+
+    let _guard = ATTRIBUTES_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let updated = FileAttributes::read(&*STORAGE_BASE_PATH, &filename).and_then(|mut attributes| {
+        let change = change.into_inner();
+        if let Some(content_type) = change.content_type {
+            attributes.content_type = Some(content_type).filter(|content_type| !content_type.is_empty());
+        }
+        if let Some(owner) = change.owner {
+            attributes.owner = Some(owner).filter(|owner| !owner.is_empty());
+        }
+        attributes.custom.extend(change.set);
+        for key in &change.remove {
+            attributes.custom.remove(key);
+        }
+        attributes.write(&*STORAGE_BASE_PATH, &filename)
+    });
+    match updated {
+        Ok(()) => HttpResponse::Ok().body(format!("Updated the attributes of {}", filename)),
+        Err(e) => error_response(filename.as_str(), e),
+    }
+}
+
+fn file_metadata(filename: &SafePath, file_path: &Path) -> io::Result<FileMetadata> {
+    let metadata = fs::metadata(file_path)?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "File not found"));
+    }
+    let attributes = FileAttributes::read(&*STORAGE_BASE_PATH, filename)?;
+    let modified_at = metadata.modified().map(secs_since_epoch).unwrap_or(0);
+    Ok(FileMetadata {
+        path: filename.as_str().to_string(),
+        size: metadata.len(),
+        checksum: file_checksum(file_path)?,
+        // Not every file system records creation times.
+        created_at: metadata.created().map(secs_since_epoch).unwrap_or(modified_at),
+        modified_at,
+        // Files are replaced in place, so only the current version exists.
+        version: 1,
+        content_type: attributes.content_type.unwrap_or_else(|| content_type::guess(filename.as_str()).to_string()),
+        owner: attributes.owner,
+        attributes: attributes.custom,
+        // The handler keeps the single copy it serves.
+        replication: ReplicationState { copies: 1, wanted: 1, erasure: None },
+    })
+}
+
+fn file_checksum(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut checksum = StreamingChecksum::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            return Ok(checksum.finish());
+        }
+        checksum.update(&buffer[..count]);
+    }
+}
+
+fn secs_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+#[derive(Deserialize)]
+struct DirectoryQuery {
+    path: String,
+    #[serde(default)]
+    parents: bool,
+    #[serde(default)]
+    recursive: bool,
+}
+
+#[derive(Serialize)]
+struct DirectoryEntry {
+    path: String,
+    kind: &'static str,
+    size: u64,
+    modified_at: u64,
+}
+
+// Request paths only ever reach the file system as a `SafePath` below the
+// storage directory, and never inside the trash or the attribute sidecars.
+fn resolve(path: &str) -> Result<(SafePath, PathBuf), PathError> {
+    let path = SafePath::parse(path).and_then(outside_reserved)?;
+    let full_path = path.under(&*STORAGE_BASE_PATH);
+    Ok((path, full_path))
+}
+
+fn resolve_file(filename: &str) -> Result<(SafePath, PathBuf), PathError> {
+    let filename = SafePath::file(filename).and_then(outside_reserved)?;
+    let full_path = filename.under(&*STORAGE_BASE_PATH);
+    Ok((filename, full_path))
+}
+
+fn outside_reserved(path: SafePath) -> Result<SafePath, PathError> {
+    match path.as_str().split('/').next() {
+        Some(first) if RESERVED_DIRS.contains(&first) => Err(PathError::Reserved(first.to_string())),
+        _ => Ok(path),
+    }
+}
+
+fn error_response(path: &str, error: io::Error) -> HttpResponse {
+    let message = format!("/{}: {}", path, error);
+    match error.kind() {
+        io::ErrorKind::NotFound => HttpResponse::NotFound().body(message),
+        io::ErrorKind::AlreadyExists | io::ErrorKind::DirectoryNotEmpty => HttpResponse::Conflict().body(message),
+        io::ErrorKind::NotADirectory | io::ErrorKind::InvalidInput => HttpResponse::BadRequest().body(message),
+        _ => HttpResponse::InternalServerError().body(message),
+    }
+}
+
+async fn create_directory(query: web::Query<DirectoryQuery>) -> HttpResponse {
+    let (path, full_path) = match resolve(&query.path) {
+        Ok(resolved) => resolved,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let created = if query.parents { fs::create_dir_all(&full_path) } else { fs::create_dir(&full_path) };
+    match created {
+        Ok(()) => HttpResponse::Created().body(format!("Created directory {}", path)),
+        Err(e) => error_response(path.as_str(), e),
+    }
+}
+
+async fn remove_directory(query: web::Query<DirectoryQuery>) -> HttpResponse {
+    let (path, full_path) = match resolve(&query.path) {
+        Ok(resolved) => resolved,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    if path.is_root() {
+        return HttpResponse::BadRequest().body("The root directory can't be removed");
+    }
+    // Only a directory with something in it is worth keeping in the trash.
+    let removed = if query.recursive {
+        match fs::metadata(&full_path) {
+            Ok(metadata) if metadata.is_dir() => TRASH.delete(&path),
+            Ok(_) => Err(io::Error::new(io::ErrorKind::NotADirectory, "Not a directory")),
+            Err(e) => Err(e),
+        }
+    } else {
+        fs::remove_dir(&full_path)
+    };
+    match removed {
+        Ok(()) => HttpResponse::Ok().body(format!("Removed directory {}", path)),
+        Err(e) => error_response(path.as_str(), e),
+    }
+}
+
+async fn list_directory(query: web::Query<DirectoryQuery>) -> HttpResponse {
+    let (path, full_path) = match resolve(&query.path) {
+        Ok(resolved) => resolved,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    match read_entries(path.as_str(), &full_path, query.recursive) {
+        Ok(mut entries) => {
+            entries.sort_by(|a, b| a.path.cmp(&b.path));
+            HttpResponse::Ok().json(entries)
+        },
+        Err(e) => error_response(path.as_str(), e),
+    }
+}
+
+fn read_entries(path: &str, full_path: &Path, recursive: bool) -> io::Result<Vec<DirectoryEntry>> {
+    let mut entries = Vec::new();
+    for dir_entry in fs::read_dir(full_path)? {
+        let dir_entry = dir_entry?;
+        let metadata = dir_entry.metadata()?;
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+        if path.is_empty() && RESERVED_DIRS.contains(&name.as_str()) {
+            continue;
+        }
+        let child = if path.is_empty() { name } else { format!("{}/{}", path, name) };
+        let modified_at = metadata.modified().map(secs_since_epoch).unwrap_or(0);
+        if metadata.is_dir() && recursive {
+            entries.extend(read_entries(&child, &dir_entry.path(), true)?);
+        }
+        entries.push(DirectoryEntry {
+            kind: if metadata.is_dir() { "directory" } else { "file" },
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            path: child,
+            modified_at,
+        });
+    }
+    Ok(entries)
+}
+
 #[actix_web::main]
 async fn main() -> io::Result<()> {
     env::set_var("RUST_LOG", "actix_web=info");
     env_logger::init();
 
+    // Deletes older than TRASH_RETENTION_DAYS are purged in the background.
+    thread::spawn(|| loop {
+        if let Err(e) = TRASH.purge() {
+            eprintln!("Failed to purge the trash: {}", e);
+        }
+        thread::sleep(Duration::from_secs(TRASH_PURGE_INTERVAL_SECS));
+    });
+
     HttpServer::new(|| {
         App::new()
             .route("/upload", web::post().to(handle_file_upload))
             .route("/download", web::get().to(serve_file_download))
             .route("/delete", web::delete().to(handle_file_deletion))
+            .route("/undelete", web::post().to(handle_undelete))
+            .route("/trash", web::get().to(list_trash))
+            .route("/stat", web::get().to(stat_file))
+            .route("/attributes", web::post().to(set_attributes))
+            .route("/directories", web::post().to(create_directory))
+            .route("/directories", web::get().to(list_directory))
+            .route("/directories", web::delete().to(remove_directory))
     })
     .bind("127.0.0.1:8080")?
     .run()
@@ -1,52 +1,366 @@
 use std::collections::HashMap;
+use std::env;
+use std::fmt;
 use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use serde::{Serialize, Deserialize};
 
+const DEFAULT_HEARTBEAT_INTERVAL_MS: u64 = 1_000;
+const DEFAULT_SUSPECT_TIMEOUT_MS: u64 = 5_000;
+const DEFAULT_DEAD_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_GOSSIP_FANOUT: usize = 3;
+
+/// Where a node is in its lifecycle. Alive and suspect nodes own data;
+/// dead and decommissioning ones have their data moved elsewhere.
+///
+/// Variants are ordered by precedence: when two views disagree about the
+/// same heartbeat, the later state wins.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum NodeStatus {
+    /// Heartbeats are arriving on time.
+    Alive,
+    /// Heartbeats have stopped for longer than the suspect timeout. The node
+    /// keeps its data in case it is only briefly unreachable.
+    Suspect,
+    /// Heartbeats have stopped for longer than the dead timeout, or the node
+    /// said goodbye.
+    Dead,
+    /// Being drained by an operator. Heartbeats don't bring it back.
+    Decommissioning,
+}
+
+impl NodeStatus {
+    pub fn is_member(self) -> bool {
+        matches!(self, NodeStatus::Alive | NodeStatus::Suspect)
+    }
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            NodeStatus::Alive => "alive",
+            NodeStatus::Suspect => "suspect",
+            NodeStatus::Dead => "dead",
+            NodeStatus::Decommissioning => "decommissioning",
+        };
+        f.write_str(name)
+    }
+}
+
+/// One node's entry in the membership view. Entries are exchanged whole
+/// between nodes when they gossip.
 #[derive(Serialize, Deserialize, Debug, Clone)]
-struct ClusterNode {
+pub struct ClusterNode {
     id: String,
-    status: String,
+    address: String,
+    heartbeat_address: Option<String>,
+    status: NodeStatus,
+    /// Counter the node bumps itself every heartbeat interval. A higher
+    /// value is always fresher news about the node.
+    #[serde(default)]
+    heartbeat: u64,
+    /// When `heartbeat` last advanced, in milliseconds since the Unix epoch
+    /// on this node's clock. Never gossiped.
+    #[serde(skip)]
+    last_heartbeat: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MembershipConfig {
+    /// How often each node bumps its heartbeat and gossips its view.
+    pub heartbeat_interval: Duration,
+    /// Number of peers each round of gossip goes to.
+    pub fanout: usize,
+    /// Silence after which an alive node becomes suspect.
+    pub suspect_after: Duration,
+    /// Silence after which a node is declared dead.
+    pub dead_after: Duration,
+}
+
+impl MembershipConfig {
+    /// Reads `DFS_HEARTBEAT_INTERVAL_MS` (default 1000), `DFS_GOSSIP_FANOUT`
+    /// (default 3), `DFS_SUSPECT_TIMEOUT_MS` (default 5000) and
+    /// `DFS_DEAD_TIMEOUT_MS` (default 30000).
+    pub fn from_env() -> Result<Self, String> {
+        let fanout = match env::var("DFS_GOSSIP_FANOUT") {
+            Ok(value) => value.parse::<usize>().map_err(|e| format!("Invalid DFS_GOSSIP_FANOUT: {}", e))?,
+            Err(_) => DEFAULT_GOSSIP_FANOUT,
+        };
+        if fanout == 0 {
+            return Err("DFS_GOSSIP_FANOUT must be at least 1".to_string());
+        }
+        let config = Self {
+            heartbeat_interval: millis_from_env("DFS_HEARTBEAT_INTERVAL_MS", DEFAULT_HEARTBEAT_INTERVAL_MS)?,
+            fanout,
+            suspect_after: millis_from_env("DFS_SUSPECT_TIMEOUT_MS", DEFAULT_SUSPECT_TIMEOUT_MS)?,
+            dead_after: millis_from_env("DFS_DEAD_TIMEOUT_MS", DEFAULT_DEAD_TIMEOUT_MS)?,
+        };
+        if config.heartbeat_interval.as_millis() == 0 {
+            return Err("DFS_HEARTBEAT_INTERVAL_MS must be at least 1".to_string());
+        }
+        if config.suspect_after <= config.heartbeat_interval || config.dead_after <= config.suspect_after {
+            return Err("Timeouts must satisfy heartbeat interval < suspect timeout < dead timeout".to_string());
+        }
+        Ok(config)
+    }
 }
 
-struct DistributedFsState {
+fn millis_from_env(key: &str, default: u64) -> Result<Duration, String> {
+    match env::var(key) {
+        Ok(value) => value.parse().map(Duration::from_millis).map_err(|e| format!("Invalid {}: {}", key, e)),
+        Err(_) => Ok(Duration::from_millis(default)),
+    }
+}
+
+/// Notified whenever cluster membership changes, e.g. so data can be moved
+/// to match the new set of nodes. A node joins when it becomes alive and
+/// leaves when it is declared dead, decommissioned or removed.
+pub trait MembershipObserver: Send + Sync {
+    fn node_added(&self, node_id: &str, address: &str);
+    fn node_removed(&self, node_id: &str);
+}
+
+pub struct DistributedFsState {
+    /// This node's own entry, set once it takes part in gossip.
+    local_node: Mutex<Option<ClusterNode>>,
     cluster_nodes: Arc<Mutex<HashMap<String, ClusterNode>>>,
+    observers: Mutex<Vec<Arc<dyn MembershipObserver>>>,
+}
+
+// A membership change to pass on to observers once the node lock is released.
+enum Change {
+    Added(String, String),
+    Removed(String),
 }
 
 impl DistributedFsState {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
+            local_node: Mutex::new(None),
             cluster_nodes: Arc::new(Mutex::new(HashMap::new())),
+            observers: Mutex::new(Vec::new()),
         }
     }
 
-    fn add_cluster_node(&self, node_id: &str, status: &str) {
-        let node = ClusterNode {
-            id: node_id.to_string(),
-            status: status.to_string(),
+    pub fn add_observer(&self, observer: Arc<dyn MembershipObserver>) {
+        self.observers.lock().unwrap().push(observer);
+    }
+
+    pub fn add_cluster_node(&self, node_id: &str, address: &str, status: NodeStatus) {
+        let change = {
+            let mut cluster_nodes = self.cluster_nodes.lock().unwrap();
+            let previous = cluster_nodes.get(node_id).cloned();
+            let node = ClusterNode {
+                id: node_id.to_string(),
+                address: address.to_string(),
+                heartbeat_address: previous.as_ref().and_then(|node| node.heartbeat_address.clone()),
+                status,
+                heartbeat: previous.as_ref().map_or(0, |node| node.heartbeat),
+                last_heartbeat: now_millis(),
+            };
+            cluster_nodes.insert(node_id.to_string(), node.clone());
+            membership_change(previous.as_ref(), Some(&node))
         };
-        let mut cluster_nodes = self.cluster_nodes.lock().unwrap();
-        cluster_nodes.insert(node_id.to_string(), node);
+        self.notify(change);
+    }
+
+    pub fn remove_cluster_node(&self, node_id: &str) {
+        let change = {
+            let removed = self.cluster_nodes.lock().unwrap().remove(node_id);
+            membership_change(removed.as_ref(), None)
+        };
+        self.notify(change);
+    }
+
+    pub fn query_node_status(&self, node_id: &str) -> Option<NodeStatus> {
+        let cluster_nodes = self.cluster_nodes.lock().unwrap();
+        cluster_nodes.get(node_id).map(|node| node.status)
+    }
+
+    /// Registers this node so it is included in the views it gossips.
+    pub fn set_local_node(&self, node_id: &str, address: &str, heartbeat_address: &str) {
+        *self.local_node.lock().unwrap() = Some(ClusterNode {
+            id: node_id.to_string(),
+            address: address.to_string(),
+            heartbeat_address: Some(heartbeat_address.to_string()),
+            status: NodeStatus::Alive,
+            heartbeat: 0,
+            last_heartbeat: now_millis(),
+        });
+    }
+
+    /// Changes the state this node announces about itself, e.g. when it is
+    /// being decommissioned.
+    pub fn set_local_status(&self, status: NodeStatus) {
+        if let Some(local_node) = self.local_node.lock().unwrap().as_mut() {
+            local_node.status = status;
+            local_node.heartbeat += 1;
+        }
+    }
+
+    /// Bumps this node's heartbeat and returns the view to gossip: this
+    /// node's entry followed by every node it knows about, in any state.
+    pub fn next_digest(&self) -> Vec<ClusterNode> {
+        let mut digest = Vec::new();
+        if let Some(local_node) = self.local_node.lock().unwrap().as_mut() {
+            local_node.heartbeat += 1;
+            digest.push(local_node.clone());
+        }
+        digest.extend(self.cluster_nodes.lock().unwrap().values().cloned());
+        digest
+    }
+
+    /// Folds a peer's view into ours. For each node the entry with the
+    /// higher heartbeat wins; for equal heartbeats the later state wins, so
+    /// failures and departures spread while a live node's next heartbeat
+    /// overrides any stale verdict about it. Suspicion is never adopted from
+    /// others; each node forms its own from the heartbeats it sees.
+    pub fn merge_digest(&self, digest: Vec<ClusterNode>) {
+        let now = now_millis();
+        let mut changes = Vec::new();
+        {
+            let mut local_node = self.local_node.lock().unwrap();
+            let mut cluster_nodes = self.cluster_nodes.lock().unwrap();
+            for mut remote in digest {
+                if let Some(local_node) = local_node.as_mut().filter(|node| node.id == remote.id) {
+                    // Refute reports of our own death by outbidding them.
+                    if remote.status > local_node.status && remote.heartbeat >= local_node.heartbeat {
+                        local_node.heartbeat = remote.heartbeat + 1;
+                    }
+                    continue;
+                }
+
+                if remote.status == NodeStatus::Suspect {
+                    remote.status = NodeStatus::Alive;
+                }
+                let previous = cluster_nodes.get(&remote.id).cloned();
+                let updated = match previous.as_ref() {
+                    None => true,
+                    Some(known) if remote.heartbeat > known.heartbeat => true,
+                    Some(known) => remote.heartbeat == known.heartbeat && remote.status > known.status,
+                };
+                if !updated {
+                    continue;
+                }
+                if previous.as_ref().is_none_or(|known| remote.heartbeat > known.heartbeat) {
+                    remote.last_heartbeat = now;
+                } else if let Some(known) = previous.as_ref() {
+                    remote.last_heartbeat = known.last_heartbeat;
+                }
+                if previous.is_none() || previous.as_ref().map(|known| known.status) != Some(remote.status) {
+                    println!("Node {} is now {} (heartbeat {})", remote.id, remote.status, remote.heartbeat);
+                }
+                changes.push(membership_change(previous.as_ref(), Some(&remote)));
+                cluster_nodes.insert(remote.id.clone(), remote);
+            }
+        }
+        for change in changes {
+            self.notify(change);
+        }
+    }
+
+    /// Marks a node that said goodbye as dead without waiting for timeouts.
+    pub fn record_departure(&self, node_id: &str) {
+        self.set_status(node_id, NodeStatus::Dead);
     }
 
-    fn remove_cluster_node(&self, node_id: &str) {
-        let mut cluster_nodes = self.cluster_nodes.lock().unwrap();
-        cluster_nodes.remove(node_id);
+    /// Starts draining a node: it stops owning data but stays listed until
+    /// it is removed.
+    pub fn decommission_node(&self, node_id: &str) -> bool {
+        self.set_status(node_id, NodeStatus::Decommissioning)
     }
 
-    fn query_node_status(&self, node_id: &str) -> Option<String> {
+    /// Moves nodes whose heartbeats are overdue to suspect or dead.
+    pub fn check_failures(&self, config: &MembershipConfig) {
+        let now = now_millis();
+        let suspect_after = config.suspect_after.as_millis() as u64;
+        let dead_after = config.dead_after.as_millis() as u64;
+
+        let mut changes = Vec::new();
+        {
+            let mut cluster_nodes = self.cluster_nodes.lock().unwrap();
+            for node in cluster_nodes.values_mut() {
+                let silence = now.saturating_sub(node.last_heartbeat);
+                let status = match node.status {
+                    NodeStatus::Alive | NodeStatus::Suspect if silence >= dead_after => NodeStatus::Dead,
+                    NodeStatus::Alive if silence >= suspect_after => NodeStatus::Suspect,
+                    status => status,
+                };
+                if status != node.status {
+                    println!("Node {} is now {} after {} ms without a heartbeat", node.id, status, silence);
+                    let previous = node.clone();
+                    node.status = status;
+                    changes.push(membership_change(Some(&previous), Some(node)));
+                }
+            }
+        }
+        for change in changes {
+            self.notify(change);
+        }
+    }
+
+    /// Heartbeat addresses of the nodes worth gossiping with: every node
+    /// that isn't dead.
+    pub fn gossip_targets(&self) -> Vec<String> {
         let cluster_nodes = self.cluster_nodes.lock().unwrap();
-        cluster_nodes.get(node_id).map(|node| node.status.clone())
+        cluster_nodes
+            .values()
+            .filter(|node| node.status != NodeStatus::Dead)
+            .filter_map(|node| node.heartbeat_address.clone())
+            .collect()
+    }
+
+    fn set_status(&self, node_id: &str, status: NodeStatus) -> bool {
+        let change = {
+            let mut cluster_nodes = self.cluster_nodes.lock().unwrap();
+            let node = match cluster_nodes.get_mut(node_id) {
+                Some(node) => node,
+                None => return false,
+            };
+            let previous = node.clone();
+            node.status = status;
+            membership_change(Some(&previous), Some(node))
+        };
+        self.notify(change);
+        true
+    }
+
+    // Observers run outside the node lock so they are free to query it.
+    fn notify(&self, change: Option<Change>) {
+        let change = match change {
+            Some(change) => change,
+            None => return,
+        };
+        for observer in self.observers.lock().unwrap().iter() {
+            match &change {
+                Change::Added(node_id, address) => observer.node_added(node_id, address),
+                Change::Removed(node_id) => observer.node_removed(node_id),
+            }
+        }
     }
 }
 
-#[tokio::main]
-async fn main() {
-    dotenv::dotenv().ok();
+fn membership_change(before: Option<&ClusterNode>, after: Option<&ClusterNode>) -> Option<Change> {
+    let before = before.filter(|node| node.status.is_member());
+    let after = after.filter(|node| node.status.is_member());
+    match (before, after) {
+        (None, Some(node)) => Some(Change::Added(node.id.clone(), node.address.clone())),
+        (Some(old), Some(node)) if old.address != node.address => Some(Change::Added(node.id.clone(), node.address.clone())),
+        (Some(node), None) => Some(Change::Removed(node.id.clone())),
+        _ => None,
+    }
+}
 
-    let distributed_fs_state = DistributedFsState::new();
+/// Runs `check_failures` every heartbeat interval until the process exits.
+pub fn spawn_failure_detector(state: Arc<DistributedFsState>, config: MembershipConfig) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(config.heartbeat_interval);
+        state.check_failures(&config);
+    })
+}
 
-    distributed_fs_state.add_cluster_node("node1", "active");
-    distributed_fs_state.add_cluster_node("node2", "inactive");
-    println!("{:?}", distributed_fs_state.query_node_status("node1")); // Some("active")
-    distributed_fs_state.remove_cluster_node("node2");
-}
\ No newline at end of file
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_millis() as u64).unwrap_or(0)
+}
@@ -0,0 +1,428 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+use super::atomic_file::AtomicFile;
+use super::attributes::FileAttributes;
+use super::paths;
+
+const LOG_FILE_NAME: &str = "metadata.log";
+// Rewrite the log once it holds this many more records than live entries.
+const COMPACTION_SLACK: usize = 1024;
+
+/// A file's current content, plus the earlier versions still kept. `path`
+/// is the current version's manifest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileEntry {
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    pub checksum: String,
+    pub created_at: u64,
+    pub modified_at: u64,
+    #[serde(default = "first_version")]
+    pub version: u64,
+    /// Earlier versions, newest first.
+    #[serde(default)]
+    pub history: Vec<FileVersion>,
+    /// New versions of the file keep them.
+    #[serde(default)]
+    pub attributes: FileAttributes,
+}
+
+/// One version of a file's content. Version ids count up from 1 with each
+/// write to the file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileVersion {
+    pub version: u64,
+    pub path: String,
+    pub size: u64,
+    pub checksum: String,
+    pub modified_at: u64,
+}
+
+impl FileEntry {
+    pub fn current(&self) -> FileVersion {
+        FileVersion {
+            version: self.version,
+            path: self.path.clone(),
+            size: self.size,
+            checksum: self.checksum.clone(),
+            modified_at: self.modified_at,
+        }
+    }
+
+    /// Every version kept, the current one first.
+    pub fn versions(&self) -> Vec<FileVersion> {
+        let mut versions = vec![self.current()];
+        versions.extend(self.history.iter().cloned());
+        versions
+    }
+}
+
+fn first_version() -> u64 {
+    1
+}
+
+/// A directory created explicitly. Directories that only exist because
+/// files were written below them have no entry of their own.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirectoryEntry {
+    pub path: String,
+    pub created_at: u64,
+}
+
+/// A deleted file, kept with all its versions until it is purged. Ids
+/// count up with each delete, so a name deleted more than once has several
+/// entries.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashEntry {
+    pub id: u64,
+    pub entry: FileEntry,
+    pub deleted_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", content = "record")]
+enum LogRecord {
+    Put(FileEntry),
+    /// Removes a file for good.
+    Delete { name: String },
+    /// Moves a file into the trash.
+    Trash(TrashEntry),
+    /// Moves a file back out of the trash under its old name.
+    Undelete { id: u64 },
+    /// Removes a file from the trash for good.
+    Purge { id: u64 },
+    MakeDirectory(DirectoryEntry),
+    RemoveDirectory { path: String },
+    /// Moves a file or directory, and everything below it, replacing
+    /// whatever was at `to`.
+    Rename { from: String, to: String },
+    /// Changes that take effect together. Being a single line, a crash
+    /// leaves either all of them in the log or none.
+    Batch(Vec<LogRecord>),
+}
+
+/// Files and directories changed together, recorded as one line.
+#[derive(Default)]
+pub struct NamespaceChange {
+    pub puts: Vec<FileEntry>,
+    pub trashed: Vec<TrashEntry>,
+    pub deletes: Vec<String>,
+    pub made_directories: Vec<DirectoryEntry>,
+    pub removed_directories: Vec<String>,
+}
+
+pub type FileIndex = HashMap<String, FileEntry>;
+pub type DirectoryIndex = HashMap<String, DirectoryEntry>;
+pub type TrashIndex = BTreeMap<u64, TrashEntry>;
+
+/// Append-only log of namespace changes kept at `base_dir/metadata.log`,
+/// one JSON record per line. Replaying it on startup rebuilds the file,
+/// directory and trash indexes; it is periodically compacted down to one
+/// record per live file, directory and trashed file.
+pub struct MetadataStore {
+    log_path: PathBuf,
+    log: File,
+    record_count: usize,
+}
+
+impl MetadataStore {
+    pub fn open<P: AsRef<Path>>(base_dir: P) -> io::Result<(Self, FileIndex, DirectoryIndex, TrashIndex)> {
+        fs::create_dir_all(&base_dir)?;
+        let log_path = base_dir.as_ref().join(LOG_FILE_NAME);
+        let (entries, directories, trash, record_count, valid_len) = replay(&log_path)?;
+
+        let log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+        // A crash mid-append leaves a torn final line; drop it so new records
+        // start on a clean line.
+        if log.metadata()?.len() > valid_len {
+            log.set_len(valid_len)?;
+        }
+
+        let mut store = Self { log_path, log, record_count };
+        store.maybe_compact(&entries, &directories, &trash)?;
+        Ok((store, entries, directories, trash))
+    }
+
+    pub fn record_rename(
+        &mut self,
+        from: &str,
+        to: &str,
+        entries: &FileIndex,
+        directories: &DirectoryIndex,
+        trash: &TrashIndex,
+    ) -> io::Result<()> {
+        self.append(&LogRecord::Rename { from: from.to_string(), to: to.to_string() })?;
+        self.maybe_compact(entries, directories, trash)
+    }
+
+    /// Records files written, moved to the trash and deleted together as
+    /// one change.
+    pub fn record_batch(
+        &mut self,
+        puts: &[FileEntry],
+        trashed: &[TrashEntry],
+        deletes: &[String],
+        entries: &FileIndex,
+        directories: &DirectoryIndex,
+        trash: &TrashIndex,
+    ) -> io::Result<()> {
+        let change = NamespaceChange { puts: puts.to_vec(), trashed: trashed.to_vec(), deletes: deletes.to_vec(), ..Default::default() };
+        self.append_change(&change)?;
+        self.maybe_compact(entries, directories, trash)
+    }
+
+    /// Records `change` as one line, leaving the indexes to the caller:
+    /// it applies the change once this returns, so they only ever hold what
+    /// is durable, and then calls `maybe_compact`.
+    pub fn append_change(&mut self, change: &NamespaceChange) -> io::Result<()> {
+        let records = change
+            .made_directories
+            .iter()
+            .map(|directory| LogRecord::MakeDirectory(directory.clone()))
+            .chain(change.puts.iter().map(|entry| LogRecord::Put(entry.clone())))
+            .chain(change.trashed.iter().map(|trashed| LogRecord::Trash(trashed.clone())))
+            .chain(change.deletes.iter().map(|name| LogRecord::Delete { name: name.clone() }))
+            .chain(change.removed_directories.iter().map(|path| LogRecord::RemoveDirectory { path: path.clone() }))
+            .collect();
+        self.append_all(records)
+    }
+
+    pub fn record_undelete(&mut self, id: u64, entries: &FileIndex, directories: &DirectoryIndex, trash: &TrashIndex) -> io::Result<()> {
+        self.append(&LogRecord::Undelete { id })?;
+        self.maybe_compact(entries, directories, trash)
+    }
+
+    pub fn record_purge(&mut self, ids: &[u64], entries: &FileIndex, directories: &DirectoryIndex, trash: &TrashIndex) -> io::Result<()> {
+        self.append_all(ids.iter().map(|id| LogRecord::Purge { id: *id }).collect())?;
+        self.maybe_compact(entries, directories, trash)
+    }
+
+    // Several records as one line, so they take effect together.
+    fn append_all(&mut self, mut records: Vec<LogRecord>) -> io::Result<()> {
+        let record = match records.len() {
+            1 => records.remove(0),
+            _ => LogRecord::Batch(records),
+        };
+        self.append(&record)
+    }
+
+    fn append(&mut self, record: &LogRecord) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.log.write_all(&line)?;
+        self.log.sync_data()?;
+        self.record_count += 1;
+        Ok(())
+    }
+
+    pub fn maybe_compact(&mut self, entries: &FileIndex, directories: &DirectoryIndex, trash: &TrashIndex) -> io::Result<()> {
+        let live = entries.len() + directories.len() + trash.len();
+        if self.record_count <= live + COMPACTION_SLACK {
+            return Ok(());
+        }
+
+        {
+            let mut compacted = AtomicFile::create(&self.log_path)?;
+            let records = directories
+                .values()
+                .map(|directory| LogRecord::MakeDirectory(directory.clone()))
+                // Moving a file to the trash removes any live file of that
+                // name, so the trash comes before the live files.
+                .chain(trash.values().map(|trashed| LogRecord::Trash(trashed.clone())))
+                .chain(entries.values().map(|entry| LogRecord::Put(entry.clone())));
+            for record in records {
+                let mut line = serde_json::to_vec(&record)?;
+                line.push(b'\n');
+                compacted.write_all(&line)?;
+            }
+            compacted.commit()?;
+        }
+
+        self.log = OpenOptions::new().append(true).open(&self.log_path)?;
+        self.record_count = live;
+        Ok(())
+    }
+}
+
+fn replay(log_path: &Path) -> io::Result<(FileIndex, DirectoryIndex, TrashIndex, usize, u64)> {
+    let mut entries = HashMap::new();
+    let mut directories = HashMap::new();
+    let mut trash = BTreeMap::new();
+    let mut record_count = 0;
+    let mut valid_len = 0u64;
+
+    let file = match File::open(log_path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((entries, directories, trash, 0, 0)),
+        Err(e) => return Err(e),
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 {
+            break;
+        }
+        if !line.ends_with('\n') {
+            break; // Torn write at the tail of the log
+        }
+        let record: LogRecord = serde_json::from_str(line.trim_end()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Corrupt metadata record at byte {}: {}", valid_len, e))
+        })?;
+        apply(record, &mut entries, &mut directories, &mut trash);
+        record_count += 1;
+        valid_len += read as u64;
+    }
+
+    Ok((entries, directories, trash, record_count, valid_len))
+}
+
+fn apply(record: LogRecord, entries: &mut FileIndex, directories: &mut DirectoryIndex, trash: &mut TrashIndex) {
+    match record {
+        LogRecord::Put(entry) => {
+            entries.insert(entry.name.clone(), entry);
+        },
+        LogRecord::Delete { name } => {
+            entries.remove(&name);
+        },
+        LogRecord::Trash(trashed) => {
+            entries.remove(&trashed.entry.name);
+            trash.insert(trashed.id, trashed);
+        },
+        LogRecord::Undelete { id } => {
+            if let Some(trashed) = trash.remove(&id) {
+                entries.insert(trashed.entry.name.clone(), trashed.entry);
+            }
+        },
+        LogRecord::Purge { id } => {
+            trash.remove(&id);
+        },
+        LogRecord::MakeDirectory(directory) => {
+            directories.insert(directory.path.clone(), directory);
+        },
+        LogRecord::RemoveDirectory { path } => {
+            directories.remove(&path);
+        },
+        LogRecord::Rename { from, to } => rename_entries(entries, directories, &from, &to),
+        LogRecord::Batch(records) => {
+            for record in records {
+                apply(record, entries, directories, trash);
+            }
+        },
+    }
+}
+
+/// Moves `from` and everything below it to `to`, dropping whatever was at
+/// `to` first. Used both for live renames and on replay, so the two always
+/// agree. Manifests stay where they are; entries keep pointing at them.
+pub fn rename_entries(entries: &mut FileIndex, directories: &mut DirectoryIndex, from: &str, to: &str) {
+    entries.remove(to);
+    directories.remove(to);
+    let moved_name = |name: &str| format!("{}{}", to, &name[from.len()..]);
+
+    let files: Vec<String> = entries.keys().filter(|name| *name == from || paths::is_under(name, from)).cloned().collect();
+    for name in files {
+        if let Some(mut entry) = entries.remove(&name) {
+            entry.name = moved_name(&name);
+            entries.insert(entry.name.clone(), entry);
+        }
+    }
+    let moved: Vec<String> = directories.keys().filter(|path| *path == from || paths::is_under(path, from)).cloned().collect();
+    for path in moved {
+        if let Some(mut directory) = directories.remove(&path) {
+            directory.path = moved_name(&path);
+            directories.insert(directory.path.clone(), directory);
+        }
+    }
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scratch::ScratchDir;
+
+    fn entry(name: &str) -> FileEntry {
+        FileEntry {
+            name: name.to_string(),
+            path: format!("manifests/{}", name),
+            size: 1,
+            checksum: "checksum".to_string(),
+            created_at: 1,
+            modified_at: 1,
+            version: 1,
+            history: Vec::new(),
+            attributes: FileAttributes::default(),
+        }
+    }
+
+    #[test]
+    fn replay_drops_a_torn_tail() {
+        let dir = ScratchDir::new("metadata-torn");
+        {
+            let (mut store, entries, directories, trash) = MetadataStore::open(&dir).unwrap();
+            store.record_batch(&[entry("a"), entry("b")], &[], &[], &entries, &directories, &trash).unwrap();
+        }
+        let log_path = dir.join(LOG_FILE_NAME);
+        let valid_len = fs::metadata(&log_path).unwrap().len();
+        let mut log = OpenOptions::new().append(true).open(&log_path).unwrap();
+        log.write_all(br#"{"op":"Put","record":{"name":"c","pa"#).unwrap();
+
+        let (mut store, entries, directories, trash) = MetadataStore::open(&dir).unwrap();
+        let mut names: Vec<&String> = entries.keys().collect();
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), valid_len);
+
+        // Records appended after the truncation replay cleanly.
+        store.record_batch(&[entry("c")], &[], &["a".to_string()], &entries, &directories, &trash).unwrap();
+        let (_, entries, _, _) = MetadataStore::open(&dir).unwrap();
+        let mut names: Vec<&String> = entries.keys().collect();
+        names.sort();
+        assert_eq!(names, ["b", "c"]);
+    }
+
+    #[test]
+    fn a_torn_batch_applies_none_of_its_changes() {
+        let dir = ScratchDir::new("metadata-torn-batch");
+        {
+            let (mut store, entries, directories, trash) = MetadataStore::open(&dir).unwrap();
+            store.record_batch(&[entry("a"), entry("b")], &[], &[], &entries, &directories, &trash).unwrap();
+        }
+        let batch = LogRecord::Batch(vec![
+            LogRecord::Put(entry("c")),
+            LogRecord::Delete { name: "a".to_string() },
+            LogRecord::Put(entry("d")),
+        ]);
+        let line = serde_json::to_vec(&batch).unwrap();
+        let mut log = OpenOptions::new().append(true).open(dir.join(LOG_FILE_NAME)).unwrap();
+        log.write_all(&line[..line.len() - 10]).unwrap();
+
+        let (_, entries, _, _) = MetadataStore::open(&dir).unwrap();
+        let mut names: Vec<&String> = entries.keys().collect();
+        names.sort();
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[test]
+    fn a_corrupt_complete_record_is_an_error() {
+        let dir = ScratchDir::new("metadata-corrupt");
+        fs::write(dir.join(LOG_FILE_NAME), "not json\n").unwrap();
+        let error = MetadataStore::open(&dir).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
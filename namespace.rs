@@ -0,0 +1,384 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use serde::{Deserialize, Serialize};
+
+use super::paths;
+use super::protocol::{DirEntry, EntryKind};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceEntry {
+    pub size: u64,
+    pub checksum: String,
+    pub created_at: u64,
+    pub modified_at: u64,
+}
+
+/// A change to the cluster-wide file namespace. Operations carry everything
+/// they need, timestamps included, so every replica applying the same
+/// sequence ends up in the same state.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", content = "args")]
+pub enum MetadataOp {
+    /// Creates a file entry, or replaces an existing one keeping its
+    /// creation time.
+    Create { name: String, size: u64, checksum: String, modified_at: u64 },
+    /// Moves a file or directory, with everything below it. With
+    /// `overwrite` an existing file at `to`, or an empty directory when
+    /// moving a directory, is replaced.
+    Rename {
+        from: String,
+        to: String,
+        #[serde(default)]
+        overwrite: bool,
+    },
+    Delete { name: String },
+    MakeDirectory { path: String, parents: bool, created_at: u64 },
+    /// Removes a directory, and with `recursive` every entry below it.
+    RemoveDirectory { path: String, recursive: bool },
+}
+
+/// Why an operation was rejected. Rejections are part of the replicated
+/// outcome: every replica rejects the same operation the same way.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum OpError {
+    NotFound(String),
+    AlreadyExists(String),
+    NotADirectory(String),
+    IsADirectory(String),
+    DirectoryNotEmpty(String),
+    InvalidOperation(String),
+}
+
+impl fmt::Display for OpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OpError::NotFound(name) => write!(f, "{}: File not found", name),
+            OpError::AlreadyExists(name) => write!(f, "{}: File already exists", name),
+            OpError::NotADirectory(name) => write!(f, "{}: Not a directory", name),
+            OpError::IsADirectory(name) => write!(f, "{}: Is a directory", name),
+            OpError::DirectoryNotEmpty(name) => write!(f, "{}: Directory not empty", name),
+            OpError::InvalidOperation(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<OpError> for io::Error {
+    fn from(error: OpError) -> io::Error {
+        let kind = match error {
+            OpError::NotFound(_) => io::ErrorKind::NotFound,
+            OpError::AlreadyExists(_) => io::ErrorKind::AlreadyExists,
+            OpError::NotADirectory(_) => io::ErrorKind::NotADirectory,
+            OpError::IsADirectory(_) => io::ErrorKind::IsADirectory,
+            OpError::DirectoryNotEmpty(_) => io::ErrorKind::DirectoryNotEmpty,
+            OpError::InvalidOperation(_) => io::ErrorKind::InvalidInput,
+        };
+        io::Error::new(kind, error.to_string())
+    }
+}
+
+/// The replicated state machine: every file name in the cluster and its
+/// entry, in name order, and every directory created explicitly with its
+/// creation time. Directories with files below them exist implicitly.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Namespace {
+    files: BTreeMap<String, NamespaceEntry>,
+    #[serde(default)]
+    directories: BTreeMap<String, u64>,
+}
+
+impl Namespace {
+    pub fn apply(&mut self, operation: &MetadataOp) -> Result<(), OpError> {
+        match operation {
+            MetadataOp::Create { name, size, checksum, modified_at } => {
+                if self.is_directory(name) {
+                    return Err(OpError::IsADirectory(name.clone()));
+                }
+                self.check_parents(name)?;
+                let created_at = self.files.get(name).map_or(*modified_at, |entry| entry.created_at);
+                let entry = NamespaceEntry {
+                    size: *size,
+                    checksum: checksum.clone(),
+                    created_at,
+                    modified_at: *modified_at,
+                };
+                self.files.insert(name.clone(), entry);
+                Ok(())
+            },
+            MetadataOp::Rename { from, to, overwrite } => {
+                if from.is_empty() || to.is_empty() {
+                    return Err(OpError::InvalidOperation("The root directory can't be renamed or replaced".to_string()));
+                }
+                if paths::is_under(to, from) {
+                    return Err(OpError::InvalidOperation(format!("Can't move {} into itself", from)));
+                }
+                let source_is_file = self.files.contains_key(from);
+                if !source_is_file && !self.is_directory(from) {
+                    return Err(OpError::NotFound(from.clone()));
+                }
+                if from == to {
+                    return Ok(());
+                }
+                self.check_parents(to)?;
+                let target_is_empty = !self.files.keys().chain(self.directories.keys()).any(|name| paths::is_under(name, to));
+                match (source_is_file, self.files.contains_key(to), self.is_directory(to)) {
+                    (_, false, false) => {},
+                    (true, true, _) if *overwrite => {},
+                    (false, false, true) if *overwrite && target_is_empty => {},
+                    (true, false, true) => return Err(OpError::IsADirectory(to.clone())),
+                    (false, true, _) => return Err(OpError::NotADirectory(to.clone())),
+                    _ => return Err(OpError::AlreadyExists(to.clone())),
+                }
+
+                self.files.remove(to);
+                self.directories.remove(to);
+                let moved = |name: &String| *name == *from || paths::is_under(name, from);
+                let files: Vec<String> = self.files.keys().filter(|name| moved(name)).cloned().collect();
+                for name in files {
+                    let entry = self.files.remove(&name).unwrap();
+                    self.files.insert(format!("{}{}", to, &name[from.len()..]), entry);
+                }
+                let directories: Vec<String> = self.directories.keys().filter(|name| moved(name)).cloned().collect();
+                for name in directories {
+                    let created_at = self.directories.remove(&name).unwrap();
+                    self.directories.insert(format!("{}{}", to, &name[from.len()..]), created_at);
+                }
+                Ok(())
+            },
+            MetadataOp::Delete { name } => match self.files.remove(name) {
+                Some(_) => Ok(()),
+                None => Err(OpError::NotFound(name.clone())),
+            },
+            MetadataOp::MakeDirectory { path, parents, created_at } => {
+                if self.files.contains_key(path) || self.is_directory(path) {
+                    return Err(OpError::AlreadyExists(path.clone()));
+                }
+                self.check_parents(path)?;
+                let mut missing = vec![path.clone()];
+                let mut current = paths::parent(path);
+                while let Some(ancestor) = current.filter(|ancestor| !self.is_directory(ancestor)) {
+                    missing.push(ancestor.to_string());
+                    current = paths::parent(ancestor);
+                }
+                if missing.len() > 1 && !parents {
+                    return Err(OpError::NotFound(paths::parent(path).unwrap_or_default().to_string()));
+                }
+                self.directories.extend(missing.into_iter().map(|directory| (directory, *created_at)));
+                Ok(())
+            },
+            MetadataOp::RemoveDirectory { path, recursive } => {
+                if path.is_empty() {
+                    return Err(OpError::InvalidOperation("The root directory can't be removed".to_string()));
+                }
+                if self.files.contains_key(path) {
+                    return Err(OpError::NotADirectory(path.clone()));
+                }
+                if !self.is_directory(path) {
+                    return Err(OpError::NotFound(path.clone()));
+                }
+                let is_empty = !self.files.keys().chain(self.directories.keys()).any(|name| paths::is_under(name, path));
+                if !is_empty && !recursive {
+                    return Err(OpError::DirectoryNotEmpty(path.clone()));
+                }
+                self.files.retain(|name, _| !paths::is_under(name, path));
+                self.directories.retain(|name, _| name != path && !paths::is_under(name, path));
+                Ok(())
+            },
+        }
+    }
+
+    pub fn is_directory(&self, path: &str) -> bool {
+        path.is_empty()
+            || self.directories.contains_key(path)
+            || self.files.keys().chain(self.directories.keys()).any(|name| paths::is_under(name, path))
+    }
+
+    /// The entries in directory `path`, or everything below it when
+    /// `recursive` is set, in path order.
+    pub fn list_directory(&self, path: &str, recursive: bool) -> Result<Vec<DirEntry>, OpError> {
+        if self.files.contains_key(path) {
+            return Err(OpError::NotADirectory(path.to_string()));
+        }
+        if !self.is_directory(path) {
+            return Err(OpError::NotFound(path.to_string()));
+        }
+
+        let mut directories: BTreeMap<String, u64> = self
+            .directories
+            .iter()
+            .filter(|(name, _)| paths::is_listed(name, path, recursive))
+            .map(|(name, created_at)| (name.clone(), *created_at))
+            .collect();
+        let below = self
+            .files
+            .iter()
+            .map(|(name, entry)| (name, entry.created_at))
+            .chain(self.directories.iter().map(|(name, created_at)| (name, *created_at)));
+        for (name, created_at) in below {
+            for implied in paths::implied_directories(name, path, recursive) {
+                directories.entry(implied).or_insert(created_at);
+            }
+        }
+
+        let mut entries: Vec<DirEntry> = directories
+            .into_iter()
+            .map(|(name, created_at)| DirEntry {
+                path: name,
+                kind: EntryKind::Directory,
+                size: 0,
+                checksum: None,
+                created_at,
+                modified_at: created_at,
+            })
+            .chain(self.files.iter().filter(|(name, _)| paths::is_listed(name, path, recursive)).map(|(name, entry)| DirEntry {
+                path: name.clone(),
+                kind: EntryKind::File,
+                size: entry.size,
+                checksum: Some(entry.checksum.clone()),
+                created_at: entry.created_at,
+                modified_at: entry.modified_at,
+            }))
+            .collect();
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    // A file can't be created below another file.
+    fn check_parents(&self, path: &str) -> Result<(), OpError> {
+        let mut current = paths::parent(path);
+        while let Some(ancestor) = current {
+            if self.files.contains_key(ancestor) {
+                return Err(OpError::NotADirectory(ancestor.to_string()));
+            }
+            current = paths::parent(ancestor);
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.files.contains_key(name)
+    }
+
+    pub fn entry(&self, name: &str) -> Option<&NamespaceEntry> {
+        self.files.get(name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.files.keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(name: &str, modified_at: u64) -> MetadataOp {
+        MetadataOp::Create { name: name.to_string(), size: 1, checksum: "c".to_string(), modified_at }
+    }
+
+    fn rename(from: &str, to: &str, overwrite: bool) -> MetadataOp {
+        MetadataOp::Rename { from: from.to_string(), to: to.to_string(), overwrite }
+    }
+
+    fn mkdir(path: &str, parents: bool) -> MetadataOp {
+        MetadataOp::MakeDirectory { path: path.to_string(), parents, created_at: 7 }
+    }
+
+    fn listing(namespace: &Namespace, path: &str, recursive: bool) -> Vec<(String, EntryKind)> {
+        namespace.list_directory(path, recursive).unwrap().into_iter().map(|entry| (entry.path, entry.kind)).collect()
+    }
+
+    #[test]
+    fn replacing_a_file_keeps_its_creation_time() {
+        let mut namespace = Namespace::default();
+        namespace.apply(&create("a", 1)).unwrap();
+        namespace.apply(&create("a", 5)).unwrap();
+        let entry = namespace.entry("a").unwrap();
+        assert_eq!((entry.created_at, entry.modified_at), (1, 5));
+    }
+
+    #[test]
+    fn files_cant_be_created_below_files_or_over_directories() {
+        let mut namespace = Namespace::default();
+        namespace.apply(&create("a", 1)).unwrap();
+        namespace.apply(&create("d/b", 1)).unwrap();
+        assert_eq!(namespace.apply(&create("a/b", 1)), Err(OpError::NotADirectory("a".to_string())));
+        assert_eq!(namespace.apply(&create("d", 1)), Err(OpError::IsADirectory("d".to_string())));
+    }
+
+    #[test]
+    fn renaming_onto_a_file_needs_overwrite() {
+        let mut namespace = Namespace::default();
+        namespace.apply(&create("a", 1)).unwrap();
+        namespace.apply(&create("b", 2)).unwrap();
+        assert_eq!(namespace.apply(&rename("a", "b", false)), Err(OpError::AlreadyExists("b".to_string())));
+        namespace.apply(&rename("a", "b", true)).unwrap();
+        assert_eq!(namespace.names(), vec!["b"]);
+        assert_eq!(namespace.entry("b").unwrap().created_at, 1);
+    }
+
+    #[test]
+    fn renaming_a_directory_moves_everything_below_it() {
+        let mut namespace = Namespace::default();
+        namespace.apply(&mkdir("d/e", true)).unwrap();
+        namespace.apply(&create("d/f", 1)).unwrap();
+        namespace.apply(&create("d/e/g", 1)).unwrap();
+        namespace.apply(&rename("d", "x/y", false)).unwrap();
+        assert_eq!(namespace.names(), vec!["x/y/e/g", "x/y/f"]);
+        assert!(namespace.is_directory("x/y/e"));
+        assert!(!namespace.is_directory("d"));
+    }
+
+    #[test]
+    fn a_directory_cant_move_into_itself_or_replace_a_full_one() {
+        let mut namespace = Namespace::default();
+        namespace.apply(&create("d/a", 1)).unwrap();
+        namespace.apply(&create("e/b", 1)).unwrap();
+        namespace.apply(&mkdir("empty", false)).unwrap();
+        assert!(matches!(namespace.apply(&rename("d", "d/x", false)), Err(OpError::InvalidOperation(_))));
+        assert_eq!(namespace.apply(&rename("d", "e", true)), Err(OpError::AlreadyExists("e".to_string())));
+        assert_eq!(namespace.apply(&rename("d/a", "e", true)), Err(OpError::IsADirectory("e".to_string())));
+        namespace.apply(&rename("d", "empty", true)).unwrap();
+        assert_eq!(namespace.names(), vec!["e/b", "empty/a"]);
+    }
+
+    #[test]
+    fn making_a_directory_needs_its_parent_unless_asked_to_create_it() {
+        let mut namespace = Namespace::default();
+        assert_eq!(namespace.apply(&mkdir("a/b", false)), Err(OpError::NotFound("a".to_string())));
+        namespace.apply(&mkdir("a/b", true)).unwrap();
+        assert!(namespace.is_directory("a"));
+        assert_eq!(namespace.apply(&mkdir("a", false)), Err(OpError::AlreadyExists("a".to_string())));
+    }
+
+    #[test]
+    fn removing_a_full_directory_needs_recursive() {
+        let mut namespace = Namespace::default();
+        namespace.apply(&mkdir("d", false)).unwrap();
+        namespace.apply(&create("d/a", 1)).unwrap();
+        namespace.apply(&create("da", 1)).unwrap();
+        let remove = |recursive| MetadataOp::RemoveDirectory { path: "d".to_string(), recursive };
+        assert_eq!(namespace.apply(&remove(false)), Err(OpError::DirectoryNotEmpty("d".to_string())));
+        namespace.apply(&remove(true)).unwrap();
+        assert_eq!(namespace.names(), vec!["da"]);
+        assert!(!namespace.is_directory("d"));
+    }
+
+    #[test]
+    fn listings_include_implied_directories() {
+        let mut namespace = Namespace::default();
+        namespace.apply(&create("a/b/c", 1)).unwrap();
+        namespace.apply(&create("top", 1)).unwrap();
+        namespace.apply(&mkdir("e", false)).unwrap();
+        assert_eq!(
+            listing(&namespace, "", false),
+            vec![("a".to_string(), EntryKind::Directory), ("e".to_string(), EntryKind::Directory), ("top".to_string(), EntryKind::File)]
+        );
+        assert_eq!(
+            listing(&namespace, "a", true),
+            vec![("a/b".to_string(), EntryKind::Directory), ("a/b/c".to_string(), EntryKind::File)]
+        );
+        assert_eq!(namespace.list_directory("top", false).unwrap_err(), OpError::NotADirectory("top".to_string()));
+        assert_eq!(namespace.list_directory("none", false).unwrap_err(), OpError::NotFound("none".to_string()));
+    }
+}
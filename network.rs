@@ -1,47 +1,145 @@
 use std::collections::HashMap;
 use std::env;
-use std::io::{self, Read, Write};
-use std::net::{TcpListener, TcpStream};
-use serde::{Deserialize, Serialize};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use serde_json::Error as SerdeError;
 use std::fmt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 
-#[derive(Serialize, Deserialize, Debug)]
+mod placement;
+
+use placement::{HashRing, DEFAULT_VIRTUAL_NODES};
+
+#[derive(Debug)]
 enum MessageType {
     Hello,
     Goodbye,
     DataTransfer,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Debug)]
 struct Message {
     msg_type: MessageType,
     sender: String,
-    content: String,
+    content: Vec<u8>,
+}
+
+/// Messages received from peers. A `Hello` doubles as a heartbeat and
+/// carries the sender's gossip; `Goodbye` means the sender is leaving on
+/// purpose. `DataTransfer` carries data of any size.
+#[derive(Debug)]
+pub enum PeerEvent {
+    Hello { sender: String, content: String },
+    Goodbye { sender: String },
+    DataTransfer { sender: String, data: Vec<u8> },
+}
+
+/// A node that owns a key. `address` is `None` for the local node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Owner {
+    pub id: String,
+    pub address: Option<String>,
 }
 
-struct NetworkTopology {
+impl Owner {
+    pub fn is_local(&self) -> bool {
+        self.address.is_none()
+    }
+}
+
+pub struct NetworkTopology {
+    local_id: String,
     peers: HashMap<String, String>,
+    ring: HashRing,
 }
 
 impl NetworkTopology {
-    fn new() -> NetworkTopology {
+    pub fn new(local_id: String) -> NetworkTopology {
+        let mut ring = HashRing::new(DEFAULT_VIRTUAL_NODES);
+        ring.add_node(&local_id);
         NetworkTopology {
+            local_id,
             peers: HashMap::new(),
+            ring,
+        }
+    }
+
+    /// Builds the topology from `PEER_ID` (this node's id, default `local`)
+    /// and `DFS_PEERS`, a comma-separated list of `id=host:port` pairs naming
+    /// the other nodes' client ports.
+    pub fn from_env() -> Result<NetworkTopology, MyError> {
+        let local_id = env::var("PEER_ID").unwrap_or_else(|_| "local".to_string());
+        let mut topology = NetworkTopology::new(local_id);
+        let peers = match env::var("DFS_PEERS") {
+            Ok(peers) => peers,
+            Err(env::VarError::NotPresent) => return Ok(topology),
+            Err(e) => return Err(MyError::EnvVar(e)),
+        };
+
+        for peer in peers.split(',').map(str::trim).filter(|peer| !peer.is_empty()) {
+            match peer.split_once('=') {
+                Some((id, address)) => topology.add_peer(id.trim().to_string(), address.trim().to_string()),
+                None => return Err(MyError::Custom(format!("Malformed peer entry '{}', expected id=address", peer))),
+            }
         }
+        Ok(topology)
     }
 
-    fn add_peer(&mut self, id: String, address: String) {
+    pub fn add_peer(&mut self, id: String, address: String) {
+        if id == self.local_id {
+            return;
+        }
+        self.ring.add_node(&id);
         self.peers.insert(id, address);
     }
 
-    fn remove_peer(&mut self, id: &String) {
-        self.peers.remove(id);
+    pub fn remove_peer(&mut self, id: &String) {
+        if self.peers.remove(id).is_some() {
+            self.ring.remove_node(id);
+        }
+    }
+
+    /// Adds or removes this node from the ring; a node being decommissioned
+    /// stops owning data so the rebalancer moves it all elsewhere.
+    pub fn set_local_owner(&mut self, owner: bool) {
+        if owner {
+            self.ring.add_node(&self.local_id);
+        } else {
+            self.ring.remove_node(&self.local_id);
+        }
+    }
+
+    pub fn local_id(&self) -> &str {
+        &self.local_id
+    }
+
+    /// The `count` nodes responsible for `key` under consistent hashing,
+    /// primary first.
+    pub fn owners(&self, key: &str, count: usize) -> Vec<Owner> {
+        self.ring
+            .owners(key, count)
+            .into_iter()
+            .map(|id| {
+                let address = self.peers.get(&id).cloned();
+                Owner { id, address }
+            })
+            .collect()
+    }
+
+    /// Known peers as `(id, address)` pairs, ordered by id.
+    pub fn peers(&self) -> Vec<(String, String)> {
+        let mut peers: Vec<(String, String)> = self.peers.iter().map(|(id, address)| (id.clone(), address.clone())).collect();
+        peers.sort();
+        peers
     }
 }
 
 #[derive(Debug)]
-enum MyError {
+pub enum MyError {
     Io(io::Error),
     EnvVar(env::VarError),
     Serde(SerdeError),
@@ -77,72 +175,452 @@ impl From<env::VarError> for MyError {
     }
 }
 
-fn handle_client(mut stream: TcpStream) -> Result<(), MyError> {
-    let mut buffer = [0; 1024];
-    let bytes_read = stream.read(&mut buffer)?;
+// Wire format shared by every peer connection. A message goes out as one
+// or more frames:
+//
+// +----------------+----------+-----------+-------------------------------------------+
+// | length: u32 BE | type: u8 | mac: 32 B | payload: sender_len u16 BE, sender, content |
+// +----------------+----------+-----------+-------------------------------------------+
+//
+// `length` counts everything after itself, so a reader always knows exactly
+// how much to consume before the next frame starts. Content too large for
+// one frame is split across several: every frame of a message but the last
+// has the `MORE_FRAMES` bit of `type` set, and the reader joins their
+// content, so a message can be any size while frames stay small. `mac` is
+// an HMAC-SHA256 keyed with the cluster key over the type byte and the
+// payload; frames without a valid one are dropped, so only nodes holding
+// the key can join or speak for the cluster.
+const FRAME_HEADER_LEN: usize = 4;
+const MAC_LEN: usize = 32;
+const MIN_FRAME_LEN: u32 = (1 + MAC_LEN + 2) as u32;
+const MAX_FRAME_LEN: u32 = 1024 * 1024;
+const MORE_FRAMES: u8 = 0x80;
 
-    if bytes_read == 0 {
-        return Ok(());
+impl MessageType {
+    fn to_byte(&self) -> u8 {
+        match self {
+            MessageType::Hello => 1,
+            MessageType::Goodbye => 2,
+            MessageType::DataTransfer => 3,
+        }
     }
 
-    let received_msg: Message = serde_json::from_slice(&buffer[..bytes_read])
-        .map_err(MyError::Serde)?;
+    fn from_byte(byte: u8) -> Result<MessageType, MyError> {
+        match byte {
+            1 => Ok(MessageType::Hello),
+            2 => Ok(MessageType::Goodbye),
+            3 => Ok(MessageType::DataTransfer),
+            other => Err(MyError::Custom(format!("Unknown message type {}", other))),
+        }
+    }
+}
 
-    println!("Received: {:?}", received_msg);
+// The MAC of a frame with `msg_type` and `payload`.
+fn frame_mac(key: &str, msg_type: u8, payload: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(&[msg_type]);
+    for part in payload {
+        mac.update(part);
+    }
+    mac
+}
 
-    match received_msg.msg_type {
-        MessageType::Hello => {
-            println!("Hello from {}", received_msg.sender);
-        },
-        MessageType::Goodbye => {
-            println!("Goodbye from {}", received_msg.sender);
-        },
-        MessageType::DataTransfer => {
-            println!("Data transfer from {}: {}", received_msg.sender, received_msg.content);
-        },
+fn write_message<W: Write>(writer: &mut W, msg: &Message, key: &str) -> Result<(), MyError> {
+    let sender = msg.sender.as_bytes();
+    if sender.len() > u16::MAX as usize {
+        return Err(MyError::Custom("Sender id too long".into()));
     }
 
-    Ok(())
+    let sender_len = (sender.len() as u16).to_be_bytes();
+    let room = (MAX_FRAME_LEN - MIN_FRAME_LEN) as usize - sender.len();
+    let mut pieces = msg.content.chunks(room).peekable();
+    // Even an empty message takes a frame.
+    let mut piece: &[u8] = pieces.next().unwrap_or_default();
+    loop {
+        let more = pieces.peek().is_some();
+        let msg_type = msg.msg_type.to_byte() | if more { MORE_FRAMES } else { 0 };
+        let frame_len = MIN_FRAME_LEN as usize + sender.len() + piece.len();
+        let mac = frame_mac(key, msg_type, &[&sender_len, sender, piece]).finalize().into_bytes();
+        writer.write_all(&(frame_len as u32).to_be_bytes())?;
+        writer.write_all(&[msg_type])?;
+        writer.write_all(&mac)?;
+        writer.write_all(&sender_len)?;
+        writer.write_all(sender)?;
+        writer.write_all(piece)?;
+        match pieces.next() {
+            Some(next) => piece = next,
+            None => return Ok(()),
+        }
+    }
 }
 
-fn connect_to_peer(peer_address: &str) -> Result<(), MyError> {
-    let mut stream = TcpStream::connect(peer_address)?;
+/// Reads the next message, returning `None` if the peer closed the
+/// connection cleanly between messages. A frame not signed with `key` is an
+/// error.
+fn read_message<R: Read>(reader: &mut R, key: &str) -> Result<Option<Message>, MyError> {
+    let (type_byte, sender, mut content) = match read_frame(reader, key)? {
+        Some(frame) => frame,
+        None => return Ok(None),
+    };
+    let msg_type = MessageType::from_byte(type_byte & !MORE_FRAMES)?;
+    let mut more = type_byte & MORE_FRAMES != 0;
+    while more {
+        let (next_type, next_sender, piece) =
+            read_frame(reader, key)?.ok_or_else(|| MyError::Custom("Connection closed mid-message".into()))?;
+        if next_type & !MORE_FRAMES != type_byte & !MORE_FRAMES || next_sender != sender {
+            return Err(MyError::Custom("Frame does not continue the message before it".into()));
+        }
+        content.extend_from_slice(&piece);
+        more = next_type & MORE_FRAMES != 0;
+    }
+    Ok(Some(Message { msg_type, sender, content }))
+}
+
+// One frame as its type byte, sender and piece of content, or `None` at a
+// clean end of the connection.
+fn read_frame<R: Read>(reader: &mut R, key: &str) -> Result<Option<(u8, String, Vec<u8>)>, MyError> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    if !read_exact_or_eof(reader, &mut header)? {
+        return Ok(None);
+    }
 
+    let frame_len = u32::from_be_bytes(header);
+    if !(MIN_FRAME_LEN..=MAX_FRAME_LEN).contains(&frame_len) {
+        return Err(MyError::Custom(format!("Invalid frame length {}", frame_len)));
+    }
+
+    let mut frame = vec![0u8; frame_len as usize];
+    reader.read_exact(&mut frame)?;
+
+    let (mac, payload) = frame[1..].split_at(MAC_LEN);
+    frame_mac(key, frame[0], &[payload])
+        .verify_slice(mac)
+        .map_err(|_| MyError::Custom("Frame is not signed with the cluster key".into()))?;
+    let sender_len = u16::from_be_bytes([payload[0], payload[1]]) as usize;
+    if 2 + sender_len > payload.len() {
+        return Err(MyError::Custom("Sender length exceeds frame".into()));
+    }
+
+    let sender = String::from_utf8(payload[2..2 + sender_len].to_vec())
+        .map_err(|e| MyError::Custom(format!("Invalid sender: {}", e)))?;
+    Ok(Some((frame[0], sender, payload[2 + sender_len..].to_vec())))
+}
+
+fn read_exact_or_eof<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<bool, MyError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(MyError::Custom("Connection closed mid-frame".into())),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(MyError::Io(e)),
+        }
+    }
+    Ok(true)
+}
+
+type PeerEventHandler = Arc<dyn Fn(PeerEvent) + Send + Sync>;
+
+fn handle_client<R: Read>(stream: R, key: &str, on_event: &PeerEventHandler) -> Result<(), MyError> {
+    let mut reader = BufReader::new(stream);
+
+    while let Some(received_msg) = read_message(&mut reader, key)? {
+        match received_msg.msg_type {
+            MessageType::Hello => {
+                let content = String::from_utf8(received_msg.content)
+                    .map_err(|e| MyError::Custom(format!("Invalid content: {}", e)))?;
+                on_event(PeerEvent::Hello { sender: received_msg.sender, content });
+            },
+            MessageType::Goodbye => {
+                on_event(PeerEvent::Goodbye { sender: received_msg.sender });
+                break;
+            },
+            MessageType::DataTransfer => {
+                on_event(PeerEvent::DataTransfer { sender: received_msg.sender, data: received_msg.content });
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Sends a `Hello` signed with `key` to a peer. `timeout` bounds how long an
+/// unresponsive peer can hold the sender up.
+pub fn send_hello(peer_address: &str, sender: &str, content: &str, key: &str, timeout: Duration) -> Result<(), MyError> {
     let msg = Message {
         msg_type: MessageType::Hello,
-        sender: env::var("PEER_ID")?,
-        content: "Hello there!".into(),
+        sender: sender.to_string(),
+        content: content.as_bytes().to_vec(),
     };
+    send_to_peer(peer_address, &msg, key, timeout)
+}
 
-    let serialized_msg = serde_json::to_vec(&msg)?;
-    stream.write_all(&serialized_msg)?;
-
-    Ok(())
+/// Tells a peer this node is leaving, so it is marked dead straight away
+/// instead of after the failure timeouts.
+pub fn send_goodbye(peer_address: &str, sender: &str, key: &str, timeout: Duration) -> Result<(), MyError> {
+    let msg = Message {
+        msg_type: MessageType::Goodbye,
+        sender: sender.to_string(),
+        content: Vec::new(),
+    };
+    send_to_peer(peer_address, &msg, key, timeout)
 }
 
-fn start_server() -> Result<(), MyError> {
-    let bind_address = env::var("LISTEN_ADDR")?;
-    let listener = TcpListener::bind(&bind_address)?;
+/// Sends `data` of any size to a peer, which hands it to its handler as a
+/// `DataTransfer`. `timeout` applies to each write.
+// Gossip only needs Hello and Goodbye; this is for other peer traffic.
+#[allow(dead_code)]
+pub fn send_data(peer_address: &str, sender: &str, data: &[u8], key: &str, timeout: Duration) -> Result<(), MyError> {
+    let msg = Message {
+        msg_type: MessageType::DataTransfer,
+        sender: sender.to_string(),
+        content: data.to_vec(),
+    };
+    send_to_peer(peer_address, &msg, key, timeout)
+}
 
-    println!("Server listening on {}", bind_address);
+fn send_to_peer(peer_address: &str, msg: &Message, key: &str, timeout: Duration) -> Result<(), MyError> {
+    let addr = peer_address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| MyError::Custom(format!("Unable to resolve {}", peer_address)))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut stream = BufWriter::new(stream);
+    write_message(&mut stream, msg, key)?;
+    stream.flush()?;
+    Ok(())
+}
 
+/// Accepts peer connections until the listener fails, handing every
+/// message signed with `key` to `on_event`. A connection is
+/// closed at its first unsigned frame. Each connection gets its own thread.
+pub fn serve_peers(listener: TcpListener, key: &str, on_event: PeerEventHandler) {
+    let key: Arc<str> = Arc::from(key);
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                if let Err(e) = handle_client(stream) {
-                    println!("Error handling client: {}", e);
-                }
+                let key = Arc::clone(&key);
+                let on_event = Arc::clone(&on_event);
+                thread::spawn(move || {
+                    if let Err(e) = handle_client(stream, &key, &on_event) {
+                        println!("Error handling client: {}", e);
+                    }
+                });
             }
             Err(e) => { println!("Failed to receive connection: {}", e); }
         }
     }
+}
+
+// Only the standalone binary starts here; the server loads this file as a
+// module and never calls it.
+#[allow(dead_code)]
+fn start_server() -> Result<(), MyError> {
+    let bind_address = env::var("LISTEN_ADDR")?;
+    let key = env::var("DFS_CLUSTER_KEY")?;
+    let listener = TcpListener::bind(&bind_address)?;
+
+    println!("Server listening on {}", bind_address);
+
+    serve_peers(listener, &key, Arc::new(|event| println!("Received: {:?}", event)));
     Ok(())
 }
 
+#[allow(dead_code)]
 fn main() {
-    let network_topology = NetworkTopology::new();
-
     if let Err(e) = start_server() {
         println!("Failed to start the server: {}", e);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::Mutex;
+
+    const KEY: &str = "cluster key";
+
+    fn message(msg_type: MessageType, sender: &str, content: &[u8]) -> Message {
+        Message { msg_type, sender: sender.to_string(), content: content.to_vec() }
+    }
+
+    fn encode(msg: &Message) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        write_message(&mut buffer, msg, KEY).unwrap();
+        buffer
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Option<Message>, MyError> {
+        read_message(&mut Cursor::new(bytes), KEY)
+    }
+
+    // A frame around `body`, the type byte and payload, signed with `key`.
+    fn frame(key: &str, body: &[u8]) -> Vec<u8> {
+        let mac = frame_mac(key, body[0], &[&body[1..]]).finalize().into_bytes();
+        let mut bytes = ((body.len() + MAC_LEN) as u32).to_be_bytes().to_vec();
+        bytes.push(body[0]);
+        bytes.extend(mac);
+        bytes.extend(&body[1..]);
+        bytes
+    }
+
+    // The type byte of every frame in `bytes`.
+    fn frame_types(bytes: &[u8]) -> Vec<u8> {
+        let mut types = Vec::new();
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let frame_len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            types.push(rest[FRAME_HEADER_LEN]);
+            rest = &rest[FRAME_HEADER_LEN + frame_len..];
+        }
+        types
+    }
+
+    fn assert_custom_error(result: Result<Option<Message>, MyError>, expected: &str) {
+        match result {
+            Err(MyError::Custom(error)) => assert!(error.contains(expected), "{}", error),
+            other => panic!("expected an error containing {:?}, got {:?}", expected, other),
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        let sent = [
+            message(MessageType::Hello, "node-1", b"[{\"id\":\"node-1\"}]"),
+            message(MessageType::Goodbye, "node-2", b""),
+            message(MessageType::DataTransfer, "", &[0, 159, 146, 150, 255]),
+        ];
+        let mut buffer = Vec::new();
+        for msg in &sent {
+            write_message(&mut buffer, msg, KEY).unwrap();
+        }
+
+        let mut reader = Cursor::new(buffer);
+        for msg in &sent {
+            let received = read_message(&mut reader, KEY).unwrap().unwrap();
+            assert_eq!(received.msg_type.to_byte(), msg.msg_type.to_byte());
+            assert_eq!(received.sender, msg.sender);
+            assert_eq!(received.content, msg.content);
+        }
+        assert!(read_message(&mut reader, KEY).unwrap().is_none());
+    }
+
+    #[test]
+    fn frame_layout_matches_the_wire_format() {
+        let bytes = encode(&message(MessageType::Goodbye, "ab", b"xyz"));
+        assert_eq!(bytes, frame(KEY, &[2, 0, 2, b'a', b'b', b'x', b'y', b'z']));
+        assert_eq!(bytes[..5], [0, 0, 0, 40, 2]);
+        assert_eq!(bytes[5 + MAC_LEN..], [0, 2, b'a', b'b', b'x', b'y', b'z']);
+    }
+
+    #[test]
+    fn large_messages_are_split_into_frames_and_joined_again() {
+        let data: Vec<u8> = (0..MAX_FRAME_LEN as usize * 5 / 2).map(|i| (i % 251) as u8).collect();
+        let bytes = encode(&message(MessageType::DataTransfer, "node-1", &data));
+        assert_eq!(frame_types(&bytes), [3 | MORE_FRAMES, 3 | MORE_FRAMES, 3]);
+
+        let mut reader = Cursor::new([bytes.clone(), encode(&message(MessageType::Goodbye, "node-1", b""))].concat());
+        assert_eq!(read_message(&mut reader, KEY).unwrap().unwrap().content, data);
+        assert_eq!(read_message(&mut reader, KEY).unwrap().unwrap().msg_type.to_byte(), 2);
+
+        // Content that exactly fills a frame doesn't need another.
+        let room = (MAX_FRAME_LEN - MIN_FRAME_LEN) as usize - "node-1".len();
+        assert_eq!(frame_types(&encode(&message(MessageType::DataTransfer, "node-1", &data[..room]))), [3]);
+        assert_eq!(frame_types(&encode(&message(MessageType::DataTransfer, "node-1", &data[..room + 1]))), [3 | MORE_FRAMES, 3]);
+    }
+
+    #[test]
+    fn a_message_cut_between_frames_is_an_error() {
+        let bytes = encode(&message(MessageType::DataTransfer, "node-1", &vec![7; MAX_FRAME_LEN as usize]));
+        let first_frame = FRAME_HEADER_LEN + MAX_FRAME_LEN as usize;
+        assert_custom_error(decode(&bytes[..first_frame]), "mid-message");
+
+        // Continuations must keep the type and sender.
+        let start = frame(KEY, &[3 | MORE_FRAMES, 0, 1, b'a', b'x']);
+        assert_custom_error(decode(&[start.clone(), frame(KEY, &[1, 0, 1, b'a', b'y'])].concat()), "does not continue");
+        assert_custom_error(decode(&[start.clone(), frame(KEY, &[3, 0, 1, b'b', b'y'])].concat()), "does not continue");
+        assert_eq!(decode(&[start, frame(KEY, &[3, 0, 1, b'a', b'y'])].concat()).unwrap().unwrap().content, b"xy");
+    }
+
+    #[test]
+    fn truncated_frames_are_errors() {
+        let bytes = encode(&message(MessageType::Hello, "node-1", b"content"));
+        assert!(decode(&[]).unwrap().is_none());
+        // Cut inside the length prefix.
+        assert_custom_error(decode(&bytes[..2]), "mid-frame");
+        // Cut inside the payload.
+        for cut in [FRAME_HEADER_LEN, FRAME_HEADER_LEN + 1, bytes.len() - 1] {
+            match decode(&bytes[..cut]) {
+                Err(MyError::Io(error)) => assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof),
+                other => panic!("cut at {}: expected a short read, got {:?}", cut, other),
+            }
+        }
+    }
+
+    #[test]
+    fn bad_lengths_are_refused_before_reading() {
+        for frame_len in [0, MIN_FRAME_LEN - 1, MAX_FRAME_LEN + 1, u32::MAX] {
+            assert_custom_error(decode(&frame_len.to_be_bytes()), "Invalid frame length");
+        }
+        // A sender longer than the frame around it.
+        assert_custom_error(decode(&frame(KEY, &[1, 0, 2, b'a'])), "Sender length exceeds frame");
+        assert_custom_error(decode(&frame(KEY, &[9, 0, 0])), "Unknown message type 9");
+    }
+
+    #[test]
+    fn oversized_senders_are_not_sent() {
+        let long_sender = "s".repeat(u16::MAX as usize + 1);
+        let mut buffer = Vec::new();
+        assert!(matches!(write_message(&mut buffer, &message(MessageType::Hello, &long_sender, b""), KEY), Err(MyError::Custom(_))));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn handle_client_passes_on_every_message() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&events);
+        let on_event: PeerEventHandler = Arc::new(move |event| recorded.lock().unwrap().push(format!("{:?}", event)));
+
+        let mut bytes = encode(&message(MessageType::Hello, "node-1", b"gossip"));
+        bytes.extend(encode(&message(MessageType::DataTransfer, "node-1", b"data")));
+        bytes.extend(encode(&message(MessageType::Goodbye, "node-1", b"")));
+        // Nothing after a goodbye is read.
+        bytes.extend(encode(&message(MessageType::Hello, "node-1", b"late")));
+        handle_client(Cursor::new(bytes), KEY, &on_event).unwrap();
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                "Hello { sender: \"node-1\", content: \"gossip\" }",
+                "DataTransfer { sender: \"node-1\", data: [100, 97, 116, 97] }",
+                "Goodbye { sender: \"node-1\" }",
+            ]
+        );
+
+        // Gossip has to be text.
+        let bytes = encode(&message(MessageType::Hello, "node-2", &[0xff]));
+        assert!(handle_client(Cursor::new(bytes), KEY, &on_event).is_err());
+        assert_eq!(events.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn frames_without_the_cluster_key_are_dropped() {
+        let events = Arc::new(Mutex::new(0));
+        let counted = Arc::clone(&events);
+        let on_event: PeerEventHandler = Arc::new(move |_| *counted.lock().unwrap() += 1);
+
+        // Signed with another key.
+        let forged = frame("other key", &[2, 0, 4, b'n', b'o', b'd', b'e']);
+        assert_custom_error(decode(&forged), "not signed");
+        assert!(handle_client(Cursor::new(forged), KEY, &on_event).is_err());
+
+        // Signed, then changed on the way.
+        let mut tampered = encode(&message(MessageType::Hello, "node-1", b"[]"));
+        *tampered.last_mut().unwrap() = b'}';
+        assert_custom_error(decode(&tampered), "not signed");
+        assert!(handle_client(Cursor::new(tampered), KEY, &on_event).is_err());
+        assert_eq!(*events.lock().unwrap(), 0);
+    }
+}
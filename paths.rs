@@ -0,0 +1,236 @@
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// Names Windows reserves for devices in every directory, with or without an
+// extension. Refused everywhere so stored data can move between hosts.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Why a path was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathError {
+    /// The root directory where a file name was needed.
+    Empty,
+    /// Starts at a file system root or drive instead of being relative.
+    Absolute(String),
+    /// Has a `..` segment, which could lead out of the storage directory.
+    Traversal(String),
+    NulByte(String),
+    /// Has a segment that is a reserved name.
+    Reserved(String),
+}
+
+impl fmt::Display for PathError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PathError::Empty => write!(f, "The root directory can't be used here"),
+            PathError::Absolute(path) => write!(f, "{}: Absolute paths are not allowed", path),
+            PathError::Traversal(path) => write!(f, "{}: '..' is not allowed in paths", path),
+            PathError::NulByte(path) => write!(f, "{:?}: Paths can't contain NUL bytes", path),
+            PathError::Reserved(name) => write!(f, "{}: Reserved name", name),
+        }
+    }
+}
+
+impl std::error::Error for PathError {}
+
+impl From<PathError> for io::Error {
+    fn from(error: PathError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, error)
+    }
+}
+
+/// A path in the file system's namespace that is safe to join onto a
+/// storage directory: relative, with no `..` segments, NUL bytes or
+/// reserved names. Both `/` and `\` separate segments; empty and `.`
+/// segments are dropped, so `a//b/./c/` is `a/b/c`. The root directory is
+/// the empty path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SafePath(String);
+
+impl SafePath {
+    pub fn parse(path: &str) -> Result<Self, PathError> {
+        if path.contains('\0') {
+            return Err(PathError::NulByte(path.to_string()));
+        }
+        let has_drive = path.len() >= 2 && path.as_bytes()[0].is_ascii_alphabetic() && path.as_bytes()[1] == b':';
+        if path.starts_with('/') || path.starts_with('\\') || has_drive {
+            return Err(PathError::Absolute(path.to_string()));
+        }
+        let mut segments = Vec::new();
+        for segment in path.split(['/', '\\']) {
+            match segment {
+                "" | "." => {},
+                ".." => return Err(PathError::Traversal(path.to_string())),
+                segment if is_reserved(segment) => return Err(PathError::Reserved(segment.to_string())),
+                segment => segments.push(segment),
+            }
+        }
+        Ok(SafePath(segments.join("/")))
+    }
+
+    /// Like `parse`, but for something that must be a file, which the root
+    /// can't be.
+    pub fn file(path: &str) -> Result<Self, PathError> {
+        let parsed = Self::parse(path)?;
+        if parsed.is_root() {
+            return Err(PathError::Empty);
+        }
+        Ok(parsed)
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn into_string(self) -> String {
+        self.0
+    }
+
+    /// Where this path lives below `base`. The result is always `base` or a
+    /// descendant of it.
+    pub fn under(&self, base: impl AsRef<Path>) -> PathBuf {
+        self.0.split('/').filter(|segment| !segment.is_empty()).fold(base.as_ref().to_path_buf(), |path, segment| path.join(segment))
+    }
+}
+
+impl fmt::Display for SafePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "/{}", self.0)
+    }
+}
+
+fn is_reserved(segment: &str) -> bool {
+    let stem = segment.split('.').next().unwrap_or_default().trim_end();
+    RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
+
+/// Validates and normalizes `path` as a `SafePath`, for callers that keep
+/// paths as strings.
+pub fn normalize(path: &str) -> io::Result<String> {
+    Ok(SafePath::parse(path)?.into_string())
+}
+
+/// The directory containing `path`; `None` for the root itself.
+pub fn parent(path: &str) -> Option<&str> {
+    if path.is_empty() {
+        return None;
+    }
+    Some(path.rsplit_once('/').map_or("", |(parent, _)| parent))
+}
+
+/// Whether `path` lies somewhere below `directory`.
+pub fn is_under(path: &str, directory: &str) -> bool {
+    if directory.is_empty() {
+        return !path.is_empty();
+    }
+    path.strip_prefix(directory).is_some_and(|rest| rest.starts_with('/'))
+}
+
+/// Whether `path` belongs in a listing of `directory`: any descendant when
+/// `recursive`, otherwise only direct children.
+pub fn is_listed(path: &str, directory: &str, recursive: bool) -> bool {
+    is_under(path, directory) && (recursive || parent(path) == Some(directory))
+}
+
+/// The directories a listing of `directory` shows because `path` exists
+/// below them without having been created explicitly: the child of
+/// `directory` on the way to `path`, and with `recursive` every directory
+/// after it too.
+pub fn implied_directories(path: &str, directory: &str, recursive: bool) -> Vec<String> {
+    let mut implied = Vec::new();
+    let mut current = parent(path);
+    while let Some(ancestor) = current {
+        if !is_under(ancestor, directory) {
+            break;
+        }
+        implied.push(ancestor.to_string());
+        current = parent(ancestor);
+    }
+    if !recursive {
+        implied.drain(..implied.len().saturating_sub(1));
+    }
+    implied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traversal_is_refused() {
+        assert_eq!(SafePath::parse("a/../b"), Err(PathError::Traversal("a/../b".to_string())));
+        assert_eq!(SafePath::parse(".."), Err(PathError::Traversal("..".to_string())));
+        assert_eq!(SafePath::parse("a\\..\\b"), Err(PathError::Traversal("a\\..\\b".to_string())));
+        // Only a whole `..` segment leads anywhere.
+        assert_eq!(SafePath::parse("a/..b/c..").unwrap().as_str(), "a/..b/c..");
+    }
+
+    #[test]
+    fn absolute_paths_are_refused() {
+        for path in ["/etc/passwd", "\\windows", "C:", "c:\\windows", "C:relative"] {
+            assert_eq!(SafePath::parse(path), Err(PathError::Absolute(path.to_string())), "{}", path);
+        }
+    }
+
+    #[test]
+    fn nul_bytes_are_refused() {
+        assert_eq!(SafePath::parse("a\0b"), Err(PathError::NulByte("a\0b".to_string())));
+    }
+
+    #[test]
+    fn reserved_names_are_refused_in_any_segment() {
+        assert_eq!(SafePath::parse("con.txt"), Err(PathError::Reserved("con.txt".to_string())));
+        assert_eq!(SafePath::parse("a/COM1 /b"), Err(PathError::Reserved("COM1 ".to_string())));
+        assert_eq!(SafePath::parse("lpt9.tar.gz"), Err(PathError::Reserved("lpt9.tar.gz".to_string())));
+        assert_eq!(SafePath::parse("console/com10").unwrap().as_str(), "console/com10");
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(SafePath::parse("a//b/./c/").unwrap().as_str(), "a/b/c");
+        assert_eq!(SafePath::parse("a\\b\\c").unwrap().as_str(), "a/b/c");
+        assert_eq!(SafePath::parse("./a").unwrap().to_string(), "/a");
+    }
+
+    #[test]
+    fn the_root_is_a_path_but_not_a_file() {
+        for root in ["", ".", "./", ".//."] {
+            assert!(SafePath::parse(root).unwrap().is_root(), "{:?}", root);
+            assert_eq!(SafePath::file(root), Err(PathError::Empty), "{:?}", root);
+        }
+        assert_eq!(SafePath::file("a/b").unwrap().as_str(), "a/b");
+    }
+
+    #[test]
+    fn under_stays_below_the_base() {
+        let base = Path::new("storage");
+        assert_eq!(SafePath::parse("").unwrap().under(base), base);
+        assert_eq!(SafePath::parse("a//b").unwrap().under(base), base.join("a").join("b"));
+    }
+
+    #[test]
+    fn is_under_compares_whole_segments() {
+        assert!(is_under("a/b", "a"));
+        assert!(is_under("a/b/c", "a"));
+        assert!(!is_under("ab", "a"));
+        assert!(!is_under("a", "a"));
+        assert!(is_under("a", ""));
+        assert!(!is_under("", ""));
+    }
+
+    #[test]
+    fn implied_directories_stop_at_the_listed_directory() {
+        assert_eq!(implied_directories("a/b/c/file", "a", false), ["a/b"]);
+        assert_eq!(implied_directories("a/b/c/file", "a", true), ["a/b/c", "a/b"]);
+        assert_eq!(implied_directories("a/file", "a", true), Vec::<String>::new());
+        assert_eq!(implied_directories("a/b/file", "", true), ["a/b", "a"]);
+    }
+}
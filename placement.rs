@@ -0,0 +1,145 @@
+use std::collections::{BTreeMap, BTreeSet};
+use sha2::{Digest, Sha256};
+
+pub const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+/// Consistent-hash ring mapping keys (file names or chunk hashes) to the
+/// nodes that own them. Each node is placed on the ring at
+/// `virtual_nodes` points so load spreads evenly, and adding or removing a
+/// node only moves the keys in the arcs that node gains or gives up.
+pub struct HashRing {
+    virtual_nodes: usize,
+    ring: BTreeMap<u64, String>,
+    nodes: BTreeSet<String>,
+}
+
+impl HashRing {
+    pub fn new(virtual_nodes: usize) -> Self {
+        Self {
+            virtual_nodes: virtual_nodes.max(1),
+            ring: BTreeMap::new(),
+            nodes: BTreeSet::new(),
+        }
+    }
+
+    pub fn add_node(&mut self, node_id: &str) {
+        if !self.nodes.insert(node_id.to_string()) {
+            return;
+        }
+        for replica in 0..self.virtual_nodes {
+            self.ring.insert(ring_position(&format!("{}#{}", node_id, replica)), node_id.to_string());
+        }
+    }
+
+    pub fn remove_node(&mut self, node_id: &str) {
+        if !self.nodes.remove(node_id) {
+            return;
+        }
+        self.ring.retain(|_, owner| owner != node_id);
+    }
+
+    /// The first `count` distinct nodes found walking clockwise from the
+    /// key's position; the first one is the primary owner.
+    pub fn owners(&self, key: &str, count: usize) -> Vec<String> {
+        let wanted = count.min(self.nodes.len());
+        let mut owners: Vec<String> = Vec::with_capacity(wanted);
+        if wanted == 0 {
+            return owners;
+        }
+
+        let position = ring_position(key);
+        let clockwise = self.ring.range(position..).chain(self.ring.range(..position));
+        for (_, node_id) in clockwise {
+            if !owners.contains(node_id) {
+                owners.push(node_id.clone());
+                if owners.len() == wanted {
+                    break;
+                }
+            }
+        }
+        owners
+    }
+}
+
+fn ring_position(key: &str) -> u64 {
+    let digest = Sha256::digest(key.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEYS: usize = 10_000;
+
+    fn ring(nodes: &[&str]) -> HashRing {
+        let mut ring = HashRing::new(DEFAULT_VIRTUAL_NODES);
+        for node in nodes {
+            ring.add_node(node);
+        }
+        ring
+    }
+
+    fn keys() -> impl Iterator<Item = String> {
+        (0..KEYS).map(|i| format!("file-{}", i))
+    }
+
+    #[test]
+    fn replicas_land_on_distinct_nodes() {
+        let ring = ring(&["a", "b", "c", "d"]);
+        for key in keys().take(1000) {
+            let mut owners = ring.owners(&key, 3);
+            assert_eq!(owners.len(), 3);
+            owners.sort();
+            owners.dedup();
+            assert_eq!(owners.len(), 3, "{}", key);
+        }
+        // Never more owners than nodes, and none from an empty ring.
+        assert_eq!(ring.owners("key", 10).len(), 4);
+        assert!(HashRing::new(DEFAULT_VIRTUAL_NODES).owners("key", 3).is_empty());
+    }
+
+    #[test]
+    fn placement_is_deterministic() {
+        let first = ring(&["a", "b", "c"]);
+        let second = ring(&["c", "a", "b", "a"]);
+        for key in keys().take(1000) {
+            assert_eq!(first.owners(&key, 2), second.owners(&key, 2), "{}", key);
+        }
+    }
+
+    #[test]
+    fn adding_a_node_only_moves_keys_to_it() {
+        let before = ring(&["a", "b", "c", "d"]);
+        let after = ring(&["a", "b", "c", "d", "e"]);
+        let mut moved = 0;
+        for key in keys() {
+            let (old, new) = (before.owners(&key, 1), after.owners(&key, 1));
+            if old != new {
+                assert_eq!(new, ["e"], "{}", key);
+                moved += 1;
+            }
+        }
+        // The new node takes about a fifth of the keys.
+        assert!(moved > KEYS / 10 && moved < KEYS * 3 / 10, "{} keys moved", moved);
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        let before = ring(&["a", "b", "c", "d", "e"]);
+        let mut after = ring(&["a", "b", "c", "d", "e"]);
+        after.remove_node("e");
+        let mut moved = 0;
+        for key in keys() {
+            let (old, new) = (before.owners(&key, 1), after.owners(&key, 1));
+            if old != new {
+                assert_eq!(old, ["e"], "{}", key);
+                moved += 1;
+            }
+        }
+        assert!(moved > KEYS / 10 && moved < KEYS * 3 / 10, "{} keys moved", moved);
+        assert_eq!(after.owners("key", 5).len(), 4);
+    }
+}
@@ -0,0 +1,580 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+// Every command and response travels as a single frame: a big-endian u32
+// byte count followed by that many bytes of JSON. This lets one connection
+// carry any number of request/response pairs.
+const MAX_FRAME_LEN: u32 = 256 * 1024 * 1024;
+
+// Streamed transfers send the file body as raw frames of at most
+// `CHUNK_SIZE` bytes after the command (upload) or response (download),
+// terminated by an empty frame. Neither side ever holds more than one
+// chunk in memory.
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", content = "content")]
+pub enum Command {
+    ListFiles,
+    UploadFile {
+        filename: String,
+        contents: Vec<u8>,
+        #[serde(default)]
+        checksum: Option<String>,
+        /// Erasure-code this file instead of replicating it, overriding
+        /// the server's per-directory settings.
+        #[serde(default)]
+        erasure: Option<ErasureScheme>,
+    },
+    DownloadFile {
+        filename: String,
+    },
+    /// Moves a file to the trash, or with `purge` deletes it for good.
+    DeleteFile {
+        filename: String,
+        #[serde(default)]
+        purge: bool,
+    },
+    UploadStream {
+        filename: String,
+        size: u64,
+        #[serde(default)]
+        checksum: Option<String>,
+        #[serde(default)]
+        erasure: Option<ErasureScheme>,
+        /// Replaces the file's attributes. Without them a new version keeps
+        /// those of the one it replaces.
+        #[serde(default)]
+        attributes: Option<FileAttributes>,
+    },
+    DownloadStream {
+        filename: String,
+    },
+    /// Creates a directory, and with `parents` any missing ancestors.
+    MakeDirectory {
+        path: String,
+        #[serde(default)]
+        parents: bool,
+    },
+    /// Removes a directory, which has to be empty unless `recursive` is set.
+    RemoveDirectory {
+        path: String,
+        #[serde(default)]
+        recursive: bool,
+    },
+    /// Every version of a file still kept, the current one first.
+    ListVersions {
+        filename: String,
+    },
+    DownloadVersion {
+        filename: String,
+        version: u64,
+    },
+    /// Writes an earlier version's content as a new version of the file.
+    RestoreVersion {
+        filename: String,
+        version: u64,
+    },
+    /// Moves a file or directory to a new path, with everything below it.
+    /// An existing `to` is only replaced with `overwrite`, and a directory
+    /// only if it is empty.
+    Rename {
+        from: String,
+        to: String,
+        #[serde(default)]
+        overwrite: bool,
+    },
+    /// Lists a directory's children, or with `recursive` everything below
+    /// it. The root directory is `""`.
+    ListDirectory {
+        path: String,
+        #[serde(default)]
+        recursive: bool,
+    },
+    /// Takes a named, read-only snapshot of the directory at `path`, the
+    /// whole file system by default.
+    CreateSnapshot {
+        name: String,
+        #[serde(default)]
+        path: String,
+    },
+    ListSnapshots,
+    /// Lists a directory of a snapshot, like `ListDirectory`.
+    BrowseSnapshot {
+        name: String,
+        #[serde(default)]
+        path: String,
+        #[serde(default)]
+        recursive: bool,
+    },
+    DownloadSnapshotFile {
+        name: String,
+        filename: String,
+    },
+    /// What changed below the snapshot's root since it was taken.
+    DiffSnapshot {
+        name: String,
+    },
+    /// Undoes every change `DiffSnapshot` reports. Files changed since get
+    /// the snapshot's content as a new version.
+    RollbackSnapshot {
+        name: String,
+    },
+    DeleteSnapshot {
+        name: String,
+    },
+    /// Brings back the most recently deleted file of that name from the
+    /// trash.
+    Undelete {
+        filename: String,
+    },
+    ListTrash,
+    /// Everything known about a file, answered with `FileMetadata`.
+    Stat {
+        filename: String,
+    },
+    /// Changes a file's attributes, leaving its content alone. An empty
+    /// `content_type` or `owner` clears it; custom attributes in `set` are
+    /// added or replaced and those named in `remove` dropped.
+    SetAttributes {
+        filename: String,
+        #[serde(default)]
+        content_type: Option<String>,
+        #[serde(default)]
+        owner: Option<String>,
+        #[serde(default)]
+        set: BTreeMap<String, String>,
+        #[serde(default)]
+        remove: Vec<String>,
+    },
+    Ping,
+    /// A request one server relays to another on behalf of a client. The
+    /// receiving server serves `command` from local storage only and does
+    /// not relay it again. `key` has to be the cluster key both servers
+    /// were started with, so clients can't pass requests off as relayed.
+    Relayed { key: String, command: Box<Command> },
+    /// Every file the receiving node itself holds, shards and other
+    /// internal files included. Servers use this to compare holdings;
+    /// clients want `ListFiles`.
+    ListStoredFiles,
+    /// A chunk of the receiving node's own store by its hash, in
+    /// `file_contents`. Only served to peers, which use it to repair a
+    /// damaged copy of their own.
+    FetchChunk { hash: String },
+    // Cluster administration, only accepted `Relayed` with the cluster key.
+    // Membership changes apply to the node that receives them; each node
+    // has to be told separately.
+    AddNode { id: String, address: String },
+    RemoveNode { id: String },
+    DecommissionNode { id: String },
+    QueryNode { id: String },
+    RebalanceStatus,
+}
+
+/// Reed-Solomon layout for an erasure-coded file: its content is split into
+/// `data_shards` pieces plus `parity_shards` parity pieces, and any
+/// `data_shards` of them are enough to rebuild it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ErasureScheme {
+    pub data_shards: usize,
+    pub parity_shards: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// One entry of a directory listing. `path` is the full path from the
+/// root. Directories have no size or checksum.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DirEntry {
+    pub path: String,
+    pub kind: EntryKind,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub checksum: Option<String>,
+    pub created_at: u64,
+    pub modified_at: u64,
+}
+
+/// One version of a file. Ids count up from 1 with each write.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VersionInfo {
+    pub version: u64,
+    pub size: u64,
+    pub checksum: String,
+    pub modified_at: u64,
+}
+
+/// A snapshot as the nodes holding it report it. Nodes holding replicas of
+/// the same files overlap, so `files` and `size` are the largest counts any
+/// one node reports.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub path: String,
+    pub created_at: u64,
+    pub files: usize,
+    pub size: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// One difference between a snapshot and the live file system.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotChange {
+    pub path: String,
+    pub kind: EntryKind,
+    pub change: ChangeKind,
+}
+
+pub use super::attributes::FileAttributes;
+
+/// Everything known about a file. `content_type` is guessed from the name
+/// when none was set.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileMetadata {
+    pub path: String,
+    pub size: u64,
+    pub checksum: String,
+    pub created_at: u64,
+    pub modified_at: u64,
+    pub version: u64,
+    pub content_type: String,
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub attributes: BTreeMap<String, String>,
+    pub replication: ReplicationState,
+}
+
+/// How well a file is protected right now: copies on its owners against
+/// the copies placement wants, or for an erasure-coded file, shards found
+/// against shards written.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReplicationState {
+    pub copies: usize,
+    pub wanted: usize,
+    #[serde(default)]
+    pub erasure: Option<ErasureScheme>,
+}
+
+/// A deleted file waiting in the trash until it is undeleted or purged.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TrashInfo {
+    pub filename: String,
+    pub size: u64,
+    pub checksum: String,
+    pub deleted_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusCode {
+    Ok,
+    BadRequest,
+    NotFound,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    ChecksumMismatch,
+    ReplicationFailed,
+    /// The metadata service has no leader it can reach; retry later.
+    Unavailable,
+    /// A relayed request without the cluster key.
+    Forbidden,
+    InternalError,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerResponse {
+    pub status: StatusCode,
+    pub message: String,
+    pub files: Option<Vec<String>>,
+    pub entries: Option<Vec<DirEntry>>,
+    pub versions: Option<Vec<VersionInfo>>,
+    pub snapshots: Option<Vec<SnapshotInfo>>,
+    pub changes: Option<Vec<SnapshotChange>>,
+    pub trash: Option<Vec<TrashInfo>>,
+    pub metadata: Option<FileMetadata>,
+    pub file_contents: Option<Vec<u8>>,
+    pub size: Option<u64>,
+    // Hex SHA-256 of the whole file, so clients can verify what they
+    // received independently of the transport.
+    pub checksum: Option<String>,
+}
+
+impl ServerResponse {
+    pub fn ok(message: &str) -> Self {
+        Self {
+            status: StatusCode::Ok,
+            message: message.to_string(),
+            files: None,
+            entries: None,
+            versions: None,
+            snapshots: None,
+            changes: None,
+            trash: None,
+            metadata: None,
+            file_contents: None,
+            size: None,
+            checksum: None,
+        }
+    }
+
+    pub fn error(status: StatusCode, message: &str) -> Self {
+        Self {
+            status,
+            message: message.to_string(),
+            files: None,
+            entries: None,
+            versions: None,
+            snapshots: None,
+            changes: None,
+            trash: None,
+            metadata: None,
+            file_contents: None,
+            size: None,
+            checksum: None,
+        }
+    }
+
+    pub fn with_files(mut self, files: Vec<String>) -> Self {
+        self.files = Some(files);
+        self
+    }
+
+    pub fn with_entries(mut self, entries: Vec<DirEntry>) -> Self {
+        self.entries = Some(entries);
+        self
+    }
+
+    pub fn with_versions(mut self, versions: Vec<VersionInfo>) -> Self {
+        self.versions = Some(versions);
+        self
+    }
+
+    pub fn with_snapshots(mut self, snapshots: Vec<SnapshotInfo>) -> Self {
+        self.snapshots = Some(snapshots);
+        self
+    }
+
+    pub fn with_changes(mut self, changes: Vec<SnapshotChange>) -> Self {
+        self.changes = Some(changes);
+        self
+    }
+
+    pub fn with_trash(mut self, trash: Vec<TrashInfo>) -> Self {
+        self.trash = Some(trash);
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: FileMetadata) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn with_contents(mut self, contents: Vec<u8>) -> Self {
+        self.file_contents = Some(contents);
+        self
+    }
+
+    pub fn with_size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn with_checksum(mut self, checksum: String) -> Self {
+        self.checksum = Some(checksum);
+        self
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.status == StatusCode::Ok
+    }
+}
+
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> io::Result<()> {
+    let payload = serde_json::to_vec(message)?;
+    write_frame(writer, &payload)?;
+    writer.flush()
+}
+
+/// Reads the next message, returning `Ok(None)` if the peer closed the
+/// connection cleanly between frames.
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> io::Result<Option<T>> {
+    match read_frame(reader)? {
+        Some(payload) => Ok(Some(serde_json::from_slice(&payload)?)),
+        None => Ok(None),
+    }
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Message exceeds maximum frame size"));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    read_frame_within(reader, MAX_FRAME_LEN)
+}
+
+// Reads one frame, refusing any longer than `limit` before allocating room
+// for it.
+fn read_frame_within<R: Read>(reader: &mut R, limit: u32) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Connection closed mid-frame")),
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    let frame_len = u32::from_be_bytes(header);
+    if frame_len > limit {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Frame length {} exceeds limit", frame_len)));
+    }
+
+    let mut payload = vec![0u8; frame_len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Adapts the chunk frames that follow a streamed command into a `Read`.
+pub struct ChunkReader<'a, R: Read> {
+    inner: &'a mut R,
+    chunk: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<'a, R: Read> ChunkReader<'a, R> {
+    pub fn new(inner: &'a mut R) -> Self {
+        Self {
+            inner,
+            chunk: Vec::new(),
+            position: 0,
+            finished: false,
+        }
+    }
+
+    /// Consumes any remaining chunks so the connection stays in sync after
+    /// a transfer is rejected part way through.
+    pub fn drain(&mut self) -> io::Result<()> {
+        io::copy(self, &mut io::sink()).map(|_| ())
+    }
+}
+
+impl<'a, R: Read> Read for ChunkReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            if self.finished {
+                return Ok(0);
+            }
+            // Senders never put more than CHUNK_SIZE bytes in a chunk.
+            match read_frame_within(self.inner, CHUNK_SIZE as u32)? {
+                Some(frame) if frame.is_empty() => self.finished = true,
+                Some(frame) => {
+                    self.chunk = frame;
+                    self.position = 0;
+                },
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Stream ended before the final chunk")),
+            }
+        }
+
+        let count = buf.len().min(self.chunk.len() - self.position);
+        buf[..count].copy_from_slice(&self.chunk[self.position..self.position + count]);
+        self.position += count;
+        Ok(count)
+    }
+}
+
+/// Splits everything written to it into chunk frames. `finish` must be
+/// called to send the terminating empty frame.
+pub struct ChunkWriter<'a, W: Write> {
+    inner: &'a mut W,
+    buffer: Vec<u8>,
+}
+
+impl<'a, W: Write> ChunkWriter<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        Self {
+            inner,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.flush()?;
+        write_frame(self.inner, &[])?;
+        self.inner.flush()
+    }
+}
+
+impl<'a, W: Write> Write for ChunkWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..count]);
+        if self.buffer.len() == CHUNK_SIZE {
+            write_frame(self.inner, &self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            write_frame(self.inner, &self.buffer)?;
+            self.buffer.clear();
+        }
+        self.inner.flush()
+    }
+}
+
+pub fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_round_trip() {
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let mut stream = Vec::new();
+        let mut writer = ChunkWriter::new(&mut stream);
+        writer.write_all(&data).unwrap();
+        writer.finish().unwrap();
+
+        let mut received = Vec::new();
+        ChunkReader::new(&mut stream.as_slice()).read_to_end(&mut received).unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn oversized_chunks_are_refused() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&(CHUNK_SIZE as u32 + 1).to_be_bytes());
+        let error = ChunkReader::new(&mut stream.as_slice()).read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
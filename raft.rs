@@ -0,0 +1,1436 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+use super::atomic_file::write_atomic;
+use super::namespace::{MetadataOp, Namespace, OpError};
+use super::protocol::{read_message, write_message};
+use super::replication::{cluster_key, keys_match};
+
+const DEFAULT_HEARTBEAT_MS: u64 = 100;
+const DEFAULT_ELECTION_TIMEOUT_MS: u64 = 1_000;
+const DEFAULT_SNAPSHOT_THRESHOLD: usize = 1_000;
+// Upper bound on entries shipped in one AppendEntries.
+const MAX_BATCH: usize = 256;
+// How many times a proposal is retried while leadership settles.
+const MAX_PROPOSE_ATTEMPTS: usize = 5;
+
+const STATE_FILE_NAME: &str = "state.json";
+const LOG_FILE_NAME: &str = "log.jsonl";
+const SNAPSHOT_FILE_NAME: &str = "snapshot.json";
+
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    pub node_id: String,
+    pub listen_address: String,
+    /// The other members of the group as `(id, address)` pairs.
+    pub peers: Vec<(String, String)>,
+    pub data_dir: PathBuf,
+    pub heartbeat_interval: Duration,
+    /// Minimum election timeout; each node picks its timeouts at random
+    /// between this and twice this.
+    pub election_timeout: Duration,
+    /// Compact the log into a snapshot once it holds this many entries.
+    pub snapshot_threshold: usize,
+    /// The cluster key, which every request between members carries.
+    pub key: String,
+}
+
+impl RaftConfig {
+    /// Reads `DFS_RAFT_PEERS`, a comma-separated list of `id=host:port`
+    /// pairs naming the other members of the metadata group, and
+    /// `DFS_RAFT_ADDR`, this node's own Raft address. Returns `None` when
+    /// `DFS_RAFT_PEERS` is unset. Also reads `DFS_RAFT_HEARTBEAT_MS`
+    /// (default 100), `DFS_RAFT_ELECTION_TIMEOUT_MS` (default 1000) and
+    /// `DFS_RAFT_SNAPSHOT_THRESHOLD` (default 1000). State is kept under
+    /// `DFS_BASE_DIR/raft`. Members prove themselves with `DFS_CLUSTER_KEY`,
+    /// which has to be set as well.
+    pub fn from_env(node_id: &str) -> Result<Option<Self>, String> {
+        let peers = match env::var("DFS_RAFT_PEERS") {
+            Ok(peers) => peers,
+            Err(_) => return Ok(None),
+        };
+        let peers = peers
+            .split(',')
+            .map(str::trim)
+            .filter(|peer| !peer.is_empty())
+            .map(|peer| match peer.split_once('=') {
+                Some((id, address)) => Ok((id.trim().to_string(), address.trim().to_string())),
+                None => Err(format!("Invalid DFS_RAFT_PEERS entry '{}', expected id=host:port", peer)),
+            })
+            .filter(|peer| !matches!(peer, Ok((id, _)) if id == node_id))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let listen_address = env::var("DFS_RAFT_ADDR").map_err(|_| "DFS_RAFT_ADDR must be set with DFS_RAFT_PEERS".to_string())?;
+        let key = cluster_key().ok_or_else(|| "DFS_CLUSTER_KEY must be set with DFS_RAFT_PEERS".to_string())?;
+        let base_dir = env::var("DFS_BASE_DIR").unwrap_or_else(|_| "./data".to_string());
+        let snapshot_threshold = match env::var("DFS_RAFT_SNAPSHOT_THRESHOLD") {
+            Ok(value) => value.parse().map_err(|e| format!("Invalid DFS_RAFT_SNAPSHOT_THRESHOLD: {}", e))?,
+            Err(_) => DEFAULT_SNAPSHOT_THRESHOLD,
+        };
+        Ok(Some(Self {
+            node_id: node_id.to_string(),
+            listen_address,
+            peers,
+            data_dir: Path::new(&base_dir).join("raft"),
+            heartbeat_interval: millis_from_env("DFS_RAFT_HEARTBEAT_MS", DEFAULT_HEARTBEAT_MS)?,
+            election_timeout: millis_from_env("DFS_RAFT_ELECTION_TIMEOUT_MS", DEFAULT_ELECTION_TIMEOUT_MS)?,
+            snapshot_threshold: snapshot_threshold.max(1),
+            key: key.to_string(),
+        }))
+    }
+}
+
+fn millis_from_env(key: &str, default: u64) -> Result<Duration, String> {
+    match env::var(key) {
+        Ok(value) => value.parse().map(Duration::from_millis).map_err(|e| format!("Invalid {}: {}", key, e)),
+        Err(_) => Ok(Duration::from_millis(default)),
+    }
+}
+
+/// `operation` is `None` for the no-op a new leader appends to commit
+/// entries left over from earlier terms.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct LogEntry {
+    index: u64,
+    term: u64,
+    operation: Option<MetadataOp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct Snapshot {
+    last_index: u64,
+    last_term: u64,
+    namespace: Namespace,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct HardState {
+    current_term: u64,
+    voted_for: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "content")]
+enum RaftRequest {
+    RequestVote { term: u64, candidate_id: String, last_log_index: u64, last_log_term: u64 },
+    AppendEntries {
+        term: u64,
+        leader_id: String,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<LogEntry>,
+        leader_commit: u64,
+    },
+    InstallSnapshot { term: u64, leader_id: String, snapshot: Snapshot },
+    /// A proposal forwarded from a follower to the leader.
+    Propose { operation: MetadataOp },
+    /// A linearizable read forwarded from a follower to the leader.
+    Read,
+}
+
+// A request as it goes over TCP. Like `Command::Relayed`, it carries the
+// cluster key, so only members can vote, replicate, install snapshots or
+// propose changes.
+#[derive(Serialize, Deserialize, Debug)]
+struct KeyedRequest<K, R> {
+    key: K,
+    request: R,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", content = "content")]
+enum RaftResponse {
+    Vote { term: u64, granted: bool },
+    /// On failure `next_index` is where the leader should retry from.
+    Append { term: u64, success: bool, match_index: u64, next_index: u64 },
+    Snapshot { term: u64 },
+    Proposed { result: Result<(), OpError> },
+    Namespace { namespace: Namespace },
+    NotLeader { leader: Option<String> },
+    Unavailable { message: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+// Durable Raft state under `data_dir`: the current term and vote, the log
+// as one JSON entry per line, and the latest snapshot. Everything is synced
+// to disk before the node acts on it.
+struct RaftStorage {
+    dir: PathBuf,
+    log: File,
+}
+
+impl RaftStorage {
+    fn open(dir: &Path) -> io::Result<(Self, HardState, Snapshot, Vec<LogEntry>)> {
+        fs::create_dir_all(dir)?;
+        let hard_state = match fs::read(dir.join(STATE_FILE_NAME)) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => HardState::default(),
+            Err(e) => return Err(e),
+        };
+        let snapshot: Snapshot = match fs::read(dir.join(SNAPSHOT_FILE_NAME)) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e),
+        };
+
+        let log_path = dir.join(LOG_FILE_NAME);
+        let (mut entries, valid_len) = replay_log(&log_path)?;
+        let log = OpenOptions::new().create(true).append(true).open(&log_path)?;
+        // A crash mid-append leaves a torn final line; drop it.
+        if log.metadata()?.len() > valid_len {
+            log.set_len(valid_len)?;
+        }
+        // A crash between saving a snapshot and rewriting the log leaves
+        // entries the snapshot already covers.
+        entries.retain(|entry| entry.index > snapshot.last_index);
+
+        Ok((Self { dir: dir.to_path_buf(), log }, hard_state, snapshot, entries))
+    }
+
+    fn save_hard_state(&self, hard_state: &HardState) -> io::Result<()> {
+        write_atomic(self.dir.join(STATE_FILE_NAME), &serde_json::to_vec(hard_state)?)
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let mut lines = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        self.log.write_all(&lines)?;
+        self.log.sync_data()
+    }
+
+    fn rewrite_log(&mut self, entries: &[LogEntry]) -> io::Result<()> {
+        let log_path = self.dir.join(LOG_FILE_NAME);
+        let mut lines = Vec::new();
+        for entry in entries {
+            serde_json::to_writer(&mut lines, entry)?;
+            lines.push(b'\n');
+        }
+        write_atomic(&log_path, &lines)?;
+        self.log = OpenOptions::new().append(true).open(&log_path)?;
+        Ok(())
+    }
+
+    fn save_snapshot(&self, snapshot: &Snapshot) -> io::Result<()> {
+        write_atomic(self.dir.join(SNAPSHOT_FILE_NAME), &serde_json::to_vec(snapshot)?)
+    }
+}
+
+fn replay_log(log_path: &Path) -> io::Result<(Vec<LogEntry>, u64)> {
+    let mut entries = Vec::new();
+    let mut valid_len = 0u64;
+    let file = match File::open(log_path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((entries, 0)),
+        Err(e) => return Err(e),
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = reader.read_line(&mut line)?;
+        if read == 0 || !line.ends_with('\n') {
+            break; // End of log, or a torn write at its tail
+        }
+        let entry: LogEntry = serde_json::from_str(line.trim_end()).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("Corrupt Raft log entry at byte {}: {}", valid_len, e))
+        })?;
+        entries.push(entry);
+        valid_len += read as u64;
+    }
+    Ok((entries, valid_len))
+}
+
+struct RaftState {
+    role: Role,
+    current_term: u64,
+    voted_for: Option<String>,
+    leader_id: Option<String>,
+    /// Entries after `snapshot.last_index`, in index order.
+    log: Vec<LogEntry>,
+    snapshot: Snapshot,
+    commit_index: u64,
+    last_applied: u64,
+    namespace: Namespace,
+    election_deadline: Instant,
+    /// When a leader was last heard from, while following one.
+    leader_heard_at: Option<Instant>,
+    votes: HashSet<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+    /// While leading, when each peer last accepted this node as leader, as
+    /// the time the request it answered was sent.
+    acknowledged: HashMap<String, Instant>,
+    /// Outcomes of applied entries someone is waiting on, by index.
+    results: HashMap<u64, Result<(), OpError>>,
+    waiting: HashSet<u64>,
+    storage: RaftStorage,
+}
+
+impl RaftState {
+    fn last_log_index(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.last_index, |entry| entry.index)
+    }
+
+    fn last_log_term(&self) -> u64 {
+        self.log.last().map_or(self.snapshot.last_term, |entry| entry.term)
+    }
+
+    /// The term of the entry at `index`, if it is still known.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.last_index {
+            return Some(self.snapshot.last_term);
+        }
+        self.entry(index).map(|entry| entry.term)
+    }
+
+    fn entry(&self, index: u64) -> Option<&LogEntry> {
+        if index <= self.snapshot.last_index {
+            return None;
+        }
+        self.log.get((index - self.snapshot.last_index - 1) as usize)
+    }
+
+    fn persist_hard_state(&self) -> io::Result<()> {
+        let hard_state = HardState {
+            current_term: self.current_term,
+            voted_for: self.voted_for.clone(),
+        };
+        self.storage.save_hard_state(&hard_state)
+    }
+
+    fn append(&mut self, entries: Vec<LogEntry>) -> io::Result<()> {
+        self.storage.append(&entries)?;
+        self.log.extend(entries);
+        Ok(())
+    }
+
+    /// Drops every entry from `index` on.
+    fn truncate_from(&mut self, index: u64) -> io::Result<()> {
+        let keep = index.saturating_sub(self.snapshot.last_index + 1) as usize;
+        self.log.truncate(keep);
+        self.storage.rewrite_log(&self.log)
+    }
+}
+
+// How requests reach the other members of the group.
+trait Transport: Send + Sync {
+    fn call(&self, address: &str, request: &RaftRequest, timeout: Duration) -> io::Result<RaftResponse>;
+}
+
+// Sends each request as a JSON message over TCP and waits for the answer.
+// A connection is kept for the next request to the same address; threads
+// calling the same peer at once each get their own.
+struct TcpTransport {
+    idle: Mutex<HashMap<String, TcpStream>>,
+    key: String,
+}
+
+impl Transport for TcpTransport {
+    fn call(&self, address: &str, request: &RaftRequest, timeout: Duration) -> io::Result<RaftResponse> {
+        let mut connection = self.idle.lock().unwrap().remove(address);
+        let response = call_on(&mut connection, address, &KeyedRequest { key: &self.key, request }, timeout);
+        if let Some(stream) = connection {
+            self.idle.lock().unwrap().insert(address.to_string(), stream);
+        }
+        response
+    }
+}
+
+struct Shared {
+    config: RaftConfig,
+    transport: Arc<dyn Transport>,
+    state: Mutex<RaftState>,
+    /// Signalled when entries are applied, leadership changes or peers
+    /// acknowledge the leader.
+    applied: Condvar,
+    /// Signalled when there is something new for the leader to send.
+    replicate: Condvar,
+    random: RandomState,
+    /// Set once the node is torn down; its threads exit on their next turn.
+    stopped: AtomicBool,
+}
+
+/// A member of the Raft group that replicates the file namespace. Every
+/// `propose` goes through the leader's log and returns once a majority has
+/// it, so create, rename and delete are linearizable and survive the loss
+/// of any minority of the group. Reads are served by the leader without
+/// going through the log.
+#[derive(Clone)]
+pub struct RaftNode {
+    shared: Arc<Shared>,
+}
+
+impl RaftNode {
+    pub fn start(config: RaftConfig) -> io::Result<Self> {
+        let listener = TcpListener::bind(&config.listen_address)?;
+        let transport = TcpTransport { idle: Mutex::new(HashMap::new()), key: config.key.clone() };
+        let node = Self::start_with(config, Arc::new(transport))?;
+        let server = Arc::clone(&node.shared);
+        thread::spawn(move || serve(server, listener));
+        Ok(node)
+    }
+
+    // Starts the node's own threads. It reaches its peers through
+    // `transport`; answering them is up to the caller.
+    fn start_with(config: RaftConfig, transport: Arc<dyn Transport>) -> io::Result<Self> {
+        let (storage, hard_state, snapshot, log) = RaftStorage::open(&config.data_dir)?;
+        println!(
+            "Metadata group member {} on {} with {} peers, term {}",
+            config.node_id,
+            config.listen_address,
+            config.peers.len(),
+            hard_state.current_term
+        );
+
+        let state = RaftState {
+            role: Role::Follower,
+            current_term: hard_state.current_term,
+            voted_for: hard_state.voted_for,
+            leader_id: None,
+            log,
+            namespace: snapshot.namespace.clone(),
+            commit_index: snapshot.last_index,
+            last_applied: snapshot.last_index,
+            snapshot,
+            election_deadline: Instant::now(),
+            leader_heard_at: None,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            acknowledged: HashMap::new(),
+            results: HashMap::new(),
+            waiting: HashSet::new(),
+            storage,
+        };
+        let shared = Arc::new(Shared {
+            config,
+            transport,
+            state: Mutex::new(state),
+            applied: Condvar::new(),
+            replicate: Condvar::new(),
+            random: RandomState::new(),
+            stopped: AtomicBool::new(false),
+        });
+        {
+            let mut state = shared.state.lock().unwrap();
+            reset_election_deadline(&shared, &mut state);
+        }
+
+        let ticker = Arc::clone(&shared);
+        thread::spawn(move || run_ticker(ticker));
+        for (peer_id, address) in shared.config.peers.clone() {
+            let replicator = Arc::clone(&shared);
+            thread::spawn(move || run_replicator(replicator, peer_id, address));
+        }
+        Ok(Self { shared })
+    }
+
+    /// Applies `operation` through the replicated log. Followers forward it
+    /// to the leader. Fails with `TimedOut` if no leader can be reached.
+    pub fn propose(&self, operation: MetadataOp) -> io::Result<()> {
+        self.with_leader(RaftRequest::Propose { operation: operation.clone() }, |shared| {
+            propose_local(shared, operation.clone()).map(|result| RaftResponse::Proposed { result })
+        })
+        .and_then(|response| match response {
+            RaftResponse::Proposed { result } => result.map_err(io::Error::from),
+            other => Err(unexpected(other)),
+        })
+    }
+
+    /// Stops the node's election timer and replicators. The node still
+    /// answers requests, but never starts anything again.
+    #[cfg(test)]
+    fn stop(&self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+        self.shared.replicate.notify_all();
+    }
+
+    /// The namespace as of some point after this call started, so no
+    /// acknowledged change is missing. See `read_local`.
+    pub fn read(&self) -> io::Result<Namespace> {
+        self.with_leader(RaftRequest::Read, read_local).and_then(|response| match response {
+            RaftResponse::Namespace { namespace } => Ok(namespace),
+            other => Err(unexpected(other)),
+        })
+    }
+
+    fn with_leader<F>(&self, request: RaftRequest, local: F) -> io::Result<RaftResponse>
+    where
+        F: Fn(&Shared) -> io::Result<RaftResponse>,
+    {
+        let shared = &self.shared;
+        let mut last_error = None;
+        for _ in 0..MAX_PROPOSE_ATTEMPTS {
+            let (role, leader) = {
+                let state = shared.state.lock().unwrap();
+                (state.role, state.leader_id.clone())
+            };
+            let leader_address = leader.and_then(|id| shared.config.peers.iter().find(|(peer_id, _)| *peer_id == id).cloned());
+            let response = match (role, leader_address) {
+                (Role::Leader, _) => local(shared),
+                (_, Some((_, address))) => shared.transport.call(&address, &request, shared.config.election_timeout * 6),
+                (_, None) => Err(io::Error::new(io::ErrorKind::TimedOut, "No metadata leader elected")),
+            };
+            match response {
+                Ok(RaftResponse::NotLeader { .. }) => {
+                    last_error = Some(io::Error::new(io::ErrorKind::TimedOut, "Metadata leader changed"));
+                },
+                Ok(RaftResponse::Unavailable { message }) => {
+                    last_error = Some(io::Error::new(io::ErrorKind::TimedOut, message));
+                },
+                Ok(response) => return Ok(response),
+                Err(e) => last_error = Some(e),
+            }
+            // Give an election time to finish before trying again.
+            thread::sleep(shared.config.election_timeout);
+        }
+        Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::TimedOut, "No metadata leader elected")))
+    }
+}
+
+fn unexpected(response: RaftResponse) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected metadata response: {:?}", response))
+}
+
+/// Appends `operation` to the leader's log and waits for it to be applied.
+/// `Ok(Err(_))` is an operation the state machine rejected.
+fn propose_local(shared: &Shared, operation: MetadataOp) -> io::Result<Result<(), OpError>> {
+    let mut state = shared.state.lock().unwrap();
+    if state.role != Role::Leader {
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "No longer the metadata leader"));
+    }
+    let term = state.current_term;
+    let index = state.last_log_index() + 1;
+    state.append(vec![LogEntry { index, term, operation: Some(operation) }])?;
+    state.waiting.insert(index);
+    advance_commit(shared, &mut state);
+    shared.replicate.notify_all();
+
+    let deadline = Instant::now() + shared.config.election_timeout * 5;
+    loop {
+        if let Some(result) = state.results.remove(&index) {
+            state.waiting.remove(&index);
+            return Ok(result);
+        }
+        let now = Instant::now();
+        if state.current_term != term || now >= deadline {
+            state.waiting.remove(&index);
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Metadata change was not committed"));
+        }
+        state = shared.applied.wait_timeout(state, deadline - now).unwrap().0;
+    }
+}
+
+// Serves a read from the leader's applied state, without writing to the
+// log (ReadIndex). That covers every acknowledged change once the leader
+// has committed an entry of its own term, as long as it is still the
+// leader. Within its lease, an election timeout since a majority last
+// accepted it, it is: those nodes ignore candidates until then. Past the
+// lease, a round of heartbeats sent after the read started confirms it.
+fn read_local(shared: &Shared) -> io::Result<RaftResponse> {
+    let started = Instant::now();
+    let deadline = started + shared.config.election_timeout * 5;
+    let mut state = shared.state.lock().unwrap();
+    let term = state.current_term;
+    loop {
+        if state.role != Role::Leader || state.current_term != term {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "No longer the metadata leader"));
+        }
+        let accepted_at = majority_accepted_at(shared, &state).filter(|_| state.term_at(state.commit_index) == Some(term));
+        if let Some(accepted_at) = accepted_at {
+            if accepted_at >= started || accepted_at + shared.config.election_timeout > Instant::now() {
+                return Ok(RaftResponse::Namespace { namespace: state.namespace.clone() });
+            }
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Could not confirm the metadata leader"));
+        }
+        shared.replicate.notify_all();
+        state = shared.applied.wait_timeout(state, deadline - now).unwrap().0;
+    }
+}
+
+// The latest time by which a majority of the group, this node included,
+// had accepted it as leader, if they have since it took over.
+fn majority_accepted_at(shared: &Shared, state: &RaftState) -> Option<Instant> {
+    // Besides this node, half the peers rounded up.
+    let needed = shared.config.peers.len().div_ceil(2);
+    if needed == 0 {
+        return Some(Instant::now());
+    }
+    let mut accepted: Vec<Instant> = state.acknowledged.values().copied().collect();
+    accepted.sort_unstable_by(|a, b| b.cmp(a));
+    accepted.get(needed - 1).copied()
+}
+
+fn reset_election_deadline(shared: &Shared, state: &mut RaftState) {
+    let mut hasher = shared.random.build_hasher();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    hasher.write_u128(now.as_nanos());
+    hasher.write_u64(state.current_term);
+    let base = shared.config.election_timeout.as_millis() as u64;
+    let jitter = hasher.finish() % base.max(1);
+    state.election_deadline = Instant::now() + Duration::from_millis(base + jitter);
+}
+
+fn step_down(shared: &Shared, state: &mut RaftState, term: u64) -> io::Result<()> {
+    if term > state.current_term {
+        state.current_term = term;
+        state.voted_for = None;
+        state.persist_hard_state()?;
+    }
+    if state.role != Role::Follower {
+        state.role = Role::Follower;
+        shared.applied.notify_all();
+    }
+    Ok(())
+}
+
+fn become_leader(shared: &Shared, state: &mut RaftState) -> io::Result<()> {
+    println!("{} is metadata leader for term {}", shared.config.node_id, state.current_term);
+    state.role = Role::Leader;
+    state.leader_id = Some(shared.config.node_id.clone());
+    state.acknowledged.clear();
+    let next = state.last_log_index() + 1;
+    for (peer_id, _) in &shared.config.peers {
+        state.next_index.insert(peer_id.clone(), next);
+        state.match_index.insert(peer_id.clone(), 0);
+    }
+    // Entries from earlier terms only count as committed once an entry
+    // from this term is, so start the term with a no-op.
+    let term = state.current_term;
+    state.append(vec![LogEntry { index: next, term, operation: None }])?;
+    advance_commit(shared, state);
+    shared.replicate.notify_all();
+    Ok(())
+}
+
+fn run_ticker(shared: Arc<Shared>) {
+    let tick = shared.config.heartbeat_interval / 2;
+    while !shared.stopped.load(Ordering::SeqCst) {
+        thread::sleep(tick);
+        let election = {
+            let mut state = shared.state.lock().unwrap();
+            if state.role == Role::Leader || Instant::now() < state.election_deadline {
+                continue;
+            }
+            state.role = Role::Candidate;
+            state.current_term += 1;
+            state.voted_for = Some(shared.config.node_id.clone());
+            state.leader_id = None;
+            state.leader_heard_at = None;
+            state.votes = std::iter::once(shared.config.node_id.clone()).collect();
+            reset_election_deadline(&shared, &mut state);
+            if let Err(e) = state.persist_hard_state() {
+                eprintln!("Failed to persist Raft state: {}", e);
+                state.role = Role::Follower;
+                continue;
+            }
+            if shared.config.peers.is_empty() {
+                if let Err(e) = become_leader(&shared, &mut state) {
+                    eprintln!("Failed to become metadata leader: {}", e);
+                }
+                continue;
+            }
+            RaftRequest::RequestVote {
+                term: state.current_term,
+                candidate_id: shared.config.node_id.clone(),
+                last_log_index: state.last_log_index(),
+                last_log_term: state.last_log_term(),
+            }
+        };
+
+        let election = Arc::new(election);
+        for (peer_id, address) in shared.config.peers.clone() {
+            let shared = Arc::clone(&shared);
+            let election = Arc::clone(&election);
+            thread::spawn(move || {
+                let response = shared.transport.call(&address, &election, shared.config.election_timeout);
+                let term = match election.as_ref() {
+                    RaftRequest::RequestVote { term, .. } => *term,
+                    _ => return,
+                };
+                if let Ok(RaftResponse::Vote { term: peer_term, granted }) = response {
+                    let mut state = shared.state.lock().unwrap();
+                    if peer_term > state.current_term {
+                        let _ = step_down(&shared, &mut state, peer_term);
+                    } else if granted && state.role == Role::Candidate && state.current_term == term {
+                        state.votes.insert(peer_id);
+                        if state.votes.len() > shared.config.peers.len().div_ceil(2) {
+                            if let Err(e) = become_leader(&shared, &mut state) {
+                                eprintln!("Failed to become metadata leader: {}", e);
+                            }
+                        }
+                    }
+                }
+            });
+        }
+    }
+}
+
+// One per peer. While this node leads, keeps the peer's log in step with
+// its own, sending empty AppendEntries as heartbeats when there's nothing
+// new and a snapshot when the peer is behind the compacted log.
+fn run_replicator(shared: Arc<Shared>, peer_id: String, address: String) {
+    while !shared.stopped.load(Ordering::SeqCst) {
+        let (request, term) = {
+            let state = shared.state.lock().unwrap();
+            if state.role != Role::Leader {
+                let _ = shared.replicate.wait_timeout(state, shared.config.heartbeat_interval).unwrap();
+                continue;
+            }
+            let next_index = state.next_index.get(&peer_id).copied().unwrap_or(1);
+            let request = if next_index <= state.snapshot.last_index {
+                RaftRequest::InstallSnapshot {
+                    term: state.current_term,
+                    leader_id: shared.config.node_id.clone(),
+                    snapshot: state.snapshot.clone(),
+                }
+            } else {
+                let prev_log_index = next_index - 1;
+                let entries: Vec<LogEntry> = (next_index..=state.last_log_index())
+                    .take(MAX_BATCH)
+                    .filter_map(|index| state.entry(index).cloned())
+                    .collect();
+                RaftRequest::AppendEntries {
+                    term: state.current_term,
+                    leader_id: shared.config.node_id.clone(),
+                    prev_log_index,
+                    prev_log_term: state.term_at(prev_log_index).unwrap_or(0),
+                    entries,
+                    leader_commit: state.commit_index,
+                }
+            };
+            (request, state.current_term)
+        };
+
+        let sent = Instant::now();
+        let response = shared.transport.call(&address, &request, shared.config.election_timeout);
+        let mut state = shared.state.lock().unwrap();
+        match response {
+            Ok(RaftResponse::Vote { term: peer_term, .. })
+            | Ok(RaftResponse::Append { term: peer_term, .. })
+            | Ok(RaftResponse::Snapshot { term: peer_term })
+                if peer_term > state.current_term =>
+            {
+                let _ = step_down(&shared, &mut state, peer_term);
+                continue;
+            },
+            _ if state.role != Role::Leader || state.current_term != term => continue,
+            Ok(RaftResponse::Append { success: true, match_index, .. }) => {
+                acknowledge(&shared, &mut state, &peer_id, sent);
+                state.match_index.insert(peer_id.clone(), match_index);
+                state.next_index.insert(peer_id.clone(), match_index + 1);
+                advance_commit(&shared, &mut state);
+            },
+            Ok(RaftResponse::Append { success: false, next_index, .. }) => {
+                acknowledge(&shared, &mut state, &peer_id, sent);
+                state.next_index.insert(peer_id.clone(), next_index.max(1));
+                continue; // Retry straight away from the new position
+            },
+            Ok(RaftResponse::Snapshot { .. }) => {
+                acknowledge(&shared, &mut state, &peer_id, sent);
+                if let RaftRequest::InstallSnapshot { snapshot, .. } = &request {
+                    state.match_index.insert(peer_id.clone(), snapshot.last_index);
+                    state.next_index.insert(peer_id.clone(), snapshot.last_index + 1);
+                }
+                continue;
+            },
+            Ok(_) => {},
+            // Unreachable peers are retried every heartbeat.
+            Err(_) => {
+                drop(state);
+                thread::sleep(shared.config.heartbeat_interval);
+                continue;
+            },
+        }
+
+        let caught_up = state.next_index.get(&peer_id).copied().unwrap_or(1) > state.last_log_index();
+        if caught_up {
+            let _ = shared.replicate.wait_timeout(state, shared.config.heartbeat_interval).unwrap();
+        }
+    }
+}
+
+// A peer answered a request sent at `sent` without rejecting this node's
+// term, so it followed this node as leader then.
+fn acknowledge(shared: &Shared, state: &mut RaftState, peer_id: &str, sent: Instant) {
+    state.acknowledged.insert(peer_id.to_string(), sent);
+    shared.applied.notify_all();
+}
+
+// Commits the highest index from the current term held by a majority.
+fn advance_commit(shared: &Shared, state: &mut RaftState) {
+    let group_size = shared.config.peers.len() + 1;
+    let mut index = state.last_log_index();
+    while index > state.commit_index {
+        if state.term_at(index) == Some(state.current_term) {
+            let replicas = 1 + state.match_index.values().filter(|matched| **matched >= index).count();
+            if replicas * 2 > group_size {
+                state.commit_index = index;
+                apply_committed(shared, state);
+                return;
+            }
+        }
+        index -= 1;
+    }
+}
+
+fn apply_committed(shared: &Shared, state: &mut RaftState) {
+    while state.last_applied < state.commit_index {
+        let index = state.last_applied + 1;
+        let operation = match state.entry(index) {
+            Some(entry) => entry.operation.clone(),
+            None => break,
+        };
+        let result = match operation.as_ref() {
+            Some(operation) => state.namespace.apply(operation),
+            None => Ok(()),
+        };
+        state.last_applied = index;
+        if state.waiting.contains(&index) {
+            state.results.insert(index, result);
+        }
+    }
+    shared.applied.notify_all();
+
+    if state.log.len() >= shared.config.snapshot_threshold {
+        if let Err(e) = take_snapshot(state) {
+            eprintln!("Failed to snapshot the metadata log: {}", e);
+        }
+    }
+}
+
+// Folds every applied entry into a snapshot and drops it from the log.
+fn take_snapshot(state: &mut RaftState) -> io::Result<()> {
+    let last_index = state.last_applied;
+    if last_index <= state.snapshot.last_index {
+        return Ok(());
+    }
+    let snapshot = Snapshot {
+        last_index,
+        last_term: state.term_at(last_index).unwrap_or(0),
+        namespace: state.namespace.clone(),
+    };
+    state.storage.save_snapshot(&snapshot)?;
+    let keep = (last_index - state.snapshot.last_index) as usize;
+    state.log.drain(..keep.min(state.log.len()));
+    state.snapshot = snapshot;
+    state.storage.rewrite_log(&state.log)
+}
+
+fn serve(shared: Arc<Shared>, listener: TcpListener) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || {
+                    if let Err(e) = serve_connection(&shared, stream) {
+                        eprintln!("Raft connection failed: {}", e);
+                    }
+                });
+            },
+            Err(e) => eprintln!("Failed to accept Raft connection: {}", e),
+        }
+    }
+}
+
+fn serve_connection(shared: &Shared, stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    while let Some(keyed) = read_message::<_, KeyedRequest<String, RaftRequest>>(&mut reader)? {
+        let response = handle_request(shared, authenticate(shared, keyed)?);
+        write_message(&mut writer, &response)?;
+    }
+    Ok(())
+}
+
+// The request, if it carries the cluster key. One that doesn't ends the
+// connection unanswered.
+fn authenticate(shared: &Shared, keyed: KeyedRequest<String, RaftRequest>) -> io::Result<RaftRequest> {
+    if !keys_match(&shared.config.key, &keyed.key) {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "Raft request without the cluster key"));
+    }
+    Ok(keyed.request)
+}
+
+fn handle_request(shared: &Shared, request: RaftRequest) -> RaftResponse {
+    let result = match request {
+        RaftRequest::RequestVote { term, candidate_id, last_log_index, last_log_term } => {
+            handle_vote(shared, term, &candidate_id, last_log_index, last_log_term)
+        },
+        RaftRequest::AppendEntries { term, leader_id, prev_log_index, prev_log_term, entries, leader_commit } => {
+            handle_append(shared, term, leader_id, prev_log_index, prev_log_term, entries, leader_commit)
+        },
+        RaftRequest::InstallSnapshot { term, leader_id, snapshot } => handle_snapshot(shared, term, leader_id, snapshot),
+        RaftRequest::Propose { operation } => {
+            propose_local(shared, operation).map(|result| RaftResponse::Proposed { result })
+        },
+        RaftRequest::Read => read_local(shared),
+    };
+    result.unwrap_or_else(|e| {
+        let state = shared.state.lock().unwrap();
+        if state.role == Role::Leader {
+            RaftResponse::Unavailable { message: e.to_string() }
+        } else {
+            RaftResponse::NotLeader { leader: state.leader_id.clone() }
+        }
+    })
+}
+
+fn handle_vote(shared: &Shared, term: u64, candidate_id: &str, last_log_index: u64, last_log_term: u64) -> io::Result<RaftResponse> {
+    let mut state = shared.state.lock().unwrap();
+    // Candidates are ignored while a leader was heard from within the
+    // minimum election timeout, which is what the leader's lease relies on.
+    let following = state.role == Role::Follower
+        && state.leader_heard_at.is_some_and(|heard_at| heard_at.elapsed() < shared.config.election_timeout);
+    if following {
+        return Ok(RaftResponse::Vote { term: state.current_term, granted: false });
+    }
+    if term > state.current_term {
+        step_down(shared, &mut state, term)?;
+    }
+    let up_to_date = (last_log_term, last_log_index) >= (state.last_log_term(), state.last_log_index());
+    let can_vote = state.voted_for.as_deref().is_none_or(|voted_for| voted_for == candidate_id);
+    let granted = term == state.current_term && can_vote && up_to_date;
+    if granted {
+        state.voted_for = Some(candidate_id.to_string());
+        state.persist_hard_state()?;
+        reset_election_deadline(shared, &mut state);
+    }
+    Ok(RaftResponse::Vote { term: state.current_term, granted })
+}
+
+// Common to AppendEntries and InstallSnapshot: reject stale leaders and
+// follow the current one.
+fn accept_leader(shared: &Shared, state: &mut MutexGuard<RaftState>, term: u64, leader_id: String) -> io::Result<bool> {
+    if term < state.current_term {
+        return Ok(false);
+    }
+    step_down(shared, state, term)?;
+    state.leader_id = Some(leader_id);
+    state.leader_heard_at = Some(Instant::now());
+    reset_election_deadline(shared, state);
+    Ok(true)
+}
+
+fn handle_append(
+    shared: &Shared,
+    term: u64,
+    leader_id: String,
+    prev_log_index: u64,
+    prev_log_term: u64,
+    entries: Vec<LogEntry>,
+    leader_commit: u64,
+) -> io::Result<RaftResponse> {
+    let mut state = shared.state.lock().unwrap();
+    let reject = |state: &RaftState, next_index: u64| RaftResponse::Append {
+        term: state.current_term,
+        success: false,
+        match_index: 0,
+        next_index,
+    };
+    if !accept_leader(shared, &mut state, term, leader_id)? {
+        return Ok(reject(&state, 0));
+    }
+
+    if prev_log_index > state.last_log_index() {
+        let next_index = state.last_log_index() + 1;
+        return Ok(reject(&state, next_index));
+    }
+    // Anything at or below the snapshot is committed and so already agrees
+    // with the leader.
+    if prev_log_index >= state.snapshot.last_index && state.term_at(prev_log_index) != Some(prev_log_term) {
+        // Skip back over the whole conflicting term at once.
+        let conflict_term = state.term_at(prev_log_index);
+        let mut next_index = prev_log_index;
+        while next_index > state.snapshot.last_index + 1 && state.term_at(next_index - 1) == conflict_term {
+            next_index -= 1;
+        }
+        return Ok(reject(&state, next_index));
+    }
+
+    let match_index = prev_log_index + entries.len() as u64;
+    let mut new_entries = Vec::new();
+    for entry in entries {
+        if entry.index <= state.snapshot.last_index {
+            continue;
+        }
+        match state.term_at(entry.index) {
+            Some(existing) if existing == entry.term && new_entries.is_empty() => continue,
+            Some(_) if new_entries.is_empty() => {
+                state.truncate_from(entry.index)?;
+                new_entries.push(entry);
+            },
+            _ => new_entries.push(entry),
+        }
+    }
+    if !new_entries.is_empty() {
+        state.append(new_entries)?;
+    }
+
+    if leader_commit > state.commit_index {
+        state.commit_index = leader_commit.min(match_index.max(state.snapshot.last_index));
+        apply_committed(shared, &mut state);
+    }
+    Ok(RaftResponse::Append { term: state.current_term, success: true, match_index, next_index: match_index + 1 })
+}
+
+fn handle_snapshot(shared: &Shared, term: u64, leader_id: String, snapshot: Snapshot) -> io::Result<RaftResponse> {
+    let mut state = shared.state.lock().unwrap();
+    if !accept_leader(shared, &mut state, term, leader_id)? || snapshot.last_index <= state.commit_index {
+        return Ok(RaftResponse::Snapshot { term: state.current_term });
+    }
+
+    state.storage.save_snapshot(&snapshot)?;
+    // Keep any log suffix that follows on from the snapshot.
+    if state.term_at(snapshot.last_index) == Some(snapshot.last_term) {
+        let keep_from = ((snapshot.last_index - state.snapshot.last_index) as usize).min(state.log.len());
+        state.log.drain(..keep_from);
+    } else {
+        state.log.clear();
+    }
+    state.namespace = snapshot.namespace.clone();
+    state.commit_index = snapshot.last_index;
+    state.last_applied = snapshot.last_index;
+    state.snapshot = snapshot;
+    let log = state.log.clone();
+    state.storage.rewrite_log(&log)?;
+    shared.applied.notify_all();
+    Ok(RaftResponse::Snapshot { term: state.current_term })
+}
+
+// Sends `request` over `connection`, opening it first if needed. The
+// connection is left open for the next call, or dropped if it failed.
+fn call_on(
+    connection: &mut Option<TcpStream>,
+    address: &str,
+    request: &KeyedRequest<&str, &RaftRequest>,
+    timeout: Duration,
+) -> io::Result<RaftResponse> {
+    if connection.is_none() {
+        let addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, format!("Unable to resolve {}", address)))?;
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_nodelay(true)?;
+        *connection = Some(stream);
+    }
+    let stream = connection.as_mut().unwrap();
+    let result = stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout)));
+    let result = result.and_then(|_| write_message(stream, request)).and_then(|_| {
+        read_message(stream)?.ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionAborted, "Raft peer closed the connection"))
+    });
+    if result.is_err() {
+        *connection = None;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scratch::ScratchDir;
+    use std::sync::Weak;
+
+    const HEARTBEAT: Duration = Duration::from_millis(20);
+    const ELECTION_TIMEOUT: Duration = Duration::from_millis(150);
+    const PATIENCE: Duration = Duration::from_secs(10);
+    const CLUSTER_KEY: &str = "cluster key";
+
+    // Nodes reach each other by address through this instead of TCP. Nodes
+    // on different sides of a partition can't reach each other.
+    #[derive(Default)]
+    struct Network {
+        nodes: Mutex<HashMap<String, Weak<Shared>>>,
+        sides: Mutex<HashMap<String, usize>>,
+    }
+
+    impl Network {
+        fn reachable(&self, from: &str, to: &str) -> bool {
+            let sides = self.sides.lock().unwrap();
+            sides.get(from).unwrap_or(&0) == sides.get(to).unwrap_or(&0)
+        }
+
+        // Cuts `minority` off from everyone else, each call on a side of
+        // its own.
+        fn partition(&self, minority: &[&TestNode]) {
+            let mut sides = self.sides.lock().unwrap();
+            let side = sides.values().max().unwrap_or(&0) + 1;
+            for node in minority {
+                sides.insert(node.address.clone(), side);
+            }
+        }
+
+        fn heal(&self) {
+            self.sides.lock().unwrap().clear();
+        }
+    }
+
+    struct MemoryTransport {
+        network: Arc<Network>,
+        address: String,
+        crashed: Mutex<bool>,
+    }
+
+    impl Transport for MemoryTransport {
+        fn call(&self, address: &str, request: &RaftRequest, _timeout: Duration) -> io::Result<RaftResponse> {
+            let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, format!("{} is unreachable", address));
+            if *self.crashed.lock().unwrap() || !self.network.reachable(&self.address, address) {
+                return Err(refused());
+            }
+            let target = self.network.nodes.lock().unwrap().get(address).and_then(Weak::upgrade).ok_or_else(refused)?;
+            // Through the wire format, like over TCP.
+            let keyed = KeyedRequest { key: CLUSTER_KEY, request };
+            let keyed = serde_json::from_slice(&serde_json::to_vec(&keyed)?)?;
+            let response = handle_request(&target, authenticate(&target, keyed)?);
+            Ok(serde_json::from_slice(&serde_json::to_vec(&response)?)?)
+        }
+    }
+
+    struct TestNode {
+        node: RaftNode,
+        transport: Arc<MemoryTransport>,
+        config: RaftConfig,
+        address: String,
+    }
+
+    impl TestNode {
+        fn state(&self) -> MutexGuard<'_, RaftState> {
+            self.node.shared.state.lock().unwrap()
+        }
+
+        fn is_leader(&self) -> bool {
+            self.state().role == Role::Leader
+        }
+
+        fn files(&self) -> Vec<String> {
+            self.state().namespace.names()
+        }
+
+        // The (index, term) of every entry still in the log.
+        fn entries(&self) -> Vec<(u64, u64)> {
+            self.state().log.iter().map(|entry| (entry.index, entry.term)).collect()
+        }
+    }
+
+    struct Cluster {
+        network: Arc<Network>,
+        nodes: Vec<TestNode>,
+        data_dirs: Vec<ScratchDir>,
+    }
+
+    impl Drop for Cluster {
+        // The data directories go once no node thread can write to them.
+        fn drop(&mut self) {
+            for index in 0..self.nodes.len() {
+                self.crash(index);
+                self.nodes[index].node.stop();
+            }
+            thread::sleep(HEARTBEAT * 2);
+            self.data_dirs.clear();
+        }
+    }
+
+    impl Cluster {
+        fn start(name: &str, size: usize, snapshot_threshold: usize) -> Self {
+            let ids: Vec<String> = (0..size).map(|n| format!("n{}", n)).collect();
+            let network = Arc::new(Network::default());
+            let mut cluster = Cluster { network, nodes: Vec::new(), data_dirs: Vec::new() };
+            for id in &ids {
+                let data_dir = ScratchDir::new(&format!("raft-{}-{}", name, id));
+                let config = RaftConfig {
+                    node_id: id.clone(),
+                    listen_address: id.clone(),
+                    peers: ids.iter().filter(|peer| *peer != id).map(|peer| (peer.clone(), peer.clone())).collect(),
+                    data_dir: data_dir.to_path_buf(),
+                    heartbeat_interval: HEARTBEAT,
+                    election_timeout: ELECTION_TIMEOUT,
+                    snapshot_threshold,
+                    key: CLUSTER_KEY.to_string(),
+                };
+                let node = cluster.launch(config);
+                cluster.nodes.push(node);
+                cluster.data_dirs.push(data_dir);
+            }
+            cluster
+        }
+
+        fn launch(&self, config: RaftConfig) -> TestNode {
+            let transport = Arc::new(MemoryTransport {
+                network: Arc::clone(&self.network),
+                address: config.listen_address.clone(),
+                crashed: Mutex::new(false),
+            });
+            let node = RaftNode::start_with(config.clone(), Arc::clone(&transport) as Arc<dyn Transport>).unwrap();
+            self.network.nodes.lock().unwrap().insert(config.listen_address.clone(), Arc::downgrade(&node.shared));
+            TestNode { node, transport, address: config.listen_address.clone(), config }
+        }
+
+        // The node stops answering and sending for good. Its threads keep
+        // running but can't reach anyone.
+        fn crash(&self, index: usize) {
+            let node = &self.nodes[index];
+            *node.transport.crashed.lock().unwrap() = true;
+            self.network.nodes.lock().unwrap().remove(&node.address);
+        }
+
+        // Starts the crashed node again from what it left on disk.
+        fn restart(&mut self, index: usize) {
+            self.nodes[index].node.stop();
+            let config = self.nodes[index].config.clone();
+            self.nodes[index] = self.launch(config);
+        }
+
+        fn leader(&self) -> usize {
+            wait_for("a leader", || self.nodes.iter().position(TestNode::is_leader))
+        }
+
+        fn leader_among(&self, candidates: &[usize]) -> usize {
+            wait_for("a leader", || candidates.iter().copied().find(|&index| self.nodes[index].is_leader()))
+        }
+    }
+
+    fn wait_for<T>(what: &str, mut check: impl FnMut() -> Option<T>) -> T {
+        let deadline = Instant::now() + PATIENCE;
+        loop {
+            if let Some(found) = check() {
+                return found;
+            }
+            assert!(Instant::now() < deadline, "Timed out waiting for {}", what);
+            thread::sleep(HEARTBEAT);
+        }
+    }
+
+    fn create(name: &str) -> MetadataOp {
+        MetadataOp::Create { name: name.to_string(), size: 1, checksum: "c".to_string(), modified_at: 1 }
+    }
+
+    #[test]
+    fn elects_one_leader_that_everyone_follows() {
+        let cluster = Cluster::start("elect", 3, 1_000);
+        let leader = cluster.leader();
+        let (term, leader_id) = {
+            let state = cluster.nodes[leader].state();
+            (state.current_term, cluster.nodes[leader].config.node_id.clone())
+        };
+        wait_for("followers", || {
+            let following = cluster.nodes.iter().enumerate().filter(|(index, _)| *index != leader).all(|(_, node)| {
+                let state = node.state();
+                state.role == Role::Follower && state.current_term == term && state.leader_id.as_deref() == Some(&leader_id)
+            });
+            Some(()).filter(|_| following)
+        });
+        // Heartbeats keep it in place.
+        thread::sleep(ELECTION_TIMEOUT * 4);
+        assert!(cluster.nodes[leader].is_leader());
+        assert_eq!(cluster.nodes[leader].state().current_term, term);
+    }
+
+    #[test]
+    fn commits_once_a_majority_has_the_entry() {
+        let cluster = Cluster::start("commit", 3, 1_000);
+        let leader = cluster.leader();
+        let followers: Vec<&TestNode> = cluster.nodes.iter().enumerate().filter(|(i, _)| *i != leader).map(|(_, n)| n).collect();
+        // Apart, so they can't elect one of their own either.
+        cluster.network.partition(&followers[..1]);
+        cluster.network.partition(&followers[1..]);
+
+        let proposer = cluster.nodes[leader].node.clone();
+        thread::spawn(move || proposer.propose(create("a")));
+        wait_for("the entry to be appended", || Some(()).filter(|_| cluster.nodes[leader].state().log.iter().any(|e| e.operation.is_some())));
+        let index = cluster.nodes[leader].state().last_log_index();
+        thread::sleep(ELECTION_TIMEOUT);
+        assert!(cluster.nodes[leader].state().commit_index < index, "Committed without a majority");
+
+        // One follower is enough for a majority of three. It may have
+        // started an election meanwhile, but without the entry it can't
+        // win one, so the entry survives and commits.
+        cluster.network.sides.lock().unwrap().remove(&followers[0].address);
+        wait_for("the follower to apply it", || Some(()).filter(|_| followers[0].files() == ["a"]));
+        assert!(followers[1].files().is_empty());
+    }
+
+    #[test]
+    fn rejected_operations_are_reported_everywhere_alike() {
+        let cluster = Cluster::start("reject", 3, 1_000);
+        cluster.leader();
+        let node = &cluster.nodes[0].node;
+        let error = node.propose(MetadataOp::Delete { name: "missing".to_string() }).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn requests_without_the_cluster_key_are_refused() {
+        let cluster = Cluster::start("forged", 3, 1_000);
+        let leader = cluster.leader();
+        cluster.nodes[leader].node.propose(create("a")).unwrap();
+        let term = cluster.nodes[leader].state().current_term;
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let shared = Arc::clone(&cluster.nodes[leader].node.shared);
+        thread::spawn(move || serve(shared, listener));
+
+        let forged = [
+            RaftRequest::InstallSnapshot { term: term + 100, leader_id: "intruder".to_string(), snapshot: Snapshot::default() },
+            RaftRequest::Propose { operation: MetadataOp::Delete { name: "a".to_string() } },
+            RaftRequest::RequestVote { term: term + 100, candidate_id: "intruder".to_string(), last_log_index: 100, last_log_term: term + 100 },
+        ];
+        for request in &forged {
+            // The connection is closed without an answer.
+            let error = call_on(&mut None, &address, &KeyedRequest { key: "guess", request }, PATIENCE).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::ConnectionAborted, "{:?}", request);
+        }
+        assert!(cluster.nodes[leader].state().current_term < term + 100);
+        assert_eq!(cluster.nodes[leader].files(), ["a"]);
+
+        let response = call_on(&mut None, &address, &KeyedRequest { key: CLUSTER_KEY, request: &RaftRequest::Read }, PATIENCE).unwrap();
+        assert!(matches!(response, RaftResponse::Namespace { .. } | RaftResponse::NotLeader { .. }), "{:?}", response);
+    }
+
+    #[test]
+    fn followers_forward_proposals_and_reads_to_the_leader() {
+        let cluster = Cluster::start("forward", 3, 1_000);
+        let leader = cluster.leader();
+        let follower = &cluster.nodes[(leader + 1) % 3];
+        follower.node.propose(create("a")).unwrap();
+        follower.node.propose(MetadataOp::Rename { from: "a".to_string(), to: "b".to_string(), overwrite: false }).unwrap();
+        assert_eq!(follower.node.read().unwrap().names(), vec!["b"]);
+        let last_index = cluster.nodes[leader].state().last_log_index();
+        // Reads don't go through the log.
+        for _ in 0..10 {
+            cluster.nodes[leader].node.read().unwrap();
+        }
+        assert_eq!(cluster.nodes[leader].state().last_log_index(), last_index);
+    }
+
+    #[test]
+    fn a_new_leader_keeps_what_the_crashed_one_committed() {
+        let cluster = Cluster::start("crash", 3, 1_000);
+        let old_leader = cluster.leader();
+        cluster.nodes[old_leader].node.propose(create("a")).unwrap();
+        let old_term = cluster.nodes[old_leader].state().current_term;
+        cluster.crash(old_leader);
+
+        let rest: Vec<usize> = (0..3).filter(|&index| index != old_leader).collect();
+        let leader = cluster.leader_among(&rest);
+        assert!(cluster.nodes[leader].state().current_term > old_term);
+        cluster.nodes[leader].node.propose(create("b")).unwrap();
+        assert_eq!(cluster.nodes[leader].node.read().unwrap().names(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn a_partitioned_minority_can_neither_commit_nor_read_and_its_log_is_overwritten() {
+        let cluster = Cluster::start("partition", 5, 1_000);
+        let old_leader = cluster.leader();
+        cluster.nodes[old_leader].node.propose(create("committed")).unwrap();
+        let buddy = (old_leader + 1) % 5;
+        cluster.network.partition(&[&cluster.nodes[old_leader], &cluster.nodes[buddy]]);
+
+        // The old leader still appends, but can't commit, and once its
+        // lease runs out it can't serve reads either.
+        thread::sleep(ELECTION_TIMEOUT);
+        let shared = Arc::clone(&cluster.nodes[old_leader].node.shared);
+        let stranded = thread::spawn(move || propose_local(&shared, create("stranded")));
+        let error = read_local(&cluster.nodes[old_leader].node.shared).expect_err("Read without a majority");
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        assert!(stranded.join().unwrap().is_err());
+        assert!(cluster.nodes[old_leader].state().log.iter().any(|entry| {
+            matches!(&entry.operation, Some(MetadataOp::Create { name, .. }) if name == "stranded")
+        }));
+
+        let majority: Vec<usize> = (0..5).filter(|&index| index != old_leader && index != buddy).collect();
+        let leader = cluster.leader_among(&majority);
+        cluster.nodes[leader].node.propose(create("elected")).unwrap();
+
+        cluster.network.heal();
+        let expected = cluster.nodes[leader].entries();
+        let commit_index = cluster.nodes[leader].state().commit_index;
+        // Leadership may move once the minority's terms are heard, but only
+        // within the majority.
+        wait_for("everyone to catch up", || {
+            let caught_up = cluster.nodes.iter().all(|node| node.state().last_applied >= commit_index);
+            let leaders = cluster.nodes.iter().filter(|node| node.is_leader()).count();
+            Some(()).filter(|_| caught_up && leaders == 1)
+        });
+        // The conflicting entry was truncated; logs match entry for entry.
+        assert_eq!(cluster.nodes[old_leader].files(), vec!["committed", "elected"]);
+        for node in &cluster.nodes {
+            let entries = node.entries();
+            assert_eq!(entries[..expected.len().min(entries.len())], expected[..expected.len().min(entries.len())]);
+            assert_eq!(node.files(), vec!["committed", "elected"]);
+        }
+    }
+
+    #[test]
+    fn a_lagging_follower_catches_up_from_a_snapshot() {
+        let mut cluster = Cluster::start("snapshot", 3, 4);
+        let leader = cluster.leader();
+        let lagging = (leader + 1) % 3;
+        cluster.crash(lagging);
+
+        let names: Vec<String> = (0..10).map(|n| format!("f{:02}", n)).collect();
+        for name in &names {
+            cluster.nodes[leader].node.propose(create(name)).unwrap();
+        }
+        let (snapshot_index, commit_index) = {
+            let state = cluster.nodes[leader].state();
+            (state.snapshot.last_index, state.commit_index)
+        };
+        assert!(snapshot_index > 0, "The log was never compacted");
+        assert!(cluster.nodes[leader].state().log.len() < 4);
+
+        // The entries it misses are only in the leader's snapshot now.
+        cluster.restart(lagging);
+        wait_for("the follower to catch up", || Some(()).filter(|_| cluster.nodes[lagging].state().last_applied >= commit_index));
+        assert!(cluster.nodes[lagging].state().snapshot.last_index >= snapshot_index);
+        assert_eq!(cluster.nodes[lagging].files(), names);
+    }
+
+    #[test]
+    fn a_restarted_node_recovers_its_snapshot_and_log() {
+        let mut cluster = Cluster::start("restart", 3, 4);
+        let leader = cluster.leader();
+        for n in 0..6 {
+            cluster.nodes[leader].node.propose(create(&format!("f{}", n))).unwrap();
+        }
+        let follower = (leader + 1) % 3;
+        let commit_index = cluster.nodes[leader].state().commit_index;
+        wait_for("the follower to apply everything", || Some(()).filter(|_| cluster.nodes[follower].state().last_applied >= commit_index));
+        let snapshot = cluster.nodes[follower].state().snapshot.last_index;
+        let entries = cluster.nodes[follower].entries();
+        cluster.crash(follower);
+        cluster.restart(follower);
+
+        let state = cluster.nodes[follower].state();
+        assert_eq!(state.snapshot.last_index, snapshot);
+        assert_eq!(state.log.iter().map(|entry| (entry.index, entry.term)).collect::<Vec<_>>(), entries);
+        drop(state);
+        wait_for("the restarted node to apply everything", || Some(()).filter(|_| cluster.nodes[follower].state().last_applied >= commit_index));
+        assert_eq!(cluster.nodes[follower].files(), (0..6).map(|n| format!("f{}", n)).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex};
+use std::slice;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::erasure::{self, is_shard};
+use super::network::NetworkTopology;
+use super::node_handler::MembershipObserver;
+use super::protocol::{Command, ErasureScheme};
+use super::replication::{request, send_command, stream_file, Replicator};
+use super::storage::DistributedFileSystem;
+
+const BANDWIDTH_ENV_KEY: &str = "DFS_REBALANCE_BANDWIDTH";
+const MAX_REPORTED_ERRORS: usize = 32;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RebalanceConfig {
+    /// Number of owners each file should have; the replication factor.
+    pub factor: usize,
+    /// Upper bound on transfer throughput in bytes per second.
+    pub bandwidth: Option<u64>,
+}
+
+impl RebalanceConfig {
+    /// Reads `DFS_REBALANCE_BANDWIDTH` in bytes per second; unset or 0 means
+    /// unlimited.
+    pub fn from_env(factor: usize) -> Result<Self, String> {
+        let bandwidth = match env::var(BANDWIDTH_ENV_KEY) {
+            Ok(value) => value.parse::<u64>().map_err(|e| format!("Invalid {}: {}", BANDWIDTH_ENV_KEY, e))?,
+            Err(_) => 0,
+        };
+        Ok(Self {
+            factor,
+            bandwidth: if bandwidth == 0 { None } else { Some(bandwidth) },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RebalanceProgress {
+    pub running: bool,
+    pub passes: u64,
+    pub started_at: u64,
+    pub finished_at: u64,
+    /// Files some owner is missing.
+    pub under_replicated: usize,
+    /// Files held by more nodes than own them.
+    pub over_replicated: usize,
+    /// Files this node holds without owning them.
+    pub misplaced: usize,
+    pub transfers_planned: usize,
+    pub transfers_done: usize,
+    pub transfers_failed: usize,
+    pub bytes_planned: u64,
+    pub bytes_moved: u64,
+    pub copies_dropped: usize,
+    pub errors: Vec<String>,
+}
+
+impl RebalanceProgress {
+    pub fn summary(&self) -> String {
+        let state = if self.running {
+            format!("running since {}", self.started_at)
+        } else {
+            format!("idle since {}", self.finished_at)
+        };
+        let mut summary = format!(
+            "Rebalance {} after {} passes: {}/{} transfers done ({} failed), {} of {} bytes moved, {} surplus copies dropped; \
+             {} under-replicated, {} over-replicated, {} misplaced",
+            state,
+            self.passes,
+            self.transfers_done,
+            self.transfers_planned,
+            self.transfers_failed,
+            self.bytes_moved,
+            self.bytes_planned,
+            self.copies_dropped,
+            self.under_replicated,
+            self.over_replicated,
+            self.misplaced,
+        );
+        if !self.errors.is_empty() {
+            summary.push_str(&format!("; errors: {}", self.errors.join("; ")));
+        }
+        summary
+    }
+
+    fn record_error(&mut self, error: String) {
+        eprintln!("Rebalance: {}", error);
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(error);
+        }
+    }
+}
+
+// A copy of a local file to an owner that doesn't have it yet.
+struct Transfer {
+    filename: String,
+    size: u64,
+    peer_id: String,
+    address: String,
+}
+
+// A copy held by a node that isn't an owner. `address` is `None` for the
+// local copy.
+struct Removal {
+    filename: String,
+    peer_id: String,
+    address: Option<String>,
+}
+
+#[derive(Default)]
+struct RebalancePlan {
+    transfers: Vec<Transfer>,
+    removals: Vec<Removal>,
+    under_replicated: usize,
+    over_replicated: usize,
+    misplaced: usize,
+}
+
+struct Shared {
+    dfs: Arc<Mutex<DistributedFileSystem>>,
+    topology: Arc<Mutex<NetworkTopology>>,
+    replicator: Replicator,
+    config: RebalanceConfig,
+    // Files kept whole because some of their shards couldn't be stored,
+    // with the scheme to code them in on the next pass.
+    stripes: Mutex<HashMap<String, ErasureScheme>>,
+    progress: Mutex<RebalanceProgress>,
+    pending: Mutex<bool>,
+    wake: Condvar,
+}
+
+/// Moves data to match the hash ring after membership changes. Each pass
+/// compares the files this node holds with what every peer reports, copies
+/// files to owners that lack them and, once all owners have a file, drops
+/// the copies held by nodes that no longer own it.
+///
+/// Passes run on a background thread; changes that arrive during a pass
+/// queue exactly one more.
+#[derive(Clone)]
+pub struct Rebalancer {
+    shared: Arc<Shared>,
+}
+
+impl Rebalancer {
+    pub fn spawn(
+        dfs: Arc<Mutex<DistributedFileSystem>>,
+        topology: Arc<Mutex<NetworkTopology>>,
+        replicator: Replicator,
+        config: RebalanceConfig,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            dfs,
+            topology,
+            replicator,
+            config,
+            stripes: Mutex::new(HashMap::new()),
+            progress: Mutex::new(RebalanceProgress::default()),
+            pending: Mutex::new(false),
+            wake: Condvar::new(),
+        });
+
+        let worker = Arc::clone(&shared);
+        thread::spawn(move || loop {
+            {
+                let mut pending = worker.pending.lock().unwrap();
+                while !*pending {
+                    pending = worker.wake.wait(pending).unwrap();
+                }
+                *pending = false;
+            }
+            run_pass(&worker);
+        });
+        Self { shared }
+    }
+
+    pub fn trigger(&self) {
+        *self.shared.pending.lock().unwrap() = true;
+        self.shared.wake.notify_one();
+    }
+
+    pub fn progress(&self) -> RebalanceProgress {
+        self.shared.progress.lock().unwrap().clone()
+    }
+
+    /// Has every following pass try to erasure-code `filename` again until
+    /// all its shards are stored. It waits for the next membership change,
+    /// since the nodes that were missing are likely still gone.
+    pub fn retry_striping(&self, filename: &str, scheme: ErasureScheme) {
+        self.shared.stripes.lock().unwrap().insert(filename.to_string(), scheme);
+    }
+}
+
+impl MembershipObserver for Rebalancer {
+    fn node_added(&self, node_id: &str, address: &str) {
+        self.shared.topology.lock().unwrap().add_peer(node_id.to_string(), address.to_string());
+        self.trigger();
+    }
+
+    fn node_removed(&self, node_id: &str) {
+        self.shared.topology.lock().unwrap().remove_peer(&node_id.to_string());
+        self.trigger();
+    }
+}
+
+fn run_pass(shared: &Shared) {
+    {
+        let mut progress = shared.progress.lock().unwrap();
+        let passes = progress.passes;
+        *progress = RebalanceProgress {
+            running: true,
+            passes: passes + 1,
+            started_at: now_secs(),
+            ..RebalanceProgress::default()
+        };
+    }
+
+    restripe(shared);
+    let plan = build_plan(shared);
+    {
+        let mut progress = shared.progress.lock().unwrap();
+        progress.under_replicated = plan.under_replicated;
+        progress.over_replicated = plan.over_replicated;
+        progress.misplaced = plan.misplaced;
+        progress.transfers_planned = plan.transfers.len();
+        progress.bytes_planned = plan.transfers.iter().map(|transfer| transfer.size).sum();
+    }
+    if !plan.transfers.is_empty() || !plan.removals.is_empty() {
+        println!("Rebalance planned {} transfers and {} removals", plan.transfers.len(), plan.removals.len());
+    }
+
+    let mut throttle = Throttle::new(shared.config.bandwidth);
+    let mut failed_files = HashSet::new();
+    for transfer in &plan.transfers {
+        match run_transfer(shared, transfer, &mut throttle) {
+            Ok(()) => shared.progress.lock().unwrap().transfers_done += 1,
+            Err(e) => {
+                failed_files.insert(transfer.filename.as_str());
+                let mut progress = shared.progress.lock().unwrap();
+                progress.transfers_failed += 1;
+                progress.record_error(format!("copy of {} to {} failed: {}", transfer.filename, transfer.peer_id, e));
+            },
+        }
+    }
+
+    // Surplus copies only go once every owner is known to hold the file.
+    for removal in plan.removals.iter().filter(|removal| !failed_files.contains(removal.filename.as_str())) {
+        match drop_copy(shared, removal) {
+            Ok(()) => shared.progress.lock().unwrap().copies_dropped += 1,
+            Err(e) => shared
+                .progress
+                .lock()
+                .unwrap()
+                .record_error(format!("dropping {} from {} failed: {}", removal.filename, removal.peer_id, e)),
+        }
+    }
+
+    let mut progress = shared.progress.lock().unwrap();
+    progress.running = false;
+    progress.finished_at = now_secs();
+    println!("{}", progress.summary());
+}
+
+// Retries the files whose striping failed. Each stays queued until all its
+// shards are stored or the file is gone.
+fn restripe(shared: &Shared) {
+    let pending: Vec<(String, ErasureScheme)> = shared.stripes.lock().unwrap().iter().map(|(filename, scheme)| (filename.clone(), *scheme)).collect();
+    for (filename, scheme) in pending {
+        let error = match erasure::store_striped(&shared.dfs, &shared.replicator, &filename, scheme) {
+            Ok(outcome) if outcome.striped => None,
+            Ok(outcome) => Some(outcome.failures.join("; ")),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => Some(e.to_string()),
+        };
+        match error {
+            None => {
+                shared.stripes.lock().unwrap().remove(&filename);
+            },
+            Some(error) => shared.progress.lock().unwrap().record_error(format!("striping {} failed: {}", filename, error)),
+        }
+    }
+}
+
+fn build_plan(shared: &Shared) -> RebalancePlan {
+    let (local_id, peers) = {
+        let topology = shared.topology.lock().unwrap();
+        (topology.local_id().to_string(), topology.peers())
+    };
+    let local_files = shared.dfs.lock().unwrap().list_files();
+
+    // Peers that can't be listed are left out of this pass entirely: nothing
+    // is copied to them and no file they might own loses a copy.
+    let mut listings: HashMap<String, HashSet<String>> = HashMap::new();
+    for (peer_id, address) in &peers {
+        match request(address, &Command::ListStoredFiles) {
+            Ok(response) if response.is_ok() => {
+                listings.insert(peer_id.clone(), response.files.unwrap_or_default().into_iter().collect());
+            },
+            Ok(response) => shared.progress.lock().unwrap().record_error(format!("listing {} failed: {}", peer_id, response.message)),
+            Err(e) => shared.progress.lock().unwrap().record_error(format!("listing {} failed: {}", peer_id, e)),
+        }
+    }
+
+    let striping: HashSet<String> = shared.stripes.lock().unwrap().keys().cloned().collect();
+    let mut plan = RebalancePlan::default();
+    for filename in local_files {
+        // Shards are placed by the node that coded them, not by the ring,
+        // and a file still waiting to be striped is coded where it is.
+        if is_shard(&filename) || striping.contains(&filename) {
+            continue;
+        }
+        let owners = shared.topology.lock().unwrap().owners(&filename, shared.config.factor);
+        let mut holders = vec![local_id.clone()];
+        holders.extend(
+            peers
+                .iter()
+                .filter(|(peer_id, _)| listings.get(peer_id).is_some_and(|files| files.contains(&filename)))
+                .map(|(peer_id, _)| peer_id.clone()),
+        );
+
+        let missing: Vec<_> = owners
+            .iter()
+            .filter(|owner| owner.address.is_some() && !holders.contains(&owner.id))
+            .collect();
+        let unknown = missing.iter().any(|owner| !listings.contains_key(&owner.id));
+        let surplus: Vec<&String> = holders.iter().filter(|holder| !owners.iter().any(|owner| &owner.id == *holder)).collect();
+
+        if !missing.is_empty() {
+            plan.under_replicated += 1;
+        }
+        if !surplus.is_empty() {
+            plan.over_replicated += 1;
+        }
+        if surplus.contains(&&local_id) {
+            plan.misplaced += 1;
+        }
+
+        // Only one holder moves each file: the first owner that has it, or
+        // failing that the lowest-id holder.
+        let source = owners
+            .iter()
+            .map(|owner| &owner.id)
+            .find(|id| holders.contains(id))
+            .or_else(|| holders.iter().min())
+            .cloned();
+        let is_source = source.as_deref() == Some(local_id.as_str());
+
+        if is_source {
+            let size = match shared.dfs.lock().unwrap().stat(&filename) {
+                Ok(entry) => entry.size,
+                Err(_) => continue, // Deleted since it was listed
+            };
+            for owner in missing.iter().filter(|owner| listings.contains_key(&owner.id)) {
+                plan.transfers.push(Transfer {
+                    filename: filename.clone(),
+                    size,
+                    peer_id: owner.id.clone(),
+                    address: owner.address.clone().unwrap_or_default(),
+                });
+            }
+        }
+        if unknown || (!missing.is_empty() && !is_source) {
+            continue;
+        }
+
+        for holder in surplus {
+            if holder == &local_id {
+                plan.removals.push(Removal { filename: filename.clone(), peer_id: holder.clone(), address: None });
+            } else if is_source {
+                let address = peers.iter().find(|(peer_id, _)| peer_id == holder).map(|(_, address)| address.clone());
+                plan.removals.push(Removal { filename: filename.clone(), peer_id: holder.clone(), address });
+            }
+        }
+    }
+    plan
+}
+
+// The file lock is only held to open the file; data is streamed without it.
+fn run_transfer(shared: &Shared, transfer: &Transfer, throttle: &mut Throttle) -> io::Result<()> {
+    let (reader, entry) = {
+        let dfs = shared.dfs.lock().unwrap();
+        let (reader, _) = dfs.open_reader(&transfer.filename)?;
+        (reader, dfs.stat(&transfer.filename)?)
+    };
+    let mut reader = ThrottledReader { inner: reader, throttle, progress: &shared.progress };
+    stream_file(&transfer.address, &transfer.filename, entry.size, entry.checksum, Some(entry.attributes), &mut reader)
+}
+
+fn drop_copy(shared: &Shared, removal: &Removal) -> io::Result<()> {
+    match removal.address.as_ref() {
+        None => shared.dfs.lock().unwrap().purge_files(slice::from_ref(&removal.filename)),
+        Some(address) => {
+            let command = Command::DeleteFile { filename: removal.filename.clone(), purge: true };
+            send_command(address, &command)
+        },
+    }
+}
+
+// Keeps the average rate across a pass at or below `limit` bytes per second
+// by sleeping whenever transfers get ahead of schedule.
+struct Throttle {
+    limit: Option<u64>,
+    started: Instant,
+    bytes: u64,
+}
+
+impl Throttle {
+    fn new(limit: Option<u64>) -> Self {
+        Self { limit, started: Instant::now(), bytes: 0 }
+    }
+
+    fn consume(&mut self, count: usize) {
+        self.bytes += count as u64;
+        if let Some(limit) = self.limit {
+            let due = Duration::from_secs_f64(self.bytes as f64 / limit as f64);
+            let elapsed = self.started.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+    }
+}
+
+struct ThrottledReader<'a, R: Read> {
+    inner: R,
+    throttle: &'a mut Throttle,
+    progress: &'a Mutex<RebalanceProgress>,
+}
+
+impl<'a, R: Read> Read for ThrottledReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.throttle.consume(count);
+        self.progress.lock().unwrap().bytes_moved += count as u64;
+        Ok(count)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
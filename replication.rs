@@ -0,0 +1,335 @@
+use std::env;
+use std::io::{self, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use super::network::{NetworkTopology, Owner};
+use super::protocol::{read_message, write_message, ChunkWriter, Command, FileAttributes, ServerResponse, StatusCode};
+use super::storage::scrubber::ReplicaSource;
+use super::storage::DistributedFileSystem;
+
+const DEFAULT_REPLICATION_FACTOR: usize = 3;
+const PEER_TIMEOUT_SECS: u64 = 30;
+
+/// How many copies must be durable before a write is acknowledged to the
+/// client. The local copy counts when this node is one of the file's owners.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AckPolicy {
+    One,
+    Quorum,
+    All,
+}
+
+impl FromStr for AckPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "one" => Ok(AckPolicy::One),
+            "quorum" => Ok(AckPolicy::Quorum),
+            "all" => Ok(AckPolicy::All),
+            other => Err(format!("Unknown ack policy '{}', expected one, quorum or all", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReplicationConfig {
+    /// Total number of copies of each file across the cluster.
+    pub factor: usize,
+    pub ack_policy: AckPolicy,
+}
+
+impl ReplicationConfig {
+    /// Reads `DFS_REPLICATION_FACTOR` (default 3) and `DFS_ACK_POLICY`
+    /// (`one`, `quorum` or `all`; default `quorum`).
+    pub fn from_env() -> Result<Self, String> {
+        let factor = match env::var("DFS_REPLICATION_FACTOR") {
+            Ok(value) => value.parse::<usize>().map_err(|e| format!("Invalid DFS_REPLICATION_FACTOR: {}", e))?,
+            Err(_) => DEFAULT_REPLICATION_FACTOR,
+        };
+        if factor == 0 {
+            return Err("DFS_REPLICATION_FACTOR must be at least 1".to_string());
+        }
+        let ack_policy = match env::var("DFS_ACK_POLICY") {
+            Ok(value) => value.parse()?,
+            Err(_) => AckPolicy::Quorum,
+        };
+        Ok(Self { factor, ack_policy })
+    }
+
+    pub fn required_acks(&self) -> usize {
+        match self.ack_policy {
+            AckPolicy::One => 1,
+            AckPolicy::Quorum => self.factor / 2 + 1,
+            AckPolicy::All => self.factor,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ReplicationOutcome {
+    pub acked: usize,
+    pub required: usize,
+    /// Fewer nodes are reachable than the replication factor asks for.
+    pub under_replicated: bool,
+    /// Whether this node is itself one of the file's owners. A node that
+    /// isn't only holds a copy until the owners have theirs.
+    pub local_is_owner: bool,
+    pub failures: Vec<String>,
+}
+
+impl ReplicationOutcome {
+    pub fn is_satisfied(&self) -> bool {
+        self.acked >= self.required
+    }
+}
+
+type PeerOperation = Arc<dyn Fn(&str) -> io::Result<()> + Send + Sync>;
+
+/// Forwards committed writes and deletes to the nodes the hash ring assigns
+/// each file to, so every file ends up on `factor` owners. Calls block until
+/// the ack policy is met; replicas beyond that finish in the background.
+#[derive(Clone)]
+pub struct Replicator {
+    config: ReplicationConfig,
+    topology: Arc<Mutex<NetworkTopology>>,
+}
+
+impl Replicator {
+    pub fn new(config: ReplicationConfig, topology: Arc<Mutex<NetworkTopology>>) -> Self {
+        Self { config, topology }
+    }
+
+    /// The nodes that should hold `filename`, primary first.
+    pub fn owners(&self, filename: &str) -> Vec<Owner> {
+        self.topology.lock().unwrap().owners(filename, self.config.factor)
+    }
+
+    /// Up to `count` distinct nodes for `filename` in ring order, for data
+    /// placed per node rather than per copy.
+    pub fn placement(&self, filename: &str, count: usize) -> Vec<Owner> {
+        self.topology.lock().unwrap().owners(filename, count)
+    }
+
+    /// How many of `filename`'s owners hold a copy of it.
+    pub fn copies(&self, dfs: &Mutex<DistributedFileSystem>, filename: &str) -> usize {
+        let command = Command::Stat { filename: filename.to_string() };
+        self.owners(filename)
+            .into_iter()
+            .filter(|owner| match &owner.address {
+                Some(address) => send_command(address, &command).is_ok(),
+                None => dfs.lock().unwrap().stat(filename).is_ok(),
+            })
+            .count()
+    }
+
+    pub fn replicate_file(&self, dfs: &Arc<Mutex<DistributedFileSystem>>, filename: &str) -> ReplicationOutcome {
+        let dfs = Arc::clone(dfs);
+        let name = filename.to_string();
+        self.fan_out(filename, Arc::new(move |address: &str| push_file(&dfs, address, &name)))
+    }
+
+    /// With `purge` the peers skip their trash as well.
+    pub fn replicate_delete(&self, filename: &str, purge: bool) -> ReplicationOutcome {
+        let name = filename.to_string();
+        self.fan_out(filename, Arc::new(move |address: &str| {
+            let command = Command::DeleteFile { filename: name.clone(), purge };
+            match send_command(address, &command) {
+                // Already gone on that peer is as good as deleted.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        }))
+    }
+
+    fn fan_out(&self, filename: &str, operation: PeerOperation) -> ReplicationOutcome {
+        let owners = self.owners(filename);
+        let local_is_owner = owners.iter().any(Owner::is_local);
+        let mut outcome = ReplicationOutcome {
+            acked: if local_is_owner { 1 } else { 0 },
+            required: self.config.required_acks().min(owners.len()),
+            under_replicated: owners.len() < self.config.factor,
+            local_is_owner,
+            failures: Vec::new(),
+        };
+
+        let (sender, receiver) = mpsc::channel();
+        for Owner { id: peer_id, address } in owners {
+            let address = match address {
+                Some(address) => address,
+                None => continue,
+            };
+            let sender = sender.clone();
+            let operation = Arc::clone(&operation);
+            thread::spawn(move || {
+                let result = operation(&address).map_err(|e| format!("{}: {}", peer_id, e));
+                if let Err(ref e) = result {
+                    eprintln!("Replication to {} failed", e);
+                }
+                let _ = sender.send(result);
+            });
+        }
+        drop(sender);
+
+        // When this node isn't an owner its copy may be dropped as soon as
+        // we return, so every push has to finish reading it first.
+        while !local_is_owner || !outcome.is_satisfied() {
+            match receiver.recv() {
+                Ok(Ok(())) => outcome.acked += 1,
+                Ok(Err(e)) => outcome.failures.push(e),
+                Err(_) => break, // Every owner has answered
+            }
+        }
+        outcome
+    }
+}
+
+fn push_file(dfs: &Mutex<DistributedFileSystem>, address: &str, filename: &str) -> io::Result<()> {
+    let (mut reader, entry) = {
+        let dfs = dfs.lock().unwrap();
+        let (reader, _) = dfs.open_reader(filename)?;
+        (reader, dfs.stat(filename)?)
+    };
+
+    stream_file(address, filename, entry.size, entry.checksum, Some(entry.attributes), &mut reader)
+}
+
+/// Sends `reader`'s contents to a peer as a relayed streamed upload and
+/// waits for the peer to commit it. Copies of a stored file take its
+/// `attributes` along.
+pub fn stream_file<R: Read>(
+    address: &str,
+    filename: &str,
+    size: u64,
+    checksum: String,
+    attributes: Option<FileAttributes>,
+    reader: &mut R,
+) -> io::Result<()> {
+    let mut stream = connect(address)?;
+    let command = Command::UploadStream {
+        filename: filename.to_string(),
+        size,
+        checksum: Some(checksum),
+        erasure: None,
+        attributes,
+    };
+    write_relayed(&mut stream, &command)?;
+    let mut chunks = ChunkWriter::new(&mut stream);
+    io::copy(reader, &mut chunks)?;
+    chunks.finish()?;
+    expect_ok(read_message(&mut stream)?).map(|_| ())
+}
+
+/// Fetches chunks from peers for the scrubber to repair damaged ones with.
+/// Chunks are addressed by their content, so any peer holding the same
+/// data has it under the same hash; peers are asked in turn until one has
+/// an intact copy.
+pub struct PeerChunkSource {
+    topology: Arc<Mutex<NetworkTopology>>,
+}
+
+impl PeerChunkSource {
+    pub fn new(topology: Arc<Mutex<NetworkTopology>>) -> Self {
+        Self { topology }
+    }
+}
+
+impl ReplicaSource for PeerChunkSource {
+    fn fetch_chunk(&self, hash: &str) -> io::Result<Vec<u8>> {
+        let peers = self.topology.lock().unwrap().peers();
+        let command = Command::FetchChunk { hash: hash.to_string() };
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No peers to fetch the chunk from");
+        for (id, address) in peers {
+            match request(&address, &command) {
+                Ok(response) => match response.file_contents {
+                    Some(data) if response.status == StatusCode::Ok => return Ok(data),
+                    _ => last_error = io::Error::new(io::ErrorKind::NotFound, format!("{}: {}", id, response.message)),
+                },
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+/// `DFS_CLUSTER_KEY`, the secret every node of a cluster is started with.
+/// Peers present it with each request they relay.
+pub fn cluster_key() -> Option<&'static str> {
+    static KEY: OnceLock<Option<String>> = OnceLock::new();
+    KEY.get_or_init(|| env::var("DFS_CLUSTER_KEY").ok().filter(|key| !key.is_empty())).as_deref()
+}
+
+/// Whether `key` is this node's cluster key, compared in constant time.
+pub fn is_cluster_key(key: &str) -> bool {
+    cluster_key().is_some_and(|expected| keys_match(expected, key))
+}
+
+/// Whether `key` is `expected`, compared in constant time.
+pub fn keys_match(expected: &str, key: &str) -> bool {
+    expected.len() == key.len() && expected.bytes().zip(key.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+// Everything one server sends another is relayed on behalf of a client.
+fn write_relayed(stream: &mut TcpStream, command: &Command) -> io::Result<()> {
+    let key = cluster_key().ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "DFS_CLUSTER_KEY is not set"))?;
+    write_message(stream, &Command::Relayed { key: key.to_string(), command: Box::new(command.clone()) })
+}
+
+/// Sends a single command to a peer and waits for it to be acknowledged.
+pub fn send_command(address: &str, command: &Command) -> io::Result<()> {
+    let mut stream = connect(address)?;
+    write_relayed(&mut stream, command)?;
+    expect_ok(read_message(&mut stream)?).map(|_| ())
+}
+
+/// Sends a single command to a peer and returns its response, whatever the
+/// status.
+pub fn request(address: &str, command: &Command) -> io::Result<ServerResponse> {
+    let mut stream = connect(address)?;
+    write_relayed(&mut stream, command)?;
+    read_message(&mut stream)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::ConnectionAborted, "Peer closed the connection"))
+}
+
+/// Starts a streamed download from a peer. On success the returned
+/// connection is positioned at the first chunk frame.
+pub fn open_remote_stream(address: &str, filename: &str) -> io::Result<(TcpStream, ServerResponse)> {
+    let mut stream = connect(address)?;
+    let command = Command::DownloadStream { filename: filename.to_string() };
+    write_relayed(&mut stream, &command)?;
+    let response = expect_ok(read_message(&mut stream)?)?;
+    Ok((stream, response))
+}
+
+pub fn connect(address: &str) -> io::Result<TcpStream> {
+    let addr = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, format!("Unable to resolve {}", address)))?;
+    let timeout = Duration::from_secs(PEER_TIMEOUT_SECS);
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+fn expect_ok(response: Option<ServerResponse>) -> io::Result<ServerResponse> {
+    match response {
+        Some(response) if response.is_ok() => Ok(response),
+        Some(response) => {
+            let kind = match response.status {
+                StatusCode::NotFound => io::ErrorKind::NotFound,
+                StatusCode::Forbidden => io::ErrorKind::PermissionDenied,
+                _ => io::ErrorKind::Other,
+            };
+            Err(io::Error::new(kind, response.message))
+        },
+        None => Err(io::Error::new(io::ErrorKind::ConnectionAborted, "Peer closed the connection")),
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use serde::Serialize;
+
+use super::atomic_file::write_atomic;
+use super::checksum::{sha256_hex, StreamingChecksum};
+use super::chunk_store::chunk_path;
+use super::metadata::{now_secs, FileEntry, FileVersion};
+use super::utils::logging::Logger;
+use super::{load_manifest, DistributedFileSystem};
+
+const REPORT_FILE_NAME: &str = "scrub_report.json";
+const CHECKSUM_DIR: &str = ".checksums";
+// Directories of the flat store that hold no files of their own.
+const COMPANION_DIRS: &[&str] = &[CHECKSUM_DIR, ".attributes", ".trash"];
+
+/// Somewhere a known-good copy of a chunk can be fetched from, typically a
+/// peer holding a replica.
+pub trait ReplicaSource: Send + Sync {
+    fn fetch_chunk(&self, hash: &str) -> io::Result<Vec<u8>>;
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind")]
+pub enum ScrubIssue {
+    /// Data on disk no longer hashes to its recorded checksum.
+    Mismatch { location: String, expected: String, actual: String },
+    /// Metadata references something that isn't on disk.
+    Missing { location: String, referenced_by: String },
+    /// Something on disk that no metadata references.
+    Orphaned { location: String },
+    /// A damaged or missing chunk was restored from a replica.
+    Repaired { location: String },
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ScrubReport {
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub files_checked: usize,
+    pub chunks_checked: usize,
+    pub issues: Vec<ScrubIssue>,
+}
+
+impl ScrubReport {
+    pub fn is_clean(&self) -> bool {
+        self.issues.iter().all(|issue| matches!(issue, ScrubIssue::Repaired { .. }))
+    }
+}
+
+// Everything the scrubber needs, copied out so the walk itself runs without
+// holding the file system lock.
+struct ScrubTargets {
+    entries: Vec<FileEntry>,
+    chunk_root: PathBuf,
+    manifest_root: PathBuf,
+}
+
+impl ScrubTargets {
+    // Files in the trash, and files only a snapshot still refers to, are
+    // checked as well, once per manifest.
+    fn capture(dfs: &DistributedFileSystem) -> Self {
+        let mut entries: Vec<FileEntry> = dfs.entries.values().cloned().collect();
+        for trashed in dfs.trash.values() {
+            let mut entry = trashed.entry.clone();
+            entry.name = format!("{} in the trash", entry.name);
+            entries.push(entry);
+        }
+        let mut seen: HashSet<String> = entries.iter().flat_map(FileEntry::versions).map(|version| version.path).collect();
+        for snapshot in dfs.snapshots.values() {
+            for entry in snapshot.files.values().filter(|entry| seen.insert(entry.path.clone())) {
+                let mut entry = entry.clone();
+                entry.name = format!("{} in snapshot {}", entry.name, snapshot.name);
+                entries.push(entry);
+            }
+        }
+        Self {
+            entries,
+            chunk_root: dfs.chunk_store.lock().unwrap().root().to_path_buf(),
+            manifest_root: Path::new(&dfs.base_dir).join("manifests"),
+        }
+    }
+}
+
+/// Runs a scrub every `interval` until the process exits, publishing each
+/// report to `base_dir/scrub_report.json` and the log.
+pub fn spawn_scrubber(
+    dfs: Arc<Mutex<DistributedFileSystem>>,
+    interval: Duration,
+    replica: Option<Arc<dyn ReplicaSource>>,
+) -> JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let report = scrub(&dfs, replica.as_deref());
+        let base_dir = PathBuf::from(&dfs.lock().unwrap().base_dir);
+        if let Err(e) = publish_report(&report, &base_dir) {
+            eprintln!("Failed to publish scrub report: {}", e);
+        }
+    })
+}
+
+pub fn scrub(dfs: &Mutex<DistributedFileSystem>, replica: Option<&dyn ReplicaSource>) -> ScrubReport {
+    let targets = ScrubTargets::capture(&dfs.lock().unwrap());
+    let mut report = ScrubReport {
+        started_at: now_secs(),
+        ..ScrubReport::default()
+    };
+
+    let mut referenced_chunks = HashSet::new();
+    for entry in &targets.entries {
+        for version in entry.versions() {
+            scrub_file(dfs, &targets, &entry.name, &version, replica, &mut referenced_chunks, &mut report);
+        }
+    }
+    find_orphans(&targets, &referenced_chunks, &mut report);
+
+    if let Ok(storage_path) = env::var("STORAGE_PATH") {
+        scrub_flat_store(Path::new(&storage_path), &mut report);
+    }
+
+    report.finished_at = now_secs();
+    report
+}
+
+fn scrub_file(
+    dfs: &Mutex<DistributedFileSystem>,
+    targets: &ScrubTargets,
+    file_name: &str,
+    version: &FileVersion,
+    replica: Option<&dyn ReplicaSource>,
+    referenced_chunks: &mut HashSet<String>,
+    report: &mut ScrubReport,
+) {
+    report.files_checked += 1;
+    let name = format!("{} (version {})", file_name, version.version);
+    let manifest = match load_manifest(&version.path) {
+        Ok(manifest) => manifest,
+        Err(_) => {
+            report.issues.push(ScrubIssue::Missing {
+                location: version.path.clone(),
+                referenced_by: name,
+            });
+            return;
+        }
+    };
+
+    let mut file_checksum = StreamingChecksum::new();
+    let mut chunks_intact = true;
+    for hash in &manifest.chunks {
+        referenced_chunks.insert(hash.clone());
+        report.chunks_checked += 1;
+        match scrub_chunk(dfs, targets, hash, &name, replica, report) {
+            Some(data) => file_checksum.update(&data),
+            None => chunks_intact = false,
+        }
+    }
+
+    // A bad chunk already explains a bad file checksum; only report the file
+    // itself when every chunk checked out.
+    if chunks_intact {
+        let actual = file_checksum.finish();
+        if actual != version.checksum {
+            report.issues.push(ScrubIssue::Mismatch {
+                location: name,
+                expected: version.checksum.clone(),
+                actual,
+            });
+        }
+    }
+}
+
+/// Returns the chunk's verified contents, or `None` if it is damaged and
+/// could not be repaired.
+fn scrub_chunk(
+    dfs: &Mutex<DistributedFileSystem>,
+    targets: &ScrubTargets,
+    hash: &str,
+    file_name: &str,
+    replica: Option<&dyn ReplicaSource>,
+    report: &mut ScrubReport,
+) -> Option<Vec<u8>> {
+    let path = chunk_path(&targets.chunk_root, hash);
+    let location = path.display().to_string();
+    let issue = match fs::read(&path) {
+        Ok(data) => {
+            let actual = sha256_hex(&data);
+            if actual == hash {
+                return Some(data);
+            }
+            ScrubIssue::Mismatch { location: location.clone(), expected: hash.to_string(), actual }
+        },
+        Err(_) => ScrubIssue::Missing { location: location.clone(), referenced_by: file_name.to_string() },
+    };
+
+    if let Some(replica) = replica {
+        match repair_chunk(dfs, replica, hash) {
+            Ok(Some(data)) => {
+                report.issues.push(ScrubIssue::Repaired { location });
+                return Some(data);
+            },
+            // Everything using the chunk was deleted while the scrub ran.
+            Ok(None) => return None,
+            Err(e) => eprintln!("Failed to repair chunk {} from replica: {}", hash, e),
+        }
+    }
+    report.issues.push(issue);
+    None
+}
+
+// The copy is fetched without holding any lock, then written through the
+// chunk store under the file system lock, so it can't race a write or
+// release of the same chunk. `None` when nothing refers to the chunk any
+// more.
+fn repair_chunk(dfs: &Mutex<DistributedFileSystem>, replica: &dyn ReplicaSource, hash: &str) -> io::Result<Option<Vec<u8>>> {
+    let data = replica.fetch_chunk(hash)?;
+    let dfs = dfs.lock().unwrap();
+    let repaired = dfs.chunk_store.lock().unwrap().repair(hash, &data)?;
+    Ok(Some(data).filter(|_| repaired))
+}
+
+// Chunks of uploads that are still in flight show up here too; orphans are
+// reported, never deleted.
+fn find_orphans(targets: &ScrubTargets, referenced_chunks: &HashSet<String>, report: &mut ScrubReport) {
+    for path in walk_files(&targets.chunk_root) {
+        let is_referenced = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| referenced_chunks.contains(name));
+        if !is_referenced {
+            report.issues.push(ScrubIssue::Orphaned { location: path.display().to_string() });
+        }
+    }
+
+    let manifest_paths: HashSet<PathBuf> = targets
+        .entries
+        .iter()
+        .flat_map(FileEntry::versions)
+        .map(|version| PathBuf::from(version.path))
+        .collect();
+    for path in walk_files(&targets.manifest_root) {
+        if !manifest_paths.contains(&path) {
+            report.issues.push(ScrubIssue::Orphaned { location: path.display().to_string() });
+        }
+    }
+}
+
+// The flat store used by the command tool keeps a checksum sidecar for each
+// file under `STORAGE_PATH/.checksums`, at the file's own relative path.
+fn scrub_flat_store(storage_path: &Path, report: &mut ScrubReport) {
+    let checksum_dir = storage_path.join(CHECKSUM_DIR);
+    for path in flat_store_files(storage_path) {
+        report.files_checked += 1;
+        let sidecar = match path.strip_prefix(storage_path) {
+            Ok(relative) => checksum_dir.join(relative),
+            Err(_) => continue,
+        };
+        let expected = match fs::read_to_string(&sidecar) {
+            Ok(expected) => expected.trim().to_string(),
+            Err(_) => {
+                report.issues.push(ScrubIssue::Missing {
+                    location: sidecar.display().to_string(),
+                    referenced_by: path.display().to_string(),
+                });
+                continue;
+            }
+        };
+        match fs::read(&path) {
+            Ok(data) => {
+                let actual = sha256_hex(&data);
+                if actual != expected {
+                    report.issues.push(ScrubIssue::Mismatch { location: path.display().to_string(), expected, actual });
+                }
+            },
+            Err(e) => eprintln!("Failed to read {}: {}", path.display(), e),
+        }
+    }
+
+    for sidecar in walk_files(&checksum_dir) {
+        if let Ok(relative) = sidecar.strip_prefix(&checksum_dir) {
+            if !storage_path.join(relative).is_file() {
+                report.issues.push(ScrubIssue::Orphaned { location: sidecar.display().to_string() });
+            }
+        }
+    }
+}
+
+// Every file in the flat store, in subdirectories too, leaving out the
+// companion directories at its top level.
+fn flat_store_files(storage_path: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(storage_path) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut files = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            let is_companion = entry.file_name().to_str().is_some_and(|name| COMPANION_DIRS.contains(&name));
+            if !is_companion {
+                files.extend(walk_files(&path));
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    files
+}
+
+fn publish_report(report: &ScrubReport, base_dir: &Path) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(report)?;
+    write_atomic(base_dir.join(REPORT_FILE_NAME), &json)?;
+
+    let mut logger = Logger::new()?;
+    let summary = format!(
+        "Scrub checked {} files and {} chunks, found {} issues",
+        report.files_checked,
+        report.chunks_checked,
+        report.issues.len()
+    );
+    if report.is_clean() {
+        logger.info(&summary)?;
+    } else {
+        logger.warning(&summary)?;
+    }
+    for issue in &report.issues {
+        let line = serde_json::to_string(issue)?;
+        match issue {
+            ScrubIssue::Repaired { .. } => logger.info(&line)?,
+            _ => logger.error(&line)?,
+        }
+    }
+    Ok(())
+}
+
+fn walk_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scratch::ScratchDir;
+    use super::super::RetentionPolicy;
+
+    // Hands out the same data for every chunk.
+    struct FixedReplica(Vec<u8>);
+
+    impl ReplicaSource for FixedReplica {
+        fn fetch_chunk(&self, _hash: &str) -> io::Result<Vec<u8>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn damaged_store(name: &str) -> (ScratchDir, Mutex<DistributedFileSystem>, PathBuf) {
+        let dir = ScratchDir::new(&format!("scrubber-{}", name));
+        let retention = RetentionPolicy { keep_last: 1, keep_days: None, trash_days: 1 };
+        let mut dfs = DistributedFileSystem::open(dir.to_string_lossy().into_owned(), retention).unwrap();
+        dfs.store_files(&[("file".to_string(), b"content".to_vec())]).unwrap();
+        let chunk = chunk_path(&dir.join("chunks"), &sha256_hex(b"content"));
+        fs::write(&chunk, b"damaged").unwrap();
+        (dir, Mutex::new(dfs), chunk)
+    }
+
+    #[test]
+    fn a_damaged_chunk_is_repaired_from_a_replica() {
+        let (_dir, dfs, chunk) = damaged_store("repaired");
+        let report = scrub(&dfs, Some(&FixedReplica(b"content".to_vec())));
+        assert!(matches!(report.issues[..], [ScrubIssue::Repaired { .. }]), "{:?}", report.issues);
+        assert!(report.is_clean());
+        assert_eq!(fs::read(&chunk).unwrap(), b"content");
+    }
+
+    // Deletes the file using the chunk while its copy is being fetched.
+    struct DeletingReplica<'a>(&'a Mutex<DistributedFileSystem>);
+
+    impl ReplicaSource for DeletingReplica<'_> {
+        fn fetch_chunk(&self, _hash: &str) -> io::Result<Vec<u8>> {
+            self.0.lock().unwrap().purge_files(&["file".to_string()])?;
+            Ok(b"content".to_vec())
+        }
+    }
+
+    #[test]
+    fn a_chunk_released_during_the_scrub_is_not_repaired() {
+        let (_dir, dfs, chunk) = damaged_store("released");
+        let report = scrub(&dfs, Some(&DeletingReplica(&dfs)));
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert!(!chunk.exists());
+    }
+
+    #[test]
+    fn a_bad_replica_copy_is_not_used() {
+        let (_dir, dfs, chunk) = damaged_store("bad-replica");
+        let report = scrub(&dfs, Some(&FixedReplica(b"also damaged".to_vec())));
+        assert!(matches!(report.issues[..], [ScrubIssue::Mismatch { .. }]), "{:?}", report.issues);
+        assert_eq!(fs::read(&chunk).unwrap(), b"damaged");
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use regex::Regex;
+
+use super::attributes::FileAttributes;
+use super::content_type;
+use super::paths::{self, SafePath};
+
+// Content search skips files too large to be worth reading line by line,
+// and files whose first bytes hold a NUL, which text never does.
+const MAX_CONTENT_SEARCH_BYTES: u64 = 16 * 1024 * 1024;
+const BINARY_SNIFF_BYTES: usize = 8 * 1024;
+// Lines reported for content matches are cut to this many characters.
+const MAX_MATCH_LINE_CHARS: usize = 200;
+
+/// How a name or line of text is matched.
+pub enum Pattern {
+    /// Anywhere in the text, as is.
+    Contains(String),
+    /// A shell-style glob. `*` and `?` stay within one path segment, `**`
+    /// crosses segments, and `[...]` is a character class. A glob without
+    /// a `/` is matched against the file name alone, otherwise against the
+    /// whole path.
+    Glob { regex: Regex, whole_path: bool },
+    /// A regular expression, found anywhere in the text.
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn contains(text: &str) -> Self {
+        Pattern::Contains(text.to_string())
+    }
+
+    pub fn glob(glob: &str) -> Result<Self, regex::Error> {
+        Ok(Pattern::Glob { regex: Regex::new(&glob_to_regex(glob))?, whole_path: glob.contains('/') })
+    }
+
+    pub fn regex(regex: &str) -> Result<Self, regex::Error> {
+        Regex::new(regex).map(Pattern::Regex)
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        match self {
+            Pattern::Glob { regex, whole_path: false } => regex.is_match(path.rsplit('/').next().unwrap_or(path)),
+            _ => self.matches(path),
+        }
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            Pattern::Contains(needle) => text.contains(needle.as_str()),
+            Pattern::Glob { regex, .. } | Pattern::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+/// What to look for. Everything left unset matches every file; what is
+/// set must all match.
+pub struct SearchQuery {
+    /// The directory to search, the root when empty.
+    pub scope: String,
+    /// Includes everything below `scope` rather than only what is in it.
+    pub recursive: bool,
+    pub name: Option<Pattern>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Seconds since the Unix epoch, inclusive.
+    pub modified_after: Option<u64>,
+    pub modified_before: Option<u64>,
+    /// An exact content type, or a whole family like `text/*`.
+    pub content_type: Option<String>,
+    pub owner: Option<String>,
+    /// Custom attributes the file must have, with these values.
+    pub attributes: BTreeMap<String, String>,
+    /// Searches the lines of text files; binary files never match.
+    pub content: Option<Pattern>,
+}
+
+impl Default for SearchQuery {
+    fn default() -> Self {
+        SearchQuery {
+            scope: String::new(),
+            recursive: true,
+            name: None,
+            min_size: None,
+            max_size: None,
+            modified_after: None,
+            modified_before: None,
+            content_type: None,
+            owner: None,
+            attributes: BTreeMap::new(),
+            content: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    pub path: String,
+    pub size: u64,
+    pub modified_at: u64,
+    pub content_type: String,
+    pub owner: Option<String>,
+    pub attributes: BTreeMap<String, String>,
+    /// Where the content pattern matched; empty without one.
+    pub matches: Vec<ContentMatch>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContentMatch {
+    /// Counted from 1.
+    pub line: usize,
+    pub text: String,
+}
+
+/// The files in a directory of the storage directory, or everything below
+/// it with `recursive`, with what searches filter on. It is read from disk
+/// each time, so a search always sees the current files. Hidden directories
+/// the tool keeps beside user files are left out.
+pub struct SearchScan {
+    storage_dir: PathBuf,
+    files: Vec<SearchResult>,
+}
+
+impl SearchScan {
+    pub fn read(storage_dir: impl AsRef<Path>, scope: &SafePath, recursive: bool, reserved: &[&str]) -> io::Result<Self> {
+        let storage_dir = storage_dir.as_ref().to_path_buf();
+        let mut files = Vec::new();
+        let mut pending = vec![(scope.under(&storage_dir), scope.as_str().to_string())];
+        while let Some((directory, path)) = pending.pop() {
+            for entry in fs::read_dir(&directory)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if path.is_empty() && reserved.contains(&name.as_str()) {
+                    continue;
+                }
+                let child = if path.is_empty() { name } else { format!("{}/{}", path, name) };
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    if recursive {
+                        pending.push((entry.path(), child));
+                    }
+                    continue;
+                }
+                let safe_path = match SafePath::file(&child) {
+                    Ok(safe_path) => safe_path,
+                    // Nothing the tools could have stored.
+                    Err(_) => continue,
+                };
+                let attributes = FileAttributes::read(&storage_dir, &safe_path)?;
+                files.push(SearchResult {
+                    size: metadata.len(),
+                    modified_at: metadata
+                        .modified()
+                        .ok()
+                        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                        .map_or(0, |elapsed| elapsed.as_secs()),
+                    content_type: attributes.content_type.unwrap_or_else(|| content_type::guess(&child).to_string()),
+                    owner: attributes.owner,
+                    attributes: attributes.custom,
+                    matches: Vec::new(),
+                    path: child,
+                });
+            }
+        }
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(SearchScan { storage_dir, files })
+    }
+
+    /// The files matching `query`, by path.
+    pub fn search(&self, query: &SearchQuery) -> io::Result<Vec<SearchResult>> {
+        let scope = SafePath::parse(&query.scope)?;
+        let mut results = Vec::new();
+        for file in &self.files {
+            if !paths::is_listed(&file.path, scope.as_str(), query.recursive) || !matches_metadata(file, query) {
+                continue;
+            }
+            let matches = match &query.content {
+                Some(pattern) => {
+                    let matches = search_content(&self.storage_dir.join(&file.path), file.size, pattern)?;
+                    if matches.is_empty() {
+                        continue;
+                    }
+                    matches
+                },
+                None => Vec::new(),
+            };
+            results.push(SearchResult { matches, ..file.clone() });
+        }
+        Ok(results)
+    }
+}
+
+fn matches_metadata(file: &SearchResult, query: &SearchQuery) -> bool {
+    query.name.as_ref().is_none_or(|name| name.matches_path(&file.path))
+        && query.min_size.is_none_or(|min| file.size >= min)
+        && query.max_size.is_none_or(|max| file.size <= max)
+        && query.modified_after.is_none_or(|after| file.modified_at >= after)
+        && query.modified_before.is_none_or(|before| file.modified_at <= before)
+        && query.content_type.as_ref().is_none_or(|wanted| matches_content_type(&file.content_type, wanted))
+        && query.owner.as_ref().is_none_or(|owner| file.owner.as_ref() == Some(owner))
+        && query.attributes.iter().all(|(key, value)| file.attributes.get(key) == Some(value))
+}
+
+fn matches_content_type(content_type: &str, wanted: &str) -> bool {
+    match wanted.strip_suffix("/*") {
+        Some(family) => content_type.split('/').next().is_some_and(|own| own.eq_ignore_ascii_case(family)),
+        None => content_type.eq_ignore_ascii_case(wanted),
+    }
+}
+
+// The matching lines of a text file. A file that turns out not to be text
+// has none.
+fn search_content(path: &Path, size: u64, pattern: &Pattern) -> io::Result<Vec<ContentMatch>> {
+    if size > MAX_CONTENT_SEARCH_BYTES {
+        return Ok(Vec::new());
+    }
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut head = Vec::with_capacity(BINARY_SNIFF_BYTES);
+    (&mut reader).take(BINARY_SNIFF_BYTES as u64).read_to_end(&mut head)?;
+    if head.contains(&0) {
+        return Ok(Vec::new());
+    }
+
+    let mut matches = Vec::new();
+    for (index, line) in head.as_slice().chain(reader).lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        if pattern.matches(&line) {
+            matches.push(ContentMatch { line: index + 1, text: line.chars().take(MAX_MATCH_LINE_CHARS).collect() });
+        }
+    }
+    Ok(matches)
+}
+
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directories at all.
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            },
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            '[' => {
+                let rest: Vec<char> = chars.clone().collect();
+                let negated = rest.first() == Some(&'!');
+                let start = if negated { 1 } else { 0 };
+                // A `]` straight after the opening bracket is part of the class.
+                match rest.iter().skip(start + 1).position(|&c| c == ']') {
+                    Some(offset) => {
+                        let end = start + 1 + offset;
+                        regex.push_str(if negated { "[^" } else { "[" });
+                        for &c in &rest[start..end] {
+                            if "\\[]^&~".contains(c) {
+                                regex.push('\\');
+                            }
+                            regex.push(c);
+                        }
+                        regex.push(']');
+                        chars.nth(end);
+                    },
+                    // An unclosed bracket is just a bracket.
+                    None => regex.push_str("\\["),
+                }
+            },
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scratch::ScratchDir;
+
+    fn glob_matches(glob: &str, path: &str) -> bool {
+        Pattern::glob(glob).unwrap().matches_path(path)
+    }
+
+    fn file(size: u64, modified_at: u64) -> SearchResult {
+        SearchResult {
+            path: "notes/todo.md".to_string(),
+            size,
+            modified_at,
+            content_type: "text/markdown".to_string(),
+            owner: None,
+            attributes: BTreeMap::new(),
+            matches: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn star_and_question_mark_stay_within_a_segment() {
+        assert!(glob_matches("*.md", "notes/todo.md"));
+        assert!(!glob_matches("*.md", "notes/todo.mdx"));
+        assert!(glob_matches("notes/*.md", "notes/todo.md"));
+        assert!(!glob_matches("notes/*.md", "notes/old/todo.md"));
+        assert!(glob_matches("notes/**/*.md", "notes/todo.md"));
+        assert!(glob_matches("notes/**/*.md", "notes/old/2020/todo.md"));
+        assert!(glob_matches("?.txt", "a.txt"));
+        assert!(!glob_matches("?.txt", "ab.txt"));
+        assert!(!glob_matches("a?b", "a/b"));
+    }
+
+    #[test]
+    fn brackets_are_character_classes() {
+        assert!(glob_matches("[abc].txt", "b.txt"));
+        assert!(!glob_matches("[abc].txt", "d.txt"));
+        assert!(glob_matches("[!abc].txt", "d.txt"));
+        assert!(!glob_matches("[!abc].txt", "a.txt"));
+        assert!(glob_matches("file[0-9]", "file7"));
+        assert!(glob_matches("[]]", "]"));
+        assert!(glob_matches("[^]", "^"));
+        // An unclosed bracket is matched as itself.
+        assert!(glob_matches("[a", "[a"));
+    }
+
+    #[test]
+    fn regex_metacharacters_in_globs_are_literal() {
+        assert!(glob_matches("a+b(1).txt", "a+b(1).txt"));
+        assert!(!glob_matches("a+b(1).txt", "aab1.txt"));
+        assert!(!glob_matches("file.txt", "fileXtxt"));
+        assert!(glob_matches("$x^{1}|y", "$x^{1}|y"));
+    }
+
+    #[test]
+    fn size_limits_are_inclusive() {
+        let query = SearchQuery { min_size: Some(10), max_size: Some(20), ..SearchQuery::default() };
+        assert!(!matches_metadata(&file(9, 0), &query));
+        assert!(matches_metadata(&file(10, 0), &query));
+        assert!(matches_metadata(&file(20, 0), &query));
+        assert!(!matches_metadata(&file(21, 0), &query));
+    }
+
+    #[test]
+    fn time_limits_are_inclusive() {
+        let query = SearchQuery { modified_after: Some(100), modified_before: Some(200), ..SearchQuery::default() };
+        assert!(!matches_metadata(&file(0, 99), &query));
+        assert!(matches_metadata(&file(0, 100), &query));
+        assert!(matches_metadata(&file(0, 200), &query));
+        assert!(!matches_metadata(&file(0, 201), &query));
+    }
+
+    #[test]
+    fn content_types_match_exactly_or_by_family() {
+        let query = |wanted: &str| SearchQuery { content_type: Some(wanted.to_string()), ..SearchQuery::default() };
+        assert!(matches_metadata(&file(0, 0), &query("text/markdown")));
+        assert!(matches_metadata(&file(0, 0), &query("TEXT/*")));
+        assert!(!matches_metadata(&file(0, 0), &query("text/plain")));
+        assert!(!matches_metadata(&file(0, 0), &query("image/*")));
+    }
+
+    #[test]
+    fn content_search_reports_matching_lines_of_text_only() {
+        let dir = ScratchDir::new("search-content");
+        let text = dir.join("text.txt");
+        fs::write(&text, "first line\nan example\nlast\nexample again").unwrap();
+        let matches = search_content(&text, 45, &Pattern::contains("example")).unwrap();
+        let lines: Vec<(usize, &str)> = matches.iter().map(|found| (found.line, found.text.as_str())).collect();
+        assert_eq!(lines, [(2, "an example"), (4, "example again")]);
+
+        let binary = dir.join("binary.bin");
+        fs::write(&binary, b"example\0example\n").unwrap();
+        assert!(search_content(&binary, 16, &Pattern::contains("example")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn the_scan_only_covers_the_scope() {
+        let dir = ScratchDir::new("search-scope");
+        for path in ["top.txt", "notes/todo.md", "notes/old/done.md", ".trash/gone.txt"] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "text").unwrap();
+        }
+        let scanned = |scope: &str, recursive: bool| -> Vec<String> {
+            let scan = SearchScan::read(&dir, &SafePath::parse(scope).unwrap(), recursive, &[".trash"]).unwrap();
+            scan.files.into_iter().map(|file| file.path).collect()
+        };
+        assert_eq!(scanned("notes", false), ["notes/todo.md"]);
+        assert_eq!(scanned("notes", true), ["notes/old/done.md", "notes/todo.md"]);
+        assert_eq!(scanned("", true), ["notes/old/done.md", "notes/todo.md", "top.txt"]);
+    }
+}
@@ -1,41 +1,240 @@
 use std::env;
-use std::io::{self, BufReader, BufWriter, Write, Read};
-use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::slice;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// network.rs and storage.rs double as standalone binaries; loading them by
+// path lets their own `mod` declarations resolve to the sibling files in
+// both builds. The modules storage.rs declares are used from there rather
+// than loaded a second time.
+mod content_type;
+mod erasure;
+mod gossip;
+#[path = "network.rs"]
+mod network;
+mod namespace;
+#[path = "handlers/node_handler.rs"]
+mod node_handler;
+mod protocol;
+mod raft;
+mod rebalancer;
+mod replication;
+#[path = "storage.rs"]
+mod storage;
+
+use protocol::{
+    is_timeout, read_frame, write_message, ChangeKind, ChunkReader, ChunkWriter, Command, DirEntry, EntryKind, ErasureScheme,
+    FileAttributes, FileMetadata, ReplicationState, ServerResponse, SnapshotChange, SnapshotInfo, StatusCode, TrashInfo, VersionInfo,
+    CHUNK_SIZE,
+};
+use checksum::sha256_hex;
+use erasure::{ErasureConfig, StripeDescriptor};
+use gossip::GossipHandle;
+use namespace::{MetadataOp, NamespaceEntry};
+use network::{NetworkTopology, Owner};
+use node_handler::{DistributedFsState, MembershipConfig, NodeStatus};
+use paths::SafePath;
+use raft::{RaftConfig, RaftNode};
+use rebalancer::{RebalanceConfig, Rebalancer};
+use replication::{
+    cluster_key, is_cluster_key, open_remote_stream, request, send_command, PeerChunkSource, ReplicationConfig,
+    ReplicationOutcome, Replicator,
+};
+use storage::snapshot::{Change, Snapshot, SnapshotDiff};
+use storage::{atomic_file, attributes, checksum, paths, scrubber, DistributedFileSystem, FileEntry, PreparedFile};
+#[cfg(test)]
+use storage::scratch;
+
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_SCRUB_INTERVAL_SECS: u64 = 6 * 60 * 60;
+const DEFAULT_RETENTION_INTERVAL_SECS: u64 = 60 * 60;
 
 fn main() {
     let server_address = env::var("SERVER_ADDRESS").unwrap_or_else(|_| "127.0.0.1:8080".to_string());
-    start_server(&server_address);
+    if let Err(e) = start_server(&server_address) {
+        eprintln!("Failed to start the server: {}", e);
+        process::exit(1);
+    }
+}
+
+// Waits for Ctrl-C or SIGTERM, then says goodbye to the cluster before the
+// process exits, so peers don't have to wait out the failure timeouts.
+fn leave_on_shutdown(gossip: GossipHandle) {
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => return eprintln!("Cannot watch for shutdown signals: {}", e),
+    };
+    let signalled = runtime.block_on(async {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    });
+    if let Err(e) = signalled {
+        return eprintln!("Cannot watch for shutdown signals: {}", e);
+    }
+    println!("Leaving the cluster");
+    gossip.leave();
+    process::exit(0);
+}
+
+struct ServerContext {
+    dfs: Arc<Mutex<DistributedFileSystem>>,
+    replicator: Replicator,
+    topology: Arc<Mutex<NetworkTopology>>,
+    membership: Arc<DistributedFsState>,
+    rebalancer: Rebalancer,
+    /// The Raft-replicated namespace, when DFS_RAFT_PEERS is set. Without
+    /// it every node only knows about the files it holds.
+    metadata: Option<RaftNode>,
+    erasure: ErasureConfig,
 }
 
-fn start_server(address: &str) {
+fn start_server(address: &str) -> Result<(), String> {
+    let dfs = Arc::new(Mutex::new(DistributedFileSystem::new().expect("Failed to open the metadata index")));
+    let topology = NetworkTopology::from_env().expect("Invalid DFS_PEERS");
+    let replication_config = ReplicationConfig::from_env().expect("Invalid replication settings");
+    let rebalance_config = RebalanceConfig::from_env(replication_config.factor).expect("Invalid rebalance settings");
+
+    // DFS_PEERS is optional once gossip is on. Peers listed there are
+    // registered like any later join, so a node restarted with a different
+    // peer list rebalances on startup.
+    let peers = topology.peers();
+    let local_id = topology.local_id().to_string();
+    // Relayed requests, gossip and the metadata group are all proven with
+    // the cluster key, so every node that talks to others needs it.
+    let clustered = !peers.is_empty() || env::var("LISTEN_ADDR").is_ok() || env::var("DFS_RAFT_PEERS").is_ok();
+    if clustered && cluster_key().is_none() {
+        return Err("DFS_CLUSTER_KEY must be set on every node of a cluster, and DFS_PEERS, LISTEN_ADDR or DFS_RAFT_PEERS is set".to_string());
+    }
+    let metadata = RaftConfig::from_env(&local_id)
+        .expect("Invalid metadata group settings")
+        .map(|config| RaftNode::start(config).expect("Could not start the metadata service"));
+    let topology = Arc::new(Mutex::new(topology));
+    let replicator = Replicator::new(replication_config, Arc::clone(&topology));
+    let rebalancer = Rebalancer::spawn(Arc::clone(&dfs), Arc::clone(&topology), replicator.clone(), rebalance_config);
+    let membership = Arc::new(DistributedFsState::new());
+    membership.add_observer(Arc::new(rebalancer.clone()));
+    for (id, address) in peers {
+        membership.add_cluster_node(&id, &address, NodeStatus::Alive);
+    }
+
+    // Gossip membership and failure detection listen on LISTEN_ADDR and
+    // make first contact through DFS_SEEDS, a comma-separated list of other
+    // nodes' LISTEN_ADDRs. Without LISTEN_ADDR membership only changes
+    // through DFS_PEERS and the admin commands.
+    if let Ok(gossip_address) = env::var("LISTEN_ADDR") {
+        let config = MembershipConfig::from_env().expect("Invalid membership settings");
+        let seeds = env::var("DFS_SEEDS")
+            .map(|seeds| seeds.split(',').map(str::trim).filter(|seed| !seed.is_empty()).map(String::from).collect())
+            .unwrap_or_default();
+        let gossip = gossip::spawn_gossip(local_id, address.to_string(), gossip_address, seeds, Arc::clone(&membership), config)
+            .expect("Could not start gossip");
+        thread::spawn(move || leave_on_shutdown(gossip));
+    }
+
+    let context = Arc::new(ServerContext {
+        dfs: Arc::clone(&dfs),
+        replicator,
+        topology,
+        membership,
+        rebalancer,
+        metadata,
+        erasure: ErasureConfig::from_env().expect("Invalid erasure coding settings"),
+    });
+    let idle_timeout = env::var("SERVER_IDLE_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_else(|| Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS));
+
+    // DFS_SCRUB_INTERVAL_SECS=0 turns background scrubbing off.
+    let scrub_interval = env::var("DFS_SCRUB_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_SCRUB_INTERVAL_SECS);
+    if scrub_interval > 0 {
+        // Damaged chunks are repaired from peers holding the same content.
+        let replica = Arc::new(PeerChunkSource::new(Arc::clone(&context.topology)));
+        scrubber::spawn_scrubber(Arc::clone(&dfs), Duration::from_secs(scrub_interval), Some(replica));
+    }
+    let retention_interval = env::var("DFS_RETENTION_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .filter(|secs| *secs > 0)
+        .unwrap_or(DEFAULT_RETENTION_INTERVAL_SECS);
+    spawn_retention(Arc::clone(&dfs), Duration::from_secs(retention_interval));
+
     let server_listener = TcpListener::bind(address).expect("Could not bind to address");
     println!("Server running on {}", address);
     for connection in server_listener.incoming() {
         match connection {
             Ok(tcp_stream) => {
-                thread::spawn(|| handle_client_connection(tcp_stream));
+                let context = Arc::clone(&context);
+                thread::spawn(move || handle_client_connection(tcp_stream, context, idle_timeout));
             },
             Err(e) => {
                 eprintln!("Failed to establish a connection: {}", e);
             },
         }
     }
+    Ok(())
 }
 
-fn handle_client_connection(stream: TcpStream) {
+// Drops file versions, and purges deleted files, that have outlived the
+// retention policy. This runs on its own schedule, so the trash is emptied
+// whether or not scrubbing is on.
+fn spawn_retention(dfs: Arc<Mutex<DistributedFileSystem>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = dfs.lock().unwrap().prune_versions() {
+            eprintln!("Failed to prune old versions: {}", e);
+        }
+        if let Err(e) = dfs.lock().unwrap().purge_trash() {
+            eprintln!("Failed to purge the trash: {}", e);
+        }
+    });
+}
+
+fn handle_client_connection(stream: TcpStream, context: Arc<ServerContext>, idle_timeout: Duration) {
+    // A connection that sends nothing for `idle_timeout` is closed; clients
+    // keep long-lived sessions open with `Command::Ping`.
+    if let Err(e) = stream.set_read_timeout(Some(idle_timeout)) {
+        eprintln!("Failed to set idle timeout: {}", e);
+        return;
+    }
+    let _ = stream.set_nodelay(true);
+
     let read_stream = stream.try_clone().expect("Failed to clone stream");
     let mut reader = BufReader::new(read_stream);
     let mut writer = BufWriter::new(stream);
 
-    let mut message_buffer = String::new();
     loop {
-        match reader.read_to_string(&mut message_buffer) {
-            Ok(0) => break, // Connection was closed
-            Ok(_) => {
-                send_response_to_client(&mut writer, &message_buffer);
-                message_buffer.clear(); // Clear buffer to avoid sending duplicate responses
-                break; // Assuming one message per connection; remove if you want to keep the connection open
+        match read_frame(&mut reader) {
+            Ok(None) => break, // Connection was closed
+            Ok(Some(request_data)) => {
+                let result = match serde_json::from_slice(&request_data) {
+                    Ok(command) => serve_command(&context, command, &mut reader, &mut writer),
+                    Err(e) => {
+                        let response = ServerResponse::error(StatusCode::BadRequest, &format!("Malformed command: {}", e));
+                        write_message(&mut writer, &response)
+                    },
+                };
+                if let Err(e) = result {
+                    eprintln!("Failed to serve request: {}", e);
+                    break;
+                }
+            },
+            Err(ref e) if is_timeout(e) => {
+                println!("Closing idle connection");
+                break;
             },
             Err(e) => {
                 eprintln!("Failed to read from connection: {}", e);
@@ -43,19 +242,1407 @@ fn handle_client_connection(stream: TcpStream) {
             },
         }
     }
+
+    if let Ok(stream) = writer.into_inner() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
+
+fn serve_command<R: Read, W: Write>(context: &ServerContext, command: Command, reader: &mut R, writer: &mut W) -> io::Result<()> {
+    // `forwarded` marks requests a peer relays on behalf of a client; they
+    // are served from local storage only and not relayed again. Only the
+    // cluster key can make a request relayed.
+    let (command, forwarded) = match command {
+        Command::Relayed { key, command } if is_cluster_key(&key) && !matches!(*command, Command::Relayed { .. }) => (*command, true),
+        Command::Relayed { command, .. } => {
+            if matches!(*command, Command::UploadStream { .. }) {
+                ChunkReader::new(reader).drain()?;
+            }
+            return write_message(writer, &ServerResponse::error(StatusCode::Forbidden, "Relayed requests need the cluster key"));
+        },
+        command => (command, false),
+    };
+    let has_body = matches!(command, Command::UploadStream { .. });
+    let command = match normalize_command(command) {
+        Ok(command) => command,
+        Err(e) => {
+            // A rejected upload's body is still on its way and has to be
+            // consumed before the next command can be read.
+            if has_body {
+                ChunkReader::new(reader).drain()?;
+            }
+            return write_message(writer, &ServerResponse::error(StatusCode::BadRequest, &e.to_string()));
+        },
+    };
+
+    match command {
+        Command::UploadStream { filename, size, checksum, erasure, attributes } => {
+            receive_stream(context, &filename, size, checksum, attributes, forwarded, reader)
+                .map(|response| replicate_upload(context, &filename, forwarded, erasure, response))
+                .and_then(|response| write_message(writer, &response))
+        },
+        Command::DownloadStream { filename } => {
+            if forwarded || context.dfs.lock().unwrap().stat(&filename).is_ok() {
+                send_stream(&context.dfs, &filename, writer)
+            } else {
+                relay_stream(context, &filename, writer)
+            }
+        },
+        command => write_message(writer, &generate_response(context, command, forwarded)),
+    }
+}
+
+// Paths are normalized once, as they arrive, so placement and storage only
+// ever see one spelling of each name.
+fn normalize_command(command: Command) -> io::Result<Command> {
+    Ok(match command {
+        Command::UploadFile { filename, contents, checksum, erasure } => Command::UploadFile {
+            filename: file_name(&filename)?,
+            contents,
+            checksum,
+            erasure,
+        },
+        Command::UploadStream { filename, size, checksum, erasure, attributes } => Command::UploadStream {
+            filename: file_name(&filename)?,
+            size,
+            checksum,
+            erasure,
+            attributes,
+        },
+        Command::DownloadFile { filename } => Command::DownloadFile { filename: file_name(&filename)? },
+        Command::DownloadStream { filename } => Command::DownloadStream { filename: file_name(&filename)? },
+        Command::DeleteFile { filename, purge } => Command::DeleteFile { filename: file_name(&filename)?, purge },
+        Command::Undelete { filename } => Command::Undelete { filename: file_name(&filename)? },
+        Command::Stat { filename } => Command::Stat { filename: file_name(&filename)? },
+        Command::SetAttributes { filename, content_type, owner, set, remove } => {
+            if set.keys().any(String::is_empty) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Attribute names can't be empty"));
+            }
+            Command::SetAttributes { filename: file_name(&filename)?, content_type, owner, set, remove }
+        },
+        Command::ListVersions { filename } => Command::ListVersions { filename: file_name(&filename)? },
+        Command::DownloadVersion { filename, version } => Command::DownloadVersion { filename: file_name(&filename)?, version },
+        Command::RestoreVersion { filename, version } => Command::RestoreVersion { filename: file_name(&filename)?, version },
+        Command::MakeDirectory { path, parents } => Command::MakeDirectory { path: paths::normalize(&path)?, parents },
+        Command::RemoveDirectory { path, recursive } => Command::RemoveDirectory { path: paths::normalize(&path)?, recursive },
+        Command::ListDirectory { path, recursive } => Command::ListDirectory { path: paths::normalize(&path)?, recursive },
+        Command::Rename { from, to, overwrite } => {
+            Command::Rename { from: paths::normalize(&from)?, to: paths::normalize(&to)?, overwrite }
+        },
+        Command::CreateSnapshot { name, path } => Command::CreateSnapshot { name, path: paths::normalize(&path)? },
+        Command::BrowseSnapshot { name, path, recursive } => Command::BrowseSnapshot { name, path: paths::normalize(&path)?, recursive },
+        Command::DownloadSnapshotFile { name, filename } => Command::DownloadSnapshotFile { name, filename: file_name(&filename)? },
+        command => command,
+    })
+}
+
+fn file_name(name: &str) -> io::Result<String> {
+    Ok(SafePath::file(name)?.into_string())
+}
+
+fn generate_response(context: &ServerContext, command: Command, forwarded: bool) -> ServerResponse {
+    match command {
+        Command::Ping => ServerResponse::ok("pong"),
+        Command::ListFiles => match context.metadata.as_ref() {
+            Some(metadata) => match metadata.read() {
+                Ok(namespace) => ServerResponse::ok("Listed files").with_files(namespace.names()),
+                Err(e) => error_response("Namespace", e),
+            },
+            None => {
+                let files = context.dfs.lock().unwrap().list_files();
+                let files = files.iter().filter_map(|name| erasure::listed_name(name)).map(String::from).collect();
+                ServerResponse::ok("Listed files").with_files(files)
+            },
+        },
+        Command::ListStoredFiles => ServerResponse::ok("Listed stored files").with_files(context.dfs.lock().unwrap().list_files()),
+        Command::FetchChunk { hash } => {
+            if !forwarded {
+                return ServerResponse::error(StatusCode::Forbidden, "Chunks are only served to peers");
+            }
+            match context.dfs.lock().unwrap().read_chunk(&hash) {
+                Ok(data) => ServerResponse::ok("Chunk found").with_contents(data),
+                Err(e) => error_response(&hash, e),
+            }
+        },
+        Command::UploadFile { filename, contents, checksum, erasure } => {
+            let prepared = {
+                let mut dfs = context.dfs.lock().unwrap();
+                dfs.create_writer(&filename).and_then(|mut file_writer| {
+                    if let Some(checksum) = checksum.as_ref() {
+                        file_writer.expect_checksum(checksum);
+                    }
+                    if let Err(e) = file_writer.write_all(&contents) {
+                        dfs.abort_writer(file_writer)?;
+                        return Err(e);
+                    }
+                    dfs.prepare_writer(file_writer)
+                })
+            };
+            let response = match prepared.and_then(|prepared| commit_upload(context, prepared, &filename, forwarded)) {
+                Ok(entry) => ServerResponse::ok(&format!("Uploaded {}", filename)).with_checksum(entry.checksum),
+                Err(e) => error_response(&filename, e),
+            };
+            replicate_upload(context, &filename, forwarded, erasure, response)
+        },
+        Command::DownloadFile { filename } => {
+            let retrieved = {
+                let dfs = context.dfs.lock().unwrap();
+                dfs.retrieve_files(slice::from_ref(&filename)).and_then(|contents| Ok((contents, dfs.stat(&filename)?)))
+            };
+            match retrieved {
+                Ok((mut contents, entry)) => {
+                    let data = contents.remove(&filename).unwrap_or_default();
+                    ServerResponse::ok(&format!("Downloaded {}", filename))
+                        .with_contents(data)
+                        .with_checksum(entry.checksum)
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && !forwarded => {
+                    let command = Command::DownloadFile { filename: filename.clone() };
+                    let response = fetch_from_owners(context, &filename, &command);
+                    if response.status != StatusCode::NotFound {
+                        return response;
+                    }
+                    match fetch_striped(context, &filename) {
+                        Ok((data, descriptor)) => ServerResponse::ok(&format!("Downloaded {}", filename))
+                            .with_contents(data)
+                            .with_checksum(descriptor.checksum),
+                        Err(e) => error_response(&filename, e),
+                    }
+                },
+                Err(e) => error_response(&filename, e),
+            }
+        },
+        Command::ListVersions { filename } => {
+            let listed = context.dfs.lock().unwrap().list_versions(&filename);
+            match listed {
+                Ok(versions) => {
+                    let versions = versions
+                        .into_iter()
+                        .map(|version| VersionInfo {
+                            version: version.version,
+                            size: version.size,
+                            checksum: version.checksum,
+                            modified_at: version.modified_at,
+                        })
+                        .collect();
+                    ServerResponse::ok(&format!("Listed versions of {}", filename)).with_versions(versions)
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && !forwarded => {
+                    fetch_from_owners(context, &filename, &Command::ListVersions { filename: filename.clone() })
+                },
+                Err(e) => error_response(&filename, e),
+            }
+        },
+        Command::DownloadVersion { filename, version } => {
+            let retrieved = context.dfs.lock().unwrap().retrieve_version(&filename, version);
+            match retrieved {
+                Ok(data) => {
+                    let checksum = sha256_hex(&data);
+                    ServerResponse::ok(&format!("Downloaded {} version {}", filename, version))
+                        .with_contents(data)
+                        .with_checksum(checksum)
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && !forwarded && !holds_file(context, &filename) => {
+                    let command = Command::DownloadVersion { filename: filename.clone(), version };
+                    fetch_from_owners(context, &filename, &command)
+                },
+                Err(e) => error_response(&filename, e),
+            }
+        },
+        Command::RestoreVersion { filename, version } => {
+            let prepared = context.dfs.lock().unwrap().prepare_version(&filename, version);
+            let response = match prepared {
+                Ok(Some(prepared)) => match commit_upload(context, prepared, &filename, false) {
+                    Ok(entry) => ServerResponse::ok(&format!("Restored {} version {} as version {}", filename, version, entry.version)),
+                    Err(e) => return error_response(&filename, e),
+                },
+                Ok(None) => return ServerResponse::ok(&format!("{} version {} is already the current one", filename, version)),
+                // Without a local copy the version is fetched from an owner
+                // and written here as new content.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && !holds_file(context, &filename) => {
+                    let command = Command::DownloadVersion { filename: filename.clone(), version };
+                    let fetched = fetch_from_owners(context, &filename, &command);
+                    if !fetched.is_ok() {
+                        return fetched;
+                    }
+                    let contents = fetched.file_contents.unwrap_or_default();
+                    let prepared = {
+                        let mut dfs = context.dfs.lock().unwrap();
+                        dfs.create_writer(&filename).and_then(|mut file_writer| {
+                            if let Err(e) = file_writer.write_all(&contents) {
+                                dfs.abort_writer(file_writer)?;
+                                return Err(e);
+                            }
+                            dfs.prepare_writer(file_writer)
+                        })
+                    };
+                    match prepared.and_then(|prepared| commit_upload(context, prepared, &filename, false)) {
+                        Ok(_) => ServerResponse::ok(&format!("Restored {} version {}", filename, version)),
+                        Err(e) => return error_response(&filename, e),
+                    }
+                },
+                Err(e) => return error_response(&filename, e),
+            };
+            // Replicas take the restored content as an ordinary upload.
+            replicate_upload(context, &filename, false, None, response)
+        },
+        Command::DeleteFile { filename, purge } => {
+            // The namespace decides whether the file exists; copies are only
+            // removed once the delete is committed there, and if that fails
+            // here the entry is put back.
+            let mut proposed = None;
+            if let (Some(metadata), false) = (context.metadata.as_ref(), forwarded) {
+                let previous = metadata.read().map(|namespace| namespace.entry(&filename).cloned());
+                match previous.and_then(|previous| metadata.propose(MetadataOp::Delete { name: filename.clone() }).map(|_| previous)) {
+                    Ok(previous) => proposed = Some((metadata, previous)),
+                    Err(e) => return error_response(&filename, e),
+                }
+            }
+            let failed = |e: io::Error| {
+                if let Some((metadata, previous)) = proposed.clone() {
+                    undo_proposal(metadata, restore_entry(&filename, previous));
+                }
+                error_response(&filename, e)
+            };
+            if !forwarded {
+                match erasure::find_stripe(&context.dfs, &context.replicator, &filename) {
+                    Ok(Some(descriptor)) => {
+                        let failures = erasure::delete_striped(&context.dfs, &context.replicator, &filename, &descriptor, purge);
+                        if failures.is_empty() {
+                            return ServerResponse::ok(&format!("Deleted {}", filename));
+                        }
+                        let message = format!("Deleted {}, but some shards remain: {}", filename, failures.join("; "));
+                        return ServerResponse::error(StatusCode::ReplicationFailed, &message);
+                    },
+                    Ok(None) => {},
+                    Err(e) => return failed(e),
+                }
+            }
+            let deleted = if purge {
+                context.dfs.lock().unwrap().purge_files(slice::from_ref(&filename))
+            } else {
+                context.dfs.lock().unwrap().delete_files(slice::from_ref(&filename))
+            };
+            match deleted {
+                Ok(()) if forwarded => ServerResponse::ok(&format!("Deleted {}", filename)),
+                Err(e) if forwarded => error_response(&filename, e),
+                Err(e) if e.kind() != io::ErrorKind::NotFound => failed(e),
+                Err(e) if context.replicator.owners(&filename).iter().all(Owner::is_local) => error_response(&filename, e),
+                // Without a local copy the file's owners may still have one.
+                _ => {
+                    let outcome = context.replicator.replicate_delete(&filename, purge);
+                    with_replication(ServerResponse::ok(&format!("Deleted {}", filename)), outcome)
+                },
+            }
+        },
+        Command::MakeDirectory { path, parents } => {
+            let display = format!("/{}", path);
+            if forwarded {
+                // Another node already checked this; the directory may
+                // exist here anyway.
+                return match context.dfs.lock().unwrap().create_directory(&path, true) {
+                    Err(ref e) if e.kind() != io::ErrorKind::AlreadyExists => error_response(&display, io::Error::new(e.kind(), e.to_string())),
+                    _ => ServerResponse::ok(&format!("Created directory {}", display)),
+                };
+            }
+            if let Some(metadata) = context.metadata.as_ref() {
+                if let Err(e) = metadata.propose(MetadataOp::MakeDirectory { path: path.clone(), parents, created_at: now_secs() }) {
+                    return error_response(&display, e);
+                }
+            }
+            // With the metadata service deciding, the local copy of the
+            // namespace just has to catch up, and the namespace drops the
+            // directory again if it can't.
+            let lenient = context.metadata.is_some();
+            match context.dfs.lock().unwrap().create_directory(&path, parents || lenient) {
+                Err(ref e) if lenient && e.kind() == io::ErrorKind::AlreadyExists => {},
+                Err(e) => {
+                    if let Some(metadata) = context.metadata.as_ref() {
+                        undo_proposal(metadata, MetadataOp::RemoveDirectory { path, recursive: false });
+                    }
+                    return error_response(&display, e);
+                },
+                Ok(()) => {},
+            }
+            let failures = broadcast(context, &Command::MakeDirectory { path, parents: true });
+            with_broadcast(ServerResponse::ok(&format!("Created directory {}", display)), failures)
+        },
+        Command::RemoveDirectory { path, recursive } => {
+            let display = format!("/{}", path);
+            if !forwarded {
+                if let Some(metadata) = context.metadata.as_ref() {
+                    if let Err(e) = metadata.propose(MetadataOp::RemoveDirectory { path: path.clone(), recursive }) {
+                        return error_response(&display, e);
+                    }
+                }
+            }
+            match remove_local_directory(context, &path, recursive) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && (forwarded || context.metadata.is_some()) => {},
+                Err(e) => return error_response(&display, e),
+                Ok(()) => {},
+            }
+            let response = ServerResponse::ok(&format!("Removed directory {}", display));
+            if forwarded {
+                return response;
+            }
+            with_broadcast(response, broadcast(context, &Command::RemoveDirectory { path, recursive }))
+        },
+        Command::Rename { from, to, overwrite } => {
+            let display = format!("/{}", from);
+            if forwarded {
+                return match rename_local(context, &from, &to, overwrite) {
+                    Ok(true) => ServerResponse::ok(&format!("Renamed {} to /{}", display, to)),
+                    Ok(false) => ServerResponse::error(StatusCode::NotFound, &format!("{}: File not found", display)),
+                    Err(e) => error_response(&display, e),
+                };
+            }
+            // With the metadata service the rename is decided there, and
+            // every node's copies just follow it. If this node's can't, the
+            // namespace is moved back, along with any file it replaced.
+            let mut proposed = None;
+            let overwrite = match context.metadata.as_ref() {
+                Some(metadata) => {
+                    let operation = MetadataOp::Rename { from: from.clone(), to: to.clone(), overwrite };
+                    let replaced = metadata.read().map(|namespace| namespace.entry(&to).cloned());
+                    match replaced.and_then(|replaced| metadata.propose(operation).map(|_| replaced)) {
+                        Ok(replaced) => proposed = Some((metadata, replaced)),
+                        Err(e) => return error_response(&display, e),
+                    }
+                    true
+                },
+                None => overwrite,
+            };
+            let mut found = match rename_local(context, &from, &to, overwrite) {
+                Ok(found) => found,
+                Err(e) => {
+                    if let Some((metadata, replaced)) = proposed {
+                        undo_proposal(metadata, MetadataOp::Rename { from: to.clone(), to: from.clone(), overwrite: false });
+                        if let Some(replaced) = replaced {
+                            undo_proposal(metadata, restore_entry(&to, Some(replaced)));
+                        }
+                    }
+                    return error_response(&display, e);
+                },
+            };
+            let (responses, failures) = gather(context, &Command::Rename { from: from.clone(), to: to.clone(), overwrite });
+            found |= !responses.is_empty();
+            if !found && failures.is_empty() && context.metadata.is_none() {
+                return ServerResponse::error(StatusCode::NotFound, &format!("{}: File not found", display));
+            }
+            with_broadcast(ServerResponse::ok(&format!("Renamed {} to /{}", display, to)), failures)
+        },
+        Command::ListDirectory { path, recursive } => {
+            let display = format!("/{}", path);
+            let listed = match context.metadata.as_ref() {
+                Some(metadata) => metadata
+                    .read()
+                    .and_then(|namespace| namespace.list_directory(&path, recursive).map_err(io::Error::from)),
+                None => local_listing(context, &path, recursive),
+            };
+            match listed {
+                Ok(entries) => ServerResponse::ok(&format!("Listed {}", display)).with_entries(entries),
+                Err(e) => error_response(&display, e),
+            }
+        },
+        Command::CreateSnapshot { name, path } => {
+            let created = context.dfs.lock().unwrap().create_snapshot(&name, &path);
+            let found = match created {
+                Ok(()) => true,
+                // Nodes holding nothing below the directory have nothing to
+                // capture.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && !forwarded => false,
+                Err(e) => return error_response(&name, e),
+            };
+            let response = ServerResponse::ok(&format!("Created snapshot {} of /{}", name, path));
+            if forwarded {
+                return response;
+            }
+            let (responses, failures) = gather(context, &Command::CreateSnapshot { name: name.clone(), path });
+            if !found && responses.is_empty() && failures.is_empty() {
+                return ServerResponse::error(StatusCode::NotFound, &format!("{}: Directory not found", name));
+            }
+            with_broadcast(response, failures)
+        },
+        Command::ListSnapshots => {
+            let mut listed: BTreeMap<String, SnapshotInfo> = BTreeMap::new();
+            let mut failures = Vec::new();
+            let mut reported = vec![context.dfs.lock().unwrap().snapshots().into_iter().map(snapshot_info).collect()];
+            if !forwarded {
+                let (responses, unreachable) = gather(context, &Command::ListSnapshots);
+                reported.extend(responses.into_iter().map(|response| response.snapshots.unwrap_or_default()));
+                failures = unreachable;
+            }
+            for snapshot in reported.into_iter().flatten() {
+                let merged = listed.entry(snapshot.name.clone()).or_insert_with(|| snapshot.clone());
+                merged.created_at = merged.created_at.min(snapshot.created_at);
+                merged.files = merged.files.max(snapshot.files);
+                merged.size = merged.size.max(snapshot.size);
+            }
+            let response = ServerResponse::ok("Listed snapshots").with_snapshots(listed.into_values().collect());
+            with_broadcast(response, failures)
+        },
+        Command::BrowseSnapshot { name, path, recursive } => {
+            let display = format!("{}:/{}", name, path);
+            let listed = snapshot_listing(&context.dfs.lock().unwrap(), &name, &path, recursive);
+            let mut found = match listed {
+                Ok(listed) => Some(listed),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return error_response(&display, e),
+            };
+            if !forwarded {
+                let command = Command::BrowseSnapshot { name: name.clone(), path: path.clone(), recursive };
+                let (responses, failures) = gather(context, &command);
+                if !failures.is_empty() {
+                    return with_broadcast(ServerResponse::ok(&format!("Listed {}", display)), failures);
+                }
+                for response in responses {
+                    let merged = found.get_or_insert_with(BTreeMap::new);
+                    for entry in response.entries.unwrap_or_default() {
+                        merged.entry(entry.path.clone()).or_insert(entry);
+                    }
+                }
+            }
+            match found {
+                Some(listed) => ServerResponse::ok(&format!("Listed {}", display)).with_entries(listed.into_values().collect()),
+                None => ServerResponse::error(StatusCode::NotFound, &format!("{}: Directory not found", display)),
+            }
+        },
+        Command::DownloadSnapshotFile { name, filename } => {
+            let display = format!("{}:/{}", name, filename);
+            let retrieved = context.dfs.lock().unwrap().retrieve_snapshot_file(&name, &filename);
+            match retrieved {
+                Ok(data) => {
+                    let checksum = sha256_hex(&data);
+                    ServerResponse::ok(&format!("Downloaded {}", display)).with_contents(data).with_checksum(checksum)
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && !forwarded => {
+                    let command = Command::DownloadSnapshotFile { name: name.clone(), filename: filename.clone() };
+                    fetch_from_owners(context, &filename, &command)
+                },
+                Err(e) => error_response(&display, e),
+            }
+        },
+        Command::DiffSnapshot { name } => {
+            let diffed = context.dfs.lock().unwrap().diff_snapshot(&name);
+            let mut found = match diffed {
+                Ok(changes) => Some(changes.into_iter().filter(|change| !erasure::is_internal(&change.path)).map(snapshot_change).collect()),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return error_response(&name, e),
+            };
+            if !forwarded {
+                let (responses, failures) = gather(context, &Command::DiffSnapshot { name: name.clone() });
+                if !failures.is_empty() {
+                    return with_broadcast(ServerResponse::ok(&format!("Compared snapshot {}", name)), failures);
+                }
+                for response in responses {
+                    let merged: &mut Vec<SnapshotChange> = found.get_or_insert_with(Vec::new);
+                    merged.extend(response.changes.unwrap_or_default());
+                }
+            }
+            match found {
+                Some(mut changes) => {
+                    changes.sort_by(|a, b| a.path.cmp(&b.path));
+                    changes.dedup_by(|a, b| a.path == b.path);
+                    ServerResponse::ok(&format!("Compared snapshot {}", name)).with_changes(changes)
+                },
+                None => ServerResponse::error(StatusCode::NotFound, &format!("{}: Snapshot not found", name)),
+            }
+        },
+        Command::RollbackSnapshot { name } => {
+            let rolled_back = context.dfs.lock().unwrap().rollback_snapshot(&name);
+            let found = match rolled_back {
+                Ok(changes) => {
+                    if let Err(e) = propose_rollback(context, &changes) {
+                        return error_response(&name, e);
+                    }
+                    if !changes.is_empty() {
+                        context.rebalancer.trigger();
+                    }
+                    true
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && !forwarded => false,
+                Err(e) => return error_response(&name, e),
+            };
+            let response = ServerResponse::ok(&format!("Rolled back to snapshot {}", name));
+            if forwarded {
+                return response;
+            }
+            let (responses, failures) = gather(context, &Command::RollbackSnapshot { name: name.clone() });
+            if !found && responses.is_empty() && failures.is_empty() {
+                return ServerResponse::error(StatusCode::NotFound, &format!("{}: Snapshot not found", name));
+            }
+            with_broadcast(response, failures)
+        },
+        Command::DeleteSnapshot { name } => {
+            let deleted = context.dfs.lock().unwrap().delete_snapshot(&name);
+            let found = match deleted {
+                Ok(()) => true,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound && !forwarded => false,
+                Err(e) => return error_response(&name, e),
+            };
+            let response = ServerResponse::ok(&format!("Deleted snapshot {}", name));
+            if forwarded {
+                return response;
+            }
+            let (responses, failures) = gather(context, &Command::DeleteSnapshot { name: name.clone() });
+            if !found && responses.is_empty() && failures.is_empty() {
+                return ServerResponse::error(StatusCode::NotFound, &format!("{}: Snapshot not found", name));
+            }
+            with_broadcast(response, failures)
+        },
+        Command::Undelete { filename } => {
+            // Nodes only check their own copies, so a name taken since the
+            // delete is caught here first.
+            if let (Some(metadata), false) = (context.metadata.as_ref(), forwarded) {
+                match metadata.read() {
+                    Ok(namespace) if namespace.contains(&filename) => {
+                        return ServerResponse::error(StatusCode::AlreadyExists, &format!("{} already exists", filename));
+                    },
+                    Ok(namespace) if namespace.is_directory(&filename) => {
+                        return ServerResponse::error(StatusCode::IsADirectory, &format!("{} is a directory", filename));
+                    },
+                    Ok(_) => {},
+                    Err(e) => return error_response("Namespace", e),
+                }
+            }
+            let restored = match undelete_local(context, &filename) {
+                Ok(restored) => restored,
+                Err(e) => return error_response(&filename, e),
+            };
+            // Nodes holding only shards restore them but can't describe the
+            // file.
+            let mut entry = if restored > 0 { listed_entry(context, &filename) } else { None };
+            if forwarded {
+                if restored == 0 {
+                    return ServerResponse::error(StatusCode::NotFound, &format!("{}: Not in the trash", filename));
+                }
+                return ServerResponse::ok(&format!("Restored {}", filename)).with_entries(entry.into_iter().collect());
+            }
+            let (responses, failures) = gather(context, &Command::Undelete { filename: filename.clone() });
+            if restored == 0 && responses.is_empty() {
+                if failures.is_empty() {
+                    return ServerResponse::error(StatusCode::NotFound, &format!("{}: Not in the trash", filename));
+                }
+                let message = format!("{} is not in the trash of any node reached: {}", filename, failures.join("; "));
+                return ServerResponse::error(StatusCode::ReplicationFailed, &message);
+            }
+            for response in responses {
+                entry = entry.or_else(|| response.entries.unwrap_or_default().into_iter().next());
+            }
+            if let (Some(metadata), Some(entry)) = (context.metadata.as_ref(), entry) {
+                let created = metadata.propose(MetadataOp::Create {
+                    name: filename.clone(),
+                    size: entry.size,
+                    checksum: entry.checksum.unwrap_or_default(),
+                    modified_at: entry.modified_at,
+                });
+                if let Err(e) = created {
+                    return error_response(&filename, e);
+                }
+            }
+            // Owners that purged their copy in the meantime get a new one.
+            context.rebalancer.trigger();
+            with_broadcast(ServerResponse::ok(&format!("Restored {}", filename)), failures)
+        },
+        Command::ListTrash => {
+            let mut trashed: BTreeMap<String, TrashInfo> = match local_trash(context) {
+                Ok(trashed) => trashed.into_iter().map(|info| (info.filename.clone(), info)).collect(),
+                Err(e) => return error_response("Trash", e),
+            };
+            let mut failures = Vec::new();
+            if !forwarded {
+                let (responses, unreached) = gather(context, &Command::ListTrash);
+                failures = unreached;
+                for info in responses.into_iter().flat_map(|response| response.trash.unwrap_or_default()) {
+                    match trashed.get(&info.filename) {
+                        Some(known) if known.deleted_at >= info.deleted_at => {},
+                        _ => {
+                            trashed.insert(info.filename.clone(), info);
+                        },
+                    }
+                }
+            }
+            let mut trashed: Vec<TrashInfo> = trashed.into_values().collect();
+            trashed.sort_by_key(|entry| Reverse(entry.deleted_at));
+            with_broadcast(ServerResponse::ok("Listed trash").with_trash(trashed), failures)
+        },
+        Command::Stat { filename } => {
+            let found = match local_metadata(context, &filename) {
+                Ok(found) => found,
+                Err(e) => return error_response(&filename, e),
+            };
+            if forwarded {
+                return match found {
+                    Some(metadata) => ServerResponse::ok(&format!("Stat {}", filename)).with_metadata(metadata),
+                    None => ServerResponse::error(StatusCode::NotFound, &format!("{}: File not found", filename)),
+                };
+            }
+            let mut metadata = match found {
+                Some(metadata) => metadata,
+                None => {
+                    // An erasure-coded file's descriptor has owners of its
+                    // own.
+                    let command = Command::Stat { filename: filename.clone() };
+                    let mut response = fetch_from_owners(context, &filename, &command);
+                    if !response.is_ok() {
+                        response = fetch_from_owners(context, &erasure::stripe_name(&filename), &command);
+                    }
+                    match response.metadata {
+                        Some(metadata) => metadata,
+                        None => return ServerResponse::error(StatusCode::NotFound, &format!("{}: File not found", filename)),
+                    }
+                },
+            };
+            metadata.replication = match metadata.replication.erasure {
+                Some(scheme) => ReplicationState {
+                    copies: erasure::count_shards(&context.dfs, &context.replicator, &filename, scheme),
+                    wanted: scheme.total(),
+                    erasure: Some(scheme),
+                },
+                None => ReplicationState {
+                    copies: context.replicator.copies(&context.dfs, &filename),
+                    wanted: context.replicator.owners(&filename).len(),
+                    erasure: None,
+                },
+            };
+            ServerResponse::ok(&format!("Stat {}", filename)).with_metadata(metadata)
+        },
+        Command::SetAttributes { filename, content_type, owner, set, remove } => {
+            let found = match set_local_attributes(context, &filename, &content_type, &owner, &set, &remove) {
+                Ok(found) => found,
+                Err(e) => return error_response(&filename, e),
+            };
+            let response = ServerResponse::ok(&format!("Updated attributes of {}", filename));
+            if forwarded {
+                if !found {
+                    return ServerResponse::error(StatusCode::NotFound, &format!("{}: File not found", filename));
+                }
+                return response;
+            }
+            // Every copy of the file, or of its stripe descriptor, has to
+            // change, wherever it is.
+            let command = Command::SetAttributes { filename: filename.clone(), content_type, owner, set, remove };
+            let (responses, failures) = gather(context, &command);
+            if !found && responses.is_empty() && failures.is_empty() {
+                return ServerResponse::error(StatusCode::NotFound, &format!("{}: File not found", filename));
+            }
+            with_broadcast(response, failures)
+        },
+        // Administration is only taken from other nodes and operators
+        // holding the cluster key.
+        Command::AddNode { .. }
+        | Command::RemoveNode { .. }
+        | Command::DecommissionNode { .. }
+        | Command::QueryNode { .. }
+        | Command::RebalanceStatus
+            if !forwarded =>
+        {
+            ServerResponse::error(StatusCode::Forbidden, "Cluster administration needs the cluster key")
+        },
+        Command::AddNode { id, address } => {
+            context.membership.add_cluster_node(&id, &address, NodeStatus::Alive);
+            ServerResponse::ok(&format!("Added node {}", id))
+        },
+        Command::RemoveNode { id } => {
+            if context.membership.query_node_status(&id).is_none() {
+                return ServerResponse::error(StatusCode::NotFound, &format!("Unknown node {}", id));
+            }
+            context.membership.remove_cluster_node(&id);
+            ServerResponse::ok(&format!("Removed node {}", id))
+        },
+        Command::DecommissionNode { id } => {
+            let is_local = id == context.topology.lock().unwrap().local_id();
+            if is_local {
+                // Draining this node: it gives up ownership, gossips that it
+                // is decommissioning, and the rebalancer pushes its data to
+                // the remaining owners.
+                context.topology.lock().unwrap().set_local_owner(false);
+                context.membership.set_local_status(NodeStatus::Decommissioning);
+                context.rebalancer.trigger();
+            } else if !context.membership.decommission_node(&id) {
+                return ServerResponse::error(StatusCode::NotFound, &format!("Unknown node {}", id));
+            }
+            ServerResponse::ok(&format!("Decommissioning node {}", id))
+        },
+        Command::QueryNode { id } => match context.membership.query_node_status(&id) {
+            Some(status) => ServerResponse::ok(&status.to_string()),
+            None => ServerResponse::error(StatusCode::NotFound, &format!("Unknown node {}", id)),
+        },
+        Command::RebalanceStatus => ServerResponse::ok(&context.rebalancer.progress().summary()),
+        Command::UploadStream { .. } | Command::DownloadStream { .. } => {
+            ServerResponse::error(StatusCode::BadRequest, "Streamed transfers need the connection")
+        },
+        Command::Relayed { .. } => ServerResponse::error(StatusCode::BadRequest, "Relayed requests can't be relayed again"),
+    }
+}
+
+fn replicate_upload(
+    context: &ServerContext,
+    filename: &str,
+    forwarded: bool,
+    erasure: Option<ErasureScheme>,
+    response: ServerResponse,
+) -> ServerResponse {
+    if forwarded || !response.is_ok() {
+        return response;
+    }
+    if let Some(scheme) = erasure.or_else(|| context.erasure.scheme_for(filename)) {
+        return match erasure::store_striped(&context.dfs, &context.replicator, filename, scheme) {
+            Ok(outcome) => {
+                if !outcome.striped {
+                    context.rebalancer.retry_striping(filename, scheme);
+                }
+                with_striping(response, outcome)
+            },
+            Err(e) => error_response(filename, e),
+        };
+    }
+    let outcome = context.replicator.replicate_file(&context.dfs, filename);
+
+    // A node that accepted a write for a file it doesn't own hands it off:
+    // once every owner has a copy the local one is dropped.
+    if !outcome.local_is_owner && outcome.failures.is_empty() {
+        if let Err(e) = context.dfs.lock().unwrap().purge_files(&[filename.to_string()]) {
+            eprintln!("Failed to drop handed-off copy of {}: {}", filename, e);
+        }
+    }
+    with_replication(response, outcome)
 }
 
-fn generate_response(_request_data: &str) -> String {
-    "Response from server\n".to_string() // Ensure it's a new line terminated for client readability
+// Makes a prepared write visible here. With the metadata service it is
+// proposed first, so a write the namespace rejects never becomes visible;
+// should it then fail here, the namespace gets its previous entry back.
+// Relayed writes were proposed by the node that took them.
+//
+// A proposal that times out may still commit later, so its file is kept
+// rather than discarded, which could leave the namespace listing a file
+// stored nowhere. Unless the namespace already shows the change, the
+// caller is told the outcome is unknown.
+fn commit_upload(context: &ServerContext, prepared: PreparedFile, filename: &str, forwarded: bool) -> io::Result<FileEntry> {
+    if let (Some(metadata), false) = (context.metadata.as_ref(), forwarded) {
+        let (checksum, modified_at) = (prepared.checksum().to_string(), prepared.modified_at());
+        let operation = MetadataOp::Create {
+            name: filename.to_string(),
+            size: prepared.size(),
+            checksum: checksum.clone(),
+            modified_at,
+        };
+        let previous = metadata.read().map(|namespace| namespace.entry(filename).cloned());
+        let proposed = previous.and_then(|previous| match metadata.propose(operation) {
+            Ok(()) => Ok((previous, true)),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                let landed = metadata.read().is_ok_and(|namespace| {
+                    namespace.entry(filename).is_some_and(|entry| entry.checksum == checksum && entry.modified_at == modified_at)
+                });
+                Ok((previous, landed))
+            },
+            Err(e) => Err(e),
+        });
+        let (previous, landed) = match proposed {
+            Ok(proposed) => proposed,
+            Err(e) => {
+                context.dfs.lock().unwrap().discard_prepared(prepared);
+                return Err(e);
+            },
+        };
+        let mut dfs = context.dfs.lock().unwrap();
+        let committed = dfs.commit_prepared(prepared).and_then(|_| dfs.stat(filename));
+        drop(dfs);
+        return match committed {
+            Ok(_) if !landed => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Outcome unknown: the metadata change timed out but may still be applied, so the file was kept",
+            )),
+            Err(e) => {
+                if landed {
+                    undo_proposal(metadata, restore_entry(filename, previous));
+                }
+                Err(e)
+            },
+            committed => committed,
+        };
+    }
+    let mut dfs = context.dfs.lock().unwrap();
+    dfs.commit_prepared(prepared)?;
+    dfs.stat(filename)
 }
 
-fn send_response_to_client(writer: &mut BufWriter<TcpStream>, request_data: &str) {
-    let response_content = generate_response(request_data);
+// Puts back what the namespace held for `name` before a change to it.
+fn restore_entry(name: &str, previous: Option<NamespaceEntry>) -> MetadataOp {
+    match previous {
+        Some(entry) => MetadataOp::Create {
+            name: name.to_string(),
+            size: entry.size,
+            checksum: entry.checksum,
+            modified_at: entry.modified_at,
+        },
+        None => MetadataOp::Delete { name: name.to_string() },
+    }
+}
 
-    if let Err(e) = writer.write_all(response_content.as_bytes()) {
-        eprintln!("Failed to send response: {}", e);
+// Reverts a change the namespace committed but this node then failed to
+// make. If that fails too the two stay apart, which is all that can be
+// reported.
+fn undo_proposal(metadata: &RaftNode, undo: MetadataOp) {
+    if let Err(e) = metadata.propose(undo.clone()) {
+        eprintln!("Failed to revert the namespace with {:?}: {}", undo, e);
     }
-    if let Err(e) = writer.flush() { // Ensure all bytes are written to the stream
-        eprintln!("Failed to flush response: {}", e);
+}
+
+// Removes the directory from this node's storage along with, when
+// recursive, the shards and stripe descriptors of erasure-coded files
+// below it.
+fn remove_local_directory(context: &ServerContext, path: &str, recursive: bool) -> io::Result<()> {
+    let mut dfs = context.dfs.lock().unwrap();
+    let [shard_directory, stripe_directory] = erasure::internal_directories(path);
+    if !recursive && dfs.is_directory(&stripe_directory) {
+        return Err(io::Error::new(io::ErrorKind::DirectoryNotEmpty, "Directory not empty"));
+    }
+    let mut removed = dfs.remove_directory(path, recursive).map(|_| ());
+    if recursive {
+        for internal in [shard_directory, stripe_directory] {
+            match dfs.remove_directory(&internal, true) {
+                Ok(_) => removed = Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+    removed
+}
+
+// Renames this node's copy of `from`, along with the stripe descriptors and
+// shards of erasure-coded files. Returns whether there was anything here to
+// rename. Files now named differently may belong on other nodes, so the
+// rebalancer is woken to move them.
+fn rename_local(context: &ServerContext, from: &str, to: &str, overwrite: bool) -> io::Result<bool> {
+    let mut dfs = context.dfs.lock().unwrap();
+    let [from_shards, from_stripe] = erasure::internal_directories(from);
+    let [to_shards, to_stripe] = erasure::internal_directories(to);
+    let mut found = false;
+    for (from, to) in [(from.to_string(), to.to_string()), (from_stripe, to_stripe), (from_shards, to_shards)] {
+        match dfs.rename(&from, &to, overwrite) {
+            Ok(_) => found = true,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+    }
+    if found {
+        context.rebalancer.trigger();
+    }
+    Ok(found)
+}
+
+// Without the metadata service a node can only list what it holds itself.
+// Erasure-coded files are listed from their stripe descriptors, and the
+// internal directories holding those and their shards are hidden.
+fn local_listing(context: &ServerContext, path: &str, recursive: bool) -> io::Result<Vec<DirEntry>> {
+    let dfs = context.dfs.lock().unwrap();
+    let mut listed: BTreeMap<String, DirEntry> = BTreeMap::new();
+    let mut found = false;
+    for (directory, striped) in [(path.to_string(), false), (erasure::stripe_name(path), true)] {
+        let (directories, files) = match dfs.list_directory(&directory, recursive) {
+            Ok(listing) => listing,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        found = true;
+        for directory in directories {
+            // Listing the root turns up the internal directories too.
+            if !striped && erasure::is_internal(&directory.path) {
+                continue;
+            }
+            let name = erasure::listed_name(&directory.path).unwrap_or_default().to_string();
+            listed.entry(name.clone()).or_insert(DirEntry {
+                path: name,
+                kind: EntryKind::Directory,
+                size: 0,
+                checksum: None,
+                created_at: directory.created_at,
+                modified_at: directory.created_at,
+            });
+        }
+        for file in files {
+            if !striped && erasure::is_internal(&file.name) {
+                continue;
+            }
+            let mut entry = DirEntry {
+                path: file.name.clone(),
+                kind: EntryKind::File,
+                size: file.size,
+                checksum: Some(file.checksum),
+                created_at: file.created_at,
+                modified_at: file.modified_at,
+            };
+            if striped {
+                let mut contents = dfs.retrieve_files(slice::from_ref(&file.name))?;
+                let descriptor: StripeDescriptor = serde_json::from_slice(&contents.remove(&file.name).unwrap_or_default())?;
+                entry.path = erasure::listed_name(&file.name).unwrap_or_default().to_string();
+                entry.size = descriptor.size;
+                entry.checksum = Some(descriptor.checksum);
+            }
+            listed.insert(entry.path.clone(), entry);
+        }
+    }
+    if !found {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Directory not found"));
+    }
+    Ok(listed.into_values().collect())
+}
+
+// Snapshots only capture what each node holds, so a node lists its part
+// and the node asked merges them. Erasure-coded files are only captured as
+// their stripe descriptors and shards, which are hidden here like in
+// `local_listing`; they can't be browsed yet.
+fn snapshot_listing(dfs: &DistributedFileSystem, name: &str, path: &str, recursive: bool) -> io::Result<BTreeMap<String, DirEntry>> {
+    let (directories, files) = dfs.list_snapshot_directory(name, path, recursive)?;
+    let directories = directories.into_iter().map(|directory| DirEntry {
+        path: directory.path,
+        kind: EntryKind::Directory,
+        size: 0,
+        checksum: None,
+        created_at: directory.created_at,
+        modified_at: directory.created_at,
+    });
+    let files = files.into_iter().map(|file| DirEntry {
+        path: file.name,
+        kind: EntryKind::File,
+        size: file.size,
+        checksum: Some(file.checksum),
+        created_at: file.created_at,
+        modified_at: file.modified_at,
+    });
+    Ok(directories
+        .chain(files)
+        .filter(|entry| !erasure::is_internal(&entry.path))
+        .map(|entry| (entry.path.clone(), entry))
+        .collect())
+}
+
+fn snapshot_info(snapshot: &Snapshot) -> SnapshotInfo {
+    SnapshotInfo {
+        name: snapshot.name.clone(),
+        path: snapshot.root.clone(),
+        created_at: snapshot.created_at,
+        files: snapshot.files.len(),
+        size: snapshot.size(),
     }
-}
\ No newline at end of file
+}
+
+fn snapshot_change(diff: SnapshotDiff) -> SnapshotChange {
+    SnapshotChange {
+        path: diff.path,
+        kind: if diff.is_directory { EntryKind::Directory } else { EntryKind::File },
+        change: match diff.change {
+            Change::Added => ChangeKind::Added,
+            Change::Removed => ChangeKind::Removed,
+            Change::Modified => ChangeKind::Modified,
+        },
+    }
+}
+
+// With the metadata service the namespace has to follow a rollback too.
+// Only the nodes holding a file know how it changed, so each node proposes
+// its own changes; replicas of a file propose the same ones, and whichever
+// comes second finds its work done.
+fn propose_rollback(context: &ServerContext, changes: &[SnapshotDiff]) -> io::Result<()> {
+    let metadata = match context.metadata.as_ref() {
+        Some(metadata) => metadata,
+        None => return Ok(()),
+    };
+    for change in changes {
+        let operation = match (change.is_directory, change.change) {
+            (false, Change::Added) => MetadataOp::Delete { name: change.path.clone() },
+            (false, _) => {
+                let entry = context.dfs.lock().unwrap().stat(&change.path)?;
+                MetadataOp::Create { name: entry.name, size: entry.size, checksum: entry.checksum, modified_at: entry.modified_at }
+            },
+            (true, Change::Added) => MetadataOp::RemoveDirectory { path: change.path.clone(), recursive: false },
+            (true, _) => MetadataOp::MakeDirectory { path: change.path.clone(), parents: true, created_at: now_secs() },
+        };
+        match metadata.propose(operation) {
+            Ok(()) => {},
+            Err(ref e) if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::AlreadyExists | io::ErrorKind::DirectoryNotEmpty) => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// What this node knows about `filename`, from its copy or, for an
+// erasure-coded file, its stripe descriptor. The replication state only
+// covers this node; the node asked fills in the cluster's.
+fn local_metadata(context: &ServerContext, filename: &str) -> io::Result<Option<FileMetadata>> {
+    let dfs = context.dfs.lock().unwrap();
+    let (entry, descriptor) = match dfs.stat(filename) {
+        Ok(entry) => (entry, None),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            let descriptor_name = erasure::stripe_name(filename);
+            let entry = match dfs.stat(&descriptor_name) {
+                Ok(entry) => entry,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e),
+            };
+            let mut contents = dfs.retrieve_files(slice::from_ref(&descriptor_name))?;
+            let descriptor: StripeDescriptor = serde_json::from_slice(&contents.remove(&descriptor_name).unwrap_or_default())?;
+            (entry, Some(descriptor))
+        },
+        Err(e) => return Err(e),
+    };
+    let attributes = entry.attributes;
+    let (size, checksum) = match &descriptor {
+        Some(descriptor) => (descriptor.size, descriptor.checksum.clone()),
+        None => (entry.size, entry.checksum),
+    };
+    Ok(Some(FileMetadata {
+        path: filename.to_string(),
+        size,
+        checksum,
+        created_at: entry.created_at,
+        modified_at: entry.modified_at,
+        version: entry.version,
+        content_type: attributes.content_type.unwrap_or_else(|| content_type::guess(filename).to_string()),
+        owner: attributes.owner,
+        attributes: attributes.custom,
+        replication: ReplicationState { copies: 1, wanted: 1, erasure: descriptor.map(|descriptor| descriptor.scheme) },
+    }))
+}
+
+// Applies a `SetAttributes` change to this node's copy of `filename`, or
+// to the stripe descriptor of an erasure-coded file. Returns whether this
+// node holds either.
+fn set_local_attributes(
+    context: &ServerContext,
+    filename: &str,
+    content_type: &Option<String>,
+    owner: &Option<String>,
+    set: &BTreeMap<String, String>,
+    remove: &[String],
+) -> io::Result<bool> {
+    let mut dfs = context.dfs.lock().unwrap();
+    let held = vec![filename.to_string(), erasure::stripe_name(filename)].into_iter().find_map(|name| Some((dfs.stat(&name).ok()?, name)));
+    let (entry, name) = match held {
+        Some(held) => held,
+        None => return Ok(false),
+    };
+    let mut attributes = entry.attributes;
+    if let Some(content_type) = content_type {
+        attributes.content_type = Some(content_type.clone()).filter(|content_type| !content_type.is_empty());
+    }
+    if let Some(owner) = owner {
+        attributes.owner = Some(owner.clone()).filter(|owner| !owner.is_empty());
+    }
+    for key in remove {
+        attributes.custom.remove(key);
+    }
+    attributes.custom.extend(set.clone());
+    dfs.set_attributes(&name, attributes)?;
+    Ok(true)
+}
+
+// Restores whatever this node held of `filename` from its trash: the file
+// itself, or for an erasure-coded file its stripe descriptor and shards.
+// Returns how many files came back.
+fn undelete_local(context: &ServerContext, filename: &str) -> io::Result<usize> {
+    let mut dfs = context.dfs.lock().unwrap();
+    let [shards, _] = erasure::internal_directories(filename);
+    let mut names: Vec<String> = dfs
+        .list_trash()
+        .into_iter()
+        .map(|trashed| trashed.entry.name.clone())
+        .filter(|name| paths::is_under(name, &shards))
+        .collect();
+    names.sort();
+    names.dedup();
+    names.insert(0, erasure::stripe_name(filename));
+    names.insert(0, filename.to_string());
+
+    let mut restored = 0;
+    for name in names {
+        match dfs.undelete(&name) {
+            Ok(_) => restored += 1,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(restored)
+}
+
+// `filename` as this node lists it, if it holds the file or its stripe
+// descriptor.
+fn listed_entry(context: &ServerContext, filename: &str) -> Option<DirEntry> {
+    let listing = local_listing(context, paths::parent(filename)?, false).ok()?;
+    listing.into_iter().find(|entry| entry.path == filename)
+}
+
+// This node's trash as clients see it: erasure-coded files under their own
+// name, with the size and checksum their stripe descriptor records.
+fn local_trash(context: &ServerContext) -> io::Result<Vec<TrashInfo>> {
+    let dfs = context.dfs.lock().unwrap();
+    let mut listed = Vec::new();
+    for trashed in dfs.list_trash() {
+        let filename = match erasure::listed_name(&trashed.entry.name) {
+            Some(filename) => filename.to_string(),
+            None => continue,
+        };
+        let (size, checksum) = if filename == trashed.entry.name {
+            (trashed.entry.size, trashed.entry.checksum.clone())
+        } else {
+            let descriptor: StripeDescriptor = serde_json::from_slice(&dfs.retrieve_trashed(trashed.id)?)?;
+            (descriptor.size, descriptor.checksum)
+        };
+        listed.push(TrashInfo { filename, size, checksum, deleted_at: trashed.deleted_at });
+    }
+    Ok(listed)
+}
+
+// Directory changes go to every node, since any of them may hold files
+// below the directory. Nodes that can't be reached are reported, not
+// retried.
+fn broadcast(context: &ServerContext, command: &Command) -> Vec<String> {
+    let peers = context.topology.lock().unwrap().peers();
+    peers
+        .into_iter()
+        .filter_map(|(peer_id, address)| send_command(&address, command).err().map(|e| format!("{}: {}", peer_id, e)))
+        .collect()
+}
+
+// Sends `command` to every peer, returning the successful responses and
+// the peers that failed. A peer answering NotFound has nothing the command
+// applies to, which is not a failure.
+fn gather(context: &ServerContext, command: &Command) -> (Vec<ServerResponse>, Vec<String>) {
+    let mut responses = Vec::new();
+    let mut failures = Vec::new();
+    for (peer_id, address) in context.topology.lock().unwrap().peers() {
+        match request(&address, command) {
+            Ok(response) if response.is_ok() => responses.push(response),
+            Ok(response) if response.status == StatusCode::NotFound => {},
+            Ok(response) => failures.push(format!("{}: {}", peer_id, response.message)),
+            Err(e) => failures.push(format!("{}: {}", peer_id, e)),
+        }
+    }
+    (responses, failures)
+}
+
+fn with_broadcast(response: ServerResponse, failures: Vec<String>) -> ServerResponse {
+    if failures.is_empty() {
+        return response;
+    }
+    ServerResponse::error(
+        StatusCode::ReplicationFailed,
+        &format!("{}, but not every node applied it: {}", response.message, failures.join("; ")),
+    )
+}
+
+// A version missing here is only worth looking for on the owners when this
+// node has no copy of the file at all.
+fn holds_file(context: &ServerContext, filename: &str) -> bool {
+    context.dfs.lock().unwrap().stat(filename).is_ok()
+}
+
+// Reads of files this node doesn't hold are served by the first owner that
+// answers `command` successfully.
+fn fetch_from_owners(context: &ServerContext, filename: &str, command: &Command) -> ServerResponse {
+    for owner in context.replicator.owners(filename) {
+        let address = match owner.address {
+            Some(address) => address,
+            None => continue,
+        };
+        match request(&address, command) {
+            Ok(response) if response.is_ok() => return response,
+            Ok(_) => {},
+            Err(e) => eprintln!("Failed to fetch {} from {}: {}", filename, owner.id, e),
+        }
+    }
+    ServerResponse::error(StatusCode::NotFound, &format!("{}: File not found", filename))
+}
+
+// Relays a streamed download from an owner chunk by chunk. As with
+// `send_stream`, a failure after the OK response cuts the stream off.
+fn relay_stream<W: Write>(context: &ServerContext, filename: &str, writer: &mut W) -> io::Result<()> {
+    for owner in context.replicator.owners(filename) {
+        let address = match owner.address {
+            Some(address) => address,
+            None => continue,
+        };
+        let (remote, response) = match open_remote_stream(&address, filename) {
+            Ok(opened) => opened,
+            Err(e) => {
+                eprintln!("Failed to fetch {} from {}: {}", filename, owner.id, e);
+                continue;
+            }
+        };
+        write_message(writer, &response)?;
+        let mut remote = BufReader::new(remote);
+        let mut chunks = ChunkWriter::new(writer);
+        io::copy(&mut ChunkReader::new(&mut remote), &mut chunks)?;
+        return chunks.finish();
+    }
+    match fetch_striped(context, filename) {
+        Ok((data, descriptor)) => {
+            let response = ServerResponse::ok(&format!("Streaming {}", filename))
+                .with_size(descriptor.size)
+                .with_checksum(descriptor.checksum);
+            write_message(writer, &response)?;
+            let mut chunks = ChunkWriter::new(writer);
+            chunks.write_all(&data)?;
+            chunks.finish()
+        },
+        Err(e) => write_message(writer, &error_response(filename, e)),
+    }
+}
+
+// Files with no whole copy anywhere may be erasure-coded; those are rebuilt
+// from their shards.
+fn fetch_striped(context: &ServerContext, filename: &str) -> io::Result<(Vec<u8>, StripeDescriptor)> {
+    let descriptor = erasure::find_stripe(&context.dfs, &context.replicator, filename)?
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
+    let data = erasure::read_striped(&context.dfs, &context.replicator, filename, &descriptor)?;
+    Ok((data, descriptor))
+}
+
+// The local change is kept whether or not replication meets its ack policy;
+// a shortfall is reported so the client knows the write isn't yet durable.
+fn with_replication(response: ServerResponse, outcome: ReplicationOutcome) -> ServerResponse {
+    if outcome.under_replicated {
+        eprintln!("Fewer nodes available than the replication factor; {} copies written", outcome.acked);
+    }
+    if outcome.is_satisfied() {
+        return response;
+    }
+    ServerResponse::error(
+        StatusCode::ReplicationFailed,
+        &format!(
+            "{}, but only {} of {} required copies were acknowledged: {}",
+            response.message,
+            outcome.acked,
+            outcome.required,
+            outcome.failures.join("; ")
+        ),
+    )
+}
+
+// Shards that couldn't be placed are reported. The file is then kept whole
+// until the rebalancer manages to store every shard.
+fn with_striping(response: ServerResponse, outcome: erasure::StripeOutcome) -> ServerResponse {
+    let total = outcome.scheme.total();
+    if outcome.nodes < total {
+        eprintln!("Fewer nodes available than shards; {} shards share {} nodes", total, outcome.nodes);
+    }
+    if outcome.failures.is_empty() {
+        return response;
+    }
+    ServerResponse::error(
+        StatusCode::ReplicationFailed,
+        &format!(
+            "{}, but only {} of {} shards were stored{}: {}",
+            response.message,
+            outcome.stored,
+            total,
+            if outcome.striped { "" } else { ", so the full copy was kept" },
+            outcome.failures.join("; ")
+        ),
+    )
+}
+
+// The storage lock is only held to open and commit the file, never while
+// chunks are in flight, so one slow transfer doesn't stall other clients.
+// Errors returned from here are connection-level; storage failures are
+// reported to the client in the response.
+fn receive_stream<R: Read>(
+    context: &ServerContext,
+    filename: &str,
+    size: u64,
+    checksum: Option<String>,
+    attributes: Option<FileAttributes>,
+    forwarded: bool,
+    reader: &mut R,
+) -> io::Result<ServerResponse> {
+    let dfs = &context.dfs;
+    let mut chunks = ChunkReader::new(reader);
+    let mut file_writer = match dfs.lock().unwrap().create_writer(filename) {
+        Ok(file_writer) => file_writer,
+        Err(e) => {
+            chunks.drain()?;
+            return Ok(error_response(filename, e));
+        }
+    };
+    if let Some(checksum) = checksum.as_ref() {
+        file_writer.expect_checksum(checksum);
+    }
+    if let Some(attributes) = attributes {
+        file_writer.set_attributes(attributes);
+    }
+
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    loop {
+        let count = match chunks.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(e) => {
+                let _ = dfs.lock().unwrap().abort_writer(file_writer);
+                return Err(e);
+            }
+        };
+        if let Err(e) = file_writer.write_all(&buffer[..count]) {
+            let _ = dfs.lock().unwrap().abort_writer(file_writer);
+            chunks.drain()?;
+            return Ok(error_response(filename, e));
+        }
+    }
+
+    if file_writer.bytes_written() != size {
+        let message = format!("{}: expected {} bytes, received {}", filename, size, file_writer.bytes_written());
+        let _ = dfs.lock().unwrap().abort_writer(file_writer);
+        return Ok(ServerResponse::error(StatusCode::BadRequest, &message));
+    }
+
+    let prepared = dfs.lock().unwrap().prepare_writer(file_writer);
+    match prepared.and_then(|prepared| commit_upload(context, prepared, filename, forwarded)) {
+        Ok(entry) => Ok(ServerResponse::ok(&format!("Uploaded {}", filename))
+            .with_size(entry.size)
+            .with_checksum(entry.checksum)),
+        Err(e) => Ok(error_response(filename, e)),
+    }
+}
+
+// Chunks are verified as they are read from disk. If one turns out to be
+// corrupt after the OK response has gone out, the stream is cut off without
+// its terminating frame so the client can't mistake it for a complete file.
+fn send_stream<W: Write>(dfs: &Mutex<DistributedFileSystem>, filename: &str, writer: &mut W) -> io::Result<()> {
+    let opened = {
+        let dfs = dfs.lock().unwrap();
+        dfs.open_reader(filename).and_then(|(file, _)| Ok((file, dfs.stat(filename)?)))
+    };
+    let (mut file, entry) = match opened {
+        Ok(opened) => opened,
+        Err(e) => return write_message(writer, &error_response(filename, e)),
+    };
+
+    let response = ServerResponse::ok(&format!("Streaming {}", filename))
+        .with_size(entry.size)
+        .with_checksum(entry.checksum);
+    write_message(writer, &response)?;
+    let mut chunks = ChunkWriter::new(writer);
+    io::copy(&mut file, &mut chunks)?;
+    chunks.finish()
+}
+
+fn error_response(filename: &str, error: io::Error) -> ServerResponse {
+    let status = match error.kind() {
+        io::ErrorKind::NotFound => StatusCode::NotFound,
+        io::ErrorKind::AlreadyExists => StatusCode::AlreadyExists,
+        io::ErrorKind::NotADirectory => StatusCode::NotADirectory,
+        io::ErrorKind::IsADirectory => StatusCode::IsADirectory,
+        io::ErrorKind::DirectoryNotEmpty => StatusCode::DirectoryNotEmpty,
+        io::ErrorKind::TimedOut | io::ErrorKind::NotConnected => StatusCode::Unavailable,
+        io::ErrorKind::InvalidInput => StatusCode::BadRequest,
+        io::ErrorKind::InvalidData => StatusCode::ChecksumMismatch,
+        _ => StatusCode::InternalError,
+    };
+    ServerResponse::error(status, &format!("{}: {}", filename, error))
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
@@ -0,0 +1,153 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+
+use super::atomic_file::{sync_directory, write_atomic};
+use super::metadata::{DirectoryIndex, FileIndex};
+use super::paths::{self, SafePath};
+
+const SNAPSHOT_DIR: &str = "snapshots";
+
+/// A read-only copy of the index for `root` and everything below it, as it
+/// was at `created_at`. Manifests are never rewritten, so the snapshot can
+/// share them, and through them the chunks, with the live file system:
+/// taking one copies no file data. Only the current version of each file
+/// is captured.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    /// The directory captured; `""` for the whole file system.
+    pub root: String,
+    pub created_at: u64,
+    pub files: FileIndex,
+    pub directories: DirectoryIndex,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    /// Exists now but not in the snapshot.
+    Added,
+    /// Exists in the snapshot but not any more.
+    Removed,
+    /// A file whose content differs from the snapshot's.
+    Modified,
+}
+
+/// One difference between a snapshot and the live file system. Only
+/// explicitly created directories are compared; the rest come and go with
+/// their files.
+#[derive(Debug, Clone)]
+pub struct SnapshotDiff {
+    pub path: String,
+    pub is_directory: bool,
+    pub change: Change,
+}
+
+impl Snapshot {
+    pub fn capture(name: &str, root: &str, created_at: u64, entries: &FileIndex, directories: &DirectoryIndex) -> Self {
+        let files = entries
+            .values()
+            .filter(|entry| paths::is_under(&entry.name, root))
+            .map(|entry| {
+                let mut entry = entry.clone();
+                entry.history.clear();
+                (entry.name.clone(), entry)
+            })
+            .collect();
+        let directories = directories
+            .values()
+            .filter(|directory| directory.path == root || paths::is_under(&directory.path, root))
+            .map(|directory| (directory.path.clone(), directory.clone()))
+            .collect();
+        Self {
+            name: name.to_string(),
+            root: root.to_string(),
+            created_at,
+            files,
+            directories,
+        }
+    }
+
+    pub fn size(&self) -> u64 {
+        self.files.values().map(|entry| entry.size).sum()
+    }
+
+    /// What changed below the snapshot's root since it was taken, in path
+    /// order.
+    pub fn diff(&self, entries: &FileIndex, directories: &DirectoryIndex) -> Vec<SnapshotDiff> {
+        let mut changes = Vec::new();
+        for entry in entries.values().filter(|entry| paths::is_under(&entry.name, &self.root)) {
+            let change = match self.files.get(&entry.name) {
+                None => Change::Added,
+                Some(captured) if captured.checksum != entry.checksum => Change::Modified,
+                Some(_) => continue,
+            };
+            changes.push(SnapshotDiff { path: entry.name.clone(), is_directory: false, change });
+        }
+        for name in self.files.keys().filter(|name| !entries.contains_key(*name)) {
+            changes.push(SnapshotDiff { path: name.clone(), is_directory: false, change: Change::Removed });
+        }
+
+        let live: HashSet<&String> = directories
+            .keys()
+            .filter(|path| **path == self.root || paths::is_under(path, &self.root))
+            .collect();
+        for path in live.iter().filter(|path| !self.directories.contains_key(**path)) {
+            changes.push(SnapshotDiff { path: path.to_string(), is_directory: true, change: Change::Added });
+        }
+        for path in self.directories.keys().filter(|path| !live.contains(path)) {
+            changes.push(SnapshotDiff { path: path.clone(), is_directory: true, change: Change::Removed });
+        }
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        changes
+    }
+}
+
+/// Snapshot names end up as file names, so they have to be valid path
+/// segments.
+pub fn validate_name(name: &str) -> io::Result<()> {
+    if SafePath::file(name)?.as_str() != name || name.contains('/') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("'{}' is not a valid snapshot name", name)));
+    }
+    Ok(())
+}
+
+/// Keeps each snapshot as a JSON file under `base_dir/snapshots`.
+pub struct SnapshotStore {
+    dir: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn open<P: AsRef<Path>>(base_dir: P) -> io::Result<(Self, HashMap<String, Snapshot>)> {
+        let dir = base_dir.as_ref().join(SNAPSHOT_DIR);
+        fs::create_dir_all(&dir)?;
+        let mut snapshots = HashMap::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            // Anything else is an interrupted save.
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let snapshot: Snapshot = serde_json::from_slice(&fs::read(&path)?).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("Corrupt snapshot {}: {}", path.display(), e))
+            })?;
+            snapshots.insert(snapshot.name.clone(), snapshot);
+        }
+        Ok((Self { dir }, snapshots))
+    }
+
+    pub fn save(&self, snapshot: &Snapshot) -> io::Result<()> {
+        write_atomic(self.path(&snapshot.name), &serde_json::to_vec(snapshot)?)
+    }
+
+    pub fn remove(&self, name: &str) -> io::Result<()> {
+        fs::remove_file(self.path(name))?;
+        sync_directory(&self.dir)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+}
@@ -1,68 +1,1172 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
-use std::fs::{self, File};
+use std::fs;
 use std::io::{self, Read, Write};
-use std::path::PathBuf;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+pub(crate) mod atomic_file;
+// The sidecar half is for the command tool and the HTTP handler.
+#[allow(dead_code)]
+pub(crate) mod attributes;
+pub(crate) mod checksum;
+mod chunk_store;
+mod metadata;
+// Shared with the command tool and the HTTP handler, which use more of it.
+#[allow(dead_code)]
+pub(crate) mod paths;
+#[cfg(test)]
+pub(crate) mod scratch;
+pub mod scrubber;
+pub mod snapshot;
+mod utils {
+    #[allow(dead_code)]
+    pub mod logging;
+}
+
+use atomic_file::write_atomic;
+use checksum::StreamingChecksum;
+use chunk_store::{read_chunk, ChunkStore, ChunkedReader, Manifest, CHUNK_SIZE};
+use metadata::{
+    now_secs, rename_entries, DirectoryEntry, DirectoryIndex, FileIndex, FileVersion, MetadataStore, NamespaceChange, TrashEntry,
+    TrashIndex,
+};
+pub use attributes::FileAttributes;
+pub use metadata::FileEntry;
+use paths::SafePath;
+use snapshot::{Change, Snapshot, SnapshotDiff, SnapshotStore};
 
 const BASE_DIR_ENV_KEY: &str = "DFS_BASE_DIR";
+const DEFAULT_KEEP_VERSIONS: usize = 10;
+const DEFAULT_TRASH_DAYS: u64 = 30;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
 
-struct DistributedFileSystem {
-    storage_paths: HashMap<String, String>,
+/// File contents live in a content-addressed chunk store under
+/// `base_dir/chunks`; each file name maps to a manifest under
+/// `base_dir/manifests` listing its chunks in order. The name -> entry index
+/// is persisted in `base_dir/metadata.log` and replayed by `new`.
+///
+/// Names are slash-separated paths. A directory exists if it was created
+/// explicitly or if anything has been stored below it.
+///
+/// Writing a file makes a new version of it; earlier versions keep their
+/// manifests until the retention policy lets them go. Snapshots, kept under
+/// `base_dir/snapshots`, hold on to the manifests they refer to as well.
+/// Deleted files go to the trash, versions and all, until it is purged.
+pub struct DistributedFileSystem {
+    entries: HashMap<String, FileEntry>,
+    directories: DirectoryIndex,
+    trash: TrashIndex,
     base_dir: String,
+    chunk_store: Arc<Mutex<ChunkStore>>,
+    metadata: MetadataStore,
+    retention: RetentionPolicy,
+    snapshots: HashMap<String, Snapshot>,
+    snapshot_store: SnapshotStore,
+    // Number of the next manifest path handed out.
+    next_manifest: AtomicU64,
+}
+
+/// How long earlier versions of a file are kept. The current version is
+/// always kept, and counts towards `keep_last`. With `keep_days`, a version
+/// also goes once it has been superseded for that long. Deleted files are
+/// purged from the trash after `trash_days`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_days: Option<u64>,
+    pub trash_days: u64,
+}
+
+impl RetentionPolicy {
+    /// Reads `DFS_KEEP_VERSIONS` (default 10), `DFS_KEEP_VERSIONS_DAYS`
+    /// (unset means no age limit) and `DFS_TRASH_DAYS` (default 30).
+    pub fn from_env() -> Result<Self, String> {
+        let keep_last = match env::var("DFS_KEEP_VERSIONS") {
+            Ok(value) => match value.parse::<usize>() {
+                Ok(count) if count > 0 => count,
+                _ => return Err(format!("DFS_KEEP_VERSIONS must be a positive number, got '{}'", value)),
+            },
+            Err(_) => DEFAULT_KEEP_VERSIONS,
+        };
+        let keep_days = match env::var("DFS_KEEP_VERSIONS_DAYS") {
+            Ok(value) => Some(value.parse::<u64>().map_err(|_| format!("Invalid DFS_KEEP_VERSIONS_DAYS '{}'", value))?),
+            Err(_) => None,
+        };
+        let trash_days = match env::var("DFS_TRASH_DAYS") {
+            Ok(value) => value.parse::<u64>().map_err(|_| format!("Invalid DFS_TRASH_DAYS '{}'", value))?,
+            Err(_) => DEFAULT_TRASH_DAYS,
+        };
+        Ok(Self { keep_last, keep_days, trash_days })
+    }
+
+    // Drops the versions of `entry` the policy no longer keeps, returning
+    // them so their content can be freed.
+    fn prune(&self, entry: &mut FileEntry, now: u64) -> Vec<FileVersion> {
+        let mut superseded_at = entry.modified_at;
+        let mut expired = Vec::new();
+        let mut kept = Vec::new();
+        for (index, version) in entry.history.drain(..).enumerate() {
+            let too_many = index + 1 >= self.keep_last;
+            let too_old = self.keep_days.is_some_and(|days| now.saturating_sub(superseded_at) > days * SECS_PER_DAY);
+            superseded_at = version.modified_at;
+            if too_many || too_old {
+                expired.push(version);
+            } else {
+                kept.push(version);
+            }
+        }
+        entry.history = kept;
+        expired
+    }
+}
+
+/// The outcome for each item of a best-effort batch, in request order.
+#[derive(Debug)]
+pub struct BatchReport<T> {
+    pub items: Vec<BatchItem<T>>,
+}
+
+#[derive(Debug)]
+pub struct BatchItem<T> {
+    pub name: String,
+    pub result: io::Result<T>,
+}
+
+impl<T> BatchReport<T> {
+    // The best-effort batches and version restore are library surface the
+    // server does not call yet.
+    #[allow(dead_code)]
+    pub fn is_complete(&self) -> bool {
+        self.items.iter().all(|item| item.result.is_ok())
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &BatchItem<T>> {
+        self.items.iter().filter(|item| item.result.is_err())
+    }
+}
+
+// A written file whose manifest is saved but which isn't in the index yet.
+// Without `attributes` it keeps those of the file it replaces.
+struct StagedFile {
+    entry: FileEntry,
+    chunks: Vec<String>,
+    attributes: Option<FileAttributes>,
+}
+
+/// A write that is complete and checked but not visible yet. It is made
+/// visible with `DistributedFileSystem::commit_prepared` or thrown away with
+/// `discard_prepared`, so something else, like the metadata service, can
+/// agree to it first.
+pub struct PreparedFile {
+    staged: StagedFile,
+}
+
+impl PreparedFile {
+    pub fn size(&self) -> u64 {
+        self.staged.entry.size
+    }
+
+    pub fn checksum(&self) -> &str {
+        &self.staged.entry.checksum
+    }
+
+    pub fn modified_at(&self) -> u64 {
+        self.staged.entry.modified_at
+    }
+}
+
+// What happens to the files a change removes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Removal {
+    Trash,
+    Purge,
+}
+
+/// An in-progress streamed write. Data is cut into chunks and handed to the
+/// chunk store as it arrives; the file only becomes visible through the file
+/// system once it is handed back to `DistributedFileSystem::commit_writer`.
+pub struct FileWriter {
+    file_name: String,
+    chunk_store: Arc<Mutex<ChunkStore>>,
+    buffer: Vec<u8>,
+    manifest: Manifest,
+    checksum: StreamingChecksum,
+    expected_checksum: Option<String>,
+    attributes: Option<FileAttributes>,
+}
+
+impl FileWriter {
+    pub fn bytes_written(&self) -> u64 {
+        self.manifest.size + self.buffer.len() as u64
+    }
+
+    /// Makes `commit_writer` reject the file unless its content hashes to
+    /// `checksum`, e.g. the value the uploading client computed.
+    pub fn expect_checksum(&mut self, checksum: &str) {
+        self.expected_checksum = Some(checksum.to_string());
+    }
+
+    /// Gives the file these attributes instead of keeping those of the
+    /// version it replaces.
+    pub fn set_attributes(&mut self, attributes: FileAttributes) {
+        self.attributes = Some(attributes);
+    }
+
+    fn store_buffered_chunk(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let hash = self.chunk_store.lock().unwrap().put(&self.buffer)?;
+        self.manifest.chunks.push(hash);
+        self.manifest.size += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+}
+
+impl Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..count]);
+        self.checksum.update(&buf[..count]);
+        if self.buffer.len() == CHUNK_SIZE {
+            self.store_buffered_chunk()?;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.store_buffered_chunk()
+    }
 }
 
 impl DistributedFileSystem {
-    fn new() -> Self {
+    pub fn new() -> io::Result<Self> {
         let base_dir = env::var(BASE_DIR_ENV_KEY).unwrap_or_else(|_| "./data".to_string());
-        Self {
-            storage_paths: HashMap::new(),
+        let retention = RetentionPolicy::from_env().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Self::open(base_dir, retention)
+    }
+
+    /// Opens the file system kept under `base_dir`, as `new` does with the
+    /// directory and policy from the environment.
+    pub fn open(base_dir: String, retention: RetentionPolicy) -> io::Result<Self> {
+        let (metadata, entries, directories, trash) = MetadataStore::open(&base_dir)?;
+
+        let (snapshot_store, snapshots) = SnapshotStore::open(&base_dir)?;
+
+        // A manifest holds one reference to each of its chunks, however many
+        // versions and snapshots share it.
+        let mut chunk_store = ChunkStore::new(Path::new(&base_dir).join("chunks"));
+        let manifest_paths: HashSet<String> = entries
+            .values()
+            .chain(trash.values().map(|trashed| &trashed.entry))
+            .flat_map(FileEntry::versions)
+            .map(|version| version.path)
+            .chain(snapshots.values().flat_map(|snapshot| snapshot.files.values().map(|entry| entry.path.clone())))
+            .collect();
+        for path in &manifest_paths {
+            let manifest = load_manifest(path)?;
+            chunk_store.retain(&manifest.chunks);
+        }
+
+        let next_manifest = first_free_manifest_number(&Path::new(&base_dir).join("manifests"))?;
+
+        let mut dfs = Self {
+            entries,
+            directories,
+            trash,
             base_dir,
+            chunk_store: Arc::new(Mutex::new(chunk_store)),
+            metadata,
+            retention,
+            snapshots,
+            snapshot_store,
+            next_manifest: AtomicU64::new(next_manifest),
+        };
+        // The policy may have changed, or versions and deleted files aged
+        // out, since the last run.
+        dfs.prune_versions()?;
+        dfs.purge_trash()?;
+        Ok(dfs)
+    }
+
+    /// Every version of a file, the current one first.
+    pub fn list_versions(&self, file_name: &str) -> io::Result<Vec<FileVersion>> {
+        Ok(self.stat(file_name)?.versions())
+    }
+
+    pub fn open_version_reader(&self, file_name: &str, version: u64) -> io::Result<(ChunkedReader, u64)> {
+        let version = self.find_version(file_name, version)?;
+        let manifest = load_manifest(&version.path)?;
+        let size = manifest.size;
+        let root = self.chunk_store.lock().unwrap().root().to_path_buf();
+        Ok((ChunkedReader::new(&root, manifest, &version.checksum), size))
+    }
+
+    pub fn retrieve_version(&self, file_name: &str, version: u64) -> io::Result<Vec<u8>> {
+        let (mut reader, size) = self.open_version_reader(file_name, version)?;
+        let mut content = Vec::with_capacity(size as usize);
+        reader.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Makes an earlier version's content current again. This is a write
+    /// like any other: it creates a new version, and the one it replaces
+    /// stays in the history. Returns the new version's id.
+    #[allow(dead_code)]
+    pub fn restore_version(&mut self, file_name: &str, version: u64) -> io::Result<u64> {
+        let restored = self.find_version(file_name, version)?;
+        let current = self.stat(file_name)?;
+        if restored.version == current.version {
+            return Ok(current.version);
         }
+
+        let file = self.stage_copy(file_name, &restored)?;
+        self.publish(vec![file], &[], Removal::Purge)?;
+        self.stat(file_name).map(|entry| entry.version)
     }
 
-    fn store_files(&mut self, files: &[(String, Vec<u8>)]) -> io::Result<()> {
-        for (file_name, content) in files {
-            let file_path = self.generate_file_path(file_name);
-            let mut file = File::create(&file_path)?;
-            file.write_all(content)?;
-            self.storage_paths.insert(file_name.to_string(), file_path);
+    /// Applies the retention policy to every file, for versions that have
+    /// aged out since the file was last written.
+    pub fn prune_versions(&mut self) -> io::Result<()> {
+        let now = now_secs();
+        let mut pruned = Vec::new();
+        let mut expired = Vec::new();
+        for entry in self.entries.values() {
+            let mut entry = entry.clone();
+            let dropped = self.retention.prune(&mut entry, now);
+            if !dropped.is_empty() {
+                expired.extend(dropped);
+                pruned.push(entry);
+            }
         }
+        if pruned.is_empty() {
+            return Ok(());
+        }
+        let previous: Vec<FileEntry> = pruned.iter().filter_map(|entry| self.entries.insert(entry.name.clone(), entry.clone())).collect();
+        if let Err(e) = self.metadata.record_batch(&pruned, &[], &[], &self.entries, &self.directories, &self.trash) {
+            for entry in previous {
+                self.entries.insert(entry.name.clone(), entry);
+            }
+            return Err(e);
+        }
+        self.free_versions(expired);
         Ok(())
     }
 
-    fn retrieve_files(&self, file_names: &[String]) -> io::Result<HashMap<String, Vec<u8>>> {
+    /// Files in the trash, most recently deleted first.
+    pub fn list_trash(&self) -> Vec<&TrashEntry> {
+        let mut trashed: Vec<&TrashEntry> = self.trash.values().collect();
+        trashed.sort_by_key(|entry| Reverse((entry.deleted_at, entry.id)));
+        trashed
+    }
+
+    pub fn retrieve_trashed(&self, id: u64) -> io::Result<Vec<u8>> {
+        let trashed = self.trash.get(&id).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Not in the trash"))?;
+        let manifest = load_manifest(&trashed.entry.path)?;
+        let root = self.chunk_store.lock().unwrap().root().to_path_buf();
+        let mut content = Vec::with_capacity(manifest.size as usize);
+        ChunkedReader::new(&root, manifest, &trashed.entry.checksum).read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Brings the most recently deleted file named `file_name` back, with
+    /// all its versions. Nothing may have taken its place in the meantime.
+    pub fn undelete(&mut self, file_name: &str) -> io::Result<FileEntry> {
+        let id = self
+            .list_trash()
+            .into_iter()
+            .find(|trashed| trashed.entry.name == file_name)
+            .map(|trashed| trashed.id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "Not in the trash"))?;
+        if self.entries.contains_key(file_name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "File exists"));
+        }
+        if self.is_directory(file_name) {
+            return Err(io::Error::new(io::ErrorKind::IsADirectory, "Is a directory"));
+        }
+        self.check_parents(file_name)?;
+
+        let trashed = self.trash.remove(&id).expect("trash entry found above");
+        self.entries.insert(file_name.to_string(), trashed.entry.clone());
+        if let Err(e) = self.metadata.record_undelete(id, &self.entries, &self.directories, &self.trash) {
+            self.entries.remove(file_name);
+            self.trash.insert(id, trashed);
+            return Err(e);
+        }
+        Ok(trashed.entry)
+    }
+
+    /// Frees the files that have been in the trash longer than the
+    /// retention policy allows. Returns how many were purged.
+    pub fn purge_trash(&mut self) -> io::Result<usize> {
+        let cutoff = now_secs().saturating_sub(self.retention.trash_days * SECS_PER_DAY);
+        let expired: Vec<u64> = self.trash.values().filter(|trashed| trashed.deleted_at < cutoff).map(|trashed| trashed.id).collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        let purged: Vec<TrashEntry> = expired.iter().filter_map(|id| self.trash.remove(id)).collect();
+        if let Err(e) = self.metadata.record_purge(&expired, &self.entries, &self.directories, &self.trash) {
+            for trashed in purged {
+                self.trash.insert(trashed.id, trashed);
+            }
+            return Err(e);
+        }
+        self.free_versions(purged.iter().flat_map(|trashed| trashed.entry.versions()).collect());
+        Ok(purged.len())
+    }
+
+    /// Stores every file or none of them. All contents are written before
+    /// a single metadata record makes them visible together; if anything
+    /// fails first, what was already written is discarded.
+    pub fn store_files(&mut self, files: &[(String, Vec<u8>)]) -> io::Result<()> {
+        let mut staged: Vec<StagedFile> = Vec::new();
+        for (file_name, content) in files {
+            let result = self.create_writer(file_name).and_then(|writer| {
+                if staged.iter().any(|file| file.entry.name == writer.file_name) {
+                    self.abort_writer(writer)?;
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} appears twice in the batch", file_name)));
+                }
+                self.write_and_stage(writer, content)
+            });
+            match result {
+                Ok(file) => staged.push(file),
+                Err(e) => {
+                    self.discard(staged);
+                    return Err(e);
+                },
+            }
+        }
+        self.publish(staged, &[], Removal::Purge)
+    }
+
+    /// Stores each file on its own, so one failure doesn't stop the rest.
+    #[allow(dead_code)]
+    pub fn store_files_best_effort(&mut self, files: &[(String, Vec<u8>)]) -> BatchReport<()> {
+        let items = files
+            .iter()
+            .map(|(file_name, content)| {
+                let result = self.create_writer(file_name).and_then(|writer| {
+                    let file = self.write_and_stage(writer, content)?;
+                    self.publish(vec![file], &[], Removal::Purge)
+                });
+                BatchItem { name: file_name.clone(), result }
+            })
+            .collect();
+        BatchReport { items }
+    }
+
+    /// Reads every file, failing on the first one that can't be read.
+    /// Reads change nothing, so there is nothing to roll back.
+    pub fn retrieve_files(&self, file_names: &[String]) -> io::Result<HashMap<String, Vec<u8>>> {
         let mut contents = HashMap::new();
         for file_name in file_names {
-            if let Some(path) = self.storage_paths.get(file_name) {
-                let mut file = File::open(path)?;
-                let mut content = Vec::new();
-                file.read_to_end(&mut content)?;
-                contents.insert(file_name.clone(), content);
-            } else {
-                return Err(io::Error::new(io::ErrorKind::NotFound, "File not found"));
-            }
+            contents.insert(file_name.clone(), self.read_file(file_name)?);
         }
         Ok(contents)
     }
 
-    fn delete_files(&mut self, file_names: &[String]) -> io::Result<()> {
+    pub fn retrieve_files_best_effort(&self, file_names: &[String]) -> BatchReport<Vec<u8>> {
+        let items = file_names
+            .iter()
+            .map(|file_name| BatchItem { name: file_name.clone(), result: self.read_file(file_name) })
+            .collect();
+        BatchReport { items }
+    }
+
+    /// Deletes every file or none of them: all names are checked before a
+    /// single metadata record moves them to the trash together.
+    pub fn delete_files(&mut self, file_names: &[String]) -> io::Result<()> {
+        self.remove_files(file_names, Removal::Trash)
+    }
+
+    /// Deletes files for good, bypassing the trash: for copies that only
+    /// move elsewhere, not for deletes a user asked for.
+    pub fn purge_files(&mut self, file_names: &[String]) -> io::Result<()> {
+        self.remove_files(file_names, Removal::Purge)
+    }
+
+    fn remove_files(&mut self, file_names: &[String], removal: Removal) -> io::Result<()> {
+        let mut seen = HashSet::new();
         for file_name in file_names {
-            if let Some(path) = self.storage_paths.remove(file_name) {
-                fs::remove_file(path)?;
-            } else {
+            if !self.entries.contains_key(file_name) {
                 return Err(io::Error::new(io::ErrorKind::NotFound, "File not found"));
             }
+            if !seen.insert(file_name) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} appears twice in the batch", file_name)));
+            }
+        }
+        self.publish(Vec::new(), file_names, removal)
+    }
+
+    #[allow(dead_code)]
+    pub fn delete_files_best_effort(&mut self, file_names: &[String]) -> BatchReport<()> {
+        let items = file_names
+            .iter()
+            .map(|file_name| BatchItem { name: file_name.clone(), result: self.delete_files(std::slice::from_ref(file_name)) })
+            .collect();
+        BatchReport { items }
+    }
+
+    pub fn create_writer(&self, file_name: &str) -> io::Result<FileWriter> {
+        let file_name = SafePath::file(file_name)?.into_string();
+        if self.is_directory(&file_name) {
+            return Err(io::Error::new(io::ErrorKind::IsADirectory, "Is a directory"));
+        }
+        self.check_parents(&file_name)?;
+        Ok(FileWriter {
+            file_name,
+            chunk_store: Arc::clone(&self.chunk_store),
+            buffer: Vec::new(),
+            manifest: Manifest::default(),
+            checksum: StreamingChecksum::new(),
+            expected_checksum: None,
+            attributes: None,
+        })
+    }
+
+    pub fn commit_writer(&mut self, writer: FileWriter) -> io::Result<u64> {
+        let file = self.stage_writer(writer)?;
+        let size = file.entry.size;
+        self.publish(vec![file], &[], Removal::Purge)?;
+        Ok(size)
+    }
+
+    /// Finishes a writer like `commit_writer`, but leaves the file to be
+    /// committed or discarded later.
+    pub fn prepare_writer(&mut self, writer: FileWriter) -> io::Result<PreparedFile> {
+        let staged = self.stage_writer(writer)?;
+        Ok(PreparedFile { staged })
+    }
+
+    /// Prepares `version` of `file_name` as a new write of it, like
+    /// `restore_version` does. `None` when it is already the current one.
+    pub fn prepare_version(&mut self, file_name: &str, version: u64) -> io::Result<Option<PreparedFile>> {
+        let restored = self.find_version(file_name, version)?;
+        if restored.version == self.stat(file_name)?.version {
+            return Ok(None);
+        }
+        let staged = self.stage_copy(file_name, &restored)?;
+        Ok(Some(PreparedFile { staged }))
+    }
+
+    pub fn commit_prepared(&mut self, prepared: PreparedFile) -> io::Result<()> {
+        self.publish(vec![prepared.staged], &[], Removal::Purge)
+    }
+
+    pub fn discard_prepared(&mut self, prepared: PreparedFile) {
+        self.discard(vec![prepared.staged]);
+    }
+
+    pub fn abort_writer(&self, mut writer: FileWriter) -> io::Result<()> {
+        writer.buffer.clear();
+        self.chunk_store.lock().unwrap().release(&writer.manifest.chunks)
+    }
+
+    pub fn open_reader(&self, file_name: &str) -> io::Result<(ChunkedReader, u64)> {
+        match self.entries.get(file_name) {
+            Some(entry) => {
+                let manifest = load_manifest(&entry.path)?;
+                let size = manifest.size;
+                let root = self.chunk_store.lock().unwrap().root().to_path_buf();
+                Ok((ChunkedReader::new(&root, manifest, &entry.checksum), size))
+            },
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "File not found")),
+        }
+    }
+
+    /// A chunk by its hash, checked against it.
+    pub fn read_chunk(&self, hash: &str) -> io::Result<Vec<u8>> {
+        // Hashes become paths, so nothing but a hash may get that far.
+        if hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a chunk hash"));
+        }
+        let root = self.chunk_store.lock().unwrap().root().to_path_buf();
+        read_chunk(&root, hash)
+    }
+
+    pub fn stat(&self, file_name: &str) -> io::Result<FileEntry> {
+        self.entries
+            .get(file_name)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not found"))
+    }
+
+    /// Replaces the attributes of `file_name`. Its content stays as it is.
+    pub fn set_attributes(&mut self, file_name: &str, attributes: FileAttributes) -> io::Result<()> {
+        let entry = self.entries.get_mut(file_name).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
+        let previous = std::mem::replace(&mut entry.attributes, attributes);
+        let entry = entry.clone();
+        if let Err(e) = self.metadata.record_batch(&[entry], &[], &[], &self.entries, &self.directories, &self.trash) {
+            if let Some(entry) = self.entries.get_mut(file_name) {
+                entry.attributes = previous;
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub fn list_files(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.entries.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Whether `path` is a directory: the root, one created explicitly, or
+    /// one with files or directories below it.
+    pub fn is_directory(&self, path: &str) -> bool {
+        is_directory_in(&self.entries, &self.directories, path)
+    }
+
+    /// Creates the directory at `path`. Its parent has to exist unless
+    /// `parents` is set, in which case missing ancestors are created too.
+    pub fn create_directory(&mut self, path: &str, parents: bool) -> io::Result<()> {
+        let path = paths::normalize(path)?;
+        if self.entries.contains_key(&path) || self.is_directory(&path) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, "Already exists"));
+        }
+        self.check_parents(&path)?;
+
+        let mut missing = vec![path.clone()];
+        let mut current = paths::parent(&path);
+        while let Some(ancestor) = current.filter(|ancestor| !self.is_directory(ancestor)) {
+            missing.push(ancestor.to_string());
+            current = paths::parent(ancestor);
+        }
+        if missing.len() > 1 && !parents {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Parent directory not found"));
+        }
+
+        let now = now_secs();
+        let made = missing.into_iter().rev().map(|path| DirectoryEntry { path, created_at: now }).collect();
+        self.publish_with_directories(Vec::new(), &[], Removal::Purge, made, Vec::new())
+    }
+
+    /// Removes the directory at `path`, which has to be empty unless
+    /// `recursive` is set. Returns the files deleted along with it, which
+    /// go to the trash.
+    pub fn remove_directory(&mut self, path: &str, recursive: bool) -> io::Result<Vec<String>> {
+        let path = paths::normalize(path)?;
+        if path.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The root directory can't be removed"));
+        }
+        if self.entries.contains_key(&path) {
+            return Err(io::Error::new(io::ErrorKind::NotADirectory, "Not a directory"));
+        }
+        if !self.is_directory(&path) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Directory not found"));
+        }
+
+        let files: Vec<String> = self.entries.keys().filter(|name| paths::is_under(name, &path)).cloned().collect();
+        let mut directories: Vec<String> = self.directories.keys().filter(|name| paths::is_under(name, &path)).cloned().collect();
+        if !recursive && (!files.is_empty() || !directories.is_empty()) {
+            return Err(io::Error::new(io::ErrorKind::DirectoryNotEmpty, "Directory not empty"));
+        }
+
+        directories.push(path);
+        directories.retain(|directory| self.directories.contains_key(directory));
+        self.publish_with_directories(Vec::new(), &files, Removal::Trash, Vec::new(), directories)?;
+        Ok(files)
+    }
+
+    /// The directories and files in `path`, or everything below it when
+    /// `recursive` is set, each in path order. Directories that only exist
+    /// because of what's below them take their creation time from the
+    /// first entry found there.
+    pub fn list_directory(&self, path: &str, recursive: bool) -> io::Result<(Vec<DirectoryEntry>, Vec<FileEntry>)> {
+        list_in(&self.entries, &self.directories, &paths::normalize(path)?, recursive)
+    }
+
+    /// Renames the file or directory at `from` to `to`, taking everything
+    /// below a directory along. The whole move is one metadata record, so
+    /// after a crash either every name has changed or none has. With
+    /// `overwrite`, a file at `to` is replaced, as is an empty directory
+    /// when moving a directory; otherwise an existing `to` is an error.
+    /// Returns the files moved as `(old, new)` name pairs.
+    pub fn rename(&mut self, from: &str, to: &str, overwrite: bool) -> io::Result<Vec<(String, String)>> {
+        let (from, to) = (paths::normalize(from)?, paths::normalize(to)?);
+        if from.is_empty() || to.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The root directory can't be renamed or replaced"));
+        }
+        if paths::is_under(&to, &from) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Can't move {} into itself", from)));
+        }
+        let source_is_file = self.entries.contains_key(&from);
+        if !source_is_file && !self.is_directory(&from) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "File not found"));
+        }
+        if from == to {
+            return Ok(Vec::new());
+        }
+        self.check_parents(&to)?;
+
+        let replaced = match (source_is_file, self.entries.get(&to), self.is_directory(&to)) {
+            (_, None, false) => None,
+            (true, Some(_), _) if overwrite => self.entries.get(&to).cloned(),
+            (false, None, true) if overwrite && !self.entries.keys().chain(self.directories.keys()).any(|name| paths::is_under(name, &to)) => None,
+            (true, None, true) => return Err(io::Error::new(io::ErrorKind::IsADirectory, format!("{} is a directory", to))),
+            (false, Some(_), _) => return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{} is not a directory", to))),
+            _ => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", to))),
+        };
+
+        let moved: Vec<(String, String)> = self
+            .entries
+            .keys()
+            .filter(|name| **name == from || paths::is_under(name, &from))
+            .map(|name| (name.clone(), format!("{}{}", to, &name[from.len()..])))
+            .collect();
+        let mut entries = self.entries.clone();
+        let mut directories = self.directories.clone();
+        rename_entries(&mut entries, &mut directories, &from, &to);
+        self.metadata.record_rename(&from, &to, &entries, &directories, &self.trash)?;
+        self.entries = entries;
+        self.directories = directories;
+
+        // The replaced file's data is only dropped once the rename is on disk.
+        if let Some(replaced) = replaced {
+            self.free_versions(replaced.versions());
+        }
+        Ok(moved)
+    }
+
+    /// Takes a snapshot named `name` of the directory at `root`, the whole
+    /// file system for `""`. It only records the index: files keep sharing
+    /// their manifests and chunks with it until they change.
+    pub fn create_snapshot(&mut self, name: &str, root: &str) -> io::Result<()> {
+        snapshot::validate_name(name)?;
+        let root = paths::normalize(root)?;
+        if self.snapshots.contains_key(name) {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Snapshot {} already exists", name)));
+        }
+        if self.entries.contains_key(&root) {
+            return Err(io::Error::new(io::ErrorKind::NotADirectory, "Not a directory"));
+        }
+        if !self.is_directory(&root) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "Directory not found"));
+        }
+        let snapshot = Snapshot::capture(name, &root, now_secs(), &self.entries, &self.directories);
+        self.snapshot_store.save(&snapshot)?;
+        self.snapshots.insert(name.to_string(), snapshot);
+        Ok(())
+    }
+
+    /// Every snapshot, oldest first.
+    pub fn snapshots(&self) -> Vec<&Snapshot> {
+        let mut snapshots: Vec<&Snapshot> = self.snapshots.values().collect();
+        snapshots.sort_by(|a, b| (a.created_at, &a.name).cmp(&(b.created_at, &b.name)));
+        snapshots
+    }
+
+    pub fn snapshot(&self, name: &str) -> io::Result<&Snapshot> {
+        self.snapshots
+            .get(name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Snapshot {} not found", name)))
+    }
+
+    /// Lists a directory as it was when the snapshot was taken, like
+    /// `list_directory` does for the live file system.
+    pub fn list_snapshot_directory(&self, name: &str, path: &str, recursive: bool) -> io::Result<(Vec<DirectoryEntry>, Vec<FileEntry>)> {
+        let snapshot = self.snapshot(name)?;
+        list_in(&snapshot.files, &snapshot.directories, &paths::normalize(path)?, recursive)
+    }
+
+    pub fn open_snapshot_reader(&self, name: &str, file_name: &str) -> io::Result<(ChunkedReader, u64)> {
+        let entry = self
+            .snapshot(name)?
+            .files
+            .get(file_name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not found"))?;
+        let manifest = load_manifest(&entry.path)?;
+        let size = manifest.size;
+        let root = self.chunk_store.lock().unwrap().root().to_path_buf();
+        Ok((ChunkedReader::new(&root, manifest, &entry.checksum), size))
+    }
+
+    pub fn retrieve_snapshot_file(&self, name: &str, file_name: &str) -> io::Result<Vec<u8>> {
+        let (mut reader, size) = self.open_snapshot_reader(name, file_name)?;
+        let mut content = Vec::with_capacity(size as usize);
+        reader.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// How the live file system below the snapshot's root differs from it.
+    pub fn diff_snapshot(&self, name: &str) -> io::Result<Vec<SnapshotDiff>> {
+        Ok(self.snapshot(name)?.diff(&self.entries, &self.directories))
+    }
+
+    /// Puts everything below the snapshot's root back the way it was.
+    /// Files changed or deleted since get the snapshot's content as a new
+    /// version, so what they held stays in their history; files added since
+    /// are deleted. All changes are one metadata record. Returns what was
+    /// undone.
+    pub fn rollback_snapshot(&mut self, name: &str) -> io::Result<Vec<SnapshotDiff>> {
+        let snapshot = self.snapshot(name)?.clone();
+        // Something may have been written where the root's ancestors were.
+        self.check_parents(&snapshot.root)?;
+        let changes = snapshot.diff(&self.entries, &self.directories);
+
+        let mut staged: Vec<StagedFile> = Vec::new();
+        let mut deletes = Vec::new();
+        for change in changes.iter().filter(|change| !change.is_directory) {
+            if change.change == Change::Added {
+                deletes.push(change.path.clone());
+                continue;
+            }
+            match self.stage_copy(&change.path, &snapshot.files[&change.path].current()) {
+                Ok(file) => staged.push(file),
+                Err(e) => {
+                    self.discard(staged);
+                    return Err(e);
+                },
+            }
         }
+        let (mut made, mut removed) = (Vec::new(), Vec::new());
+        for change in changes.iter().filter(|change| change.is_directory) {
+            if change.change == Change::Added {
+                removed.push(change.path.clone());
+            } else {
+                made.push(snapshot.directories[&change.path].clone());
+            }
+        }
+        self.publish_with_directories(staged, &deletes, Removal::Trash, made, removed)?;
+        Ok(changes)
+    }
+
+    /// Deletes a snapshot, freeing the content only it still referred to.
+    pub fn delete_snapshot(&mut self, name: &str) -> io::Result<()> {
+        self.snapshot(name)?;
+        self.snapshot_store.remove(name)?;
+        let snapshot = self.snapshots.remove(name).expect("snapshot checked above");
+        let live: HashSet<String> = self.kept_versions().map(|version| version.path).collect();
+        let unused = snapshot
+            .files
+            .values()
+            .map(FileEntry::current)
+            .filter(|version| !live.contains(&version.path))
+            .collect();
+        self.free_versions(unused);
         Ok(())
     }
 
-    fn generate_file_path(&self, file_name: &str) -> String {
-        format!("{}/{}", self.base_dir, file_name)
+    // A file can't be created below another file.
+    fn check_parents(&self, path: &str) -> io::Result<()> {
+        let mut current = paths::parent(path);
+        while let Some(ancestor) = current {
+            if self.entries.contains_key(ancestor) {
+                return Err(io::Error::new(io::ErrorKind::NotADirectory, format!("{} is not a directory", ancestor)));
+            }
+            current = paths::parent(ancestor);
+        }
+        Ok(())
+    }
+
+    fn read_file(&self, file_name: &str) -> io::Result<Vec<u8>> {
+        let (mut reader, size) = self.open_reader(file_name)?;
+        let mut content = Vec::with_capacity(size as usize);
+        reader.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    fn write_and_stage(&self, mut writer: FileWriter, content: &[u8]) -> io::Result<StagedFile> {
+        if let Err(e) = writer.write_all(content) {
+            self.abort_writer(writer)?;
+            return Err(e);
+        }
+        self.stage_writer(writer)
+    }
+
+    // Finishes a writer and saves its manifest, without making the file
+    // visible yet. The manifest gets a path of its own, never the one of the
+    // file it replaces, so nothing changes for readers until `publish`.
+    fn stage_writer(&self, mut writer: FileWriter) -> io::Result<StagedFile> {
+        if let Err(e) = writer.flush() {
+            self.abort_writer(writer)?;
+            return Err(e);
+        }
+        let checksum = writer.checksum.finish();
+        if let Some(expected) = writer.expected_checksum.as_ref() {
+            if *expected != checksum {
+                let message = format!("Checksum mismatch for {}: expected {}, got {}", writer.file_name, expected, checksum);
+                self.chunk_store.lock().unwrap().release(&writer.manifest.chunks)?;
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+        }
+        let manifest = writer.manifest;
+        let path = self.manifest_path();
+        let path = match save_manifest(&path, &manifest).map(|_| path) {
+            Ok(path) => path,
+            Err(e) => {
+                self.chunk_store.lock().unwrap().release(&manifest.chunks)?;
+                return Err(e);
+            },
+        };
+        let now = now_secs();
+        Ok(StagedFile {
+            entry: FileEntry {
+                name: writer.file_name,
+                path,
+                size: manifest.size,
+                checksum,
+                created_at: now,
+                modified_at: now,
+                version: 1,
+                history: Vec::new(),
+                attributes: FileAttributes::default(),
+            },
+            chunks: manifest.chunks,
+            attributes: writer.attributes,
+        })
+    }
+
+    // Makes staged files visible and removes deleted ones with a single
+    // metadata record. A staged file becomes the next version of any file
+    // it replaces. Deleted files go to the trash, or with `Removal::Purge`
+    // are dropped. Only once the record is on disk are versions the
+    // retention policy drops, and purged files, freed; if it can't be
+    // written, the staged files are discarded and the index is left as it
+    // was.
+    fn publish(&mut self, staged: Vec<StagedFile>, deletes: &[String], removal: Removal) -> io::Result<()> {
+        self.publish_with_directories(staged, deletes, removal, Vec::new(), Vec::new())
     }
+
+    // Like `publish`, also creating and removing directories in the same
+    // record. The indexes only change once that record is durable.
+    fn publish_with_directories(
+        &mut self,
+        staged: Vec<StagedFile>,
+        deletes: &[String],
+        removal: Removal,
+        made_directories: Vec<DirectoryEntry>,
+        removed_directories: Vec<String>,
+    ) -> io::Result<()> {
+        if staged.is_empty() && deletes.is_empty() && made_directories.is_empty() && removed_directories.is_empty() {
+            return Ok(());
+        }
+        let now = now_secs();
+        let mut expired = Vec::new();
+        let mut puts = Vec::new();
+        for file in &staged {
+            let mut entry = file.entry.clone();
+            let previous = self.entries.get(&entry.name);
+            if let Some(previous) = previous {
+                entry.created_at = previous.created_at;
+                entry.version = previous.version + 1;
+                entry.history = previous.versions();
+            }
+            entry.attributes = match (&file.attributes, previous) {
+                (Some(attributes), _) => attributes.clone(),
+                (None, Some(previous)) => previous.attributes.clone(),
+                (None, None) => FileAttributes::default(),
+            };
+            expired.extend(self.retention.prune(&mut entry, now));
+            puts.push(entry);
+        }
+        let mut trashed = Vec::new();
+        for name in deletes {
+            let removed = self.entries.get(name);
+            match (removal, removed) {
+                (Removal::Trash, Some(entry)) => {
+                    let id = self.next_trash_id() + trashed.len() as u64;
+                    trashed.push(TrashEntry { id, entry: entry.clone(), deleted_at: now });
+                },
+                _ => expired.extend(removed.into_iter().flat_map(FileEntry::versions)),
+            }
+        }
+        let purged = if removal == Removal::Purge { deletes.to_vec() } else { Vec::new() };
+
+        let change = NamespaceChange { puts, trashed, deletes: purged, made_directories, removed_directories };
+        if let Err(e) = self.metadata.append_change(&change) {
+            self.discard(staged);
+            return Err(e);
+        }
+
+        for entry in change.puts {
+            self.entries.insert(entry.name.clone(), entry);
+        }
+        for name in deletes {
+            self.entries.remove(name);
+        }
+        for trashed in change.trashed {
+            self.trash.insert(trashed.id, trashed);
+        }
+        for directory in change.made_directories {
+            self.directories.insert(directory.path.clone(), directory);
+        }
+        for path in &change.removed_directories {
+            self.directories.remove(path);
+        }
+        self.free_versions(expired);
+        self.metadata.maybe_compact(&self.entries, &self.directories, &self.trash)
+    }
+
+    fn next_trash_id(&self) -> u64 {
+        self.trash.keys().next_back().map_or(1, |id| id + 1)
+    }
+
+    // Chunks shared with content still kept hold their reference from its
+    // manifest, so this only frees data that is no longer used. Manifests a
+    // snapshot refers to stay until the snapshot is deleted.
+    fn free_versions(&self, versions: Vec<FileVersion>) {
+        let pinned: HashSet<&String> = self.snapshots.values().flat_map(|snapshot| snapshot.files.values().map(|entry| &entry.path)).collect();
+        for version in versions.into_iter().filter(|version| !pinned.contains(&version.path)) {
+            let removed = load_manifest(&version.path).and_then(|manifest| {
+                fs::remove_file(&version.path)?;
+                self.chunk_store.lock().unwrap().release(&manifest.chunks)
+            });
+            if let Err(e) = removed {
+                eprintln!("Failed to free {}: {}", version.path, e);
+            }
+        }
+    }
+
+    // Every version of every file, live or in the trash.
+    fn kept_versions(&self) -> impl Iterator<Item = FileVersion> + '_ {
+        self.entries.values().chain(self.trash.values().map(|trashed| &trashed.entry)).flat_map(FileEntry::versions)
+    }
+
+    fn find_version(&self, file_name: &str, version: u64) -> io::Result<FileVersion> {
+        self.stat(file_name)?
+            .versions()
+            .into_iter()
+            .find(|kept| kept.version == version)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Version {} not found", version)))
+    }
+
+    // Stages `version`'s content as a new write of `file_name`. The copy of
+    // the manifest shares the version's chunks, so no data is copied.
+    fn stage_copy(&self, file_name: &str, version: &FileVersion) -> io::Result<StagedFile> {
+        let manifest = load_manifest(&version.path)?;
+        self.chunk_store.lock().unwrap().retain(&manifest.chunks);
+        let path = self.manifest_path();
+        let path = match save_manifest(&path, &manifest).map(|_| path) {
+            Ok(path) => path,
+            Err(e) => {
+                self.chunk_store.lock().unwrap().release(&manifest.chunks)?;
+                return Err(e);
+            },
+        };
+        let now = now_secs();
+        Ok(StagedFile {
+            entry: FileEntry {
+                name: file_name.to_string(),
+                path,
+                size: version.size,
+                checksum: version.checksum.clone(),
+                created_at: now,
+                modified_at: now,
+                version: 1,
+                history: Vec::new(),
+                attributes: FileAttributes::default(),
+            },
+            chunks: manifest.chunks,
+            attributes: None,
+        })
+    }
+
+    // Rolls back staged files that will never be published.
+    fn discard(&self, staged: Vec<StagedFile>) {
+        for file in staged {
+            let removed = fs::remove_file(&file.entry.path).and_then(|_| self.chunk_store.lock().unwrap().release(&file.chunks));
+            if let Err(e) = removed {
+                eprintln!("Failed to discard staged write of {}: {}", file.entry.name, e);
+            }
+        }
+    }
+
+    // A new manifest never overwrites one in use, since until its file is
+    // published the old one is still read. Numbering every manifest from a
+    // counter keeps them apart without looking at what is in use: versions,
+    // the trash, snapshots and prepared writes all hold numbers below it.
+    fn manifest_path(&self) -> String {
+        let number = self.next_manifest.fetch_add(1, Ordering::Relaxed);
+        Path::new(&self.base_dir).join("manifests").join(format!("~{}", number)).to_string_lossy().into_owned()
+    }
+}
+
+// Shared by the live file system and snapshots, which keep the same kind
+// of index.
+fn is_directory_in(entries: &FileIndex, directories: &DirectoryIndex, path: &str) -> bool {
+    path.is_empty()
+        || directories.contains_key(path)
+        || entries.keys().chain(directories.keys()).any(|name| paths::is_under(name, path))
+}
+
+fn list_in(
+    entries: &FileIndex,
+    directories: &DirectoryIndex,
+    path: &str,
+    recursive: bool,
+) -> io::Result<(Vec<DirectoryEntry>, Vec<FileEntry>)> {
+    if entries.contains_key(path) {
+        return Err(io::Error::new(io::ErrorKind::NotADirectory, "Not a directory"));
+    }
+    if !is_directory_in(entries, directories, path) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "Directory not found"));
+    }
+
+    let mut listed: BTreeMap<String, DirectoryEntry> = directories
+        .values()
+        .filter(|directory| paths::is_listed(&directory.path, path, recursive))
+        .map(|directory| (directory.path.clone(), directory.clone()))
+        .collect();
+    let below = entries
+        .values()
+        .map(|entry| (&entry.name, entry.created_at))
+        .chain(directories.values().map(|directory| (&directory.path, directory.created_at)));
+    for (name, created_at) in below {
+        for implied in paths::implied_directories(name, path, recursive) {
+            listed
+                .entry(implied.clone())
+                .or_insert(DirectoryEntry { path: implied, created_at });
+        }
+    }
+
+    let mut files: Vec<FileEntry> = entries
+        .values()
+        .filter(|entry| paths::is_listed(&entry.name, path, recursive))
+        .cloned()
+        .collect();
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok((listed.into_values().collect(), files))
+}
+
+fn load_manifest(path: &str) -> io::Result<Manifest> {
+    let data = fs::read(path)?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+// Numbering starts past every manifest on disk, whether the index still
+// refers to it or not. Manifests from before numbering sit at paths named
+// after their files; a top-level one that looks like a number counts too.
+fn first_free_manifest_number(manifest_dir: &Path) -> io::Result<u64> {
+    let names = match fs::read_dir(manifest_dir) {
+        Ok(names) => names,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(1),
+        Err(e) => return Err(e),
+    };
+    let mut next = 1;
+    for name in names {
+        let name = name?.file_name();
+        if let Some(number) = name.to_str().and_then(|name| name.strip_prefix('~')).and_then(|number| number.parse::<u64>().ok()) {
+            next = next.max(number + 1);
+        }
+    }
+    Ok(next)
+}
+
+// A manifest is only found under its path once it is complete.
+fn save_manifest(path: &str, manifest: &Manifest) -> io::Result<()> {
+    write_atomic(path, &serde_json::to_vec(manifest)?)
 }
 
+// Only the standalone binary starts here; the server loads this file as a
+// module and never calls it.
+#[allow(dead_code)]
 fn main() {
-    let mut dfs = DistributedFileSystem::new();
+    let mut dfs = DistributedFileSystem::new().expect("Failed to open the metadata index");
 
     // Example of batching storage operations
     let files_to_store = vec![
@@ -71,6 +1175,31 @@ fn main() {
     ];
     dfs.store_files(&files_to_store).unwrap();
 
+    // Example of a best-effort batch: the missing file is reported while
+    // the others are still read
+    let names = vec!["example.txt".to_string(), "missing.txt".to_string(), "another_file.txt".to_string()];
+    let report = dfs.retrieve_files_best_effort(&names);
+    for failure in report.failures() {
+        println!("Could not retrieve {}: {:?}", failure.name, failure.result.as_ref().err());
+    }
+
+    // Example of directories
+    dfs.create_directory("examples/nested", true).unwrap();
+    let (directories, files) = dfs.list_directory("", true).unwrap();
+    println!("Directories: {:?}", directories.iter().map(|directory| &directory.path).collect::<Vec<_>>());
+    println!("Files: {:?}", files.iter().map(|file| &file.name).collect::<Vec<_>>());
+    dfs.remove_directory("examples", true).unwrap();
+
+    // Example of a snapshot: later changes show up in the diff, and rolling
+    // back undoes them
+    dfs.create_snapshot("before-edit", "").unwrap();
+    dfs.store_files(&[("example.txt".to_string(), b"Edited".to_vec())]).unwrap();
+    for change in dfs.diff_snapshot("before-edit").unwrap() {
+        println!("Changed since snapshot: {} ({:?})", change.path, change.change);
+    }
+    dfs.rollback_snapshot("before-edit").unwrap();
+    dfs.delete_snapshot("before-edit").unwrap();
+
     // Example of batched retrieval
     let file_names = files_to_store.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
     let retrieved_contents = dfs.retrieve_files(&file_names).unwrap();
@@ -78,6 +1207,146 @@ fn main() {
         println!("Retrieved content for {}: {:?}", name, String::from_utf8_lossy(&content));
     }
 
-    // Example of batched delete
+    // Example of a one-off integrity check
+    let dfs = Mutex::new(dfs);
+    let report = scrubber::scrub(&dfs, None);
+    println!("Scrub report: {}", serde_json::to_string_pretty(&report).unwrap());
+
+    // Example of batched delete: the files go to the trash, from where one
+    // is brought back
+    let mut dfs = dfs.lock().unwrap();
     dfs.delete_files(&file_names).unwrap();
-}
\ No newline at end of file
+    println!("Trash: {:?}", dfs.list_trash().iter().map(|trashed| &trashed.entry.name).collect::<Vec<_>>());
+    dfs.undelete("example.txt").unwrap();
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::scratch::ScratchDir;
+
+    fn open(dir: &Path) -> DistributedFileSystem {
+        let retention = RetentionPolicy { keep_last: 1, keep_days: None, trash_days: DEFAULT_TRASH_DAYS };
+        DistributedFileSystem::open(dir.to_string_lossy().into_owned(), retention).unwrap()
+    }
+
+    // Chunks on disk, which only stay while something holds a reference.
+    fn stored_chunks(dir: &Path) -> Vec<String> {
+        let mut chunks = Vec::new();
+        let mut pending = vec![dir.join("chunks")];
+        while let Some(dir) = pending.pop() {
+            for entry in fs::read_dir(&dir).into_iter().flatten().flatten() {
+                if entry.path().is_dir() {
+                    pending.push(entry.path());
+                } else {
+                    chunks.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+        chunks.sort();
+        chunks
+    }
+
+    fn batch(files: &[(&str, &[u8])]) -> Vec<(String, Vec<u8>)> {
+        files.iter().map(|(name, content)| (name.to_string(), content.to_vec())).collect()
+    }
+
+    #[test]
+    fn a_failed_store_batch_leaves_nothing_behind() {
+        let dir = ScratchDir::new("storage-store-batch");
+        let mut dfs = open(&dir);
+        dfs.store_files(&batch(&[("kept", b"shared")])).unwrap();
+        let chunks = stored_chunks(&dir);
+
+        // Both files are written before the repeated name fails the batch.
+        let error = dfs.store_files(&batch(&[("new", b"fresh"), ("other", b"shared"), ("new", b"again")])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(dfs.list_files(), ["kept"]);
+        assert_eq!(stored_chunks(&dir), chunks);
+
+        // The shared chunk holds a single reference again.
+        dfs.purge_files(&["kept".to_string()]).unwrap();
+        assert!(stored_chunks(&dir).is_empty());
+
+        // Nothing of the failed batch was recorded either.
+        assert!(open(&dir).list_files().is_empty());
+    }
+
+    #[test]
+    fn a_failed_delete_batch_deletes_nothing() {
+        let dir = ScratchDir::new("storage-delete-batch");
+        let mut dfs = open(&dir);
+        dfs.store_files(&batch(&[("a", b"a"), ("b", b"b")])).unwrap();
+
+        let error = dfs.delete_files(&["a".to_string(), "missing".to_string()]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(dfs.list_files(), ["a", "b"]);
+        assert!(dfs.list_trash().is_empty());
+        assert_eq!(stored_chunks(&dir).len(), 2);
+    }
+
+    #[test]
+    fn a_snapshot_keeps_overwritten_and_deleted_files_readable() {
+        let dir = ScratchDir::new("storage-snapshot-pins");
+        let mut dfs = open(&dir);
+        dfs.store_files(&batch(&[("a", b"first a"), ("b", b"first b")])).unwrap();
+        dfs.create_snapshot("before", "").unwrap();
+
+        dfs.store_files(&batch(&[("a", b"second a")])).unwrap();
+        dfs.purge_files(&["b".to_string()]).unwrap();
+        dfs.prune_versions().unwrap();
+        assert_eq!(dfs.list_files(), ["a"]);
+        // The live `a` and both files the snapshot holds.
+        assert_eq!(stored_chunks(&dir).len(), 3);
+
+        // The snapshot still holds its manifests and chunks after a restart.
+        let dfs = open(&dir);
+        assert_eq!(dfs.retrieve_snapshot_file("before", "a").unwrap(), b"first a");
+        assert_eq!(dfs.retrieve_snapshot_file("before", "b").unwrap(), b"first b");
+    }
+
+    #[test]
+    fn rolling_back_a_snapshot_restores_its_exact_content() {
+        let dir = ScratchDir::new("storage-snapshot-rollback");
+        let mut dfs = open(&dir);
+        dfs.store_files(&batch(&[("a", b"first a"), ("b", b"first b")])).unwrap();
+        dfs.create_snapshot("before", "").unwrap();
+        dfs.store_files(&batch(&[("a", b"second a"), ("c", b"added")])).unwrap();
+        dfs.purge_files(&["b".to_string()]).unwrap();
+        dfs.prune_versions().unwrap();
+
+        dfs.rollback_snapshot("before").unwrap();
+        assert_eq!(dfs.list_files(), ["a", "b"]);
+        let files = dfs.retrieve_files(&["a".to_string(), "b".to_string()]).unwrap();
+        assert_eq!(files["a"], b"first a");
+        assert_eq!(files["b"], b"first b");
+        assert!(dfs.diff_snapshot("before").unwrap().is_empty());
+
+        // The file added since went to the trash.
+        let trashed: Vec<&str> = dfs.list_trash().iter().map(|trashed| trashed.entry.name.as_str()).collect();
+        assert_eq!(trashed, ["c"]);
+
+        // Once the snapshot is gone, only the live files and the trash are kept.
+        dfs.delete_snapshot("before").unwrap();
+        dfs.prune_versions().unwrap();
+        assert_eq!(stored_chunks(&dir).len(), 3);
+    }
+
+    #[test]
+    fn directory_changes_are_one_record_each() {
+        let dir = ScratchDir::new("storage-directories");
+        let mut dfs = open(&dir);
+        let log_lines = || fs::read_to_string(dir.join("metadata.log")).unwrap().lines().count();
+
+        dfs.create_directory("a/b/c", true).unwrap();
+        assert_eq!(log_lines(), 1);
+        dfs.store_files(&batch(&[("a/b/file", b"content")])).unwrap();
+        let before = log_lines();
+        assert_eq!(dfs.remove_directory("a", true).unwrap(), ["a/b/file"]);
+        assert_eq!(log_lines(), before + 1);
+
+        let dfs = open(&dir);
+        assert!(!dfs.is_directory("a"));
+        let trashed: Vec<&str> = dfs.list_trash().iter().map(|trashed| trashed.entry.name.as_str()).collect();
+        assert_eq!(trashed, ["a/b/file"]);
+    }
+}
@@ -0,0 +1,255 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::paths::SafePath;
+
+/// Deleted files and directories are kept here, below the storage
+/// directory, until they are restored or purged.
+pub const TRASH_DIR: &str = ".trash";
+
+const DEFAULT_RETENTION_DAYS: u64 = 30;
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+// Each bin records the path it holds in this file, beside a `files`
+// directory mirroring the storage directory.
+const PATH_FILE: &str = "path";
+const FILES_DIR: &str = "files";
+
+/// Soft deletes for tools that store files directly in a directory tree.
+/// Every delete moves its path into a bin of its own, `.trash/<secs>-<n>`,
+/// at the same place in the tree, so restoring is a rename back. Hidden
+/// directories that mirror the tree, like a checksum directory, are
+/// `companions`: what they hold for the path moves along with it.
+pub struct Trash {
+    storage_dir: PathBuf,
+    companions: Vec<&'static str>,
+    retention_days: u64,
+}
+
+/// One file or directory waiting in the trash.
+pub struct Trashed {
+    pub path: String,
+    pub deleted_at: u64,
+    bin: PathBuf,
+}
+
+impl Trash {
+    /// TRASH_RETENTION_DAYS sets how long deletes are kept, 30 days by
+    /// default.
+    pub fn from_env(storage_dir: impl AsRef<Path>, companions: &[&'static str]) -> Result<Self, String> {
+        let retention_days = match env::var("TRASH_RETENTION_DAYS") {
+            Ok(days) => days.parse().map_err(|_| format!("Invalid TRASH_RETENTION_DAYS: {}", days))?,
+            Err(_) => DEFAULT_RETENTION_DAYS,
+        };
+        Ok(Self { storage_dir: storage_dir.as_ref().to_path_buf(), companions: companions.to_vec(), retention_days })
+    }
+
+    /// Moves `path` to the trash.
+    pub fn delete(&self, path: &SafePath) -> io::Result<()> {
+        let source = path.under(&self.storage_dir);
+        if fs::symlink_metadata(&source).is_err() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} not found", path)));
+        }
+        let bin = self.new_bin()?;
+        fs::write(bin.join(PATH_FILE), path.as_str())?;
+        let files = bin.join(FILES_DIR);
+        if let Err(e) = move_path(&source, &path.under(&files)) {
+            let _ = fs::remove_dir_all(&bin);
+            return Err(e);
+        }
+        for companion in &self.companions {
+            move_path(&path.under(self.storage_dir.join(companion)), &path.under(files.join(companion)))?;
+        }
+        Ok(())
+    }
+
+    /// Brings back the most recent delete of `path`. Nothing may have taken
+    /// its place in the meantime.
+    pub fn restore(&self, path: &SafePath) -> io::Result<()> {
+        let bin = self
+            .list()?
+            .into_iter()
+            .find(|trashed| trashed.path == path.as_str())
+            .map(|trashed| trashed.bin)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not in the trash", path)))?;
+        let target = path.under(&self.storage_dir);
+        if fs::symlink_metadata(&target).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", path)));
+        }
+        let files = bin.join(FILES_DIR);
+        move_path(&path.under(&files), &target)?;
+        for companion in &self.companions {
+            move_path(&path.under(files.join(companion)), &path.under(self.storage_dir.join(companion)))?;
+        }
+        fs::remove_dir_all(&bin)
+    }
+
+    /// Everything in the trash, newest first.
+    pub fn list(&self) -> io::Result<Vec<Trashed>> {
+        let mut listed = Vec::new();
+        for (bin, deleted_at) in self.bins()?.into_iter().rev() {
+            // A bin without its path is a delete that was interrupted
+            // before it moved anything.
+            let path = match fs::read_to_string(bin.join(PATH_FILE)) {
+                Ok(path) => path,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            listed.push(Trashed { path, deleted_at, bin });
+        }
+        Ok(listed)
+    }
+
+    /// Removes what was deleted longer ago than the retention period.
+    /// Returns how many deletes were purged.
+    pub fn purge(&self) -> io::Result<usize> {
+        let cutoff = now_secs().saturating_sub(self.retention_days * SECS_PER_DAY);
+        let mut purged = 0;
+        for (bin, deleted_at) in self.bins()? {
+            if deleted_at < cutoff {
+                fs::remove_dir_all(&bin)?;
+                purged += 1;
+            }
+        }
+        Ok(purged)
+    }
+
+    fn new_bin(&self) -> io::Result<PathBuf> {
+        let trash = self.storage_dir.join(TRASH_DIR);
+        fs::create_dir_all(&trash)?;
+        let secs = now_secs();
+        for n in 0.. {
+            let bin = trash.join(format!("{}-{}", secs, n));
+            match fs::create_dir(&bin) {
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                result => return result.map(|_| bin),
+            }
+        }
+        unreachable!()
+    }
+
+    // Every bin with the time of its delete, oldest first.
+    fn bins(&self) -> io::Result<Vec<(PathBuf, u64)>> {
+        let entries = match fs::read_dir(self.storage_dir.join(TRASH_DIR)) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut bins = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let order = name.split_once('-').and_then(|(secs, n)| Some((secs.parse::<u64>().ok()?, n.parse::<u64>().ok()?)));
+            if let Some(order) = order {
+                bins.push((order, entry.path()));
+            }
+        }
+        bins.sort();
+        Ok(bins.into_iter().map(|((secs, _), bin)| (bin, secs)).collect())
+    }
+}
+
+// Renames `from` to `to`, creating `to`'s parents. A missing `from` is
+// nothing to move.
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(from).is_err() {
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::rename(from, to)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::scratch::ScratchDir;
+
+    const COMPANION: &str = ".companion";
+
+    fn scratch_trash(name: &str, retention_days: u64) -> (ScratchDir, Trash) {
+        let dir = ScratchDir::new(&format!("trash-{}", name));
+        let trash = Trash { storage_dir: dir.to_path_buf(), companions: vec![COMPANION], retention_days };
+        (dir, trash)
+    }
+
+    fn write(trash: &Trash, path: &str, content: &str) {
+        let path = trash.storage_dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn read(trash: &Trash, path: &str) -> Option<String> {
+        fs::read_to_string(trash.storage_dir.join(path)).ok()
+    }
+
+    fn safe(path: &str) -> SafePath {
+        SafePath::parse(path).unwrap()
+    }
+
+    #[test]
+    fn restore_brings_back_a_file_and_its_companions() {
+        let (_dir, trash) = scratch_trash("restore", 30);
+        write(&trash, "dir/file", "content");
+        write(&trash, ".companion/dir/file", "sidecar");
+
+        trash.delete(&safe("dir/file")).unwrap();
+        assert_eq!(read(&trash, "dir/file"), None);
+        assert_eq!(read(&trash, ".companion/dir/file"), None);
+        let listed: Vec<String> = trash.list().unwrap().into_iter().map(|trashed| trashed.path).collect();
+        assert_eq!(listed, ["dir/file"]);
+
+        trash.restore(&safe("dir/file")).unwrap();
+        assert_eq!(read(&trash, "dir/file").as_deref(), Some("content"));
+        assert_eq!(read(&trash, ".companion/dir/file").as_deref(), Some("sidecar"));
+        assert!(trash.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restore_refuses_to_replace_and_prefers_the_latest_delete() {
+        let (_dir, trash) = scratch_trash("collision", 30);
+        write(&trash, "file", "first");
+        trash.delete(&safe("file")).unwrap();
+        write(&trash, "file", "second");
+        trash.delete(&safe("file")).unwrap();
+        write(&trash, "file", "third");
+
+        let error = trash.restore(&safe("file")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(read(&trash, "file").as_deref(), Some("third"));
+        assert_eq!(trash.list().unwrap().len(), 2);
+
+        fs::remove_file(trash.storage_dir.join("file")).unwrap();
+        trash.restore(&safe("file")).unwrap();
+        assert_eq!(read(&trash, "file").as_deref(), Some("second"));
+        fs::remove_file(trash.storage_dir.join("file")).unwrap();
+        trash.restore(&safe("file")).unwrap();
+        assert_eq!(read(&trash, "file").as_deref(), Some("first"));
+        assert_eq!(trash.restore(&safe("file")).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn purge_removes_only_deletes_past_retention() {
+        let (_dir, trash) = scratch_trash("purge", 1);
+        write(&trash, "old", "old");
+        write(&trash, "new", "new");
+        trash.delete(&safe("old")).unwrap();
+        trash.delete(&safe("new")).unwrap();
+        // Backdates the first delete to two days ago.
+        let (old_bin, _) = trash.bins().unwrap().remove(0);
+        let two_days_ago = now_secs() - 2 * SECS_PER_DAY;
+        fs::rename(&old_bin, trash.storage_dir.join(TRASH_DIR).join(format!("{}-0", two_days_ago))).unwrap();
+
+        assert_eq!(trash.purge().unwrap(), 1);
+        let listed: Vec<String> = trash.list().unwrap().into_iter().map(|trashed| trashed.path).collect();
+        assert_eq!(listed, ["new"]);
+    }
+}
@@ -1,7 +1,6 @@
 use std::env;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Write};
-use std::path::Path;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LogLevel {
@@ -24,7 +23,7 @@ impl Logger {
             Ok("ERROR") => LogLevel::Error,
             Ok("WARNING") => LogLevel::Warning,
             Ok("INFO") => LogLevel::Info,
-            Ok("DEBUG") | _ => LogLevel::Debug,
+            _ => LogLevel::Debug,
         };
 
         Ok(Self {